@@ -0,0 +1,430 @@
+//! Wallet accounts given by an extended public key or an output descriptor, whose scripts are
+//! derived server-side until a gap of unused ones, so that wallets can query a whole account with
+//! a single request rather than one per address.
+//!
+//! Supported are the single-key descriptors `pkh(KEY)`, `wpkh(KEY)` and `sh(wpkh(KEY))`, where
+//! `KEY` is an xpub (optionally preceded by its `[fingerprint/path]` origin) followed by unhardened
+//! derivation steps and a final `/*`, like `wpkh([d34db33f/84'/0'/0']xpub.../<0;1>/*)`. A bare
+//! xpub derives its receive (`/0/*`) and change (`/1/*`) chains, as `pkh` for xpub/tpub, `sh(wpkh)`
+//! for ypub/upub and `wpkh` for zpub/vpub (SLIP-132). Descriptor checksums are not verified.
+
+use std::collections::HashMap;
+
+use bitcoin::network::constants::Network as BNetwork;
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::util::base58;
+use bitcoin::util::bip32::{ChildNumber, ExtendedPubKey};
+use bitcoin::{PublicKey, Script};
+
+use crate::chain::{Network, Txid};
+use crate::errors::*;
+use crate::new_index::{compute_script_hash, Query, Utxo};
+use crate::util::{BlockId, FullHash};
+
+pub const DEFAULT_GAP_LIMIT: u32 = 20;
+pub const MAX_GAP_LIMIT: u32 = 1000;
+/// Scripts derived per chain, beyond which the scan fails rather than going on
+const MAX_CHAIN_SCRIPTS: u32 = 10_000;
+/// Scripts derived across all the chains of an account, beyond which the scan fails
+const MAX_ACCOUNT_SCRIPTS: u32 = 20_000;
+/// Chains of a multipath `<a;b;..>` step
+const MAX_CHAINS: usize = 8;
+
+const XPUB: [u8; 4] = [0x04, 0x88, 0xb2, 0x1e];
+const TPUB: [u8; 4] = [0x04, 0x35, 0x87, 0xcf];
+const YPUB: [u8; 4] = [0x04, 0x9d, 0x7c, 0xb2];
+const UPUB: [u8; 4] = [0x04, 0x4a, 0x52, 0x62];
+const ZPUB: [u8; 4] = [0x04, 0xb2, 0x47, 0x46];
+const VPUB: [u8; 4] = [0x04, 0x5f, 0x1c, 0xf6];
+// SLIP-132 version bytes, along with the xpub version and the script type they stand for
+const VERSIONS: [([u8; 4], [u8; 4], ScriptType); 6] = [
+    (XPUB, XPUB, ScriptType::P2pkh),
+    (TPUB, TPUB, ScriptType::P2pkh),
+    (YPUB, XPUB, ScriptType::P2shP2wpkh),
+    (UPUB, TPUB, ScriptType::P2shP2wpkh),
+    (ZPUB, XPUB, ScriptType::P2wpkh),
+    (VPUB, TPUB, ScriptType::P2wpkh),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ScriptType {
+    P2pkh,
+    P2shP2wpkh,
+    P2wpkh,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Account {
+    xpub: ExtendedPubKey,
+    script_type: ScriptType,
+    /// The derivation path of each chain, before the index of its scripts
+    chains: Vec<Vec<ChildNumber>>,
+}
+
+/// A used script of the account
+#[derive(Debug)]
+pub struct AccountScript {
+    pub chain: usize,
+    pub index: u32,
+    pub script: Script,
+    pub scripthash: FullHash,
+}
+
+/// The used scripts of an account, found by scanning its chains up to the gap limit
+#[derive(Debug)]
+pub struct AccountScan {
+    pub scripts: Vec<AccountScript>,
+    /// The index of the first unused script of each chain, following the last used one
+    pub next_indexes: Vec<u32>,
+}
+
+impl Account {
+    pub fn parse(s: &str, network: Network) -> Result<Self> {
+        let s = s.trim();
+        // the checksum is optional
+        let s = s.split('#').next().unwrap();
+
+        let (script_type, key) = if let Some(key) = strip_wrapper(s, "sh(wpkh(", "))") {
+            (Some(ScriptType::P2shP2wpkh), key)
+        } else if let Some(key) = strip_wrapper(s, "wpkh(", ")") {
+            (Some(ScriptType::P2wpkh), key)
+        } else if let Some(key) = strip_wrapper(s, "pkh(", ")") {
+            (Some(ScriptType::P2pkh), key)
+        } else {
+            ensure!(!s.contains('('), "unsupported descriptor {:?}", s);
+            (None, s)
+        };
+
+        // the key origin only tells where the xpub comes from
+        let key = match key.strip_prefix('[') {
+            Some(key) => key
+                .splitn(2, ']')
+                .nth(1)
+                .chain_err(|| "invalid key origin")?,
+            None => key,
+        };
+        let mut steps = key.split('/');
+        let (xpub, key_type) = parse_xpub(steps.next().unwrap())?;
+
+        let expected_network = match network {
+            Network::Testnet | Network::Regtest | Network::Signet => BNetwork::Testnet,
+            _ => BNetwork::Bitcoin,
+        };
+        ensure!(
+            xpub.network == expected_network,
+            "extended public key is for another network"
+        );
+
+        let steps: Vec<&str> = steps.collect();
+        let chains = if script_type.is_none() && steps.is_empty() {
+            // bare xpubs derive their receive and change chains
+            vec![
+                vec![ChildNumber::Normal { index: 0 }],
+                vec![ChildNumber::Normal { index: 1 }],
+            ]
+        } else {
+            parse_chains(steps)?
+        };
+
+        Ok(Account {
+            xpub,
+            script_type: script_type.unwrap_or(key_type),
+            chains,
+        })
+    }
+
+    /// Derive the scripts of each chain until `gap_limit` consecutive ones were never used
+    pub fn scan(&self, query: &Query, gap_limit: u32) -> Result<AccountScan> {
+        self.scan_with(gap_limit, |scripthash| {
+            !query.history_txids(scripthash, 1).is_empty()
+        })
+    }
+
+    fn scan_with<F>(&self, gap_limit: u32, is_used: F) -> Result<AccountScan>
+    where
+        F: Fn(&FullHash) -> bool,
+    {
+        ensure!(
+            gap_limit > 0 && gap_limit <= MAX_GAP_LIMIT,
+            "gap limit must be between 1 and {}",
+            MAX_GAP_LIMIT
+        );
+        let secp = Secp256k1::verification_only();
+        let mut scan = AccountScan {
+            scripts: vec![],
+            next_indexes: vec![],
+        };
+        let mut derived = 0;
+        for (chain, path) in self.chains.iter().enumerate() {
+            let chain_xpub = self
+                .xpub
+                .derive_pub(&secp, path)
+                .chain_err(|| "failed deriving chain")?;
+            let mut next_index = 0;
+            let mut index = 0;
+            while index < next_index + gap_limit {
+                ensure!(
+                    index < MAX_CHAIN_SCRIPTS,
+                    "account has more than {} scripts per chain",
+                    MAX_CHAIN_SCRIPTS
+                );
+                ensure!(
+                    derived < MAX_ACCOUNT_SCRIPTS,
+                    "account has more than {} scripts",
+                    MAX_ACCOUNT_SCRIPTS
+                );
+                derived += 1;
+                let child = ChildNumber::from_normal_idx(index).chain_err(|| "invalid index")?;
+                let key = chain_xpub
+                    .derive_pub(&secp, &[child])
+                    .chain_err(|| "failed deriving script")?;
+                let script = self.script_type.script(PublicKey::new(key.public_key));
+                let scripthash = compute_script_hash(&script);
+                if is_used(&scripthash) {
+                    scan.scripts.push(AccountScript {
+                        chain,
+                        index,
+                        script,
+                        scripthash,
+                    });
+                    next_index = index + 1;
+                }
+                index += 1;
+            }
+            scan.next_indexes.push(next_index);
+        }
+        Ok(scan)
+    }
+}
+
+impl AccountScan {
+    /// The confirmed and unconfirmed balance of the account
+    pub fn balance(&self, query: &Query) -> (u64, i64) {
+        self.scripts
+            .iter()
+            .map(|script| query.stats(&script.scripthash))
+            .fold((0, 0), |(confirmed, unconfirmed), (chain, mempool)| {
+                (
+                    confirmed + chain.funded_txo_sum - chain.spent_txo_sum,
+                    unconfirmed + mempool.funded_txo_sum as i64 - mempool.spent_txo_sum as i64,
+                )
+            })
+    }
+
+    /// The unspent outputs of the account, along with the script they belong to
+    pub fn utxos(&self, query: &Query) -> Result<Vec<(&AccountScript, Utxo)>> {
+        let mut utxos = vec![];
+        for script in &self.scripts {
+            let script_utxos = query.utxo(&script.scripthash)?;
+            utxos.extend(script_utxos.into_iter().map(|utxo| (script, utxo)));
+        }
+        Ok(utxos)
+    }
+
+    /// The transactions of the account, the confirmed ones by height followed by the unconfirmed
+    /// ones, failing if there are more than `limit`
+    pub fn history_txids(
+        &self,
+        query: &Query,
+        limit: usize,
+    ) -> Result<Vec<(Txid, Option<BlockId>)>> {
+        let mut txids: HashMap<Txid, Option<BlockId>> = HashMap::new();
+        for script in &self.scripts {
+            // one extra to tell whether the history was truncated
+            txids.extend(query.history_txids(&script.scripthash, limit + 1));
+            ensure!(txids.len() <= limit, ErrorKind::TooPopular);
+        }
+        let mut txids: Vec<_> = txids.into_iter().collect();
+        // transactions of the same block are ordered by txid, as their position isn't indexed
+        txids.sort_by_key(|(txid, blockid)| {
+            (blockid.as_ref().map_or(usize::MAX, |b| b.height), *txid)
+        });
+        Ok(txids)
+    }
+}
+
+impl ScriptType {
+    fn script(self, key: PublicKey) -> Script {
+        let wpkh = || Script::new_v0_p2wpkh(&key.wpubkey_hash().expect("compressed key"));
+        match self {
+            ScriptType::P2pkh => Script::new_p2pkh(&key.pubkey_hash()),
+            ScriptType::P2shP2wpkh => Script::new_p2sh(&wpkh().script_hash()),
+            ScriptType::P2wpkh => wpkh(),
+        }
+    }
+}
+
+fn strip_wrapper<'a>(s: &'a str, prefix: &str, suffix: &str) -> Option<&'a str> {
+    s.strip_prefix(prefix)?.strip_suffix(suffix)
+}
+
+/// Parse the extended public key, given with any of the SLIP-132 version bytes
+fn parse_xpub(s: &str) -> Result<(ExtendedPubKey, ScriptType)> {
+    let mut data = base58::from_check(s).chain_err(|| "invalid extended public key")?;
+    ensure!(data.len() == 78, "invalid extended public key length");
+    let (_, xpub_version, script_type) = VERSIONS
+        .iter()
+        .find(|(version, _, _)| data[..4] == version[..])
+        .chain_err(|| "unknown extended public key version")?;
+    // only the xpub and tpub versions are understood when decoding
+    data[..4].copy_from_slice(xpub_version);
+    let xpub = ExtendedPubKey::decode(&data).chain_err(|| "invalid extended public key")?;
+    Ok((xpub, *script_type))
+}
+
+/// Parse the derivation steps following the xpub, ending with `/*` and possibly using a single
+/// `<a;b;..>` step for several chains
+fn parse_chains(steps: Vec<&str>) -> Result<Vec<Vec<ChildNumber>>> {
+    let (last, steps) = steps
+        .split_last()
+        .chain_err(|| "derivation path must end with /*")?;
+    ensure!(*last == "*", "derivation path must end with unhardened /*");
+
+    let mut chains = vec![vec![]];
+    for step in steps {
+        let indexes = match strip_wrapper(step, "<", ">") {
+            Some(indexes) => {
+                ensure!(chains.len() == 1, "only one multipath step is supported");
+                let indexes: Vec<&str> = indexes.split(';').take(MAX_CHAINS + 1).collect();
+                ensure!(
+                    indexes.len() <= MAX_CHAINS,
+                    "multipath step has more than {} chains",
+                    MAX_CHAINS
+                );
+                indexes
+            }
+            None => vec![*step],
+        };
+        let indexes = indexes
+            .into_iter()
+            .map(|index| {
+                index
+                    .parse()
+                    .ok()
+                    .and_then(|index| ChildNumber::from_normal_idx(index).ok())
+                    .chain_err(|| format!("invalid unhardened derivation step {:?}", index))
+            })
+            .collect::<Result<Vec<ChildNumber>>>()?;
+        chains = chains
+            .into_iter()
+            .flat_map(|chain| {
+                indexes.iter().map(move |index| {
+                    let mut chain = chain.clone();
+                    chain.push(*index);
+                    chain
+                })
+            })
+            .collect();
+    }
+    Ok(chains)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the BIP32 test vector 1 master key, as xpub and zpub
+    const MASTER_XPUB: &str = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+    const MASTER_ZPUB: &str = "zpub6jftahH18ngZxUuv6oSniLNrBCSSE1B4EEU59bwTCEt8x6aS6b2mdfLxbS4QS53g85SWWP6wexqeer516433gYpZQoJie2tcMYdJ1SYYYAL";
+
+    #[test]
+    fn test_parse() {
+        let xpub = Account::parse(MASTER_XPUB, Network::Bitcoin).unwrap();
+        assert_eq!(xpub.script_type, ScriptType::P2pkh);
+        assert_eq!(xpub.chains.len(), 2);
+
+        let zpub = Account::parse(MASTER_ZPUB, Network::Bitcoin).unwrap();
+        assert_eq!(zpub.script_type, ScriptType::P2wpkh);
+        assert_eq!(zpub.xpub, xpub.xpub);
+
+        let descriptor = format!(
+            "sh(wpkh([d34db33f/49'/0'/0']{}/<0;1>/*))#checksum",
+            MASTER_XPUB
+        );
+        let account = Account::parse(&descriptor, Network::Bitcoin).unwrap();
+        assert_eq!(account.script_type, ScriptType::P2shP2wpkh);
+        assert_eq!(
+            account,
+            Account {
+                script_type: ScriptType::P2shP2wpkh,
+                ..xpub
+            }
+        );
+
+        let descriptor = format!("wpkh({}/0/*)", MASTER_XPUB);
+        let account = Account::parse(&descriptor, Network::Bitcoin).unwrap();
+        assert_eq!(account.chains, vec![vec![ChildNumber::Normal { index: 0 }]]);
+
+        assert!(Account::parse(MASTER_XPUB, Network::Testnet).is_err());
+        assert!(Account::parse(&format!("wpkh({}/0h/*)", MASTER_XPUB), Network::Bitcoin).is_err());
+        assert!(Account::parse(&format!("wpkh({}/0)", MASTER_XPUB), Network::Bitcoin).is_err());
+        assert!(Account::parse(&format!("tr({}/0/*)", MASTER_XPUB), Network::Bitcoin).is_err());
+
+        let chains = |n: u32| {
+            let indexes: Vec<String> = (0..n).map(|i| i.to_string()).collect();
+            format!("wpkh({}/<{}>/*)", MASTER_XPUB, indexes.join(";"))
+        };
+        let account = Account::parse(&chains(MAX_CHAINS as u32), Network::Bitcoin).unwrap();
+        assert_eq!(account.chains.len(), MAX_CHAINS);
+        assert!(Account::parse(&chains(MAX_CHAINS as u32 + 1), Network::Bitcoin).is_err());
+        assert!(Account::parse(&chains(100_000), Network::Bitcoin).is_err());
+    }
+
+    fn scripthash(account: &Account, chain: usize, index: u32) -> FullHash {
+        let secp = Secp256k1::verification_only();
+        let mut path = account.chains[chain].clone();
+        path.push(ChildNumber::Normal { index });
+        let key = account.xpub.derive_pub(&secp, &path).unwrap();
+        compute_script_hash(&account.script_type.script(PublicKey::new(key.public_key)))
+    }
+
+    #[test]
+    fn test_scan() {
+        let descriptor = format!("wpkh({}/<0;1>/*)", MASTER_XPUB);
+        let account = Account::parse(&descriptor, Network::Bitcoin).unwrap();
+        let used = vec![scripthash(&account, 0, 3), scripthash(&account, 1, 0)];
+
+        let scan = account
+            .scan_with(5, |scripthash| used.contains(scripthash))
+            .unwrap();
+        let found: Vec<_> = scan.scripts.iter().map(|s| (s.chain, s.index)).collect();
+        assert_eq!(found, vec![(0, 3), (1, 0)]);
+        assert_eq!(scan.next_indexes, vec![4, 1]);
+
+        // a script beyond the gap limit isn't found
+        let scan = account
+            .scan_with(3, |scripthash| used.contains(scripthash))
+            .unwrap();
+        let found: Vec<_> = scan.scripts.iter().map(|s| (s.chain, s.index)).collect();
+        assert_eq!(found, vec![(1, 0)]);
+        assert_eq!(scan.next_indexes, vec![0, 1]);
+
+        assert!(account.scan_with(0, |_| false).is_err());
+        assert!(account.scan_with(MAX_GAP_LIMIT + 1, |_| false).is_err());
+    }
+
+    #[test]
+    fn test_scan_limits() {
+        // every script being used, the scan stops at the per-chain limit
+        let descriptor = format!("wpkh({}/0/*)", MASTER_XPUB);
+        let account = Account::parse(&descriptor, Network::Bitcoin).unwrap();
+        let checked = std::cell::Cell::new(0);
+        let result = account.scan_with(1, |_| {
+            checked.set(checked.get() + 1);
+            true
+        });
+        assert!(result.is_err());
+        assert_eq!(checked.get(), MAX_CHAIN_SCRIPTS);
+
+        // and at the total limit across chains, before deriving the third chain's scripts
+        let descriptor = format!("wpkh({}/<0;1;2>/*)", MASTER_XPUB);
+        let account = Account::parse(&descriptor, Network::Bitcoin).unwrap();
+        let checked = std::cell::Cell::new(0);
+        let result = account.scan_with(1, |_| {
+            checked.set(checked.get() + 1);
+            checked.get() % MAX_CHAIN_SCRIPTS != 0
+        });
+        assert!(result.is_err());
+        assert_eq!(checked.get(), MAX_ACCOUNT_SCRIPTS);
+    }
+}
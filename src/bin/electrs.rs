@@ -106,7 +106,6 @@ fn run_server(config: Arc<Config>) -> Result<()> {
         if let Err(err) = signal.wait(Duration::from_secs(5), true) {
             info!("stopping server: {}", err);
             rest_server.stop();
-            // the electrum server is stopped when dropped
             break;
         }
 
@@ -122,7 +121,11 @@ fn run_server(config: Arc<Config>) -> Result<()> {
 
         // Update subscribed clients
         electrum_server.notify();
+        rest_server.notify();
     }
+    // the electrum server drains its connections when dropped
+    drop(electrum_server);
+    store.flush();
     info!("server stopped");
     Ok(())
 }
@@ -0,0 +1,354 @@
+//! Broadcasting transactions through other backends than the attached bitcoind, so that its
+//! mempool policy rejecting a transaction doesn't keep it from propagating. Transactions are
+//! submitted to all backends at once, and are considered broadcast if any of the JSON-RPC ones
+//! accepts. Relaying to P2P peers is best-effort, as they don't report whether they accepted. In
+//! `--tor-only` mode, all backends are connected to through the tor proxy.
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+use bitcoin::hashes::hex::FromHex;
+use crossbeam_channel::{Sender, TrySendError};
+
+use crate::chain::Txid;
+use crate::config::Config;
+use crate::daemon::{self, Daemon};
+use crate::errors::*;
+use crate::util::spawn_thread;
+
+/// Connecting to and hearing back from each backend
+const BACKEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Submissions to the backends waiting for a worker thread. Further ones are dropped, rather than
+/// piling up while the backends are slow.
+const MAX_QUEUED_JOBS: usize = 100;
+
+/// A submission of a transaction to a backend
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A bitcoind JSON-RPC endpoint, given as `[user:password@]ip:port`
+#[derive(Clone)]
+pub struct RpcEndpoint {
+    addr: SocketAddr,
+    auth: Option<String>,
+}
+
+impl FromStr for RpcEndpoint {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (auth, addr) = match s.rfind('@') {
+            Some(i) => (Some(s[..i].to_string()), &s[i + 1..]),
+            None => (None, s),
+        };
+        let addr = addr
+            .parse()
+            .chain_err(|| format!("invalid RPC endpoint address {:?}", addr))?;
+        Ok(RpcEndpoint { addr, auth })
+    }
+}
+
+// the credentials are left out, as the config is logged
+impl fmt::Debug for RpcEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RpcEndpoint({})", self.addr)
+    }
+}
+
+impl RpcEndpoint {
+    fn send_raw_transaction(&self, proxy: Option<SocketAddr>, txhex: &str) -> Result<Txid> {
+        let auth = self.auth.as_ref().map(String::as_bytes);
+        let txid = daemon::call_endpoint(
+            self.addr,
+            proxy,
+            auth,
+            "sendrawtransaction",
+            json!([txhex]),
+            BACKEND_TIMEOUT,
+        )?;
+        Ok(
+            Txid::from_hex(txid.as_str().chain_err(|| "non-string txid")?)
+                .chain_err(|| "failed to parse txid")?,
+        )
+    }
+}
+
+/// Submits transactions to the attached bitcoind, and to the other configured backends
+pub struct Broadcaster {
+    rpc_endpoints: Vec<RpcEndpoint>,
+    #[cfg(not(feature = "liquid"))]
+    p2p_peers: Vec<SocketAddr>,
+    #[cfg(not(feature = "liquid"))]
+    magic: u32,
+    /// The SOCKS5 proxy to connect to the backends through
+    proxy: Option<SocketAddr>,
+    jobs: Option<Sender<Job>>,
+}
+
+impl Broadcaster {
+    /// Start a worker thread per backend, so that a broadcast reaches all of them at once
+    pub fn new(config: &Config) -> Self {
+        #[cfg(not(feature = "liquid"))]
+        let backends = config.broadcast_rpc.len() + config.broadcast_p2p_peers.len();
+        #[cfg(feature = "liquid")]
+        let backends = config.broadcast_rpc.len();
+
+        #[cfg(feature = "electrum-discovery")]
+        let proxy = config.tor_proxy.filter(|_| config.tor_only);
+        #[cfg(not(feature = "electrum-discovery"))]
+        let proxy = None;
+
+        let jobs = if backends > 0 {
+            let (sender, receiver) = crossbeam_channel::bounded::<Job>(MAX_QUEUED_JOBS);
+            for _ in 0..backends {
+                let receiver = receiver.clone();
+                spawn_thread("broadcast", move || {
+                    for job in receiver {
+                        job();
+                    }
+                });
+            }
+            Some(sender)
+        } else {
+            None
+        };
+        Broadcaster {
+            rpc_endpoints: config.broadcast_rpc.clone(),
+            #[cfg(not(feature = "liquid"))]
+            p2p_peers: config.broadcast_p2p_peers.clone(),
+            #[cfg(not(feature = "liquid"))]
+            magic: config.network_type.magic(),
+            proxy,
+            jobs,
+        }
+    }
+
+    /// Queue the submission to a backend for the worker threads
+    fn submit(&self, backend: &SocketAddr, job: Job) {
+        let jobs = self.jobs.as_ref().expect("no broadcast workers");
+        if let Err(TrySendError::Full(_)) = jobs.try_send(job) {
+            warn!("not broadcasting through {}, too many queued", backend);
+        }
+    }
+
+    /// Broadcast the transaction, succeeding if the attached bitcoind or any of the JSON-RPC
+    /// backends accepts it. The error of the attached bitcoind is returned if none does. Once
+    /// bitcoind accepted it, the other backends are left to finish in the background.
+    pub fn broadcast(&self, daemon: &Daemon, txhex: &str) -> Result<Txid> {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        for endpoint in &self.rpc_endpoints {
+            let (endpoint, txhex, sender) = (endpoint.clone(), txhex.to_string(), sender.clone());
+            let (addr, proxy) = (endpoint.addr, self.proxy);
+            self.submit(
+                &addr,
+                Box::new(move || {
+                    let result = endpoint.send_raw_transaction(proxy, &txhex);
+                    log_result(&endpoint.addr, &result);
+                    let _ = sender.send(result);
+                }),
+            );
+        }
+        drop(sender);
+        #[cfg(not(feature = "liquid"))]
+        self.broadcast_p2p(txhex);
+
+        let err = match daemon.broadcast_raw(txhex) {
+            Ok(txid) => return Ok(txid),
+            Err(err) => err,
+        };
+        match receiver.iter().find_map(|result| result.ok()) {
+            Some(txid) => {
+                info!(
+                    "transaction {} was accepted by another backend, although bitcoind rejected it: {}",
+                    txid, err
+                );
+                Ok(txid)
+            }
+            None => Err(err),
+        }
+    }
+
+    /// Relay the transaction to the P2P peers in the background, without waiting for them
+    #[cfg(not(feature = "liquid"))]
+    fn broadcast_p2p(&self, txhex: &str) {
+        if self.p2p_peers.is_empty() {
+            return;
+        }
+        // invalid transactions are left to be rejected by bitcoind
+        let tx: crate::chain::Transaction = match hex::decode(txhex)
+            .ok()
+            .and_then(|raw| bitcoin::consensus::deserialize(&raw).ok())
+        {
+            Some(tx) => tx,
+            None => return,
+        };
+        for peer in &self.p2p_peers {
+            let (peer, tx, magic, proxy) = (*peer, tx.clone(), self.magic, self.proxy);
+            self.submit(
+                &peer,
+                Box::new(move || {
+                    let result = p2p::send_transaction(peer, proxy, magic, tx);
+                    log_result(&peer, &result);
+                }),
+            );
+        }
+    }
+}
+
+fn log_result(backend: &SocketAddr, result: &Result<Txid>) {
+    match result {
+        Ok(txid) => debug!("broadcast {} through {}", txid, backend),
+        Err(e) => warn!("broadcasting through {} failed: {}", backend, e),
+    }
+}
+
+/// Relaying transactions to bitcoin nodes over their P2P protocol
+#[cfg(not(feature = "liquid"))]
+mod p2p {
+    use std::io::{BufReader, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+    use bitcoin::consensus::{serialize, Decodable};
+    use bitcoin::network::address::Address;
+    use bitcoin::network::constants::ServiceFlags;
+    use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+    use bitcoin::network::message_blockdata::Inventory;
+    use bitcoin::network::message_network::VersionMessage;
+
+    use super::BACKEND_TIMEOUT;
+    use crate::chain::{Transaction, Txid};
+    use crate::errors::*;
+    use crate::util::socks;
+
+    /// Announce the transaction to the peer, and send it once the peer requests it. Nodes don't
+    /// report whether they accepted it, so this only tells that the peer wanted it, which doesn't
+    /// count as the transaction being broadcast.
+    pub fn send_transaction(
+        peer: SocketAddr,
+        proxy: Option<SocketAddr>,
+        magic: u32,
+        tx: Transaction,
+    ) -> Result<Txid> {
+        let txid = tx.txid();
+        let mut stream = match proxy {
+            Some(proxy) => {
+                let host = peer.ip().to_string();
+                socks::connect(proxy, None, &host, peer.port(), Some(BACKEND_TIMEOUT))?
+            }
+            None => {
+                let stream = TcpStream::connect_timeout(&peer, BACKEND_TIMEOUT)
+                    .chain_err(|| format!("failed to connect to {}", peer))?;
+                stream
+                    .set_read_timeout(Some(BACKEND_TIMEOUT))
+                    .chain_err(|| "failed setting read timeout")?;
+                stream
+            }
+        };
+        let mut reader = BufReader::new(stream.try_clone().chain_err(|| "failed to clone stream")?);
+        let mut send = |payload| {
+            let msg = RawNetworkMessage { magic, payload };
+            stream
+                .write_all(&serialize(&msg))
+                .chain_err(|| format!("failed sending to {}", peer))
+        };
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |t| t.as_secs() as i64);
+        let local = SocketAddr::from(([0, 0, 0, 0], 0));
+        send(NetworkMessage::Version(VersionMessage::new(
+            ServiceFlags::NONE,
+            timestamp,
+            Address::new(&peer, ServiceFlags::NONE),
+            Address::new(&local, ServiceFlags::NONE),
+            timestamp as u64, // nonce
+            format!("/electrs:{}/", env!("CARGO_PKG_VERSION")),
+            0,
+        )))?;
+
+        let deadline = Instant::now() + BACKEND_TIMEOUT;
+        while Instant::now() < deadline {
+            let msg = RawNetworkMessage::consensus_decode(&mut reader)
+                .chain_err(|| format!("failed reading from {}", peer))?;
+            ensure!(msg.magic == magic, "{} is on another network", peer);
+            match msg.payload {
+                NetworkMessage::Version(_) => send(NetworkMessage::Verack)?,
+                NetworkMessage::Verack => {
+                    send(NetworkMessage::Inv(vec![Inventory::Transaction(txid)]))?
+                }
+                NetworkMessage::Ping(nonce) => send(NetworkMessage::Pong(nonce))?,
+                NetworkMessage::GetData(inventory) => {
+                    let requested = inventory.iter().any(|inv| match inv {
+                        Inventory::Transaction(id) | Inventory::WitnessTransaction(id) => {
+                            *id == txid
+                        }
+                        _ => false,
+                    });
+                    if requested {
+                        send(NetworkMessage::Tx(tx))?;
+                        return Ok(txid);
+                    }
+                }
+                _ => (),
+            }
+        }
+        bail!("{} didn't request the transaction", peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::io::{BufRead, Read, Write};
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_rpc_endpoint() {
+        let endpoint: RpcEndpoint = "user:p@ss@127.0.0.1:8332".parse().unwrap();
+        assert_eq!(endpoint.auth.as_deref(), Some("user:p@ss"));
+        assert_eq!(format!("{:?}", endpoint), "RpcEndpoint(127.0.0.1:8332)");
+        assert!("user:pass@localhost".parse::<RpcEndpoint>().is_err());
+
+        // a node rejecting the transaction
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint: RpcEndpoint = listener.local_addr().unwrap().to_string().parse().unwrap();
+        let node = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+            let (mut line, mut content_length) = (String::new(), 0);
+            loop {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+                if let Some(len) = line.strip_prefix("Content-Length: ") {
+                    content_length = len.trim().parse().unwrap();
+                }
+                if line.trim().is_empty() {
+                    break;
+                }
+            }
+            let mut request = vec![0; content_length];
+            reader.read_exact(&mut request).unwrap();
+            let request: Value = serde_json::from_slice(&request).unwrap();
+            assert_eq!(request["method"], "sendrawtransaction");
+            assert_eq!(request["params"], json!(["00"]));
+            let body = concat!(
+                r#"{"result":null,"error":{"code":-26,"message":"min relay fee not met"},"id":0}"#,
+                "\n"
+            );
+            write!(
+                &stream,
+                "HTTP/1.1 500 Internal Server Error\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+        });
+        let err = endpoint.send_raw_transaction(None, "00").unwrap_err();
+        assert!(err.to_string().contains("min relay fee not met"), "{}", err);
+        node.join().unwrap();
+    }
+}
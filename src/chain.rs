@@ -21,7 +21,7 @@ pub type Value = u64;
 #[cfg(feature = "liquid")]
 pub use confidential::Value;
 
-#[derive(Debug, Copy, Clone, PartialEq, Hash, Serialize, Ord, PartialOrd, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd, Eq)]
 pub enum Network {
     #[cfg(not(feature = "liquid"))]
     Bitcoin,
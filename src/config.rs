@@ -1,8 +1,9 @@
 use clap::{App, Arg};
 use dirs::home_dir;
+use hyper::header::HeaderValue;
 use std::fs;
-use std::net::SocketAddr;
 use std::net::ToSocketAddrs;
+use std::net::{IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use stderrlog;
@@ -27,18 +28,48 @@ pub struct Config {
     pub daemon_rpc_addr: SocketAddr,
     pub cookie: Option<String>,
     pub electrum_rpc_addr: SocketAddr,
+    pub electrum_ws_addr: Option<SocketAddr>,
+    pub electrum_ssl_addr: Option<SocketAddr>,
+    pub electrum_wss_addr: Option<SocketAddr>,
+    pub electrum_tls_cert: Option<PathBuf>,
+    pub electrum_tls_key: Option<PathBuf>,
+    pub electrum_socket_file: Option<PathBuf>,
+    pub electrum_socket_mode: Option<u32>,
     pub http_addr: SocketAddr,
     pub http_socket_file: Option<PathBuf>,
+    pub trusted_proxies: Vec<IpAddr>,
+    pub broadcast_rpc: Vec<crate::broadcast::RpcEndpoint>,
+    #[cfg(not(feature = "liquid"))]
+    pub broadcast_p2p_peers: Vec<SocketAddr>,
     pub monitoring_addr: SocketAddr,
     pub jsonrpc_import: bool,
     pub light_mode: bool,
     pub address_search: bool,
     pub index_unspendables: bool,
     pub cors: Option<String>,
+    pub cors_methods: String,
+    pub cors_headers: String,
+    pub cors_max_age: u64,
+    pub http_batch_limit: usize,
+    pub http_compression_min_size: usize,
     pub precache_scripts: Option<String>,
     pub utxos_limit: usize,
     pub electrum_txs_limit: usize,
+    pub electrum_max_batch_size: usize,
     pub electrum_banner: String,
+    pub electrum_banner_file: Option<PathBuf>,
+    pub electrum_address_methods: bool,
+    pub electrum_admin_addr: Option<SocketAddr>,
+    pub electrum_donation_address: Option<String>,
+    pub electrum_rate_limit: Option<f64>,
+    pub electrum_max_in_flight: Option<usize>,
+    pub electrum_max_subscriptions: Option<usize>,
+    pub electrum_max_subscriptions_per_ip: Option<usize>,
+    pub electrum_max_connections_per_ip: Option<usize>,
+    pub electrum_max_tracked_ips: usize,
+    pub electrum_idle_timeout: Option<std::time::Duration>,
+    pub electrum_drain_timeout: std::time::Duration,
+    pub electrum_resume_ttl: Option<std::time::Duration>,
 
     #[cfg(feature = "liquid")]
     pub parent_network: BNetwork,
@@ -50,7 +81,91 @@ pub struct Config {
     #[cfg(feature = "electrum-discovery")]
     pub electrum_announce: bool,
     #[cfg(feature = "electrum-discovery")]
+    pub electrum_default_servers: Option<PathBuf>,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_cert_verification: crate::electrum::CertVerification,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_check_freq: std::time::Duration,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_max_failures: usize,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_max_queue: usize,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_max_servers_per_request: usize,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_max_services_per_request: usize,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_interleave: bool,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_snapshot_dir: Option<PathBuf>,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_snapshot_interval: std::time::Duration,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_snapshot_retain: usize,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_bootstrap_url: Option<String>,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_bootstrap_interval: std::time::Duration,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_bootstrap_as_default: bool,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_workers: usize,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_max_onion_checks: Option<usize>,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_request_limit_policy: crate::electrum::RequestLimitPolicy,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_trust_private_ranges: bool,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_require_self_advertised: bool,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_pinned_readd_cooldown: Option<std::time::Duration>,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_verify_subscriptions: bool,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_banned_hosts: std::collections::HashSet<String>,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_banned_ranges: Vec<crate::electrum::IpRange>,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_ban_precedence: crate::electrum::BanPrecedence,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_latency_method: crate::electrum::LatencyMethod,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_reresolve_interval: Option<std::time::Duration>,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_reresolve_after_failures: Option<usize>,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_gossip_lifetime: Option<std::time::Duration>,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_crawl: bool,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_max_crawled_per_peer: usize,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_sample_size: Option<usize>,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_sample_include_defaults: bool,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_max_requests_per_ip: Option<usize>,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_request_window: std::time::Duration,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_max_pending_per_ip: Option<usize>,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_min_score: Option<f64>,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_min_score_checks: usize,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_timeout: std::time::Duration,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_asn_db: Option<PathBuf>,
+    #[cfg(feature = "electrum-discovery")]
+    pub electrum_discovery_max_features_no_response: Option<usize>,
+    #[cfg(feature = "electrum-discovery")]
     pub tor_proxy: Option<std::net::SocketAddr>,
+    #[cfg(feature = "electrum-discovery")]
+    pub tor_control: Option<std::net::SocketAddr>,
+    #[cfg(feature = "electrum-discovery")]
+    pub tor_only: bool,
 }
 
 fn str_to_socketaddr(address: &str, what: &str) -> SocketAddr {
@@ -62,6 +177,17 @@ fn str_to_socketaddr(address: &str, what: &str) -> SocketAddr {
         .unwrap()
 }
 
+/// Check that the comma-separated values of the CORS option can be sent as header values, for the
+/// HTTP server to build its CORS headers out of them without failing
+fn cors_values(values: &str, what: &str) -> String {
+    for value in values.split(',') {
+        if HeaderValue::from_str(value.trim()).is_err() {
+            panic!("invalid {}: {}", what, value);
+        }
+    }
+    values.to_string()
+}
+
 impl Config {
     pub fn from_args() -> Config {
         let network_help = format!("Select network type ({})", Network::names().join(", "));
@@ -115,12 +241,58 @@ impl Config {
                     .help("Electrum server JSONRPC 'addr:port' to listen on (default: '127.0.0.1:50001' for mainnet, '127.0.0.1:60001' for testnet and '127.0.0.1:60401' for regtest)")
                     .takes_value(true),
             )
+            .arg(
+                Arg::with_name("electrum_ws_addr")
+                    .long("electrum-ws-addr")
+                    .help("Electrum server JSONRPC over WebSocket 'addr:port' to listen on, for browser wallets (disabled by default)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("electrum_ssl_addr")
+                    .long("electrum-ssl-addr")
+                    .help("Electrum server JSONRPC over SSL 'addr:port' to listen on (disabled by default)")
+                    .takes_value(true)
+                    .requires_all(&["electrum_tls_cert", "electrum_tls_key"]),
+            )
+            .arg(
+                Arg::with_name("electrum_wss_addr")
+                    .long("electrum-wss-addr")
+                    .help("Electrum server JSONRPC over secure WebSocket 'addr:port' to listen on (disabled by default)")
+                    .takes_value(true)
+                    .requires_all(&["electrum_tls_cert", "electrum_tls_key"]),
+            )
+            .arg(
+                Arg::with_name("electrum_tls_cert")
+                    .long("electrum-tls-cert")
+                    .help("Path to the PEM certificate chain of the SSL listeners. Re-read on SIGHUP, along with the key.")
+                    .takes_value(true)
+                    .requires("electrum_tls_key"),
+            )
+            .arg(
+                Arg::with_name("electrum_tls_key")
+                    .long("electrum-tls-key")
+                    .help("Path to the PEM private key (PKCS#8 or RSA) of the SSL listeners")
+                    .takes_value(true)
+                    .requires("electrum_tls_cert"),
+            )
             .arg(
                 Arg::with_name("http_addr")
                     .long("http-addr")
                     .help("HTTP server 'addr:port' to listen on (default: '127.0.0.1:3000' for mainnet, '127.0.0.1:3001' for testnet and '127.0.0.1:3002' for regtest)")
                     .takes_value(true),
             )
+            .arg(
+                Arg::with_name("trusted_proxies")
+                    .long("trusted-proxies")
+                    .help("Comma-separated IPs of proxies sending a PROXY protocol (v1 or v2) header with the client's address, on the Electrum and HTTP (TCP) listeners. Connections from them must start with the header.")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("broadcast_rpc")
+                    .long("broadcast-rpc")
+                    .help("Comma-separated bitcoind JSON-RPC endpoints ('[user:password@]ip:port') to broadcast transactions through too. Broadcasts succeed if any of them or the attached bitcoind accepts.")
+                    .takes_value(true),
+            )
             .arg(
                 Arg::with_name("daemon_rpc_addr")
                     .long("daemon-rpc-addr")
@@ -156,9 +328,39 @@ impl Config {
             .arg(
                 Arg::with_name("cors")
                     .long("cors")
-                    .help("Origins allowed to make cross-site requests")
+                    .help("Origins allowed to make cross-site requests to the HTTP API, comma-separated, or '*' for any origin")
                     .takes_value(true)
             )
+            .arg(
+                Arg::with_name("cors_methods")
+                    .long("cors-methods")
+                    .help("Methods allowed in cross-site requests, comma-separated")
+                    .default_value("GET, POST")
+            )
+            .arg(
+                Arg::with_name("cors_headers")
+                    .long("cors-headers")
+                    .help("Request headers allowed in cross-site requests, comma-separated (any with '--cors *')")
+                    .default_value("Content-Type")
+            )
+            .arg(
+                Arg::with_name("cors_max_age")
+                    .long("cors-max-age")
+                    .help("Number of seconds browsers may cache the CORS preflight responses for")
+                    .default_value("3600")
+            )
+            .arg(
+                Arg::with_name("http_batch_limit")
+                    .long("http-batch-limit")
+                    .help("Maximum number of addresses and scripthashes in the batch queries of the HTTP API")
+                    .default_value("50")
+            )
+            .arg(
+                Arg::with_name("http_compression_min_size")
+                    .long("http-compression-min-size")
+                    .help("Minimum size in bytes of the HTTP API's JSON and hex responses to compress, for the clients accepting brotli or gzip")
+                    .default_value("1024")
+            )
             .arg(
                 Arg::with_name("precache_scripts")
                     .long("precache-scripts")
@@ -174,21 +376,109 @@ impl Config {
             .arg(
                 Arg::with_name("electrum_txs_limit")
                     .long("electrum-txs-limit")
-                    .help("Maximum number of transactions returned by Electrum history queries. Lookups with more results will fail.")
+                    .help("Maximum number of transactions returned by Electrum history queries, and by account history queries of the HTTP API. Lookups with more results will fail.")
                     .default_value("500")
+            ).arg(
+                Arg::with_name("electrum_max_batch_size")
+                    .long("electrum-max-batch-size")
+                    .help("Maximum number of requests in Electrum JSON-RPC batches")
+                    .default_value("100")
             ).arg(
                 Arg::with_name("electrum_banner")
                     .long("electrum-banner")
                     .help("Welcome banner for the Electrum server, shown in the console to clients.")
                     .takes_value(true)
+            ).arg(
+                Arg::with_name("electrum_banner_file")
+                    .long("electrum-banner-file")
+                    .help("Path to a banner template for the Electrum server, re-read on every request. $VERSION, $TIP_HEIGHT, $NETWORK, $UPTIME, $TIME and $DONATION_ADDRESS are substituted.")
+                    .takes_value(true)
+            ).arg(
+                Arg::with_name("electrum_disable_address_methods")
+                    .long("electrum-disable-address-methods")
+                    .help("Disable the legacy blockchain.address.* Electrum methods, which take addresses rather than scripthashes")
+            ).arg(
+                Arg::with_name("electrum_admin_addr")
+                    .long("electrum-admin-addr")
+                    .help("Electrum server JSONRPC 'addr:port' to listen on for the operator, serving the admin.* methods for inspecting and managing the server too. Bind it to localhost or a private interface (disabled by default)")
+                    .takes_value(true)
+            ).arg(
+                Arg::with_name("electrum_donation_address")
+                    .long("electrum-donation-address")
+                    .help("Donation address returned to Electrum clients")
+                    .takes_value(true)
+            ).arg(
+                Arg::with_name("electrum_rate_limit")
+                    .long("electrum-rate-limit")
+                    .help("Maximum Electrum RPC requests per second per client IP, with bursts of up to a second's worth (unlimited by default)")
+                    .takes_value(true)
+            ).arg(
+                Arg::with_name("electrum_max_in_flight")
+                    .long("electrum-max-in-flight")
+                    .help("Maximum Electrum RPC requests processed at once per client IP, across its connections (unlimited by default)")
+                    .takes_value(true)
+            ).arg(
+                Arg::with_name("electrum_max_subscriptions")
+                    .long("electrum-max-subscriptions")
+                    .help("Maximum Electrum subscriptions per connection (unlimited by default)")
+                    .takes_value(true)
+            ).arg(
+                Arg::with_name("electrum_max_subscriptions_per_ip")
+                    .long("electrum-max-subscriptions-per-ip")
+                    .help("Maximum Electrum subscriptions per client IP, across its connections (unlimited by default)")
+                    .takes_value(true)
+            ).arg(
+                Arg::with_name("electrum_max_connections_per_ip")
+                    .long("electrum-max-connections-per-ip")
+                    .help("Maximum Electrum connections per client IP (unlimited by default)")
+                    .takes_value(true)
+            ).arg(
+                Arg::with_name("electrum_max_tracked_ips")
+                    .long("electrum-max-tracked-ips")
+                    .help("Maximum number of client IPs tracked at once by the per-IP limits (and of peer IPs tracked by discovery), beyond which the least recently active ones are forgotten")
+                    .default_value("10000")
+            ).arg(
+                Arg::with_name("electrum_idle_timeout")
+                    .long("electrum-idle-timeout")
+                    .help("Disconnect Electrum clients that made no requests for this many minutes and have no subscriptions (disabled by default)")
+                    .takes_value(true)
+            ).arg(
+                Arg::with_name("electrum_drain_timeout")
+                    .long("electrum-drain-timeout")
+                    .help("Seconds to let Electrum clients finish their requests when shutting down, before disconnecting them")
+                    .default_value("10")
+            ).arg(
+                Arg::with_name("electrum_resume_ttl")
+                    .long("electrum-resume-ttl")
+                    .help("Enable session resumption, keeping the subscriptions of disconnected Electrum clients for this many minutes (disabled by default)")
+                    .takes_value(true)
             );
 
+        #[cfg(not(feature = "liquid"))]
+        let args = args.arg(
+            Arg::with_name("broadcast_p2p_peers")
+                .long("broadcast-p2p-peers")
+                .help("Comma-separated bitcoin nodes ('ip:port') to relay broadcast transactions to over the P2P network")
+                .takes_value(true),
+        );
+
         #[cfg(unix)]
         let args = args.arg(
                 Arg::with_name("http_socket_file")
                     .long("http-socket-file")
                     .help("HTTP server 'unix socket file' to listen on (default disabled, enabling this disables the http server)")
                     .takes_value(true),
+            ).arg(
+                Arg::with_name("electrum_socket_file")
+                    .long("electrum-socket-file")
+                    .help("Electrum RPC 'unix socket file' to listen on, in addition to the TCP listeners (default disabled)")
+                    .takes_value(true),
+            ).arg(
+                Arg::with_name("electrum_socket_mode")
+                    .long("electrum-socket-mode")
+                    .help("Octal permissions of the Electrum RPC unix socket file, like 660 (default: according to the umask)")
+                    .requires("electrum_socket_file")
+                    .takes_value(true),
             );
 
         #[cfg(feature = "liquid")]
@@ -216,11 +506,218 @@ impl Config {
                 Arg::with_name("electrum_announce")
                     .long("electrum-announce")
                     .help("Announce the Electrum server to other servers")
+            ).arg(
+                Arg::with_name("electrum_default_servers")
+                    .long("electrum-default-servers")
+                    .help("Path to a JSON file of default servers to use instead of the built-in ones, mapping hostnames to their services (like {\"electrum.example.com\": [\"t50001\", \"s50002\"]}). Re-read on SIGHUP.")
+                    .takes_value(true)
+            ).arg(
+                Arg::with_name("electrum_discovery_cert_verification")
+                    .long("electrum-discovery-cert-verification")
+                    .help("Which discovered SSL servers are required to present a CA-signed certificate. Others may use self-signed certificates, which are advertised as such.")
+                    .possible_values(&["all", "clearnet", "none"])
+                    .default_value("clearnet")
+            ).arg(
+                Arg::with_name("electrum_discovery_check_freq")
+                    .long("electrum-discovery-check-freq")
+                    .help("How often to health check each discovered server (in seconds)")
+                    .default_value("3600")
+            ).arg(
+                Arg::with_name("electrum_discovery_max_failures")
+                    .long("electrum-discovery-max-failures")
+                    .help("Give up on discovered servers after this many consecutive failed health checks")
+                    .default_value("24")
+            ).arg(
+                Arg::with_name("electrum_discovery_max_queue")
+                    .long("electrum-discovery-max-queue")
+                    .help("Maximum number of discovered services to track. Requests to add more will be refused.")
+                    .default_value("500")
+            ).arg(
+                Arg::with_name("electrum_discovery_max_servers_per_request")
+                    .long("electrum-discovery-max-servers-per-request")
+                    .help("Maximum number of hosts accepted per server.add_peer request")
+                    .default_value("3")
+            ).arg(
+                Arg::with_name("electrum_discovery_max_services_per_request")
+                    .long("electrum-discovery-max-services-per-request")
+                    .help("Maximum number of services (ports) accepted per server.add_peer request")
+                    .default_value("6")
+            ).arg(
+                Arg::with_name("electrum_discovery_interleave")
+                    .long("electrum-discovery-interleave")
+                    .help("Alternate between clearnet and onion health checks when both are due, to mix outgoing traffic rather than batch it by transport")
+            ).arg(
+                Arg::with_name("electrum_discovery_snapshot_dir")
+                    .long("electrum-discovery-snapshot-dir")
+                    .help("Directory to periodically write timestamped snapshots of the advertised server list to, for auditing")
+                    .takes_value(true)
+            ).arg(
+                Arg::with_name("electrum_discovery_snapshot_interval")
+                    .long("electrum-discovery-snapshot-interval")
+                    .help("How often to write a server list snapshot (in minutes)")
+                    .default_value("60")
+            ).arg(
+                Arg::with_name("electrum_discovery_snapshot_retain")
+                    .long("electrum-discovery-snapshot-retain")
+                    .help("Number of server list snapshots to keep, older ones are deleted")
+                    .default_value("24")
+            ).arg(
+                Arg::with_name("electrum_discovery_bootstrap_url")
+                    .long("electrum-discovery-bootstrap-url")
+                    .help("URL of a JSON peer list, in the server.peers.subscribe format, to fetch servers from at startup and then periodically")
+                    .takes_value(true)
+            ).arg(
+                Arg::with_name("electrum_discovery_bootstrap_interval")
+                    .long("electrum-discovery-bootstrap-interval")
+                    .help("How often to refetch the bootstrap peer list (in minutes)")
+                    .default_value("360")
+            ).arg(
+                Arg::with_name("electrum_discovery_bootstrap_as_default")
+                    .long("electrum-discovery-bootstrap-as-default")
+                    .help("Treat the servers of the bootstrap peer list as default servers, rather than as candidates subject to the server.add_peer limits")
+            ).arg(
+                Arg::with_name("electrum_discovery_workers")
+                    .long("electrum-discovery-workers")
+                    .help("Number of threads running health checks concurrently")
+                    .default_value("1")
+            ).arg(
+                Arg::with_name("electrum_discovery_max_onion_checks")
+                    .long("electrum-discovery-max-onion-checks")
+                    .help("Maximum number of (slow) onion health checks running at once, to keep the remaining workers available for clearnet checks. Defaults to half of the workers, rounded up.")
+                    .takes_value(true)
+            ).arg(
+                Arg::with_name("electrum_discovery_request_limit_policy")
+                    .long("electrum-discovery-request-limit-policy")
+                    .help("How to handle server.add_peer requests with more hosts or services than accepted per request: reject them, or process the entries within the limit, either silently (truncate) or logging the dropped ones (warn)")
+                    .possible_values(&["reject", "truncate", "warn"])
+                    .default_value("truncate")
+            ).arg(
+                Arg::with_name("electrum_discovery_trust_private_ranges")
+                    .long("electrum-discovery-trust-private-ranges")
+                    .help("Accept servers advertising loopback, private and link-local addresses, for lab setups")
+            ).arg(
+                Arg::with_name("electrum_discovery_require_self_advertised")
+                    .long("electrum-discovery-require-self-advertised")
+                    .help("Reject server.add_peer requests that don't advertise the requesting host itself. Note that this rejects requests made over tor.")
+            ).arg(
+                Arg::with_name("electrum_discovery_pinned_readd_cooldown")
+                    .long("electrum-discovery-pinned-readd-cooldown")
+                    .help("Re-queue the servers pinned with discovery.pin this long after giving up on them, for another chance (in minutes)")
+                    .takes_value(true)
+            ).arg(
+                Arg::with_name("electrum_discovery_verify_subscriptions")
+                    .long("electrum-discovery-verify-subscriptions")
+                    .help("Verify that discovered servers handle blockchain.scripthash.subscribe on every health check, and don't list those that don't")
+            ).arg(
+                Arg::with_name("electrum_discovery_banned_hosts")
+                    .long("electrum-discovery-banned-hosts")
+                    .help("Comma-separated hostnames of servers never to queue or list")
+                    .takes_value(true)
+            ).arg(
+                Arg::with_name("electrum_discovery_banned_ranges")
+                    .long("electrum-discovery-banned-ranges")
+                    .help("Comma-separated IP ranges in CIDR notation (like 192.0.2.0/24) never to queue or list servers in")
+                    .takes_value(true)
+            ).arg(
+                Arg::with_name("electrum_discovery_ban_precedence")
+                    .long("electrum-discovery-ban-precedence")
+                    .help("Whether the bans or the default servers win when a default server is also banned")
+                    .possible_values(&["ban", "default"])
+                    .default_value("ban")
+            ).arg(
+                Arg::with_name("electrum_discovery_latency_method")
+                    .long("electrum-discovery-latency-method")
+                    .help("What to measure as the latency of discovered servers: opening the TCP connection, completing its TLS and websocket handshakes too, or the server.features round trip on top")
+                    .possible_values(&["connect", "handshake", "round-trip"])
+                    .default_value("round-trip")
+            ).arg(
+                Arg::with_name("electrum_discovery_reresolve_interval")
+                    .long("electrum-discovery-reresolve-interval")
+                    .help("Re-resolve the hostnames of discovered servers this often, following their IP changes and dropping those that moved into a banned range (in minutes)")
+                    .takes_value(true)
+            ).arg(
+                Arg::with_name("electrum_discovery_reresolve_after_failures")
+                    .long("electrum-discovery-reresolve-after-failures")
+                    .help("Re-resolve the hostname of a failing default server every this many consecutive failures, 0 to disable")
+                    .default_value("3")
+            ).arg(
+                Arg::with_name("electrum_discovery_gossip_lifetime")
+                    .long("electrum-discovery-gossip-lifetime")
+                    .help("Forget servers learned via server.add_peer that no other peer re-advertised within this long, unless they're healthy (in hours)")
+                    .takes_value(true)
+            ).arg(
+                Arg::with_name("electrum_discovery_crawl")
+                    .long("electrum-discovery-crawl")
+                    .help("Queue the servers listed by the server.peers.subscribe of healthy discovered servers")
+            ).arg(
+                Arg::with_name("electrum_discovery_max_crawled_per_peer")
+                    .long("electrum-discovery-max-crawled-per-peer")
+                    .help("Maximum number of servers queued from a single peer's list per health check")
+                    .default_value("50")
+            ).arg(
+                Arg::with_name("electrum_discovery_sample_size")
+                    .long("electrum-discovery-sample-size")
+                    .help("Advertise a random sample of this many discovered servers per server.peers.subscribe request, weighted by their reputation and latency, rather than all of them")
+                    .takes_value(true)
+            ).arg(
+                Arg::with_name("electrum_discovery_sample_include_defaults")
+                    .long("electrum-discovery-sample-include-defaults")
+                    .help("Always advertise the default servers, on top of the sampled ones")
+            ).arg(
+                Arg::with_name("electrum_discovery_max_requests_per_ip")
+                    .long("electrum-discovery-max-requests-per-ip")
+                    .help("Maximum number of server.add_peer requests accepted from a single IP per --electrum-discovery-request-window")
+                    .takes_value(true)
+            ).arg(
+                Arg::with_name("electrum_discovery_request_window")
+                    .long("electrum-discovery-request-window")
+                    .help("The window --electrum-discovery-max-requests-per-ip applies to (in minutes)")
+                    .default_value("60")
+            ).arg(
+                Arg::with_name("electrum_discovery_max_pending_per_ip")
+                    .long("electrum-discovery-max-pending-per-ip")
+                    .help("Maximum number of servers requested by a single IP that may be queued without being healthy")
+                    .takes_value(true)
+            ).arg(
+                Arg::with_name("electrum_discovery_min_score")
+                    .long("electrum-discovery-min-score")
+                    .help("Give up on discovered servers scoring below this (between 0 and 1), which decreases with their failure rate and response time. Default and pinned servers are exempt.")
+                    .takes_value(true)
+            ).arg(
+                Arg::with_name("electrum_discovery_min_score_checks")
+                    .long("electrum-discovery-min-score-checks")
+                    .help("The number of health checks a server has to go through before --electrum-discovery-min-score applies to it")
+                    .default_value("10")
+            ).arg(
+                Arg::with_name("electrum_discovery_timeout")
+                    .long("electrum-discovery-timeout")
+                    .help("How long to wait for discovered servers to respond during health checks before giving up on the check (in seconds)")
+                    .default_value("30")
+            ).arg(
+                Arg::with_name("electrum_discovery_asn_db")
+                    .long("electrum-discovery-asn-db")
+                    .help("Path to an IP-to-ASN database in the TSV format of https://iptoasn.com (like ip2asn-combined.tsv), to spread the advertised servers over distinct providers")
+                    .takes_value(true)
+            ).arg(
+                Arg::with_name("electrum_discovery_max_features_no_response")
+                    .long("electrum-discovery-max-features-no-response")
+                    .help("Give up on discovered servers after this many consecutive health checks in which they didn't respond to server.features within --electrum-discovery-timeout")
+                    .takes_value(true)
             ).arg(
             Arg::with_name("tor_proxy")
                 .long("tor-proxy")
                 .help("ip:addr of socks proxy for accessing onion hosts")
                 .takes_value(true),
+        ).arg(
+            Arg::with_name("tor_control")
+                .long("tor-control")
+                .help("ip:addr of the tor control port, for creating an onion service for the Electrum server and advertising it")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("tor_only")
+                .long("tor-only")
+                .help("Listen on localhost only, advertise onion hosts only and reach other servers and broadcast backends through the tor proxy only")
+                .requires("tor_proxy"),
         );
 
         let m = args.get_matches();
@@ -321,6 +818,18 @@ impl Config {
                 .unwrap_or(&format!("127.0.0.1:{}", default_electrum_port)),
             "Electrum RPC",
         );
+        let electrum_ws_addr: Option<SocketAddr> = m
+            .value_of("electrum_ws_addr")
+            .map(|addr| str_to_socketaddr(addr, "Electrum WebSocket"));
+        let electrum_ssl_addr: Option<SocketAddr> = m
+            .value_of("electrum_ssl_addr")
+            .map(|addr| str_to_socketaddr(addr, "Electrum SSL"));
+        let electrum_wss_addr: Option<SocketAddr> = m
+            .value_of("electrum_wss_addr")
+            .map(|addr| str_to_socketaddr(addr, "Electrum secure WebSocket"));
+        let electrum_admin_addr: Option<SocketAddr> = m
+            .value_of("electrum_admin_addr")
+            .map(|addr| str_to_socketaddr(addr, "Electrum admin"));
         let http_addr: SocketAddr = str_to_socketaddr(
             m.value_of("http_addr")
                 .unwrap_or(&format!("127.0.0.1:{}", default_http_port)),
@@ -375,6 +884,29 @@ impl Config {
             .value_of("electrum_public_hosts")
             .map(|s| serde_json::from_str(s).expect("invalid --electrum-public-hosts"));
 
+        #[cfg(feature = "electrum-discovery")]
+        let tor_only = m.is_present("tor_only");
+        #[cfg(feature = "electrum-discovery")]
+        if tor_only {
+            let listeners = [
+                (Some(electrum_rpc_addr), "Electrum RPC"),
+                (electrum_ws_addr, "Electrum WebSocket"),
+                (electrum_ssl_addr, "Electrum SSL"),
+                (electrum_wss_addr, "Electrum secure WebSocket"),
+                (electrum_admin_addr, "Electrum admin"),
+                (Some(http_addr), "HTTP"),
+                (Some(monitoring_addr), "Prometheus monitoring"),
+            ];
+            for (addr, what) in listeners.iter() {
+                if let Some(addr) = addr.filter(|addr| !addr.ip().is_loopback()) {
+                    panic!(
+                        "--tor-only requires the {} server to listen on localhost, not {}",
+                        what, addr
+                    );
+                }
+            }
+        }
+
         let mut log = stderrlog::new();
         log.verbosity(m.occurrences_of("verbosity") as usize);
         log.timestamp(if m.is_present("timestamp") {
@@ -393,16 +925,88 @@ impl Config {
             cookie,
             utxos_limit: value_t_or_exit!(m, "utxos_limit", usize),
             electrum_rpc_addr,
+            electrum_ws_addr,
+            electrum_ssl_addr,
+            electrum_wss_addr,
+            electrum_tls_cert: m.value_of("electrum_tls_cert").map(PathBuf::from),
+            electrum_tls_key: m.value_of("electrum_tls_key").map(PathBuf::from),
+            electrum_socket_file: m.value_of("electrum_socket_file").map(PathBuf::from),
+            electrum_socket_mode: m
+                .value_of("electrum_socket_mode")
+                .map(|s| u32::from_str_radix(s, 8).expect("invalid --electrum-socket-mode")),
             electrum_txs_limit: value_t_or_exit!(m, "electrum_txs_limit", usize),
+            electrum_max_batch_size: value_t_or_exit!(m, "electrum_max_batch_size", usize),
             electrum_banner,
+            electrum_banner_file: m.value_of("electrum_banner_file").map(PathBuf::from),
+            electrum_address_methods: !m.is_present("electrum_disable_address_methods"),
+            electrum_admin_addr,
+            electrum_donation_address: m.value_of("electrum_donation_address").map(String::from),
+            electrum_rate_limit: m
+                .value_of("electrum_rate_limit")
+                .map(|s| s.parse().expect("invalid --electrum-rate-limit")),
+            electrum_max_in_flight: m
+                .value_of("electrum_max_in_flight")
+                .map(|s| s.parse().expect("invalid --electrum-max-in-flight")),
+            electrum_max_subscriptions: m
+                .value_of("electrum_max_subscriptions")
+                .map(|s| s.parse().expect("invalid --electrum-max-subscriptions")),
+            electrum_max_subscriptions_per_ip: m.value_of("electrum_max_subscriptions_per_ip").map(
+                |s| {
+                    s.parse()
+                        .expect("invalid --electrum-max-subscriptions-per-ip")
+                },
+            ),
+            electrum_max_connections_per_ip: m.value_of("electrum_max_connections_per_ip").map(
+                |s| {
+                    s.parse()
+                        .expect("invalid --electrum-max-connections-per-ip")
+                },
+            ),
+            electrum_max_tracked_ips: value_t_or_exit!(m, "electrum_max_tracked_ips", usize),
+            electrum_idle_timeout: m.value_of("electrum_idle_timeout").map(|s| {
+                let minutes: u64 = s.parse().expect("invalid --electrum-idle-timeout");
+                std::time::Duration::from_secs(minutes * 60)
+            }),
+            electrum_drain_timeout: std::time::Duration::from_secs(value_t_or_exit!(
+                m,
+                "electrum_drain_timeout",
+                u64
+            )),
+            electrum_resume_ttl: m.value_of("electrum_resume_ttl").map(|s| {
+                let minutes: u64 = s.parse().expect("invalid --electrum-resume-ttl");
+                std::time::Duration::from_secs(minutes * 60)
+            }),
             http_addr,
             http_socket_file,
+            trusted_proxies: m.value_of("trusted_proxies").map_or_else(Vec::new, |s| {
+                s.split(',')
+                    .map(|ip| ip.trim().parse().expect("invalid --trusted-proxies"))
+                    .collect()
+            }),
+            broadcast_rpc: m.value_of("broadcast_rpc").map_or_else(Vec::new, |s| {
+                s.split(',')
+                    .map(|endpoint| endpoint.trim().parse().expect("invalid --broadcast-rpc"))
+                    .collect()
+            }),
+            #[cfg(not(feature = "liquid"))]
+            broadcast_p2p_peers: m
+                .value_of("broadcast_p2p_peers")
+                .map_or_else(Vec::new, |s| {
+                    s.split(',')
+                        .map(|peer| peer.trim().parse().expect("invalid --broadcast-p2p-peers"))
+                        .collect()
+                }),
             monitoring_addr,
             jsonrpc_import: m.is_present("jsonrpc_import"),
             light_mode: m.is_present("light_mode"),
             address_search: m.is_present("address_search"),
             index_unspendables: m.is_present("index_unspendables"),
-            cors: m.value_of("cors").map(|s| s.to_string()),
+            cors: m.value_of("cors").map(|s| cors_values(s, "--cors origin")),
+            cors_methods: cors_values(m.value_of("cors_methods").unwrap(), "--cors-methods"),
+            cors_headers: cors_values(m.value_of("cors_headers").unwrap(), "--cors-headers"),
+            cors_max_age: value_t_or_exit!(m, "cors_max_age", u64),
+            http_batch_limit: value_t_or_exit!(m, "http_batch_limit", usize),
+            http_compression_min_size: value_t_or_exit!(m, "http_compression_min_size", usize),
             precache_scripts: m.value_of("precache_scripts").map(|s| s.to_string()),
 
             #[cfg(feature = "liquid")]
@@ -415,7 +1019,222 @@ impl Config {
             #[cfg(feature = "electrum-discovery")]
             electrum_announce: m.is_present("electrum_announce"),
             #[cfg(feature = "electrum-discovery")]
+            electrum_default_servers: m.value_of("electrum_default_servers").map(PathBuf::from),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_cert_verification: value_t_or_exit!(
+                m,
+                "electrum_discovery_cert_verification",
+                crate::electrum::CertVerification
+            ),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_check_freq: std::time::Duration::from_secs(value_t_or_exit!(
+                m,
+                "electrum_discovery_check_freq",
+                u64
+            )),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_max_failures: value_t_or_exit!(
+                m,
+                "electrum_discovery_max_failures",
+                usize
+            ),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_max_queue: value_t_or_exit!(
+                m,
+                "electrum_discovery_max_queue",
+                usize
+            ),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_max_servers_per_request: value_t_or_exit!(
+                m,
+                "electrum_discovery_max_servers_per_request",
+                usize
+            ),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_max_services_per_request: value_t_or_exit!(
+                m,
+                "electrum_discovery_max_services_per_request",
+                usize
+            ),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_interleave: m.is_present("electrum_discovery_interleave"),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_snapshot_dir: m
+                .value_of("electrum_discovery_snapshot_dir")
+                .map(PathBuf::from),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_snapshot_interval: std::time::Duration::from_secs(
+                value_t_or_exit!(m, "electrum_discovery_snapshot_interval", u64) * 60,
+            ),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_snapshot_retain: value_t_or_exit!(
+                m,
+                "electrum_discovery_snapshot_retain",
+                usize
+            ),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_bootstrap_url: m
+                .value_of("electrum_discovery_bootstrap_url")
+                .map(String::from),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_bootstrap_interval: std::time::Duration::from_secs(
+                value_t_or_exit!(m, "electrum_discovery_bootstrap_interval", u64) * 60,
+            ),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_bootstrap_as_default: m
+                .is_present("electrum_discovery_bootstrap_as_default"),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_workers: value_t_or_exit!(m, "electrum_discovery_workers", usize),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_max_onion_checks: m
+                .value_of("electrum_discovery_max_onion_checks")
+                .map(|s| {
+                    s.parse()
+                        .expect("invalid --electrum-discovery-max-onion-checks")
+                }),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_request_limit_policy: value_t_or_exit!(
+                m,
+                "electrum_discovery_request_limit_policy",
+                crate::electrum::RequestLimitPolicy
+            ),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_trust_private_ranges: m
+                .is_present("electrum_discovery_trust_private_ranges"),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_require_self_advertised: m
+                .is_present("electrum_discovery_require_self_advertised"),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_pinned_readd_cooldown: m
+                .value_of("electrum_discovery_pinned_readd_cooldown")
+                .map(|s| {
+                    let minutes: u64 = s
+                        .parse()
+                        .expect("invalid --electrum-discovery-pinned-readd-cooldown");
+                    std::time::Duration::from_secs(minutes * 60)
+                }),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_verify_subscriptions: m
+                .is_present("electrum_discovery_verify_subscriptions"),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_banned_hosts: m
+                .value_of("electrum_discovery_banned_hosts")
+                .map_or_else(Default::default, |s| {
+                    s.split(',').map(|host| host.trim().to_string()).collect()
+                }),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_banned_ranges: m
+                .value_of("electrum_discovery_banned_ranges")
+                .map_or_else(Vec::new, |s| {
+                    s.split(',')
+                        .map(|range| {
+                            range
+                                .parse()
+                                .expect("invalid --electrum-discovery-banned-ranges")
+                        })
+                        .collect()
+                }),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_ban_precedence: value_t_or_exit!(
+                m,
+                "electrum_discovery_ban_precedence",
+                crate::electrum::BanPrecedence
+            ),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_latency_method: value_t_or_exit!(
+                m,
+                "electrum_discovery_latency_method",
+                crate::electrum::LatencyMethod
+            ),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_reresolve_interval: m
+                .value_of("electrum_discovery_reresolve_interval")
+                .map(|s| {
+                    let minutes: u64 = s
+                        .parse()
+                        .expect("invalid --electrum-discovery-reresolve-interval");
+                    std::time::Duration::from_secs(minutes * 60)
+                }),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_reresolve_after_failures: Some(value_t_or_exit!(
+                m,
+                "electrum_discovery_reresolve_after_failures",
+                usize
+            ))
+            .filter(|failures| *failures > 0),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_gossip_lifetime: m
+                .value_of("electrum_discovery_gossip_lifetime")
+                .map(|s| {
+                    let hours: u64 = s
+                        .parse()
+                        .expect("invalid --electrum-discovery-gossip-lifetime");
+                    std::time::Duration::from_secs(hours * 3600)
+                }),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_crawl: m.is_present("electrum_discovery_crawl"),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_max_crawled_per_peer: value_t_or_exit!(
+                m,
+                "electrum_discovery_max_crawled_per_peer",
+                usize
+            ),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_sample_size: m
+                .value_of("electrum_discovery_sample_size")
+                .map(|s| s.parse().expect("invalid --electrum-discovery-sample-size")),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_sample_include_defaults: m
+                .is_present("electrum_discovery_sample_include_defaults"),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_max_requests_per_ip: m
+                .value_of("electrum_discovery_max_requests_per_ip")
+                .map(|s| {
+                    s.parse()
+                        .expect("invalid --electrum-discovery-max-requests-per-ip")
+                }),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_request_window: std::time::Duration::from_secs(
+                value_t_or_exit!(m, "electrum_discovery_request_window", u64) * 60,
+            ),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_max_pending_per_ip: m
+                .value_of("electrum_discovery_max_pending_per_ip")
+                .map(|s| {
+                    s.parse()
+                        .expect("invalid --electrum-discovery-max-pending-per-ip")
+                }),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_min_score: m
+                .value_of("electrum_discovery_min_score")
+                .map(|s| s.parse().expect("invalid --electrum-discovery-min-score")),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_min_score_checks: value_t_or_exit!(
+                m,
+                "electrum_discovery_min_score_checks",
+                usize
+            ),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_timeout: std::time::Duration::from_secs(value_t_or_exit!(
+                m,
+                "electrum_discovery_timeout",
+                u64
+            )),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_asn_db: m.value_of("electrum_discovery_asn_db").map(PathBuf::from),
+            #[cfg(feature = "electrum-discovery")]
+            electrum_discovery_max_features_no_response: m
+                .value_of("electrum_discovery_max_features_no_response")
+                .map(|s| {
+                    s.parse()
+                        .expect("invalid --electrum-discovery-max-features-no-response")
+                }),
+            #[cfg(feature = "electrum-discovery")]
             tor_proxy: m.value_of("tor_proxy").map(|s| s.parse().unwrap()),
+            #[cfg(feature = "electrum-discovery")]
+            tor_control: m.value_of("tor_control").map(|s| s.parse().unwrap()),
+            #[cfg(feature = "electrum-discovery")]
+            tor_only,
         };
         eprintln!("{:?}", config);
         config
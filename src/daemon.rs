@@ -19,7 +19,7 @@ use elements::encode::{deserialize, serialize};
 use crate::chain::{Block, BlockHash, BlockHeader, Network, Transaction, Txid};
 use crate::metrics::{HistogramOpts, HistogramVec, Metrics};
 use crate::signal::Waiter;
-use crate::util::HeaderList;
+use crate::util::{socks, HeaderList};
 
 use crate::errors::*;
 
@@ -165,22 +165,33 @@ impl Connection {
 
     fn send(&mut self, request: &str) -> Result<()> {
         let cookie = &self.cookie_getter.get()?;
+        Connection::write_request(&mut self.tx, Some(cookie), request)
+    }
+
+    fn recv(&mut self) -> Result<String> {
+        Connection::read_reply(&mut self.rx)
+    }
+
+    fn write_request(tx: &mut TcpStream, cookie: Option<&[u8]>, request: &str) -> Result<()> {
+        let auth = cookie.map_or_else(String::new, |cookie| {
+            format!("Authorization: Basic {}\n", base64::encode(cookie))
+        });
         let msg = format!(
-            "POST / HTTP/1.1\nAuthorization: Basic {}\nContent-Length: {}\n\n{}",
-            base64::encode(cookie),
+            "POST / HTTP/1.1\n{}Content-Length: {}\n\n{}",
+            auth,
             request.len(),
             request,
         );
-        self.tx.write_all(msg.as_bytes()).chain_err(|| {
+        tx.write_all(msg.as_bytes()).chain_err(|| {
             ErrorKind::Connection("disconnected from daemon while sending".to_owned())
         })
     }
 
-    fn recv(&mut self) -> Result<String> {
+    fn read_reply(rx: &mut Lines<BufReader<TcpStream>>) -> Result<String> {
         // TODO: use proper HTTP parser.
         let mut in_header = true;
         let mut contents: Option<String> = None;
-        let iter = self.rx.by_ref();
+        let iter = rx.by_ref();
         let status = iter
             .next()
             .chain_err(|| {
@@ -239,6 +250,44 @@ impl Connection {
     }
 }
 
+/// Call a bitcoind other than the attached one, like the `--broadcast-rpc` endpoints, with the
+/// same JSON-RPC client, optionally through a SOCKS5 proxy. Unreachable endpoints fail right away
+/// rather than being retried.
+pub fn call_endpoint(
+    addr: SocketAddr,
+    proxy: Option<SocketAddr>,
+    auth: Option<&[u8]>,
+    method: &str,
+    params: Value,
+    timeout: Duration,
+) -> Result<Value> {
+    let mut tx = match proxy {
+        Some(proxy) => {
+            let host = addr.ip().to_string();
+            socks::connect(proxy, None, &host, addr.port(), Some(timeout))?
+        }
+        None => {
+            let tx = TcpStream::connect_timeout(&addr, timeout)
+                .chain_err(|| format!("failed to connect to {}", addr))?;
+            tx.set_read_timeout(Some(timeout))
+                .chain_err(|| "failed setting read timeout")?;
+            tx.set_write_timeout(Some(timeout))
+                .chain_err(|| "failed setting write timeout")?;
+            tx
+        }
+    };
+    let mut rx = BufReader::new(
+        tx.try_clone()
+            .chain_err(|| format!("failed to clone {:?}", tx))?,
+    )
+    .lines();
+
+    let request = json!({"method": method, "params": params, "id": 0});
+    Connection::write_request(&mut tx, auth, &request.to_string())?;
+    let reply = from_str(&Connection::read_reply(&mut rx)?).chain_err(|| "invalid JSON")?;
+    parse_jsonrpc_reply(reply, method, 0)
+}
+
 struct Counter {
     value: Mutex<u64>,
 }
@@ -532,6 +581,12 @@ impl Daemon {
         )
     }
 
+    /// Whether the transactions (a package if there are several) would be accepted to the mempool
+    pub fn test_mempool_accept(&self, txhexes: &[String]) -> Result<Vec<Value>> {
+        let res = self.request("testmempoolaccept", json!([txhexes]))?;
+        Ok(serde_json::from_value(res).chain_err(|| "invalid testmempoolaccept reply")?)
+    }
+
     // Get estimated feerates for the provided confirmation targets using a batch RPC request
     // Missing estimates are logged but do not cause a failure, whatever is available is returned
     #[allow(clippy::float_cmp)]
@@ -0,0 +1,124 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use error_chain::ChainedError;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime as DateTime;
+
+use crate::errors::*;
+
+/// The `server.banner`, either fixed or read from a template file. The file is re-read on every
+/// request, so that operators can update it (like to announce maintenance) without restarting.
+///
+/// Templates may use the variables `$VERSION`, `$TIP_HEIGHT`, `$NETWORK`, `$UPTIME`, `$TIME` and
+/// `$DONATION_ADDRESS`. `$TIME` is the current time in RFC 3339 format, which lets discovery
+/// servers measure our clock skew.
+#[derive(Debug)]
+pub struct Banner {
+    text: String,
+    template_path: Option<PathBuf>,
+    started_at: Instant,
+}
+
+/// The values substituted into banner templates
+pub struct BannerVars<'a> {
+    pub version: &'a str,
+    pub tip_height: usize,
+    pub network: &'a str,
+    pub donation_address: Option<&'a str>,
+}
+
+impl Banner {
+    /// Use the template at `template_path` if set, falling back to `text` when it can't be read
+    pub fn new(text: String, template_path: Option<PathBuf>) -> Self {
+        Banner {
+            text,
+            template_path,
+            started_at: Instant::now(),
+        }
+    }
+
+    pub fn render(&self, vars: &BannerVars) -> String {
+        let template = match &self.template_path {
+            Some(path) => match fs::read_to_string(path)
+                .chain_err(|| format!("failed reading banner file {:?}", path))
+            {
+                Ok(template) => template,
+                Err(e) => {
+                    warn!("{}", e.display_chain());
+                    return self.text.clone();
+                }
+            },
+            None => return self.text.clone(),
+        };
+        substitute(
+            &template,
+            vars,
+            self.started_at.elapsed(),
+            SystemTime::now(),
+        )
+    }
+}
+
+fn substitute(template: &str, vars: &BannerVars, uptime: Duration, now: SystemTime) -> String {
+    template
+        .replace("$VERSION", vars.version)
+        .replace("$TIP_HEIGHT", &vars.tip_height.to_string())
+        .replace("$NETWORK", vars.network)
+        .replace("$UPTIME", &format_uptime(uptime))
+        .replace("$TIME", &format_time(now))
+        .replace("$DONATION_ADDRESS", vars.donation_address.unwrap_or(""))
+}
+
+fn format_uptime(uptime: Duration) -> String {
+    let minutes = uptime.as_secs() / 60;
+    let (days, hours, minutes) = (minutes / (24 * 60), minutes / 60 % 24, minutes % 60);
+    if days > 0 {
+        format!("{}d {}h {}m", days, hours, minutes)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else {
+        format!("{}m", minutes)
+    }
+}
+
+fn format_time(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    DateTime::from_unix_timestamp(secs as i64)
+        .ok()
+        .and_then(|time| time.format(&Rfc3339).ok())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_substitute() {
+        let vars = BannerVars {
+            version: "electrs-esplora 0.4.1",
+            tip_height: 700_000,
+            network: "bitcoin",
+            donation_address: None,
+        };
+        let uptime = Duration::from_secs(2 * 24 * 3600 + 3 * 3600 + 4 * 60 + 5);
+        assert_eq!(
+            substitute(
+                "$VERSION on $NETWORK at $TIP_HEIGHT, up $UPTIME. Donate: $DONATION_ADDRESS",
+                &vars,
+                uptime,
+                SystemTime::now()
+            ),
+            "electrs-esplora 0.4.1 on bitcoin at 700000, up 2d 3h 4m. Donate: "
+        );
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(
+            substitute("Server time: $TIME", &vars, uptime, now),
+            "Server time: 2023-11-14T22:13:20Z"
+        );
+        assert_eq!(format_uptime(Duration::from_secs(3 * 3600 + 59)), "3h 0m");
+        assert_eq!(format_uptime(Duration::from_secs(59)), "0m");
+    }
+}
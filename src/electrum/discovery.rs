@@ -1,30 +1,429 @@
 use std::cmp::Ordering;
-use std::collections::{hash_map::Entry, BinaryHeap, HashMap, HashSet};
-use std::convert::TryInto;
+use std::collections::{hash_map::Entry, BinaryHeap, HashMap, HashSet, VecDeque};
 use std::fmt;
-use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
 use std::str::FromStr;
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
-use electrum_client::ElectrumApi;
+use tokio::sync::broadcast;
 
-use crate::chain::Network;
-use crate::electrum::{Client, Hostname, Port, ProtocolVersion, ServerFeatures};
-use crate::errors::{Result, ResultExt};
-use crate::util::spawn_thread;
+use crate::chain::{genesis_hash, BlockHash, Network};
+use crate::electrum::{Hostname, Port, ProtocolVersion, ServerFeatures, ServerPorts};
+use crate::errors::{Error, ErrorKind, Result, ResultExt};
+use crate::util::{spawn_thread, LruMap};
 
+mod admin;
+mod announce;
+mod ban;
+mod bootstrap;
+mod connection;
+mod crawl;
 mod default_servers;
+mod diversity;
+mod events;
+mod expiry;
+mod fixtures;
+mod ip_reputation;
+mod lag;
+mod onion;
+mod operator;
+mod persist;
+mod probe;
+mod quota;
+mod reputation;
+mod reresolve;
+mod resolver;
+mod sampling;
+mod score;
+mod self_test;
+mod snapshot;
+mod stats;
+mod subscription;
+#[cfg(test)]
+mod test_util;
+mod tor;
+mod tor_control;
+use announce::AnnounceThrottle;
+pub use ban::{Ban, BanPrecedence, IpRange};
 use default_servers::add_default_servers;
+pub use diversity::{AsnLookup, Ip2AsnLookup, NoAsnLookup};
+pub use events::{DiscoveryEvent, DiscoveryEvents};
+pub use fixtures::{KnownTransaction, VerificationData};
+pub use ip_reputation::{IpReputation, NoIpReputation};
+pub use lag::{ChainTip, NoChainTip};
+pub use probe::{
+    CertKind, CertVerification, ElectrumProbe, Probe, ProbeClient, Socks5Proxy, TlsVersion,
+};
+use reputation::Reputation;
+use resolver::TorResolver;
+pub use resolver::{Resolution, Resolver, SystemResolver};
+pub use score::ServiceMetrics;
+pub use self_test::{SelfTestReport, SelfTestStage, StageReport};
+pub use stats::DiscoveryStats;
+use stats::{DiscoveryMetrics, RequestOutcome};
+pub use tor_control::OnionService;
 
 const HEALTH_CHECK_FREQ: Duration = Duration::from_secs(3600); // check servers every hour
-const JOB_INTERVAL: Duration = Duration::from_secs(1); // run one health check job every second
+const JOB_INTERVAL: Duration = Duration::from_secs(1); // run the maintenance jobs every second
+const MIN_JOB_INTERVAL: Duration = Duration::from_millis(100); // pace of health checks while catching up on due jobs
+const MAX_JOB_INTERVAL: Duration = Duration::from_secs(10); // slowest pace of health checks, for small queues
 const MAX_CONSECUTIVE_FAILURES: usize = 24; // drop servers after 24 consecutive failing attempts (~24 hours) (~24 hours)
+const MAX_BACKOFF_FACTOR: u32 = 8; // check failing servers up to 8 times less often than healthy ones
+const CHECK_TIMEOUT_FACTOR: u32 = 4; // give up on health checks lasting over 4 features timeouts overall
 const MAX_QUEUE_SIZE: usize = 500; // refuse accepting new servers if we have that many health check jobs
 const MAX_SERVERS_PER_REQUEST: usize = 3; // maximum number of server hosts added per server.add_peer call
 const MAX_SERVICES_PER_REQUEST: usize = 6; // maximum number of services added per server.add_peer call
 
+/// Tuning knobs for the discovery manager
+#[derive(Clone, Debug, Serialize)]
+pub struct DiscoveryConfig {
+    /// How often to health check each service
+    pub health_check_freq: Duration,
+    /// Give up on services after this many consecutive failed health checks
+    pub max_consecutive_failures: usize,
+    /// Refuse accepting new servers once this many services are queued. Default servers are
+    /// queued regardless.
+    pub max_queue_size: usize,
+    /// Maximum number of hosts and of services accepted per `server.add_peer` request, see
+    /// `request_limit_policy`
+    pub max_servers_per_request: usize,
+    pub max_services_per_request: usize,
+
+    /// Alternate between clearnet and onion health checks when both are due, rather than
+    /// processing them in whatever order they happen to be queued in
+    pub interleave_transports: bool,
+
+    /// Number of worker threads running health checks concurrently
+    pub health_check_workers: usize,
+    /// Maximum number of (slow) onion health checks running at once across all workers, so that
+    /// the remaining workers stay available for clearnet checks. Defaults to half of the workers,
+    /// rounded up.
+    pub max_concurrent_onion_checks: Option<usize>,
+
+    /// Maximum number of servers to register ourselves with (via `server.add_peer`) per `announce_window`
+    pub announce_max_per_window: usize,
+    pub announce_window: Duration,
+    /// Minimum time between repeated registrations with the same server
+    pub announce_interval: Duration,
+
+    /// Known-good data per network, used to verify that servers serve correct transactions and
+    /// merkle proofs. Servers on networks without any are not deep checked.
+    pub verification_fixtures: HashMap<Network, VerificationData>,
+
+    /// URL of a JSON peer list (in the `server.peers.subscribe` format) to fetch servers from,
+    /// at startup and then periodically
+    pub bootstrap_url: Option<String>,
+    /// How often to refetch the bootstrap peer list
+    pub bootstrap_interval: Duration,
+    /// Whether servers from the bootstrap peer list are treated as default servers, or as
+    /// candidates subject to the same limits as servers added via `server.add_peer`
+    pub bootstrap_as_default: bool,
+
+    /// The reputation servers earn through successful health checks is halved every `reputation_half_life`
+    /// of inactivity, so that a returning server has to re-earn trust
+    pub reputation_half_life: Duration,
+
+    /// Directory to periodically write timestamped snapshots of the advertised server list to,
+    /// as an audit trail. Disabled when unset.
+    pub snapshot_dir: Option<PathBuf>,
+    /// How often to write a server list snapshot
+    pub snapshot_interval: Duration,
+    /// How many of the most recent snapshots to keep, older ones are deleted
+    pub snapshot_retain: usize,
+
+    /// Maximum difference between a server's reported time and ours before it is considered
+    /// unhealthy. The time is read from the server's banner, servers that don't include it there
+    /// are not checked. Disabled when unset.
+    pub max_clock_skew: Option<Duration>,
+
+    /// How to handle `server.add_peer` requests with more hosts or services than we accept per request
+    pub request_limit_policy: RequestLimitPolicy,
+
+    /// A known-good server to exercise the discovery pipeline against with `self_test`
+    pub self_test_server: Option<(Hostname, Service)>,
+
+    /// Maximum number of entries kept in each per-IP tracking map, beyond which the least
+    /// recently used ones are evicted
+    pub max_tracked_ips: usize,
+
+    /// Accept servers advertising loopback, private and link-local addresses, for lab setups.
+    /// Unspecified and multicast addresses are always rejected.
+    pub trust_private_ranges: bool,
+
+    /// Only accept `server.add_peer` requests that advertise the requesting host itself (among
+    /// other hosts), rejecting peers that advertise third-party hosts only. Note that this
+    /// rejects requests made over tor, which can only advertise onion hosts.
+    pub require_self_advertised: bool,
+
+    /// Number of events buffered for `subscribe`rs, beyond which slow subscribers start missing events
+    pub event_capacity: usize,
+
+    /// Additional networks to discover servers for, next to the one we're serving. Servers on these
+    /// networks are health checked and available via `get_servers_for`, but not advertised to our clients.
+    pub extra_networks: Vec<Network>,
+    /// Default servers by network, replacing the compiled-in defaults for the networks listed
+    pub default_servers: HashMap<Network, Vec<(Hostname, Vec<Service>)>>,
+    /// A JSON file of default servers for our network, mapping hostnames to their services in the
+    /// `server.peers.subscribe` format (like `{"electrum.example.com": ["t50001", "s50002"]}`).
+    /// Takes precedence over `default_servers`, and is re-read on SIGHUP.
+    pub default_servers_path: Option<PathBuf>,
+
+    /// Number of distinct feature variants remembered per server, beyond which the oldest is forgotten
+    pub max_feature_variants: usize,
+
+    /// Username and password to authenticate with the tor proxy
+    #[serde(skip_serializing)]
+    pub socks5_credentials: Option<(String, String)>,
+    /// Connect to each onion server over a distinct tor circuit, by authenticating with the proxy
+    /// using the server's hostname as the password (tor isolates streams by SOCKS5 credentials).
+    /// The username from `socks5_credentials` is kept, if any.
+    pub isolate_onion_circuits: bool,
+    /// Reach clearnet servers through the tor proxy too, by hostname, so that health checks and
+    /// announcements never leave over clearnet. Without a tor proxy, only onion servers are
+    /// reachable. The bootstrap peer list isn't fetched. Hostnames are resolved through the tor
+    /// proxy as well (unless a custom resolver is set), to deduplicate servers and check them
+    /// against the `banned_ranges` without DNS queries leaving over clearnet.
+    pub tor_only: bool,
+
+    /// Accept hostnames that are aliases (CNAMEs) of another name, as used by some load balancers.
+    /// Only effective with a resolver that reports CNAME chains, which the system resolver doesn't.
+    pub accept_cname_hosts: bool,
+
+    /// Minimum TLS version SSL services have to negotiate, servers negotiating an older one are
+    /// considered incompatible. Only enforced with probes that report the negotiated version.
+    pub min_tls_version: TlsVersion,
+    /// Which SSL services to verify the certificates of, including those of onion servers reached
+    /// through the tor proxy. Sets how strict we are: the services it applies to have to present
+    /// CA-signed certificates, the others may be self-signed.
+    pub cert_verification: CertVerification,
+    /// Tell apart the CA-signed and self-signed certificates of the SSL services not subject to
+    /// `cert_verification`, by trying to verify them first and reconnecting without verification
+    /// if that fails. The kind is advertised next to the services, see `CertKind`.
+    pub detect_self_signed: bool,
+
+    /// Re-queue pinned servers this long after they were given up on, for another chance.
+    /// Pinned servers are forgotten like any other when unset.
+    pub pinned_readd_cooldown: Option<Duration>,
+
+    /// Verify that servers handle `blockchain.scripthash.subscribe` on every health check.
+    /// Servers with broken subscription support are flagged and not listed.
+    pub verify_subscriptions: bool,
+
+    /// How long the verdicts of the IP reputation source are reused for
+    pub ip_reputation_cache_ttl: Duration,
+
+    /// Hostnames never to queue or list
+    pub banned_hosts: HashSet<Hostname>,
+    /// IP ranges never to queue or list servers in
+    pub banned_ranges: Vec<IpRange>,
+    /// Whether bans also apply to default servers
+    pub ban_precedence: BanPrecedence,
+    /// How long servers are banned for when misbehaving, permanently if unset. Bans made by the
+    /// operator via `ban_server` specify their own duration.
+    pub ban_duration: Option<Duration>,
+    /// The number of misbehaviors (like advertising mismatching IPs, or failing compatibility
+    /// checks) after which a server is banned
+    pub strikes_before_ban: usize,
+
+    /// What part of the health check to measure as the service latency
+    pub latency_method: LatencyMethod,
+
+    /// Re-check all onion services right away when the tor proxy becomes reachable again after
+    /// being down, rather than waiting for their next scheduled check
+    pub recheck_onions_on_tor_recovery: bool,
+
+    /// Log every health check at info level rather than debug, for correlating outgoing connection
+    /// attempts with the peer that advertised the server (logged as `added_by`)
+    pub log_connection_attempts: bool,
+
+    /// Operator identity by hostname or parent domain (like `example.com`, covering its subdomains),
+    /// for servers known to be run by the same operator
+    pub operators: HashMap<Hostname, String>,
+    /// Advertise at most this many servers of the same operator, keeping the most reputable ones.
+    /// Only servers listed in `operators` are grouped.
+    pub max_servers_per_operator: Option<usize>,
+
+    /// How long to wait for responses from servers before aborting the connection, most notably for
+    /// `server.features`. Health checks are given up on once they last over a few times as long
+    /// overall, so that servers trickling their responses can't hold up the workers.
+    pub features_timeout: Duration,
+    /// Give up on servers after this many consecutive checks in which they didn't respond to
+    /// `server.features`, as they're likely broken rather than busy. Servers are retried up to
+    /// `max_consecutive_failures` times like for any other failure when unset.
+    pub max_features_no_response: Option<usize>,
+
+    /// Advertise no servers at all until at least this many are healthy, rather than a thin list
+    /// that could centralize our clients' connections while discovery is still warming up
+    pub min_advertised_servers: usize,
+    /// Advertise no servers at all until the healthy ones span at least this many distinct subnets
+    /// (/16 for IPv4, /32 for IPv6). Onion servers don't count towards it.
+    pub min_advertised_subnets: usize,
+
+    /// Re-resolve the hostnames of queued servers this often, following their IP changes and
+    /// evicting servers that moved into one of the `banned_ranges`. Disabled when unset.
+    pub reresolve_interval: Option<Duration>,
+    /// Re-resolve the hostname of a failing default server every this many consecutive failures,
+    /// moving its health checks to the new IP if it changed. Disabled when unset.
+    pub reresolve_after_failures: Option<usize>,
+
+    /// Boost the reputation weight of servers that accepted our `server.add_peer` by this factor
+    /// (0.5 ranks them as 50% more reputable), rewarding servers that list us back
+    pub reciprocity_boost: f64,
+
+    /// Forget servers learned via `server.add_peer` that no other peer re-advertised within this
+    /// long, unless they're healthy. Gossip-learned servers are kept indefinitely when unset.
+    pub gossip_lifetime: Option<Duration>,
+
+    /// File to periodically save the discovery state to with `save_to`, for restoring it with
+    /// `with_saved_state` after a restart. Disabled when unset.
+    pub state_path: Option<PathBuf>,
+    /// How often to save the discovery state
+    pub state_save_interval: Duration,
+
+    /// Queue the servers listed by healthy peers' `server.peers.subscribe`, growing the set of
+    /// known servers beyond the ones advertised to us
+    pub crawl: bool,
+    /// Maximum number of servers queued from a single peer's list per health check
+    pub max_crawled_per_peer: usize,
+
+    /// Advertise a random sample of this many servers, weighted by their reputation and latency,
+    /// rather than all of them
+    pub sample_size: Option<usize>,
+    /// Always advertise the default servers, on top of the sampled ones
+    pub sample_include_defaults: bool,
+    /// When sampling, prefer a mix of IPv4, IPv6 and onion servers spread over distinct subnets
+    /// and ASNs, over picking strictly by weight
+    pub sample_diversity: bool,
+
+    /// Maximum number of `server.add_peer` requests accepted from a single IP per `request_window`.
+    /// Unlimited when unset.
+    pub max_requests_per_ip: Option<usize>,
+    /// The window `max_requests_per_ip` applies to
+    pub request_window: Duration,
+    /// Maximum number of servers requested by a single IP that may be queued without being healthy,
+    /// so that one client can't fill the queue. Unlimited when unset.
+    pub max_pending_per_ip: Option<usize>,
+
+    /// Give up on services scoring below this (see `ServiceMetrics::score`), rather than waiting for
+    /// them to fail `max_consecutive_failures` times. Catches chronically slow or flaky servers.
+    /// Default and pinned servers are exempt. Disabled when unset.
+    pub min_score: Option<f64>,
+    /// The number of health checks a service has to go through before `min_score` applies to it
+    pub min_score_checks: usize,
+
+    /// Maximum number of blocks a server's tip may be behind ours before it is considered unhealthy.
+    /// Only checked when our tip is known (see `with_chain_tip`). Disabled when unset.
+    pub max_height_lag: Option<usize>,
+}
+
+/// How to handle `server.add_peer` requests exceeding `max_servers_per_request`/`max_services_per_request`
+#[derive(Serialize, Eq, PartialEq, Copy, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestLimitPolicy {
+    /// Reject the whole request
+    Reject,
+    /// Silently process only the entries within the limit
+    Truncate,
+    /// Process only the entries within the limit, logging the dropped ones
+    TruncateWithWarning,
+}
+
+impl FromStr for RequestLimitPolicy {
+    type Err = Error;
+
+    /// Parse the `--electrum-discovery-request-limit-policy` option: `reject`, `truncate` or `warn`
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "reject" => Ok(RequestLimitPolicy::Reject),
+            "truncate" => Ok(RequestLimitPolicy::Truncate),
+            "warn" => Ok(RequestLimitPolicy::TruncateWithWarning),
+            _ => bail!("invalid request limit policy {}", s),
+        }
+    }
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig {
+            health_check_freq: HEALTH_CHECK_FREQ,
+            max_consecutive_failures: MAX_CONSECUTIVE_FAILURES,
+            max_queue_size: MAX_QUEUE_SIZE,
+            max_servers_per_request: MAX_SERVERS_PER_REQUEST,
+            max_services_per_request: MAX_SERVICES_PER_REQUEST,
+            interleave_transports: false,
+            health_check_workers: 1,
+            max_concurrent_onion_checks: None,
+            announce_max_per_window: 20,
+            announce_window: Duration::from_secs(3600),
+            announce_interval: Duration::from_secs(86400),
+            verification_fixtures: HashMap::new(),
+            bootstrap_url: None,
+            bootstrap_interval: Duration::from_secs(6 * 3600),
+            bootstrap_as_default: false,
+            reputation_half_life: Duration::from_secs(30 * 86400),
+            snapshot_dir: None,
+            snapshot_interval: Duration::from_secs(3600),
+            snapshot_retain: 24,
+            max_clock_skew: None,
+            request_limit_policy: RequestLimitPolicy::Truncate,
+            self_test_server: None,
+            max_tracked_ips: 10_000,
+            trust_private_ranges: false,
+            require_self_advertised: false,
+            event_capacity: 1024,
+            extra_networks: vec![],
+            default_servers: HashMap::new(),
+            default_servers_path: None,
+            max_feature_variants: 4,
+            socks5_credentials: None,
+            isolate_onion_circuits: false,
+            tor_only: false,
+            accept_cname_hosts: true,
+            min_tls_version: TlsVersion::Tls12,
+            cert_verification: CertVerification::ClearnetOnly,
+            detect_self_signed: true,
+            pinned_readd_cooldown: None,
+            verify_subscriptions: false,
+            ip_reputation_cache_ttl: Duration::from_secs(600),
+            banned_hosts: HashSet::new(),
+            banned_ranges: vec![],
+            ban_precedence: BanPrecedence::Ban,
+            ban_duration: Some(Duration::from_secs(24 * 3600)),
+            strikes_before_ban: 3,
+            latency_method: LatencyMethod::RoundTrip,
+            recheck_onions_on_tor_recovery: true,
+            log_connection_attempts: false,
+            operators: HashMap::new(),
+            max_servers_per_operator: None,
+            features_timeout: Duration::from_secs(30),
+            max_features_no_response: None,
+            min_advertised_servers: 0,
+            min_advertised_subnets: 0,
+            reresolve_interval: None,
+            reresolve_after_failures: Some(3),
+            reciprocity_boost: 0.0,
+            gossip_lifetime: None,
+            state_path: None,
+            state_save_interval: Duration::from_secs(300),
+            crawl: false,
+            max_crawled_per_peer: 50,
+            sample_size: None,
+            sample_include_defaults: false,
+            sample_diversity: true,
+            max_requests_per_ip: None,
+            request_window: Duration::from_secs(3600),
+            max_pending_per_ip: None,
+            min_score: None,
+            min_score_checks: 10,
+            max_height_lag: Some(6),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct DiscoveryManager {
     /// A queue of scheduled health check jobs, including for healthy, unhealthy and untested servers
@@ -33,7 +432,11 @@ pub struct DiscoveryManager {
     /// A list of servers that were found to be healthy on their last health check
     healthy: RwLock<HashMap<ServerAddr, Server>>,
 
-    /// Used to test for protocol version compatibility
+    /// The network we're serving
+    our_network: Network,
+
+    /// The highest protocol version we support. Along with the `protocol_min` of our features, used
+    /// to test for protocol version compatibility.
     our_version: ProtocolVersion,
 
     /// So that we don't list ourselves
@@ -42,20 +445,114 @@ pub struct DiscoveryManager {
     /// For advertising ourself to other servers
     our_features: ServerFeatures,
 
+    /// Our onion service, if created via the tor control port, kept alive along with the manager
+    onion_service: Option<OnionService>,
+
     /// Whether we should announce ourselves to the servers we're connecting to
     announce: bool,
 
+    /// Limits the rate at which we announce ourselves
+    announce_throttle: Mutex<AnnounceThrottle>,
+
     /// Optional, will not support onion hosts without this
     tor_proxy: Option<SocketAddr>,
+
+    config: DiscoveryConfig,
+
+    /// Used to connect to the servers being health checked
+    probe: Arc<dyn Probe>,
+
+    /// Used to resolve the hostnames of servers being added
+    resolver: Arc<dyn Resolver>,
+
+    /// Consulted to reject servers advertised via `server.add_peer` with bad IPs
+    ip_reputation: Arc<dyn IpReputation>,
+    /// Recent IP reputation lookups, with the time they were made
+    ip_reputation_cache: Mutex<LruMap<IpAddr, (Instant, bool)>>,
+
+    /// Maps server IPs to the ASN hosting them, for sampling diverse servers
+    asn_lookup: Arc<dyn AsnLookup>,
+
+    /// Our chain tip, that servers lagging behind are checked against
+    chain_tip: Arc<dyn ChainTip>,
+
+    /// Times of the recent `server.add_peer` requests made by each IP, for `max_requests_per_ip`
+    requests_by_ip: Mutex<LruMap<IpAddr, VecDeque<Instant>>>,
+
+    /// Long-term reputation of servers, kept across them being dropped and re-added
+    reputation: RwLock<HashMap<ServerAddr, Reputation>>,
+
+    /// When reputation was last decayed
+    last_decay: Mutex<Option<Instant>>,
+
+    /// Servers that were banned for misbehaving or by the operator. Further requests to add them are rejected.
+    bans: RwLock<HashMap<ServerAddr, Ban>>,
+    /// The number of misbehaviors of each server, towards `strikes_before_ban`
+    strikes: Mutex<LruMap<ServerAddr, usize>>,
+
+    /// The transport used by the last health check job of each worker, for interleaving
+    last_transports: Mutex<HashMap<usize, Transport>>,
+
+    /// The number of onion health checks currently running
+    onion_in_flight: Mutex<usize>,
+
+    /// When the last server list snapshot was written
+    last_snapshot: Mutex<Option<Instant>>,
+
+    /// When the bootstrap peer list was last fetched
+    last_bootstrap: Mutex<Option<Instant>>,
+
+    /// Pinned services that were given up on, with the time they were, awaiting re-queueing
+    dropped_pinned: Mutex<Vec<(Instant, HealthCheck)>>,
+
+    /// Whether the tor proxy was reachable when last probed, None if not probed yet
+    tor_reachable: Mutex<Option<bool>>,
+    /// When the tor proxy was last probed
+    last_tor_probe: Mutex<Option<Instant>>,
+    /// When the hostnames of queued servers were last re-resolved
+    last_reresolve: Mutex<Option<Instant>>,
+    /// When the discovery state was last saved to `state_path`
+    last_state_save: Mutex<Option<Instant>>,
+    /// Set on SIGHUP, to re-read the `default_servers_path` file
+    reload_requested: Arc<AtomicBool>,
+
+    /// Cumulative time spent by the health check workers
+    stats: Mutex<DiscoveryStats>,
+    /// Exports `stats` and the state of the queue to prometheus, if enabled
+    metrics: Option<DiscoveryMetrics>,
+
+    /// Streams discovery events to subscribers
+    events: broadcast::Sender<DiscoveryEvent>,
 }
 
 /// A Server corresponds to a single IP address or onion hostname, with one or more services
 /// exposed on different ports.
 #[derive(Debug)]
 struct Server {
-    services: HashSet<Service>,
+    /// The healthy services, with the pruning limit each reported (None for archival). Tracked per
+    /// service as a single host may front both pruned and archival nodes.
+    services: HashMap<Service, Option<usize>>,
     hostname: Hostname,
+    network: Network,
+    /// The features reported on the last health check
     features: ServerFeatures,
+    /// The distinct features reported over time, most recent last
+    feature_variants: VecDeque<ServerFeatures>,
+    /// The difference between the server's reported time and ours as of the last health check,
+    /// in seconds. None if it wasn't measured.
+    clock_skew: Option<i64>,
+    /// The TLS version negotiated by the last checked SSL service, if known
+    tls_version: Option<TlsVersion>,
+    /// Whether scripthash subscriptions worked on the last health check. None if not verified.
+    subscriptions: Option<bool>,
+    /// Whether the server accepted our `server.add_peer` registration
+    reciprocates: bool,
+    /// The score of each healthy service as of its last health check
+    scores: HashMap<Service, f64>,
+    /// The ASN hosting the server, if known
+    asn: Option<u32>,
+    /// The kind of certificate presented by each SSL service, as of its last health check
+    certs: HashMap<Service, CertKind>,
     // the `ServerAddr` isn't kept here directly, but is also available next to `Server` as the key for
     // the `healthy` field on `DiscoveryManager`
 }
@@ -66,24 +563,181 @@ enum ServerAddr {
     Onion(Hostname),
 }
 
+#[derive(Serialize, Eq, PartialEq, Copy, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Clearnet,
+    Onion,
+}
+
 #[derive(Eq, PartialEq, Hash, Copy, Clone, Debug)]
 pub enum Service {
     Tcp(Port),
     Ssl(Port),
-    // unimplemented: Ws and Wss
+    Ws(Port),
+    Wss(Port),
+}
+
+/// The classification of a failed health check
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+enum FailureKind {
+    /// The server closed the connection before completing the handshake. This often indicates an
+    /// overloaded but alive server, and is treated as a soft failure.
+    HandshakeReset,
+    /// The server accepted the connection but never responded to `server.features`
+    FeaturesNoResponse,
+    /// The server is not compatible with us
+    Incompatible,
+    Other,
+}
+
+/// What part of the health check is measured as the service latency
+#[derive(Serialize, Eq, PartialEq, Copy, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum LatencyMethod {
+    /// Opening the TCP connection, through the tor proxy where applicable
+    Connect,
+    /// Opening the TCP connection and completing the TLS and websocket handshakes
+    Handshake,
+    /// Opening the connection, completing the handshakes and the `server.features` round trip
+    RoundTrip,
+}
+
+impl FromStr for LatencyMethod {
+    type Err = Error;
+
+    /// Parse the `--electrum-discovery-latency-method` option: `connect`, `handshake` or `round-trip`
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "connect" => Ok(LatencyMethod::Connect),
+            "handshake" => Ok(LatencyMethod::Handshake),
+            "round-trip" => Ok(LatencyMethod::RoundTrip),
+            _ => bail!("invalid latency method {}", s),
+        }
+    }
+}
+
+/// A latency measurement, along with how it was measured
+#[derive(Serialize, Eq, PartialEq, Copy, Clone, Debug)]
+pub struct Latency {
+    pub duration: Duration,
+    pub method: LatencyMethod,
+}
+
+/// How a server entered discovery
+#[derive(Serialize, Deserialize, Eq, PartialEq, Copy, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum AdmissionSource {
+    /// A compiled-in default server
+    Default,
+    /// Advertised to us by a peer via `server.add_peer`
+    Gossip,
+    /// Fetched from the bootstrap peer list
+    Bootstrap,
+    /// Imported from a file
+    Import,
+    /// Listed by a healthy peer's `server.peers.subscribe`
+    Crawl,
+    /// Added by the operator, via `add_server`
+    Manual,
 }
 
 /// A queued health check job, one per service/port (and not per server)
-#[derive(Eq, Debug)]
+#[derive(Clone, Debug)]
 struct HealthCheck {
     addr: ServerAddr,
     hostname: Hostname,
     service: Service,
+    network: Network,
+    source: AdmissionSource,
+    /// The name the hostname resolved through, if it's an alias
+    canonical_name: Option<Hostname>,
+    /// All the addresses the hostname resolved to, `addr` among them. Empty for onion hosts.
+    ips: Vec<IpAddr>,
     is_default: bool,
+    /// Curated by the operator, retried like default servers and re-queued after
+    /// `pinned_readd_cooldown` when given up on
+    is_pinned: bool,
     added_by: Option<IpAddr>,
     last_check: Option<Instant>,
     last_healthy: Option<Instant>,
+    last_failure: Option<FailureKind>,
     consecutive_failures: usize,
+    /// Consecutive checks in which the server didn't respond to `server.features`, counted
+    /// separately from other failures for `max_features_no_response`
+    consecutive_no_response: usize,
+    /// Added to the health check frequency after consecutive failures, reset once healthy
+    backoff: Duration,
+    latency: Option<Latency>,
+    metrics: ServiceMetrics,
+    /// When the service last turned healthy, and when it last turned unhealthy after being healthy
+    became_healthy_at: Option<SystemTime>,
+    became_unhealthy_at: Option<SystemTime>,
+    /// When the service was last advertised to us, for `gossip_lifetime`
+    advertised_at: Instant,
+    /// Due for a check regardless of when it was last checked, ahead of the other due jobs
+    recheck: bool,
+}
+
+/// The outcome of a successful health check
+#[derive(Debug)]
+struct CheckResult {
+    features: ServerFeatures,
+    clock_skew: Option<i64>,
+    tls_version: Option<TlsVersion>,
+    /// The kind of certificate presented, for SSL services. None if unknown.
+    cert_kind: Option<CertKind>,
+    subscriptions: Option<bool>,
+    latency: Latency,
+    connect_time: Duration,
+    handshake_time: Duration,
+    height_lag: Option<usize>,
+    /// Whether the server accepted our `server.add_peer`, if we registered with it on this check
+    reciprocates: bool,
+}
+
+/// The status of a single queued service, as returned by `server_status`
+#[derive(Serialize, Debug)]
+pub struct ServiceStatus {
+    pub addr: String,
+    pub hostname: Hostname,
+    /// The name the hostname resolved through, if it's an alias
+    pub canonical_name: Option<Hostname>,
+    /// All the addresses the hostname resolved to, for dual-stack hosts
+    pub ips: Vec<IpAddr>,
+    pub service: String,
+    pub transport: Transport,
+    pub network: Network,
+    pub source: AdmissionSource,
+    pub healthy: bool,
+    pub consecutive_failures: usize,
+    /// The latency measured on the last successful health check
+    pub latency: Option<Latency>,
+    pub metrics: ServiceMetrics,
+    /// The score derived from the metrics, see `ServiceMetrics::score`
+    pub score: f64,
+    /// When the service last turned healthy
+    pub became_healthy_at: Option<SystemTime>,
+    /// When the service last turned unhealthy, after having been healthy
+    pub became_unhealthy_at: Option<SystemTime>,
+}
+
+/// Criteria for `list_servers`, all of which have to match
+#[derive(Default, Clone, Debug)]
+pub struct ServerFilter {
+    pub healthy: Option<bool>,
+    pub transport: Option<Transport>,
+    pub network: Option<Network>,
+    /// A substring of the hostname
+    pub hostname: Option<String>,
+}
+
+/// A page of tracked services, as returned by `list_servers`
+#[derive(Serialize, Debug)]
+pub struct ServerPage {
+    /// The number of services matching the filter, across all pages
+    pub total: usize,
+    pub servers: Vec<ServiceStatus>,
 }
 
 /// The server entry format returned from server.peers.subscribe
@@ -97,35 +751,125 @@ impl DiscoveryManager {
         our_version: ProtocolVersion,
         announce: bool,
         tor_proxy: Option<SocketAddr>,
+        config: DiscoveryConfig,
     ) -> Self {
+        let resolver: Arc<dyn Resolver> = match tor_proxy.filter(|_| config.tor_only) {
+            Some(addr) => Arc::new(TorResolver {
+                proxy: Socks5Proxy {
+                    addr,
+                    credentials: config.socks5_credentials.clone(),
+                },
+                timeout: config.features_timeout,
+            }),
+            None => Arc::new(SystemResolver),
+        };
         let our_addrs = our_features
             .hosts
             .keys()
             .filter_map(|hostname| {
-                ServerAddr::resolve(hostname)
+                ServerAddr::resolve_with(hostname, &*resolver)
+                    .map(|(addr, _)| addr)
                     .map_err(|e| warn!("failed resolving own hostname {}: {:?}", hostname, e))
                     .ok()
             })
             .collect();
         let discovery = Self {
+            our_network,
             our_addrs,
             our_version,
             our_features,
+            onion_service: None,
             announce,
+            announce_throttle: Mutex::new(AnnounceThrottle::new(&config)),
+            ip_reputation_cache: Mutex::new(LruMap::new(config.max_tracked_ips)),
+            requests_by_ip: Mutex::new(LruMap::new(config.max_tracked_ips)),
+            events: broadcast::channel(config.event_capacity.max(1)).0,
             tor_proxy,
+            config,
+            probe: Arc::new(ElectrumProbe),
+            resolver,
+            ip_reputation: Arc::new(NoIpReputation),
+            asn_lookup: Arc::new(NoAsnLookup),
+            chain_tip: Arc::new(NoChainTip),
+            bans: Default::default(),
+            strikes: Mutex::new(LruMap::new(config.max_tracked_ips)),
+            reputation: Default::default(),
+            last_decay: Default::default(),
             healthy: Default::default(),
             queue: Default::default(),
+            last_transports: Default::default(),
+            onion_in_flight: Default::default(),
+            last_snapshot: Default::default(),
+            last_bootstrap: Default::default(),
+            dropped_pinned: Default::default(),
+            tor_reachable: Default::default(),
+            last_tor_probe: Default::default(),
+            last_reresolve: Default::default(),
+            last_state_save: Default::default(),
+            reload_requested: Default::default(),
+            stats: Default::default(),
+            metrics: None,
         };
-        add_default_servers(&discovery, our_network);
+        add_default_servers(&discovery, &discovery.networks());
         discovery
     }
 
+    /// Use a custom probe for connecting to servers
+    pub fn with_probe(mut self, probe: Arc<dyn Probe>) -> Self {
+        self.probe = probe;
+        self
+    }
+
+    /// Use a custom resolver for resolving server hostnames
+    pub fn with_resolver(mut self, resolver: Arc<dyn Resolver>) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Reject servers advertised via `server.add_peer` that have bad IPs according to `ip_reputation`
+    pub fn with_ip_reputation(mut self, ip_reputation: Arc<dyn IpReputation>) -> Self {
+        self.ip_reputation = ip_reputation;
+        self
+    }
+
+    /// Look up the ASNs of healthy servers using `asn_lookup`, to spread the sampled servers over
+    /// distinct ASNs (see `sample_diversity`)
+    pub fn with_asn_lookup(mut self, asn_lookup: Arc<dyn AsnLookup>) -> Self {
+        self.asn_lookup = asn_lookup;
+        self
+    }
+
+    /// Consider servers lagging more than `max_height_lag` blocks behind `chain_tip` unhealthy
+    pub fn with_chain_tip(mut self, chain_tip: Arc<dyn ChainTip>) -> Self {
+        self.chain_tip = chain_tip;
+        self
+    }
+
     /// Add a server requested via `server.add_peer`
     pub fn add_server_request(&self, added_by: IpAddr, features: ServerFeatures) -> Result<()> {
-        self.verify_compatibility(&features)?;
+        let result = self.queue_requested_servers(added_by, features);
+        self.record_request_metrics(match result {
+            Ok(0) => RequestOutcome::Ignored,
+            Ok(_) => RequestOutcome::Accepted,
+            Err(_) => RequestOutcome::Rejected,
+        });
+        result.map(|_| ())
+    }
+
+    /// Queue the services of the requested servers that pass the checks, returning their number
+    fn queue_requested_servers(&self, added_by: IpAddr, features: ServerFeatures) -> Result<usize> {
+        if let Some(reason) = self.active_ban(&ServerAddr::Clearnet(added_by)) {
+            bail!("{} is banned ({})", added_by, reason);
+        }
+        self.check_request_quota(added_by, Instant::now())?;
+        let network = self.network_of(&features)?;
+        self.verify_compatibility(&features, network)?;
 
-        let mut queue = self.queue.write().unwrap();
-        ensure!(queue.len() < MAX_QUEUE_SIZE, "queue size exceeded");
+        let mut queue = write_lock(&self.queue);
+        ensure!(
+            queue.len() < self.config.max_queue_size,
+            "queue size exceeded"
+        );
 
         // TODO optimize
         let mut existing_services: HashMap<ServerAddr, HashSet<Service>> = HashMap::new();
@@ -136,46 +880,103 @@ impl DiscoveryManager {
                 .insert(job.service);
         }
 
-        // collect HealthChecks for candidate services
-        let jobs = features
-            .hosts
-            .iter()
-            .take(MAX_SERVERS_PER_REQUEST)
+        let mut hosts = features.hosts.iter().collect::<Vec<_>>();
+        self.limit_request(
+            added_by,
+            &mut hosts,
+            self.config.max_servers_per_request,
+            "hosts",
+            |(h, _)| h.to_string(),
+        )?;
+
+        let candidates = hosts
+            .into_iter()
             .filter_map(|(hostname, ports)| {
-                let hostname = hostname.to_lowercase();
+                let hostname = normalize_hostname(&hostname);
 
                 if hostname.len() > 100 {
                     warn!("skipping invalid hostname");
                     return None;
                 }
-                let addr = match ServerAddr::resolve(&hostname) {
-                    Ok(addr) => addr,
+                let (addrs, canonical_name) = match self.resolve_all(&hostname) {
+                    Ok(resolved) => resolved,
                     Err(e) => {
                         warn!("failed resolving {}: {:?}", hostname, e);
                         return None;
                     }
                 };
-                if !is_remote_addr(&addr) || self.our_addrs.contains(&addr) {
-                    warn!("skipping own or non-remote server addr");
-                    return None;
+                // dual-stack hosts have to pass the checks on all of their addresses
+                for addr in &addrs {
+                    if !is_remote_addr(addr, self.config.trust_private_ranges)
+                        || self.our_addrs.contains(addr)
+                    {
+                        warn!("skipping own or non-remote server addr");
+                        return None;
+                    }
+                    if let Some(reason) = self.active_ban(addr) {
+                        warn!("skipping banned server {} ({})", hostname, reason);
+                        return None;
+                    }
+                    if let Some(reason) = self.ban_reason(&hostname, addr) {
+                        warn!("skipping banned server ({})", reason);
+                        return None;
+                    }
+                    if let ServerAddr::Clearnet(ip) = *addr {
+                        if self.is_bad_ip(ip, Instant::now()) {
+                            warn!(
+                                "skipping server {} with bad ip reputation ({})",
+                                hostname, ip
+                            );
+                            return None;
+                        }
+                    }
                 }
-                // ensure the server address matches the ip that advertised it to us.
-                // onion hosts are exempt.
-                if let ServerAddr::Clearnet(ip) = addr {
-                    if ip != added_by {
+                let ips = resolver::clearnet_ips(&addrs);
+                // ensure one of the server addresses matches the ip that advertised it to us,
+                // and check the server over it. onion hosts are exempt.
+                let addr = match addrs.into_iter().next()? {
+                    ServerAddr::Clearnet(_) if !ips.contains(&added_by) => {
                         warn!(
-                            "server ip does not match source ip ({}, {} != {})",
-                            hostname, ip, added_by
+                            "server ip does not match source ip ({}, {:?} != {})",
+                            hostname, ips, added_by
+                        );
+                        self.strike(
+                            &ServerAddr::Clearnet(added_by),
+                            "advertised mismatching ips",
                         );
                         return None;
                     }
-                }
-                Some((addr, hostname, ports))
+                    ServerAddr::Clearnet(_) => ServerAddr::Clearnet(added_by),
+                    onion => onion,
+                };
+                Some((addr, ips, canonical_name, hostname, ports))
+            })
+            .collect::<Vec<_>>();
+
+        // onion hosts are exempt from the source ip match above, so a peer could otherwise use
+        // us to relay only third-party onion hosts
+        ensure!(
+            !self.config.require_self_advertised
+                || candidates
+                    .iter()
+                    .any(|(addr, _, _, _, _)| *addr == ServerAddr::Clearnet(added_by)),
+            "request does not advertise the requesting host {}",
+            added_by
+        );
+
+        let advertised = candidates
+            .iter()
+            .flat_map(|(addr, _, _, _, ports)| {
+                Service::all_of(ports).map(move |s| (addr.clone(), s))
             })
-            .flat_map(|(addr, hostname, ports)| {
-                let tcp_service = ports.tcp_port.into_iter().map(Service::Tcp);
-                let ssl_service = ports.ssl_port.into_iter().map(Service::Ssl);
-                let services = tcp_service.chain(ssl_service).collect::<HashSet<Service>>();
+            .collect::<HashSet<_>>();
+        self.refresh_advertised(&mut queue, added_by, &advertised, Instant::now());
+
+        // collect HealthChecks for candidate services
+        let mut jobs = candidates
+            .into_iter()
+            .flat_map(|(addr, ips, canonical_name, hostname, ports)| {
+                let services = Service::all_of(ports).collect::<HashSet<Service>>();
 
                 services
                     .into_iter()
@@ -185,99 +986,464 @@ impl DiscoveryManager {
                             .map_or(true, |s| !s.contains(service))
                     })
                     .map(|service| {
-                        HealthCheck::new(addr.clone(), hostname.clone(), service, Some(added_by))
+                        let added_by = Some(added_by);
+                        let mut job = HealthCheck::new(
+                            addr.clone(),
+                            hostname.clone(),
+                            service,
+                            network,
+                            added_by,
+                        );
+                        job.canonical_name = canonical_name.clone();
+                        job.ips = ips.clone();
+                        job
                     })
                     .collect::<Vec<_>>()
             })
-            .take(MAX_SERVICES_PER_REQUEST)
             .collect::<Vec<_>>();
+        self.limit_request(
+            added_by,
+            &mut jobs,
+            self.config.max_services_per_request,
+            "services",
+            |job| format!("{} {}", job.hostname, job.service),
+        )?;
 
         ensure!(
-            queue.len() + jobs.len() <= MAX_QUEUE_SIZE,
+            queue.len() + jobs.len() <= self.config.max_queue_size,
             "queue size exceeded"
         );
+        self.check_pending_quota(added_by, queue.iter(), jobs.len())?;
 
+        for job in &jobs {
+            self.emit(job.queued_event());
+        }
+        let queued = jobs.len();
         queue.extend(jobs);
+        Ok(queued)
+    }
+
+    /// Enforce a per-request limit on the number of entries according to the configured policy,
+    /// truncating them or failing the request
+    fn limit_request<T>(
+        &self,
+        added_by: IpAddr,
+        entries: &mut Vec<T>,
+        limit: usize,
+        what: &str,
+        describe: impl Fn(&T) -> String,
+    ) -> Result<()> {
+        if entries.len() <= limit {
+            return Ok(());
+        }
+        let dropped = entries.split_off(limit);
+        match self.config.request_limit_policy {
+            RequestLimitPolicy::Reject => bail!(
+                "too many {} in request ({} > {})",
+                what,
+                limit + dropped.len(),
+                limit
+            ),
+            RequestLimitPolicy::Truncate => (),
+            RequestLimitPolicy::TruncateWithWarning => warn!(
+                "request from {} exceeds the limit of {} {}, dropping: {}",
+                added_by,
+                limit,
+                what,
+                dropped.iter().map(describe).collect::<Vec<_>>().join(", ")
+            ),
+        }
         Ok(())
     }
 
     /// Add a default server. Default servers are exempt from limits and given more leniency
     /// before being removed due to unavailability.
     pub fn add_default_server(&self, hostname: Hostname, services: Vec<Service>) -> Result<()> {
-        let addr = ServerAddr::resolve(&hostname)?;
-        let mut queue = self.queue.write().unwrap();
-        queue.extend(
-            services
-                .into_iter()
-                .map(|service| HealthCheck::new(addr.clone(), hostname.clone(), service, None)),
-        );
+        self.add_default_server_for(self.our_network, hostname, services)
+    }
+
+    /// Add a default server on one of the `extra_networks`, or on ours
+    pub fn add_default_server_for(
+        &self,
+        network: Network,
+        hostname: Hostname,
+        services: Vec<Service>,
+    ) -> Result<()> {
+        let hostname = normalize_hostname(&hostname);
+        let (addrs, canonical_name) = self.resolve_all(&hostname)?;
+        let addr = addrs[0].clone();
+        if self.skip_banned_default(&hostname, &addr) {
+            return Ok(());
+        }
+        let ips = resolver::clearnet_ips(&addrs);
+        let mut queue = write_lock(&self.queue);
+        for service in services {
+            let mut job = HealthCheck::new(addr.clone(), hostname.clone(), service, network, None);
+            if queue.iter().any(|queued| *queued == job) {
+                continue;
+            }
+            job.canonical_name = canonical_name.clone();
+            job.ips = ips.clone();
+            self.emit(job.queued_event());
+            queue.push(job);
+        }
         Ok(())
     }
 
-    /// Get the list of healthy servers formatted for `servers.peers.subscribe`
+    /// Pin the queued services of the given host, so that they're given another chance after
+    /// `pinned_readd_cooldown` rather than forgotten when failing. Returns the number of services pinned.
+    pub fn pin_server(&self, hostname: &str) -> usize {
+        let hostname = normalize_hostname(hostname);
+        let mut queue = write_lock(&self.queue);
+        let mut jobs = std::mem::take(&mut *queue).into_vec();
+        let mut pinned = 0;
+        for job in jobs.iter_mut().filter(|job| job.hostname == hostname) {
+            job.is_pinned = true;
+            pinned += 1;
+        }
+        *queue = BinaryHeap::from(jobs);
+        pinned
+    }
+
+    /// Re-queue the pinned services given up on at least `pinned_readd_cooldown` ago
+    fn requeue_pinned(&self, now: Instant) {
+        let cooldown = match self.config.pinned_readd_cooldown {
+            Some(cooldown) => cooldown,
+            None => return,
+        };
+        let mut dropped = lock(&self.dropped_pinned);
+        if dropped.is_empty() {
+            return;
+        }
+        let (due, pending) = std::mem::take(&mut *dropped)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(dropped_at, _)| {
+                now.saturating_duration_since(*dropped_at) >= cooldown
+            });
+        *dropped = pending;
+        drop(dropped);
+
+        let mut queue = write_lock(&self.queue);
+        for (_, mut job) in due {
+            info!(
+                "re-queueing pinned server {} {:?}",
+                job.hostname, job.service
+            );
+            job.last_check = None;
+            job.last_failure = None;
+            job.consecutive_failures = 0;
+            job.consecutive_no_response = 0;
+            self.emit(job.queued_event());
+            queue.push(job);
+        }
+    }
+
+    /// Get the list of healthy servers formatted for `servers.peers.subscribe`, sampled if
+    /// `sample_size` is set. Empty until the `min_advertised_servers`/`min_advertised_subnets`
+    /// diversity thresholds are met.
     pub fn get_servers(&self) -> Vec<ServerEntry> {
-        // XXX return a random sample instead of everything?
-        self.healthy
-            .read()
-            .unwrap()
+        let servers = self.get_servers_for(self.our_network);
+        if !self.is_diverse(&servers) {
+            debug!(
+                "withholding {} servers until the diversity thresholds are met",
+                servers.len()
+            );
+            return vec![];
+        }
+        self.sample(servers)
+    }
+
+    /// Get the list of healthy servers on one of the `extra_networks`, or on ours
+    pub fn get_servers_for(&self, network: Network) -> Vec<ServerEntry> {
+        self.healthy_entries(network, |_| true)
+    }
+
+    /// Get the list of healthy servers keeping at least `min_pruning` blocks of history, or only
+    /// archival ones if None. Filtered per service, so servers are listed with the matching services only.
+    pub fn get_servers_by_pruning(&self, min_pruning: Option<usize>) -> Vec<ServerEntry> {
+        self.healthy_entries(self.our_network, |pruning| match (pruning, min_pruning) {
+            (None, _) => true,
+            (Some(pruning), Some(min_pruning)) => pruning >= min_pruning,
+            (Some(_), None) => false,
+        })
+    }
+
+    fn healthy_entries(
+        &self,
+        network: Network,
+        include: impl Fn(Option<usize>) -> bool,
+    ) -> Vec<ServerEntry> {
+        let mut servers = read_lock(&self.healthy)
+            .iter()
+            .filter(|(_, server)| server.network == network && server.subscriptions != Some(false))
+            .filter_map(|(addr, server)| {
+                let feature_strs = server.feature_strs(&include)?;
+                let entry = ServerEntry(addr.clone(), server.hostname.clone(), feature_strs);
+                Some((self.server_weight(addr, server) * server.score(), entry))
+            })
+            .collect::<Vec<_>>();
+        // list the most reputable and best scoring servers first
+        servers.sort_by(|(a, _), (b, _)| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+        self.cap_per_operator(servers.into_iter().map(|(_, entry)| entry).collect())
+    }
+
+    /// The server's reputation weight, boosted if it reciprocates
+    fn server_weight(&self, addr: &ServerAddr, server: &Server) -> f64 {
+        let weight = self.reputation_weight(addr);
+        if server.reciprocates {
+            weight * (1.0 + self.config.reciprocity_boost)
+        } else {
+            weight
+        }
+    }
+
+    /// Get a page of the tracked services matching the filter, ordered by hostname so that pages
+    /// are stable
+    pub fn list_servers(
+        &self,
+        offset: usize,
+        limit: usize,
+        filter: Option<ServerFilter>,
+    ) -> ServerPage {
+        let mut servers = self.server_status();
+        if let Some(filter) = filter {
+            servers.retain(|status| filter.matches(status));
+        }
+        ServerPage {
+            total: servers.len(),
+            servers: servers.into_iter().skip(offset).take(limit).collect(),
+        }
+    }
+
+    /// Get the status of all queued services, including unhealthy and untested ones
+    pub fn server_status(&self) -> Vec<ServiceStatus> {
+        let mut status = read_lock(&self.queue)
             .iter()
-            .map(|(addr, server)| {
-                ServerEntry(addr.clone(), server.hostname.clone(), server.feature_strs())
+            .map(|job| ServiceStatus {
+                addr: job.addr.to_string(),
+                hostname: job.hostname.clone(),
+                canonical_name: job.canonical_name.clone(),
+                ips: job.ips.clone(),
+                service: job.service.to_string(),
+                transport: job.addr.transport(),
+                network: job.network,
+                source: job.source,
+                healthy: job.is_healthy(),
+                consecutive_failures: job.consecutive_failures,
+                latency: job.latency,
+                metrics: job.metrics,
+                score: job.metrics.score(),
+                became_healthy_at: job.became_healthy_at,
+                became_unhealthy_at: job.became_unhealthy_at,
             })
-            .collect()
+            .collect::<Vec<_>>();
+        status.sort_by(|a, b| (&a.hostname, &a.service).cmp(&(&b.hostname, &b.service)));
+        status
     }
 
     pub fn our_features(&self) -> &ServerFeatures {
         &self.our_features
     }
 
-    /// Run the next health check in the queue (a single one)
-    fn run_health_check(&self) -> Result<()> {
-        // abort if there are no entries in the queue, or its still too early for the next one up
-        if self.queue.read().unwrap().peek().map_or(true, |next| {
-            next.last_check
-                .map_or(false, |t| t.elapsed() < HEALTH_CHECK_FREQ)
-        }) {
-            return Ok(());
+    /// The configuration currently in effect
+    pub fn config(&self) -> &DiscoveryConfig {
+        &self.config
+    }
+
+    /// Pop the next health check job that is due for the given worker, or None if there are no
+    /// entries in the queue, its still too early for the next one up, or the only ones due are
+    /// onion jobs and too many of them are already running
+    fn pop_due_job(&self, worker: usize) -> Option<HealthCheck> {
+        let mut queue = write_lock(&self.queue);
+        let freq = self.config.health_check_freq;
+        if !queue.peek().map_or(false, |job| job.is_due(freq)) {
+            return None;
         }
 
-        let mut job = self.queue.write().unwrap().pop().unwrap();
-        debug!("processing {:?}", job);
+        let mut onion_in_flight = lock(&self.onion_in_flight);
+        let mut last_transports = lock(&self.last_transports);
+        let onion_full = *onion_in_flight >= self.max_concurrent_onion_checks();
+        let allowed = |job: &HealthCheck| {
+            job.is_due(freq) && !(onion_full && job.addr.transport() == Transport::Onion)
+        };
+        // when running multiple workers, each alternates between transports so that a single
+        // worker doesn't end up with all the slow onion jobs while the others go through the fast ones
+        let avoid = match last_transports.get(&worker) {
+            Some(&last)
+                if self.config.interleave_transports || self.config.health_check_workers > 1 =>
+            {
+                Some(last)
+            }
+            _ => None,
+        };
+
+        let top = queue.peek().unwrap();
+        let job = if allowed(top) && avoid.map_or(true, |last| top.addr.transport() != last) {
+            queue.pop().unwrap()
+        } else {
+            // prefer the most overdue job using the other transport, falling back to any allowed one
+            let mut jobs = std::mem::take(&mut *queue).into_vec();
+            let most_overdue = |pred: &dyn Fn(&HealthCheck) -> bool| {
+                jobs.iter()
+                    .enumerate()
+                    .filter(|(_, job)| pred(*job))
+                    .max_by(|(_, a), (_, b)| a.cmp(b))
+                    .map(|(index, _)| index)
+            };
+            let index = most_overdue(&|job: &HealthCheck| {
+                allowed(job) && Some(job.addr.transport()) != avoid
+            })
+            .or_else(|| most_overdue(&allowed));
+            let job = index.map(|index| jobs.swap_remove(index));
+            *queue = BinaryHeap::from(jobs);
+            job?
+        };
+
+        let transport = job.addr.transport();
+        if transport == Transport::Onion {
+            *onion_in_flight += 1;
+        }
+        last_transports.insert(worker, transport);
+        Some(job)
+    }
+
+    /// Mark a job of the transport popped via `pop_due_job` as no longer running
+    fn finish_job(&self, transport: Transport) {
+        if transport == Transport::Onion {
+            *lock(&self.onion_in_flight) -= 1;
+        }
+    }
+
+    fn max_concurrent_onion_checks(&self) -> usize {
+        let workers = self.config.health_check_workers;
+        self.config
+            .max_concurrent_onion_checks
+            .unwrap_or((workers + 1) / 2)
+    }
+
+    /// Run the next health check in the queue for the given worker (a single one)
+    fn run_health_check(&self, worker: usize) -> Result<()> {
+        let mut job = match self.pop_due_job(worker) {
+            Some(job) => job,
+            None => return Ok(()),
+        };
+        debug!("worker {} processing {:?}", worker, job);
+        let running = RunningJob {
+            manager: self,
+            transport: job.addr.transport(),
+        };
+        job.recheck = false;
 
         let was_healthy = job.is_healthy();
 
-        match self.check_server(&job.addr, &job.hostname, job.service) {
-            Ok(features) => {
-                debug!("{} {:?} is available", job.hostname, job.service);
+        let start = Instant::now();
+        let result = self.check_server(&job.addr, &job.hostname, job.service, job.network);
+        match &result {
+            Ok(result) => job.metrics.record_success(
+                result.connect_time,
+                result.handshake_time,
+                result.height_lag,
+            ),
+            Err(_) => job.metrics.record_failure(),
+        }
+        let result = result.and_then(|result| {
+            ensure!(
+                !self.is_poor(&job),
+                "score {:.2} below the minimum",
+                job.metrics.score()
+            );
+            Ok(result)
+        });
+        drop(running);
+        self.record_check_time(result.is_ok(), start.elapsed());
+        self.record_check_metrics(&job, result.as_ref().ok().map(|result| result.latency));
+        self.log_check(&job, result.as_ref().err());
 
+        match result {
+            Ok(result) => {
                 if !was_healthy {
-                    self.save_healthy_service(&job, features);
+                    job.became_healthy_at = Some(SystemTime::now());
+                    self.emit(DiscoveryEvent::Healthy {
+                        hostname: job.hostname.clone(),
+                        service: job.service,
+                    });
                 }
-                // XXX update features?
+                job.latency = Some(result.latency);
+                self.save_healthy_service(&job, result);
+                self.record_reputation(&job.addr, SystemTime::now());
 
                 job.last_check = Some(Instant::now());
                 job.last_healthy = job.last_check;
+                job.last_failure = None;
                 job.consecutive_failures = 0;
+                job.consecutive_no_response = 0;
+                job.backoff = Duration::default();
                 // schedule the next health check
-                self.queue.write().unwrap().push(job);
+                write_lock(&self.queue).push(job);
 
                 Ok(())
             }
             Err(e) => {
-                debug!("{} {:?} is unavailable: {:?}", job.hostname, job.service, e);
+                // a server that was previously verified as compatible but no longer is may have swapped
+                // its backend or be getting MITM'd, drop it entirely rather than retrying
+                if let ErrorKind::IncompatibleServer(reason) = e.kind() {
+                    if job.last_healthy.is_some() {
+                        warn!("dropping {} {:?}: {}", job.hostname, job.service, reason);
+                        self.drop_server(&job.addr);
+                        self.ban(&job.addr, reason.clone(), self.config.ban_duration);
+                        self.emit(DiscoveryEvent::Dropped {
+                            hostname: job.hostname,
+                            reason: reason.clone(),
+                        });
+                        return Err(e);
+                    }
+                    self.strike(&job.addr, "failed compatibility checks");
+                }
 
                 if was_healthy {
                     // XXX should we assume the server's other services are down too?
                     self.remove_unhealthy_service(&job);
+                    job.became_unhealthy_at = Some(SystemTime::now());
+                    self.emit(DiscoveryEvent::Unhealthy {
+                        hostname: job.hostname.clone(),
+                        service: job.service,
+                    });
                 }
 
                 job.last_check = Some(Instant::now());
+                let failure = FailureKind::classify(&e);
+                job.last_failure = Some(failure);
                 job.consecutive_failures += 1;
+                if failure == FailureKind::FeaturesNoResponse {
+                    job.consecutive_no_response += 1;
+                } else {
+                    job.consecutive_no_response = 0;
+                }
+                job.backoff =
+                    failure.backoff(self.config.health_check_freq, job.consecutive_failures);
+                if job.is_default {
+                    self.reresolve_failing(&mut job);
+                }
+
+                let unresponsive = self
+                    .config
+                    .max_features_no_response
+                    .map_or(false, |max| job.consecutive_no_response >= max);
 
-                if job.should_retry() {
-                    self.queue.write().unwrap().push(job);
+                if job.should_retry(self.config.max_consecutive_failures)
+                    && !unresponsive
+                    && !self.is_poor(&job)
+                {
+                    write_lock(&self.queue).push(job);
                 } else {
                     debug!("giving up on {:?}", job);
+                    self.emit(DiscoveryEvent::Removed {
+                        hostname: job.hostname.clone(),
+                        service: job.service,
+                    });
+                    if job.is_pinned && self.config.pinned_readd_cooldown.is_some() {
+                        lock(&self.dropped_pinned).push((Instant::now(), job));
+                    }
                 }
 
                 Err(e)
@@ -285,145 +1451,530 @@ impl DiscoveryManager {
         }
     }
 
+    /// Log the outcome of a health check as `key=value` pairs for log ingestion, and emit it as a
+    /// `Checked` event. Both carry the IP the server was advertised by, if it was learned via gossip.
+    fn log_check(&self, job: &HealthCheck, error: Option<&Error>) {
+        let level = if self.config.log_connection_attempts {
+            log::Level::Info
+        } else {
+            log::Level::Debug
+        };
+        let added_by = job
+            .added_by
+            .map_or_else(|| "none".to_string(), |ip| ip.to_string());
+        match error {
+            None => log!(
+                level,
+                "health check hostname={} addr={} service={} added_by={} result=ok",
+                job.hostname,
+                job.addr,
+                job.service,
+                added_by
+            ),
+            Some(e) => log!(
+                level,
+                "health check hostname={} addr={} service={} added_by={} result=error error={:?}",
+                job.hostname,
+                job.addr,
+                job.service,
+                added_by,
+                e.to_string()
+            ),
+        }
+        self.emit(DiscoveryEvent::Checked {
+            hostname: job.hostname.clone(),
+            service: job.service,
+            added_by: job.added_by,
+            error: error.map(|e| e.to_string()),
+        });
+    }
+
     /// Upsert the server/service into the healthy set
-    fn save_healthy_service(&self, job: &HealthCheck, features: ServerFeatures) {
+    fn save_healthy_service(&self, job: &HealthCheck, result: CheckResult) {
         let addr = job.addr.clone();
-        let mut healthy = self.healthy.write().unwrap();
-        healthy
-            .entry(addr)
-            .or_insert_with(|| Server::new(job.hostname.clone(), features))
-            .services
-            .insert(job.service);
+        let mut healthy = write_lock(&self.healthy);
+        let server = healthy.entry(addr).or_insert_with(|| {
+            let mut server =
+                Server::new(job.hostname.clone(), job.network, result.features.clone());
+            server.asn = self.lookup_asn(&job.addr);
+            server
+        });
+        server.services.insert(job.service, result.features.pruning);
+        server.scores.insert(job.service, job.metrics.score());
+        if server.features != result.features {
+            debug!("{} features changed: {:?}", job.hostname, result.features);
+            server.update_features(result.features, self.config.max_feature_variants);
+        }
+        if server.subscriptions != result.subscriptions {
+            if result.subscriptions == Some(false) {
+                warn!("{} has broken scripthash subscriptions", job.hostname);
+            }
+            server.subscriptions = result.subscriptions;
+        }
+        if result.reciprocates && !server.reciprocates {
+            debug!("{} accepted our registration", job.hostname);
+            server.reciprocates = true;
+        }
+        if let Some(cert_kind) = result.cert_kind {
+            if server.certs.insert(job.service, cert_kind) != Some(cert_kind) {
+                debug!(
+                    "{} {} presented a {:?} certificate",
+                    job.hostname, job.service, cert_kind
+                );
+            }
+        }
+        if result.tls_version.is_some() && server.tls_version != result.tls_version {
+            debug!("{} negotiated {:?}", job.hostname, result.tls_version);
+            server.tls_version = result.tls_version;
+        }
+        if server.clock_skew != result.clock_skew {
+            debug!("{} clock skew: {:?}s", job.hostname, result.clock_skew);
+            server.clock_skew = result.clock_skew;
+        }
     }
 
     /// Remove the service, and remove the server entirely if it has no other reamining healthy services
     fn remove_unhealthy_service(&self, job: &HealthCheck) {
         let addr = job.addr.clone();
-        let mut healthy = self.healthy.write().unwrap();
+        let mut healthy = write_lock(&self.healthy);
+        // the server may be missing if it was dropped by another worker while this job was running
         if let Entry::Occupied(mut entry) = healthy.entry(addr) {
             let server = entry.get_mut();
-            assert!(server.services.remove(&job.service));
+            server.services.remove(&job.service);
+            server.scores.remove(&job.service);
             if server.services.is_empty() {
                 entry.remove_entry();
             }
-        } else {
-            unreachable!("missing expected server, corrupted state");
         }
     }
 
+    /// Remove the server and all of its services from the healthy set and the queue
+    fn drop_server(&self, addr: &ServerAddr) {
+        write_lock(&self.healthy).remove(addr);
+        let mut queue = write_lock(&self.queue);
+        let mut jobs = std::mem::take(&mut *queue).into_vec();
+        jobs.retain(|job| job.addr != *addr);
+        *queue = BinaryHeap::from(jobs);
+    }
+
     fn check_server(
         &self,
         addr: &ServerAddr,
         hostname: &Hostname,
         service: Service,
-    ) -> Result<ServerFeatures> {
+        network: Network,
+    ) -> Result<CheckResult> {
         debug!("checking service {:?} {:?}", addr, service);
 
-        let server_url = match (addr, service) {
-            (ServerAddr::Clearnet(ip), Service::Tcp(port)) => format!("tcp://{}:{}", ip, port),
-            (ServerAddr::Clearnet(_), Service::Ssl(port)) => format!("ssl://{}:{}", hostname, port),
-            (ServerAddr::Onion(onion_host), Service::Tcp(port)) => {
-                format!("tcp://{}:{}", onion_host, port)
-            }
-            (ServerAddr::Onion(onion_host), Service::Ssl(port)) => {
-                format!("ssl://{}:{}", onion_host, port)
+        let start = Instant::now();
+        let deadline = self.check_deadline(start);
+        let (client, cert_kind) = self.connect(addr, hostname, service, deadline)?;
+        let connect_time = start.elapsed();
+        let tls_version = self.verify_tls_version(&*client, service)?;
+        let request_start = Instant::now();
+        // the connection is aborted as the client is dropped, if the server doesn't respond in time
+        let features = client.features().map_err(features_error)?;
+        let handshake_time = request_start.elapsed();
+        let latency = self.config.latency_method.measure(
+            client.tcp_connect_time().unwrap_or(connect_time),
+            connect_time,
+            handshake_time,
+        );
+        self.verify_compatibility(&features, network)?;
+        self.verify_fixtures(&*client, network)?;
+        let clock_skew = self.verify_clock_skew(&*client)?;
+        let height_lag = self.verify_height_lag(&*client)?;
+        let subscriptions = self.verify_subscriptions(&*client);
+        if self.config.crawl {
+            let crawled = self.crawl(&*client, network);
+            if crawled > 0 {
+                debug!("queued {} services crawled from {}", crawled, hostname);
             }
-        };
+        }
 
-        let mut config = electrum_client::ConfigBuilder::new();
-        if let ServerAddr::Onion(_) = addr {
-            let socks = electrum_client::Socks5Config::new(
-                self.tor_proxy
-                    .chain_err(|| "no tor proxy configured, onion hosts are unsupported")?,
+        let mut reciprocates = false;
+        if self.announce && lock(&self.announce_throttle).try_acquire(addr, Instant::now()) {
+            // XXX should we require the other side to reciprocate?
+            ensure!(
+                client.add_peer(&self.our_features)?,
+                "server does not reciprocate"
             );
-            config = config.socks5(Some(socks)).unwrap()
+            reciprocates = true;
         }
 
-        let client = Client::from_config(&server_url, config.build())?;
-
-        let features = client.server_features()?.try_into()?;
-        self.verify_compatibility(&features)?;
+        Ok(CheckResult {
+            features,
+            clock_skew,
+            tls_version,
+            cert_kind,
+            subscriptions,
+            latency,
+            connect_time,
+            handshake_time,
+            height_lag,
+            reciprocates,
+        })
+    }
 
-        if self.announce {
-            // XXX should we require the other side to reciprocate?
+    /// Verify that SSL services negotiated at least `min_tls_version`, returning the negotiated
+    /// version if the probe reports it
+    fn verify_tls_version(
+        &self,
+        client: &dyn ProbeClient,
+        service: Service,
+    ) -> Result<Option<TlsVersion>> {
+        if !service.is_tls() {
+            return Ok(None);
+        }
+        let tls_version = client.tls_version();
+        if let Some(tls_version) = tls_version {
+            let min_tls_version = self.config.min_tls_version;
             ensure!(
-                client.server_add_peer(&self.our_features)?,
-                "server does not reciprocate"
+                tls_version >= min_tls_version,
+                ErrorKind::IncompatibleServer(format!(
+                    "negotiated {}, below the minimum {}",
+                    tls_version, min_tls_version
+                ))
             );
         }
+        Ok(tls_version)
+    }
 
-        Ok(features)
+    /// The deadline of a health check started at `start`, past which all requests fail
+    fn check_deadline(&self, start: Instant) -> Instant {
+        start + self.config.features_timeout * CHECK_TIMEOUT_FACTOR
     }
 
-    fn verify_compatibility(&self, features: &ServerFeatures) -> Result<()> {
-        ensure!(
-            features.genesis_hash == self.our_features.genesis_hash,
-            "incompatible networks"
-        );
+    /// Open a connection to the service, through the tor proxy for onion hosts (or all hosts in
+    /// `tor_only` mode), failing any requests once `deadline` passes. Returns the kind of
+    /// certificate presented by SSL services along with it, if known.
+    fn connect(
+        &self,
+        addr: &ServerAddr,
+        hostname: &Hostname,
+        service: Service,
+        deadline: Instant,
+    ) -> Result<(Box<dyn ProbeClient>, Option<CertKind>)> {
+        // TLS services are connected to by hostname, for the certificate to be verified against it
+        let host = match addr {
+            ServerAddr::Clearnet(ip) if !service.is_tls() && !self.config.tor_only => {
+                ip.to_string()
+            }
+            ServerAddr::Clearnet(_) => hostname.clone(),
+            ServerAddr::Onion(onion_host) => onion_host.clone(),
+        };
+        let server_url = format!("{}://{}:{}", service.scheme(), host, service.port());
 
-        ensure!(
-            features.protocol_min <= self.our_version && features.protocol_max >= self.our_version,
-            "incompatible protocol versions"
-        );
+        let socks5 = match addr {
+            ServerAddr::Onion(onion_host) => Some(self.tor_proxy_for(onion_host)?),
+            ServerAddr::Clearnet(_) if self.config.tor_only => Some(self.tor_proxy_for(hostname)?),
+            ServerAddr::Clearnet(_) => None,
+        };
 
-        ensure!(
-            features.hash_function == "sha256",
-            "incompatible hash function"
-        );
+        let require_ca_signed = match (self.config.cert_verification, addr) {
+            (CertVerification::All, _) => true,
+            (CertVerification::ClearnetOnly, ServerAddr::Clearnet(_)) => true,
+            (CertVerification::ClearnetOnly, ServerAddr::Onion(_)) => false,
+            (CertVerification::None, _) => false,
+        };
 
-        Ok(())
+        let timeout = Some(self.config.features_timeout);
+        let connect = |verify_certificate| {
+            self.probe
+                .connect(
+                    &server_url,
+                    socks5.as_ref(),
+                    timeout,
+                    Some(deadline),
+                    verify_certificate,
+                )
+                .map_err(handshake_reset)
+        };
+        if !service.is_tls() {
+            Ok((connect(false)?, None))
+        } else if require_ca_signed {
+            Ok((connect(true)?, Some(CertKind::CaSigned)))
+        } else if !self.config.detect_self_signed {
+            Ok((connect(false)?, None))
+        } else {
+            match connect(true) {
+                Ok(client) => Ok((client, Some(CertKind::CaSigned))),
+                Err(e) if probe::is_untrusted_certificate(&e) => {
+                    Ok((connect(false)?, Some(CertKind::SelfSigned)))
+                }
+                Err(e) => Err(e),
+            }
+        }
     }
 
-    pub fn spawn_jobs_thread(manager: Arc<DiscoveryManager>) {
-        spawn_thread("discovery-jobs", move || loop {
-            if let Err(e) = manager.run_health_check() {
-                debug!("health check failed: {:?}", e);
+    /// The tor proxy to connect to `host` through, with isolation credentials if enabled
+    fn tor_proxy_for(&self, host: &str) -> Result<Socks5Proxy> {
+        let addr = self.tor_proxy.chain_err(|| {
+            if self.config.tor_only {
+                "no tor proxy configured, servers are unreachable in tor-only mode"
+            } else {
+                "no tor proxy configured, onion hosts are unsupported"
             }
-            // XXX use a dynamic JOB_INTERVAL, adjusted according to the queue size and HEALTH_CHECK_FREQ?
-            thread::sleep(JOB_INTERVAL);
-        });
+        })?;
+        let credentials = match (
+            &self.config.socks5_credentials,
+            self.config.isolate_onion_circuits,
+        ) {
+            (Some((username, _)), true) => Some((username.clone(), host.to_string())),
+            (None, true) => Some(("electrs".to_string(), host.to_string())),
+            (credentials, false) => credentials.clone(),
+        };
+        Ok(Socks5Proxy { addr, credentials })
     }
-}
 
-impl Server {
-    fn new(hostname: Hostname, features: ServerFeatures) -> Self {
-        Server {
-            hostname,
-            features,
-            services: HashSet::new(),
+    /// Measure the server's clock skew, and verify that it is within `max_clock_skew`.
+    /// Skipped if no maximum is configured or if the server doesn't report its time.
+    fn verify_clock_skew(&self, client: &dyn ProbeClient) -> Result<Option<i64>> {
+        let max_skew = match self.config.max_clock_skew {
+            Some(max_skew) => max_skew,
+            None => return Ok(None),
+        };
+        let server_time = match client.server_time() {
+            Some(server_time) => server_time,
+            None => return Ok(None),
+        };
+        let skew = match server_time.duration_since(SystemTime::now()) {
+            Ok(ahead) => ahead.as_secs() as i64,
+            Err(behind) => -(behind.duration().as_secs() as i64),
+        };
+        ensure!(
+            skew.abs() as u64 <= max_skew.as_secs(),
+            "clock skew of {}s exceeds the maximum of {}s",
+            skew,
+            max_skew.as_secs()
+        );
+        Ok(Some(skew))
+    }
+
+    /// The networks we're discovering servers for, ours first
+    fn networks(&self) -> Vec<Network> {
+        let mut networks = vec![self.our_network];
+        networks.extend(
+            self.config
+                .extra_networks
+                .iter()
+                .filter(|network| **network != self.our_network),
+        );
+        networks
+    }
+
+    fn genesis_of(&self, network: Network) -> BlockHash {
+        if network == self.our_network {
+            self.our_features.genesis_hash
+        } else {
+            genesis_hash(network)
         }
     }
 
-    /// Get server features and services in the compact string array format used for `servers.peers.subscribe`
-    fn feature_strs(&self) -> Vec<String> {
-        let mut strs = Vec::with_capacity(self.services.len() + 1);
+    /// Find which of the networks we're discovering servers for the server is on
+    fn network_of(&self, features: &ServerFeatures) -> Result<Network> {
+        self.networks()
+            .into_iter()
+            .find(|network| self.genesis_of(*network) == features.genesis_hash)
+            .chain_err(|| ErrorKind::IncompatibleServer("incompatible networks".into()))
+    }
+
+    fn verify_compatibility(&self, features: &ServerFeatures, network: Network) -> Result<()> {
+        ensure!(
+            features.genesis_hash == self.genesis_of(network),
+            ErrorKind::IncompatibleServer("incompatible networks".into())
+        );
+
+        ensure!(
+            features.protocol_min <= features.protocol_max,
+            ErrorKind::IncompatibleServer(format!(
+                "invalid protocol version range {}-{}",
+                features.protocol_min, features.protocol_max
+            ))
+        );
+
+        // compatible if the version ranges overlap, as we'd negotiate a version in common
+        ensure!(
+            features.protocol_min <= self.our_version
+                && features.protocol_max >= self.our_features.protocol_min,
+            ErrorKind::IncompatibleServer("incompatible protocol versions".into())
+        );
+
+        ensure!(
+            features.hash_function == "sha256",
+            ErrorKind::IncompatibleServer("incompatible hash function".into())
+        );
+
+        Ok(())
+    }
+
+    /// How long workers wait between health checks. Checks are spread out so that the workers go
+    /// through the whole queue once per `health_check_freq`, but run back to back while there
+    /// are due jobs to catch up on.
+    fn job_interval(&self) -> Duration {
+        let queue = read_lock(&self.queue);
+        let freq = self.config.health_check_freq;
+        if queue.peek().map_or(false, |job| job.is_due(freq)) {
+            return MIN_JOB_INTERVAL;
+        }
+        if queue.is_empty() {
+            return MAX_JOB_INTERVAL;
+        }
+        let workers = self.config.health_check_workers.max(1) as u32;
+        let interval = freq * workers / queue.len() as u32;
+        interval.max(MIN_JOB_INTERVAL).min(MAX_JOB_INTERVAL)
+    }
+
+    pub fn spawn_jobs_thread(manager: Arc<DiscoveryManager>) {
+        for worker in 0..manager.config.health_check_workers {
+            let manager = Arc::clone(&manager);
+            spawn_thread(&format!("discovery-worker-{}", worker), move || loop {
+                if let Err(e) = manager.run_health_check(worker) {
+                    debug!("health check failed: {:?}", e);
+                }
+                let start = Instant::now();
+                thread::sleep(manager.job_interval());
+                manager.record_idle_time(start.elapsed());
+            });
+        }
+        if manager.config.default_servers_path.is_some() {
+            if let Err(e) = signal_hook::flag::register(
+                signal_hook::consts::SIGHUP,
+                Arc::clone(&manager.reload_requested),
+            ) {
+                warn!("failed registering the SIGHUP handler: {:?}", e);
+            }
+        }
+        spawn_thread("discovery-jobs", move || loop {
+            manager.reload_default_servers_if_requested();
+            manager.decay_reputation_if_due();
+            manager.requeue_pinned(Instant::now());
+            manager.probe_tor_proxy_if_due();
+            manager.reresolve_if_due();
+            manager.expire_gossip(Instant::now());
+            manager.expire_bans(SystemTime::now());
+            if let Err(e) = manager.bootstrap_if_due() {
+                warn!("bootstrap failed: {:?}", e);
+            }
+            if let Err(e) = manager.write_snapshot_if_due() {
+                warn!("failed writing server list snapshot: {:?}", e);
+            }
+            if let Err(e) = manager.save_state_if_due() {
+                warn!("failed saving discovery state: {:?}", e);
+            }
+            manager.update_gauges();
+            thread::sleep(JOB_INTERVAL);
+        });
+    }
+}
+
+/// Marks a job popped via `pop_due_job` as no longer running once dropped, so that onion slots are
+/// released even if the health check panics
+struct RunningJob<'a> {
+    manager: &'a DiscoveryManager,
+    transport: Transport,
+}
+
+impl Drop for RunningJob<'_> {
+    fn drop(&mut self) {
+        self.manager.finish_job(self.transport);
+    }
+}
+
+impl Server {
+    fn new(hostname: Hostname, network: Network, features: ServerFeatures) -> Self {
+        Server {
+            hostname,
+            network,
+            feature_variants: VecDeque::from(vec![features.clone()]),
+            features,
+            services: HashMap::new(),
+            clock_skew: None,
+            tls_version: None,
+            subscriptions: None,
+            reciprocates: false,
+            scores: HashMap::new(),
+            asn: None,
+            certs: HashMap::new(),
+        }
+    }
+
+    /// The score of the best scoring service
+    fn score(&self) -> f64 {
+        self.scores.values().cloned().fold(0.0, f64::max)
+    }
+
+    /// Update the server features, remembering up to `max_variants` distinct ones
+    fn update_features(&mut self, features: ServerFeatures, max_variants: usize) {
+        if let Some(index) = self.feature_variants.iter().position(|f| *f == features) {
+            self.feature_variants.remove(index);
+        }
+        self.feature_variants.push_back(features.clone());
+        while self.feature_variants.len() > max_variants.max(1) {
+            self.feature_variants.pop_front();
+        }
+        self.features = features;
+    }
+
+    /// Get server features and services in the compact string array format used for `servers.peers.subscribe`,
+    /// limited to the services whose pruning limit is accepted by `include`. The most restrictive
+    /// pruning limit among them is advertised. None if no services are included.
+    fn feature_strs(&self, include: impl Fn(Option<usize>) -> bool) -> Option<Vec<String>> {
+        let services = self
+            .services
+            .iter()
+            .filter(|(_, pruning)| include(**pruning))
+            .collect::<Vec<_>>();
+        if services.is_empty() {
+            return None;
+        }
+        let mut strs = Vec::with_capacity(services.len() + 1);
         strs.push(format!("v{}", self.features.protocol_max));
-        if let Some(pruning) = self.features.pruning {
+        if let Some(pruning) = services.iter().filter_map(|(_, pruning)| **pruning).min() {
             strs.push(format!("p{}", pruning));
         }
-        strs.extend(self.services.iter().map(|s| s.to_string()));
-        strs
+        strs.extend(services.iter().map(|(service, _)| service.to_string()));
+        strs.extend(services.iter().filter_map(|(service, _)| {
+            let cert_kind = self.certs.get(service)?;
+            Some(format!("{}{}", cert_kind.feature_prefix(), service.port()))
+        }));
+        Some(strs)
     }
 }
 
 impl ServerAddr {
     fn resolve(host: &str) -> Result<Self> {
+        Ok(Self::resolve_with(host, &SystemResolver)?.0)
+    }
+
+    /// Resolve the host using the given resolver. Onion hosts and IP addresses are not resolved,
+    /// but onion hosts are validated as v3 onion addresses.
+    fn resolve_with(host: &str, resolver: &dyn Resolver) -> Result<(Self, Resolution)> {
         Ok(if host.ends_with(".onion") {
-            ServerAddr::Onion(host.into())
+            onion::validate_onion(host)?;
+            (ServerAddr::Onion(host.into()), Resolution::default())
         } else if let Ok(ip) = IpAddr::from_str(host) {
-            ServerAddr::Clearnet(ip)
+            (ServerAddr::Clearnet(ip), Resolution::default())
         } else {
-            let ip = format!("{}:1", host)
-                .to_socket_addrs()
-                .chain_err(|| "hostname resolution failed")?
-                .next()
-                .chain_err(|| "hostname resolution failed")?
-                .ip();
-            ServerAddr::Clearnet(ip)
+            let resolution = resolver.resolve(host)?;
+            let ip = *resolution
+                .ips
+                .first()
+                .chain_err(|| "hostname resolution failed")?;
+            (ServerAddr::Clearnet(ip), resolution)
         })
     }
 }
 
+impl ServerAddr {
+    fn transport(&self) -> Transport {
+        match self {
+            ServerAddr::Clearnet(_) => Transport::Clearnet,
+            ServerAddr::Onion(_) => Transport::Onion,
+        }
+    }
+}
+
 impl fmt::Display for ServerAddr {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -442,25 +1993,69 @@ impl serde::Serialize for ServerAddr {
     }
 }
 
+impl<'de> serde::Deserialize<'de> for ServerAddr {
+    /// Parse the onion host or IP address, resolving hostnames
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = <String as serde::Deserialize>::deserialize(deserializer)?;
+        ServerAddr::resolve(&s).map_err(|e| serde::de::Error::custom(e.to_string()))
+    }
+}
+
 impl HealthCheck {
     fn new(
         addr: ServerAddr,
         hostname: Hostname,
         service: Service,
+        network: Network,
         added_by: Option<IpAddr>,
     ) -> Self {
         HealthCheck {
             addr,
             hostname,
             service,
+            network,
+            source: match added_by {
+                Some(_) => AdmissionSource::Gossip,
+                None => AdmissionSource::Default,
+            },
+            canonical_name: None,
+            ips: match addr {
+                ServerAddr::Clearnet(ip) => vec![ip],
+                ServerAddr::Onion(_) => vec![],
+            },
             is_default: added_by.is_none(),
+            is_pinned: false,
             added_by,
             last_check: None,
             last_healthy: None,
+            last_failure: None,
             consecutive_failures: 0,
+            consecutive_no_response: 0,
+            backoff: Duration::default(),
+            latency: None,
+            metrics: ServiceMetrics::default(),
+            became_healthy_at: None,
+            became_unhealthy_at: None,
+            advertised_at: Instant::now(),
+            recheck: false,
         }
     }
 
+    fn is_due(&self, freq: Duration) -> bool {
+        self.recheck
+            || self
+                .last_check
+                .map_or(true, |t| t.elapsed() >= freq + self.backoff)
+    }
+
+    // when the next health check is due, relative to the regular frequency
+    fn due_after(&self) -> Option<Instant> {
+        self.last_check.map(|t| t + self.backoff)
+    }
+
     fn is_healthy(&self) -> bool {
         match (self.last_check, self.last_healthy) {
             (Some(last_check), Some(last_healthy)) => last_check == last_healthy,
@@ -468,11 +2063,82 @@ impl HealthCheck {
         }
     }
 
-    // allow the server to fail up to MAX_CONSECTIVE_FAILURES time before giving up on it.
-    // if its a non-default server and the very first attempt fails, give up immediatly.
-    fn should_retry(&self) -> bool {
-        (self.last_healthy.is_some() || self.is_default)
-            && self.consecutive_failures < MAX_CONSECUTIVE_FAILURES
+    // allow the server to fail up to max_failures time before giving up on it.
+    // if its a non-default server and the very first attempt fails, give up immediatly,
+    // unless it was a soft failure.
+    fn should_retry(&self, max_failures: usize) -> bool {
+        (self.last_healthy.is_some()
+            || self.is_default
+            || self.is_pinned
+            || self.last_failure.map_or(false, FailureKind::is_soft))
+            && self.consecutive_failures < max_failures
+    }
+}
+
+impl ServerFilter {
+    fn matches(&self, status: &ServiceStatus) -> bool {
+        self.healthy
+            .map_or(true, |healthy| status.healthy == healthy)
+            && self
+                .transport
+                .map_or(true, |transport| status.transport == transport)
+            && self
+                .network
+                .map_or(true, |network| status.network == network)
+            && self.hostname.as_ref().map_or(true, |hostname| {
+                status.hostname.contains(&hostname.to_lowercase())
+            })
+    }
+}
+
+impl LatencyMethod {
+    /// Pick the latency out of the time it took to open the TCP connection, to complete its
+    /// handshakes too, and the `server.features` round trip over it
+    fn measure(
+        self,
+        tcp_connect: Duration,
+        established: Duration,
+        round_trip: Duration,
+    ) -> Latency {
+        let duration = match self {
+            LatencyMethod::Connect => tcp_connect,
+            LatencyMethod::Handshake => established,
+            LatencyMethod::RoundTrip => established + round_trip,
+        };
+        Latency {
+            duration,
+            method: self,
+        }
+    }
+}
+
+impl FailureKind {
+    fn classify(e: &Error) -> Self {
+        match e.kind() {
+            ErrorKind::HandshakeReset => FailureKind::HandshakeReset,
+            ErrorKind::FeaturesNoResponse => FailureKind::FeaturesNoResponse,
+            ErrorKind::IncompatibleServer(_) => FailureKind::Incompatible,
+            _ => FailureKind::Other,
+        }
+    }
+
+    fn is_soft(self) -> bool {
+        self == FailureKind::HandshakeReset
+    }
+
+    /// How much later than usual to check a service again after its `failures`th consecutive
+    /// failure: twice as late after each hard failure (four times for servers that didn't respond
+    /// to `server.features`), up to `MAX_BACKOFF_FACTOR` times the frequency. Soft failures are
+    /// retried at the usual frequency.
+    fn backoff(self, freq: Duration, failures: usize) -> Duration {
+        let base: u32 = match self {
+            FailureKind::HandshakeReset => return Duration::default(),
+            FailureKind::FeaturesNoResponse => 4,
+            FailureKind::Incompatible | FailureKind::Other => 2,
+        };
+        let exponent = failures.saturating_sub(1).min(31) as u32;
+        let factor = base.saturating_pow(exponent).min(MAX_BACKOFF_FACTOR);
+        freq * (factor - 1)
     }
 }
 
@@ -482,9 +2148,13 @@ impl PartialEq for HealthCheck {
     }
 }
 
+impl Eq for HealthCheck {}
+
 impl Ord for HealthCheck {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.last_check.cmp(&other.last_check).reverse()
+        self.recheck
+            .cmp(&other.recheck)
+            .then_with(|| self.due_after().cmp(&other.due_after()).reverse())
     }
 }
 
@@ -494,39 +2164,191 @@ impl PartialOrd for HealthCheck {
     }
 }
 
+impl Service {
+    /// The services available on the advertised ports
+    fn all_of(ports: &ServerPorts) -> impl Iterator<Item = Service> {
+        let tcp = ports.tcp_port.into_iter().map(Service::Tcp);
+        let ssl = ports.ssl_port.into_iter().map(Service::Ssl);
+        let ws = ports.ws_port.into_iter().map(Service::Ws);
+        let wss = ports.wss_port.into_iter().map(Service::Wss);
+        tcp.chain(ssl).chain(ws).chain(wss)
+    }
+
+    fn port(self) -> Port {
+        match self {
+            Service::Tcp(port) | Service::Ssl(port) | Service::Ws(port) | Service::Wss(port) => {
+                port
+            }
+        }
+    }
+
+    /// The URL scheme the service is connected to with
+    fn scheme(self) -> &'static str {
+        match self {
+            Service::Tcp(_) => "tcp",
+            Service::Ssl(_) => "ssl",
+            Service::Ws(_) => "ws",
+            Service::Wss(_) => "wss",
+        }
+    }
+
+    fn is_tls(self) -> bool {
+        match self {
+            Service::Ssl(_) | Service::Wss(_) => true,
+            Service::Tcp(_) | Service::Ws(_) => false,
+        }
+    }
+}
+
 impl fmt::Display for Service {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Service::Tcp(port) => write!(f, "t{}", port),
             Service::Ssl(port) => write!(f, "s{}", port),
+            Service::Ws(port) => write!(f, "w{}", port),
+            Service::Wss(port) => write!(f, "ws{}", port),
+        }
+    }
+}
+
+impl serde::Serialize for Service {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Service {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = <String as serde::Deserialize>::deserialize(deserializer)?;
+        s.parse()
+            .map_err(|e: Error| serde::de::Error::custom(e.to_string()))
+    }
+}
+
+impl FromStr for Service {
+    type Err = crate::errors::Error;
+
+    /// Parse the compact service format used for `servers.peers.subscribe`, like `t50001`
+    fn from_str(s: &str) -> Result<Self> {
+        let port = |prefix_len: usize| {
+            s[prefix_len..]
+                .parse()
+                .chain_err(|| format!("invalid port in {}", s))
+        };
+        if s.starts_with("ws") {
+            return Ok(Service::Wss(port(2)?));
         }
+        match s.chars().next() {
+            Some('t') => Ok(Service::Tcp(port(1)?)),
+            Some('s') => Ok(Service::Ssl(port(1)?)),
+            Some('w') => Ok(Service::Ws(port(1)?)),
+            _ => bail!("unknown service {}", s),
+        }
+    }
+}
+
+// Lock helpers that recover from poisoned locks, so that a panic in one thread while holding a
+// lock doesn't permanently break discovery for all others.
+
+fn read_lock<T>(lock: &RwLock<T>) -> RwLockReadGuard<T> {
+    lock.read().unwrap_or_else(|e| {
+        warn!("recovering poisoned discovery lock");
+        e.into_inner()
+    })
+}
+
+fn write_lock<T>(lock: &RwLock<T>) -> RwLockWriteGuard<T> {
+    lock.write().unwrap_or_else(|e| {
+        warn!("recovering poisoned discovery lock");
+        e.into_inner()
+    })
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    mutex.lock().unwrap_or_else(|e| {
+        warn!("recovering poisoned discovery lock");
+        e.into_inner()
+    })
+}
+
+// connection resets before we got the server features are classified as handshake resets
+fn handshake_reset(e: Error) -> Error {
+    if probe::is_connection_reset(&e) {
+        e.chain_err(|| ErrorKind::HandshakeReset)
+    } else {
+        e
+    }
+}
+
+// timing out waiting for the server features is classified separately from other failures
+fn features_error(e: Error) -> Error {
+    if probe::is_timeout(&e) {
+        e.chain_err(|| ErrorKind::FeaturesNoResponse)
+    } else {
+        handshake_reset(e)
     }
 }
 
-fn is_remote_addr(addr: &ServerAddr) -> bool {
+/// Normalize the hostname so that equivalent forms of it compare equal: lowercased, with
+/// surrounding whitespace and the trailing dot of fully qualified names removed
+fn normalize_hostname(hostname: &str) -> Hostname {
+    hostname.trim().trim_end_matches('.').to_lowercase()
+}
+
+/// Check whether the address is globally routable, or also allow private ones if `trust_private` is set
+fn is_remote_addr(addr: &ServerAddr, trust_private: bool) -> bool {
     match addr {
         ServerAddr::Onion(_) => true,
         ServerAddr::Clearnet(ip) => {
-            !ip.is_loopback()
-                && !ip.is_unspecified()
-                && !ip.is_multicast()
-                && !match ip {
-                    IpAddr::V4(ipv4) => ipv4.is_private(),
-                    IpAddr::V6(_) => false,
-                }
+            !ip.is_unspecified() && !ip.is_multicast() && (trust_private || !is_private_ip(ip))
+        }
+    }
+}
+
+/// Check whether the ip is in a loopback, private, link-local or otherwise non-globally-routable range
+fn is_private_ip(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ipv4) => {
+            let octets = ipv4.octets();
+            ipv4.is_loopback()
+                || ipv4.is_private()
+                || ipv4.is_link_local()
+                || ipv4.is_broadcast()
+                || ipv4.is_documentation()
+                // shared address space (100.64.0.0/10)
+                || (octets[0] == 100 && octets[1] & 0xc0 == 64)
+        }
+        IpAddr::V6(ipv6) => {
+            let segments = ipv6.segments();
+            if segments[..5] == [0, 0, 0, 0, 0] && segments[5] == 0xffff {
+                // ipv4-mapped
+                let octets = ipv6.octets();
+                let ipv4 = Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]);
+                return is_private_ip(&IpAddr::V4(ipv4));
+            }
+            ipv6.is_loopback()
+                // unique local (fc00::/7)
+                || segments[0] & 0xfe00 == 0xfc00
+                // link-local (fe80::/10)
+                || segments[0] & 0xffc0 == 0xfe80
+                // documentation (2001:db8::/32)
+                || (segments[0] == 0x2001 && segments[1] == 0xdb8)
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::test_util::*;
     use super::*;
-    use crate::chain::genesis_hash;
-    use crate::chain::Network;
     use std::time;
 
-    const PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion::new(1, 4);
-
     #[test]
     fn test() -> Result<()> {
         stderrlog::new().verbosity(4).init().unwrap();
@@ -546,6 +2368,7 @@ mod tests {
             PROTOCOL_VERSION,
             false,
             None,
+            DiscoveryConfig::default(),
         ));
         discovery.add_default_server(
             "electrum.blockstream.info".into(),
@@ -569,7 +2392,7 @@ mod tests {
 
         for _ in 0..12 {
             discovery
-                .run_health_check()
+                .run_health_check(0)
                 .map_err(|e| warn!("{:?}", e))
                 .ok();
             thread::sleep(time::Duration::from_secs(1));
@@ -581,4 +2404,1227 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_interleave_transports() {
+        let discovery = test_manager(DiscoveryConfig {
+            interleave_transports: true,
+            ..Default::default()
+        });
+        for ip in &["1.1.1.1", "1.1.1.2", "1.1.1.3"] {
+            discovery
+                .add_default_server(ip.to_string(), vec![Service::Tcp(50001)])
+                .unwrap();
+        }
+        for onion in &ONION_HOSTS {
+            discovery
+                .add_default_server(onion.to_string(), vec![Service::Tcp(50001)])
+                .unwrap();
+        }
+
+        let transports = (0..6)
+            .map(|_| {
+                let job = discovery.pop_due_job(0).unwrap();
+                discovery.finish_job(job.addr.transport());
+                job.addr.transport()
+            })
+            .collect::<Vec<_>>();
+        assert!(transports.windows(2).all(|pair| pair[0] != pair[1]));
+        assert!(discovery.pop_due_job(0).is_none());
+    }
+
+    #[test]
+    fn test_genesis_change() {
+        let (discovery, probe) = stub_manager(DiscoveryConfig::default());
+        probe.serve("tcp://1.1.1.1:50001", StubServer::new(test_features("{}")));
+        discovery
+            .add_default_server("1.1.1.1".into(), vec![Service::Tcp(50001)])
+            .unwrap();
+        discovery.run_health_check(0).unwrap();
+        assert_eq!(discovery.get_servers().len(), 1);
+
+        let mut features = test_features("{}");
+        features.genesis_hash = genesis_hash(Network::Testnet);
+        probe.serve("tcp://1.1.1.1:50001", StubServer::new(features));
+        assert!(discovery.run_health_check(0).is_err());
+
+        let addr = ServerAddr::resolve("1.1.1.1").unwrap();
+        assert!(discovery.get_servers().is_empty());
+        assert!(discovery.queue.read().unwrap().is_empty());
+        assert!(discovery.active_ban(&addr).is_some());
+    }
+
+    #[test]
+    fn test_handshake_reset() {
+        let (discovery, probe) = stub_manager(DiscoveryConfig::default());
+        let mut server = StubServer::new(test_features("{}"));
+        server.connect_error = Some(std::io::ErrorKind::ConnectionReset);
+        probe.serve("tcp://1.1.1.1:50001", server);
+
+        let request = |ip: &str| {
+            let hosts = format!("{{\"{}\":{{\"tcp_port\":50001}}}}", ip);
+            discovery.add_server_request(ip.parse().unwrap(), test_features(&hosts))
+        };
+        request("1.1.1.1").unwrap();
+        request("1.1.1.2").unwrap();
+        assert!(discovery.run_health_check(0).is_err());
+        assert!(discovery.run_health_check(0).is_err());
+
+        // the reset server is retried despite never being healthy, the refusing one is dropped
+        let queue = discovery.queue.read().unwrap();
+        assert_eq!(queue.len(), 1);
+        let job = queue.peek().unwrap();
+        assert_eq!(job.hostname, "1.1.1.1");
+        assert_eq!(job.last_failure, Some(FailureKind::HandshakeReset));
+        // soft failures are retried at the regular frequency
+        assert_eq!(job.backoff, Duration::default());
+    }
+
+    #[test]
+    fn test_backoff() {
+        let freq = Duration::from_secs(60);
+        let backoff = |kind: FailureKind, failures| kind.backoff(freq, failures).as_secs();
+        assert_eq!(backoff(FailureKind::HandshakeReset, 5), 0);
+        assert_eq!(backoff(FailureKind::Other, 1), 0);
+        assert_eq!(backoff(FailureKind::Other, 2), 60);
+        assert_eq!(backoff(FailureKind::Other, 3), 180);
+        assert_eq!(backoff(FailureKind::Incompatible, 4), 420);
+        assert_eq!(backoff(FailureKind::Other, 1000), 420);
+        assert_eq!(backoff(FailureKind::FeaturesNoResponse, 1), 0);
+        assert_eq!(backoff(FailureKind::FeaturesNoResponse, 2), 180);
+        assert_eq!(backoff(FailureKind::FeaturesNoResponse, 3), 420);
+
+        let addr = ServerAddr::resolve("1.1.1.1").unwrap();
+        let service = Service::Tcp(50001);
+        let mut job = HealthCheck::new(addr, "1.1.1.1".into(), service, Network::Regtest, None);
+        job.last_check = Some(Instant::now() - freq * 2);
+        assert!(job.is_due(freq));
+        job.backoff = FailureKind::Other.backoff(freq, 3);
+        assert!(!job.is_due(freq));
+    }
+
+    #[test]
+    fn test_config() {
+        let config = DiscoveryConfig {
+            interleave_transports: true,
+            announce_max_per_window: 7,
+            snapshot_dir: Some("/tmp/snapshots".into()),
+            ..Default::default()
+        };
+        let discovery = test_manager(config.clone());
+        assert_eq!(json!(discovery.config()), json!(config));
+        assert_eq!(discovery.config().announce_max_per_window, 7);
+    }
+
+    #[test]
+    fn test_poisoned_lock() {
+        let discovery = Arc::new(test_manager(DiscoveryConfig::default()));
+        add_healthy(&discovery, "1.1.1.1", Service::Tcp(50001));
+
+        let poisoner = Arc::clone(&discovery);
+        let result = thread::spawn(move || {
+            let _queue = poisoner.queue.write();
+            let _healthy = poisoner.healthy.write();
+            let _last_transports = poisoner.last_transports.lock();
+            panic!("poisoning discovery locks");
+        })
+        .join();
+        assert!(result.is_err());
+        assert!(discovery.queue.is_poisoned() && discovery.healthy.is_poisoned());
+
+        discovery
+            .add_default_server("1.1.1.2".into(), vec![Service::Tcp(50001)])
+            .unwrap();
+        assert_eq!(discovery.get_servers().len(), 1);
+        assert_eq!(discovery.pop_due_job(0).unwrap().hostname, "1.1.1.2");
+    }
+
+    #[test]
+    fn test_clock_skew() {
+        use ::time::format_description::well_known::Rfc3339;
+        use ::time::OffsetDateTime;
+
+        let (discovery, probe) = stub_manager(DiscoveryConfig {
+            max_clock_skew: Some(Duration::from_secs(300)),
+            ..Default::default()
+        });
+        let now = SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let server = |secs: Option<u64>| {
+            let server = StubServer::new(test_features("{}"));
+            match secs {
+                Some(secs) => {
+                    let time = OffsetDateTime::from_unix_timestamp(secs as i64).unwrap();
+                    let time = time.format(&Rfc3339).unwrap();
+                    server.respond("server.banner", json!(format!("Server time: {}", time)))
+                }
+                None => server.respond("server.banner", json!("Welcome!")),
+            }
+        };
+        probe.serve("tcp://1.1.1.1:50001", server(Some(now + 10)));
+        probe.serve("tcp://1.1.1.2:50001", server(Some(now - 3600)));
+        probe.serve("tcp://1.1.1.3:50001", server(None));
+        for ip in &["1.1.1.1", "1.1.1.2", "1.1.1.3"] {
+            discovery
+                .add_default_server(ip.to_string(), vec![Service::Tcp(50001)])
+                .unwrap();
+        }
+        let passed = (0..3)
+            .filter(|_| discovery.run_health_check(0).is_ok())
+            .count();
+        assert_eq!(passed, 2);
+
+        // the skewed server is not listed, the one not reporting its time is left unchecked
+        let healthy = read_lock(&discovery.healthy);
+        let skew = |ip: &str| healthy[&ServerAddr::resolve(ip).unwrap()].clock_skew;
+        assert!(!healthy.contains_key(&ServerAddr::resolve("1.1.1.2").unwrap()));
+        assert!(skew("1.1.1.1").map_or(false, |skew| (5..=10).contains(&skew)));
+        assert_eq!(skew("1.1.1.3"), None);
+    }
+
+    #[test]
+    fn test_worker_fairness() {
+        let discovery = test_manager(DiscoveryConfig {
+            health_check_workers: 2,
+            ..Default::default()
+        });
+        for ip in &["1.1.1.1", "1.1.1.2", "1.1.1.3"] {
+            discovery
+                .add_default_server(ip.to_string(), vec![Service::Tcp(50001)])
+                .unwrap();
+        }
+        for onion in &ONION_HOSTS {
+            discovery
+                .add_default_server(onion.to_string(), vec![Service::Tcp(50001)])
+                .unwrap();
+        }
+
+        // run rounds of concurrent jobs, with each worker holding on to its job until the round ends
+        let mut onion_jobs = [0; 2];
+        while !read_lock(&discovery.queue).is_empty() {
+            let running = (0..2)
+                .filter_map(|worker| discovery.pop_due_job(worker).map(|job| (worker, job)))
+                .collect::<Vec<_>>();
+            let onions = running
+                .iter()
+                .filter(|(_, job)| job.addr.transport() == Transport::Onion)
+                .map(|(worker, _)| *worker)
+                .collect::<Vec<_>>();
+            assert!(onions.len() <= 1, "only one slow job may run at a time");
+            for worker in onions {
+                onion_jobs[worker] += 1;
+            }
+            for (_, job) in &running {
+                discovery.finish_job(job.addr.transport());
+            }
+        }
+        assert_eq!(onion_jobs.iter().sum::<usize>(), 3);
+        assert!(onion_jobs.iter().all(|&count| count > 0));
+    }
+
+    #[test]
+    fn test_onion_slot_released_on_panic() {
+        #[derive(Debug)]
+        struct PanickingProbe;
+
+        impl Probe for PanickingProbe {
+            fn connect(
+                &self,
+                _url: &str,
+                _socks5: Option<&Socks5Proxy>,
+                _timeout: Option<Duration>,
+                _deadline: Option<Instant>,
+                _verify_certificate: bool,
+            ) -> Result<Box<dyn ProbeClient>> {
+                panic!("probe panicked")
+            }
+        }
+
+        let discovery = DiscoveryManager::new(
+            Network::Regtest,
+            test_features("{}"),
+            PROTOCOL_VERSION,
+            false,
+            Some("127.0.0.1:9050".parse().unwrap()),
+            DiscoveryConfig {
+                health_check_freq: Duration::from_secs(0),
+                max_concurrent_onion_checks: Some(1),
+                ..Default::default()
+            },
+        )
+        .with_probe(Arc::new(PanickingProbe));
+        discovery
+            .add_default_server(ONION_HOSTS[0].to_string(), vec![Service::Tcp(50001)])
+            .unwrap();
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            discovery.run_health_check(0)
+        }));
+        assert!(result.is_err());
+        assert_eq!(*lock(&discovery.onion_in_flight), 0);
+    }
+
+    #[test]
+    fn test_admission_source() {
+        let discovery = test_manager(DiscoveryConfig::default());
+        discovery
+            .add_default_server("1.1.1.1".into(), vec![Service::Tcp(50001)])
+            .unwrap();
+        let hosts = "{\"1.1.1.2\":{\"tcp_port\":50001}}";
+        discovery
+            .add_server_request("1.1.1.2".parse().unwrap(), test_features(hosts))
+            .unwrap();
+        discovery
+            .add_bootstrap_server("1.1.1.3".into(), vec![Service::Tcp(50001)])
+            .unwrap();
+
+        let sources = discovery
+            .server_status()
+            .into_iter()
+            .map(|status| (status.hostname, status.source))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sources,
+            vec![
+                ("1.1.1.1".into(), AdmissionSource::Default),
+                ("1.1.1.2".into(), AdmissionSource::Gossip),
+                ("1.1.1.3".into(), AdmissionSource::Bootstrap),
+            ]
+        );
+        assert_eq!(json!(AdmissionSource::Bootstrap), json!("bootstrap"));
+    }
+
+    #[test]
+    fn test_request_limit_policy() {
+        let extra_host = "aqcaibaeaqcaibaeaqcaibaeaqcaibaeaqcaibaeaqcaibaeaqcf7dyd.onion";
+        let hosts = ONION_HOSTS
+            .iter()
+            .chain(&[extra_host])
+            .map(|host| format!("\"{}\":{{\"tcp_port\":50001}}", host))
+            .collect::<Vec<_>>();
+        let features = test_features(&format!("{{{}}}", hosts.join(",")));
+        let added_by = "1.1.1.1".parse().unwrap();
+
+        for policy in &[
+            RequestLimitPolicy::Truncate,
+            RequestLimitPolicy::TruncateWithWarning,
+        ] {
+            let discovery = test_manager(DiscoveryConfig {
+                request_limit_policy: *policy,
+                ..Default::default()
+            });
+            discovery
+                .add_server_request(added_by, features.clone())
+                .unwrap();
+            assert_eq!(read_lock(&discovery.queue).len(), MAX_SERVERS_PER_REQUEST);
+        }
+
+        let discovery = test_manager(DiscoveryConfig {
+            request_limit_policy: RequestLimitPolicy::Reject,
+            ..Default::default()
+        });
+        assert!(discovery.add_server_request(added_by, features).is_err());
+        assert!(read_lock(&discovery.queue).is_empty());
+    }
+
+    #[test]
+    fn test_configured_limits() {
+        let hosts = ONION_HOSTS
+            .iter()
+            .map(|host| format!("\"{}\":{{\"tcp_port\":50001}}", host))
+            .collect::<Vec<_>>();
+        let features = test_features(&format!("{{{}}}", hosts.join(",")));
+        let added_by = "1.1.1.1".parse().unwrap();
+
+        let discovery = test_manager(DiscoveryConfig {
+            max_servers_per_request: 2,
+            request_limit_policy: RequestLimitPolicy::Truncate,
+            ..Default::default()
+        });
+        discovery
+            .add_server_request(added_by, features.clone())
+            .unwrap();
+        assert_eq!(read_lock(&discovery.queue).len(), 2);
+
+        let discovery = test_manager(DiscoveryConfig {
+            max_queue_size: 2,
+            ..Default::default()
+        });
+        assert!(discovery.add_server_request(added_by, features).is_err());
+        assert!(read_lock(&discovery.queue).is_empty());
+
+        let (discovery, _probe) = stub_manager(DiscoveryConfig {
+            max_consecutive_failures: 2,
+            ..Default::default()
+        });
+        discovery
+            .add_default_server("1.1.1.1".into(), vec![Service::Tcp(50001)])
+            .unwrap();
+        assert!(discovery.run_health_check(0).is_err());
+        assert_eq!(read_lock(&discovery.queue).len(), 1);
+        assert!(discovery.run_health_check(0).is_err());
+        assert!(read_lock(&discovery.queue).is_empty());
+    }
+
+    #[test]
+    fn test_non_routable_addrs() {
+        let request = |discovery: &DiscoveryManager, ip: &str| {
+            let hosts = format!("{{\"{}\":{{\"tcp_port\":50001}}}}", ip);
+            discovery
+                .add_server_request(ip.parse().unwrap(), test_features(&hosts))
+                .unwrap();
+            read_lock(&discovery.queue)
+                .iter()
+                .any(|job| job.hostname == ip)
+        };
+
+        let discovery = test_manager(DiscoveryConfig::default());
+        for ip in &[
+            "127.0.0.1",
+            "10.0.0.1",
+            "169.254.1.1",
+            "::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!request(&discovery, *ip), "{} should be rejected", ip);
+        }
+        assert!(request(&discovery, "1.1.1.1"));
+
+        // lab setups may trust private ranges
+        let discovery = test_manager(DiscoveryConfig {
+            trust_private_ranges: true,
+            ..Default::default()
+        });
+        assert!(request(&discovery, "127.0.0.1"));
+        assert!(request(&discovery, "10.0.0.1"));
+        assert!(!request(&discovery, "0.0.0.0"));
+    }
+
+    #[test]
+    fn test_require_self_advertised() {
+        let added_by = "1.1.1.1".parse().unwrap();
+        let third_party = test_features(&format!(
+            "{{\"1.1.1.2\":{{\"tcp_port\":50001}},\"{}\":{{\"tcp_port\":50001}}}}",
+            ONION_HOSTS[0]
+        ));
+
+        // by default, the third-party onion host is accepted
+        let discovery = test_manager(DiscoveryConfig::default());
+        discovery
+            .add_server_request(added_by, third_party.clone())
+            .unwrap();
+        assert_eq!(read_lock(&discovery.queue).len(), 1);
+
+        let discovery = test_manager(DiscoveryConfig {
+            require_self_advertised: true,
+            ..Default::default()
+        });
+        assert!(discovery.add_server_request(added_by, third_party).is_err());
+        assert!(read_lock(&discovery.queue).is_empty());
+
+        // peers advertising themselves may still advertise additional onion hosts
+        let hosts = format!(
+            "{{\"1.1.1.1\":{{\"tcp_port\":50001}},\"{}\":{{\"tcp_port\":50001}}}}",
+            ONION_HOSTS[0]
+        );
+        discovery
+            .add_server_request(added_by, test_features(&hosts))
+            .unwrap();
+        assert_eq!(read_lock(&discovery.queue).len(), 2);
+    }
+
+    #[test]
+    fn test_protocol_version_format() {
+        let parse = |version: &str| version.parse::<ProtocolVersion>();
+        assert_eq!(parse("1.4").unwrap(), ProtocolVersion::new(1, 4));
+        assert_eq!(parse("1.4.2").unwrap(), ProtocolVersion::new(1, 4));
+        for malformed in &[
+            "1.4.5.6", "abc", "1", "1.", ".4", "1.x", "+1.4", "1.-4", " 1.4", "",
+        ] {
+            assert!(
+                parse(malformed).is_err(),
+                "{:?} should be rejected",
+                malformed
+            );
+        }
+        let overflow = format!("{}0.1", usize::MAX);
+        assert!(parse(&overflow).is_err());
+
+        // malformed versions advertised by peers are rejected
+        let mut features = json!(test_features("{}"));
+        features["protocol_max"] = json!("1.4.5.6");
+        assert!(serde_json::from_value::<ServerFeatures>(features).is_err());
+
+        // as are nonsensical version ranges
+        let discovery = test_manager(DiscoveryConfig::default());
+        let mut features = test_features("{}");
+        features.protocol_min = ProtocolVersion::new(1, 5);
+        features.protocol_max = ProtocolVersion::new(1, 2);
+        let err = discovery
+            .verify_compatibility(&features, Network::Regtest)
+            .unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::IncompatibleServer(_)));
+    }
+
+    #[test]
+    fn test_protocol_version_ranges() {
+        // supporting 1.4 to 1.5
+        let mut features = test_features("{}");
+        features.protocol_max = ProtocolVersion::new(1, 5);
+        let discovery = DiscoveryManager::new(
+            Network::Regtest,
+            features,
+            ProtocolVersion::new(1, 5),
+            false,
+            None,
+            DiscoveryConfig::default(),
+        );
+        let compatible = |min: usize, max: usize| {
+            let mut features = test_features("{}");
+            features.protocol_min = ProtocolVersion::new(1, min);
+            features.protocol_max = ProtocolVersion::new(1, max);
+            discovery
+                .verify_compatibility(&features, Network::Regtest)
+                .is_ok()
+        };
+        assert!(compatible(4, 4));
+        assert!(compatible(2, 4));
+        assert!(compatible(5, 6));
+        assert!(compatible(0, 9));
+        assert!(!compatible(2, 3));
+        assert!(!compatible(6, 6));
+    }
+
+    #[test]
+    fn test_feature_variants() {
+        let (discovery, probe) = stub_manager(DiscoveryConfig {
+            max_feature_variants: 3,
+            ..Default::default()
+        });
+        discovery
+            .add_default_server("1.1.1.1".into(), vec![Service::Tcp(50001)])
+            .unwrap();
+        let versions = (0..6)
+            .map(|i| format!("electrs-esplora {}", i))
+            .collect::<Vec<_>>();
+        for version in versions.iter().chain(&versions[3..4]) {
+            let mut features = test_features("{}");
+            features.server_version = version.clone();
+            probe.serve("tcp://1.1.1.1:50001", StubServer::new(features));
+            discovery.run_health_check(0).unwrap();
+        }
+
+        // the store is bounded, with a variant seen again becoming the most recent
+        let healthy = read_lock(&discovery.healthy);
+        let server = &healthy[&ServerAddr::resolve("1.1.1.1").unwrap()];
+        let variants = server
+            .feature_variants
+            .iter()
+            .map(|features| features.server_version.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            variants,
+            vec![
+                "electrs-esplora 4",
+                "electrs-esplora 5",
+                "electrs-esplora 3"
+            ]
+        );
+        assert_eq!(server.features.server_version, "electrs-esplora 3");
+    }
+
+    #[test]
+    fn test_features_refresh() {
+        let (discovery, probe) = stub_manager(DiscoveryConfig::default());
+        discovery
+            .add_default_server("1.1.1.1".into(), vec![Service::Tcp(50001)])
+            .unwrap();
+        probe.serve("tcp://1.1.1.1:50001", StubServer::new(test_features("{}")));
+        discovery.run_health_check(0).unwrap();
+        assert_eq!(discovery.get_servers()[0].2, vec!["v1.4", "t50001"]);
+
+        // changes are picked up on the next health check
+        let mut features = test_features("{}");
+        features.pruning = Some(1000);
+        features.protocol_max = ProtocolVersion::new(1, 5);
+        probe.serve("tcp://1.1.1.1:50001", StubServer::new(features.clone()));
+        discovery.run_health_check(0).unwrap();
+        assert_eq!(
+            discovery.get_servers()[0].2,
+            vec!["v1.5", "p1000", "t50001"]
+        );
+
+        // servers that become incompatible are dropped
+        features.protocol_min = ProtocolVersion::new(1, 5);
+        probe.serve("tcp://1.1.1.1:50001", StubServer::new(features));
+        assert!(discovery.run_health_check(0).is_err());
+        assert!(discovery.get_servers().is_empty());
+        assert!(read_lock(&discovery.queue).is_empty());
+    }
+
+    #[test]
+    fn test_probe_timeout() {
+        // a server accepting connections but never replying
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("tcp://{}", listener.local_addr().unwrap());
+        let timeout = Some(Duration::from_millis(50));
+        let client = ElectrumProbe.connect(&url, None, timeout, None, false).unwrap();
+        let start = Instant::now();
+        let err = client.features().unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(probe::is_timeout(&err));
+        assert_eq!(
+            FailureKind::classify(&features_error(err)),
+            FailureKind::FeaturesNoResponse
+        );
+    }
+
+    #[test]
+    fn test_job_interval() {
+        let discovery = test_manager(DiscoveryConfig {
+            health_check_freq: Duration::from_secs(20),
+            health_check_workers: 2,
+            ..Default::default()
+        });
+        assert_eq!(discovery.job_interval(), MAX_JOB_INTERVAL);
+
+        for i in 1..=10 {
+            discovery
+                .add_default_server(format!("1.1.1.{}", i), vec![Service::Tcp(50001)])
+                .unwrap();
+        }
+        // never checked jobs are due
+        assert_eq!(discovery.job_interval(), MIN_JOB_INTERVAL);
+
+        {
+            let mut queue = write_lock(&discovery.queue);
+            let mut jobs = std::mem::take(&mut *queue).into_vec();
+            for job in &mut jobs {
+                job.last_check = Some(Instant::now());
+            }
+            *queue = BinaryHeap::from(jobs);
+        }
+        // 10 jobs checked every 20s by 2 workers
+        assert_eq!(discovery.job_interval(), Duration::from_secs(4));
+    }
+
+    #[test]
+    fn test_socks5_isolation() {
+        let proxy: SocketAddr = "127.0.0.1:9050".parse().unwrap();
+        let probe = Arc::new(StubProbe::default());
+        let discovery = DiscoveryManager::new(
+            Network::Regtest,
+            test_features("{}"),
+            PROTOCOL_VERSION,
+            false,
+            Some(proxy),
+            DiscoveryConfig {
+                health_check_freq: Duration::from_secs(0),
+                socks5_credentials: Some(("user".into(), "pass".into())),
+                isolate_onion_circuits: true,
+                ..Default::default()
+            },
+        )
+        .with_probe(probe.clone());
+        for hostname in &ONION_HOSTS[0..2] {
+            probe.serve(
+                &format!("tcp://{}:50001", hostname),
+                StubServer::new(test_features("{}")),
+            );
+            discovery
+                .add_default_server(hostname.to_string(), vec![Service::Tcp(50001)])
+                .unwrap();
+        }
+        probe.serve("tcp://1.1.1.1:50001", StubServer::new(test_features("{}")));
+        discovery
+            .add_default_server("1.1.1.1".into(), vec![Service::Tcp(50001)])
+            .unwrap();
+        for _ in 0..3 {
+            discovery.run_health_check(0).unwrap();
+        }
+
+        // each onion server is reached with its own credentials, clearnet servers directly
+        let mut connections = probe.connections.lock().unwrap().clone();
+        connections.sort_by(|a, b| a.0.cmp(&b.0));
+        let isolated = |hostname: &str| Socks5Proxy {
+            addr: proxy,
+            credentials: Some(("user".into(), hostname.into())),
+        };
+        assert_eq!(
+            connections,
+            vec![
+                ("tcp://1.1.1.1:50001".into(), None),
+                (
+                    format!("tcp://{}:50001", ONION_HOSTS[0]),
+                    Some(isolated(ONION_HOSTS[0]))
+                ),
+                (
+                    format!("tcp://{}:50001", ONION_HOSTS[1]),
+                    Some(isolated(ONION_HOSTS[1]))
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tor_only() {
+        let proxy: SocketAddr = "127.0.0.1:9050".parse().unwrap();
+        let manager = |tor_proxy| {
+            let probe = Arc::new(StubProbe::default());
+            probe.serve("tcp://1.1.1.1:50001", StubServer::new(test_features("{}")));
+            let discovery = DiscoveryManager::new(
+                Network::Regtest,
+                test_features("{}"),
+                PROTOCOL_VERSION,
+                false,
+                tor_proxy,
+                DiscoveryConfig {
+                    health_check_freq: Duration::from_secs(0),
+                    tor_only: true,
+                    ..Default::default()
+                },
+            )
+            .with_probe(probe.clone());
+            discovery
+                .add_default_server("1.1.1.1".into(), vec![Service::Tcp(50001)])
+                .unwrap();
+            discovery.run_health_check(0).unwrap();
+            let connections = probe.connections.lock().unwrap().clone();
+            connections
+        };
+
+        // clearnet servers are reached through the proxy too, and not at all without one
+        let via_proxy = Socks5Proxy {
+            addr: proxy,
+            credentials: None,
+        };
+        assert_eq!(
+            manager(Some(proxy)),
+            vec![("tcp://1.1.1.1:50001".into(), Some(via_proxy))]
+        );
+        assert_eq!(manager(None), vec![]);
+
+        // hostnames are resolved through the proxy too
+        let discovery = DiscoveryManager::new(
+            Network::Regtest,
+            test_features("{}"),
+            PROTOCOL_VERSION,
+            false,
+            Some(proxy),
+            DiscoveryConfig {
+                tor_only: true,
+                ..Default::default()
+            },
+        );
+        assert!(format!("{:?}", discovery.resolver).starts_with("TorResolver"));
+    }
+
+    #[test]
+    fn test_ssl_over_onion() {
+        let manager = |cert_verification| {
+            let probe = Arc::new(StubProbe::default());
+            let discovery = DiscoveryManager::new(
+                Network::Regtest,
+                test_features("{}"),
+                PROTOCOL_VERSION,
+                false,
+                Some("127.0.0.1:9050".parse().unwrap()),
+                DiscoveryConfig {
+                    health_check_freq: Duration::from_secs(0),
+                    cert_verification,
+                    detect_self_signed: false,
+                    ..Default::default()
+                },
+            )
+            .with_probe(probe.clone());
+            let onion_url = format!("ssl://{}:50002", ONION_HOSTS[0]);
+            probe.serve(&onion_url, StubServer::new(test_features("{}")));
+            probe.serve("ssl://1.1.1.1:50002", StubServer::new(test_features("{}")));
+            probe.serve("tcp://1.1.1.1:50001", StubServer::new(test_features("{}")));
+            discovery
+                .add_default_server(ONION_HOSTS[0].into(), vec![Service::Ssl(50002)])
+                .unwrap();
+            discovery
+                .add_default_server(
+                    "1.1.1.1".into(),
+                    vec![Service::Tcp(50001), Service::Ssl(50002)],
+                )
+                .unwrap();
+            for _ in 0..3 {
+                discovery.run_health_check(0).unwrap();
+            }
+            let verified = lock(&probe.verified).clone();
+            let verified = |url: &str| verified[url];
+            (
+                discovery.get_servers().len(),
+                verified(&onion_url),
+                verified("ssl://1.1.1.1:50002"),
+                verified("tcp://1.1.1.1:50001"),
+            )
+        };
+
+        // onion ssl services are checked and advertised, without verifying their certificate
+        assert_eq!(
+            manager(CertVerification::ClearnetOnly),
+            (2, false, true, false)
+        );
+        assert_eq!(manager(CertVerification::All), (2, true, true, false));
+        assert_eq!(manager(CertVerification::None), (2, false, false, false));
+    }
+
+    #[test]
+    fn test_pruning_per_service() {
+        let discovery = test_manager(DiscoveryConfig::default());
+        let save = |hostname: &str, service: Service, pruning: Option<usize>| {
+            let addr = ServerAddr::resolve(hostname).unwrap();
+            let job = HealthCheck::new(addr, hostname.into(), service, Network::Regtest, None);
+            let mut features = test_features("{}");
+            features.pruning = pruning;
+            let result = CheckResult {
+                features,
+                clock_skew: None,
+                tls_version: None,
+                cert_kind: None,
+                subscriptions: None,
+                latency: LatencyMethod::RoundTrip
+                    .measure(Duration::from_secs(0), Duration::from_secs(0)),
+                connect_time: Duration::from_secs(0),
+                handshake_time: Duration::from_secs(0),
+                height_lag: None,
+                reciprocates: false,
+            };
+            discovery.save_healthy_service(&job, result);
+        };
+        // one host fronting both an archival and a pruned node
+        save("1.1.1.1", Service::Tcp(50001), None);
+        save("1.1.1.1", Service::Ssl(50002), Some(1000));
+        save("1.1.1.2", Service::Tcp(50001), Some(5000));
+
+        let servers = |min_pruning: Option<usize>| {
+            let mut servers = discovery
+                .get_servers_by_pruning(min_pruning)
+                .into_iter()
+                .map(|ServerEntry(_, hostname, mut strs)| {
+                    strs.sort();
+                    (hostname, strs)
+                })
+                .collect::<Vec<_>>();
+            servers.sort();
+            servers
+        };
+        let v = format!("v{}", PROTOCOL_VERSION);
+        assert_eq!(
+            servers(None),
+            vec![("1.1.1.1".into(), vec!["t50001".into(), v.clone()])]
+        );
+        assert_eq!(
+            servers(Some(2000)),
+            vec![
+                ("1.1.1.1".into(), vec!["t50001".into(), v.clone()]),
+                (
+                    "1.1.1.2".into(),
+                    vec!["p5000".into(), "t50001".into(), v.clone()]
+                ),
+            ]
+        );
+        assert_eq!(
+            servers(Some(500)),
+            vec![
+                (
+                    "1.1.1.1".into(),
+                    vec!["p1000".into(), "s50002".into(), "t50001".into(), v.clone()]
+                ),
+                ("1.1.1.2".into(), vec!["p5000".into(), "t50001".into(), v]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_min_tls_version() {
+        let (discovery, probe) = stub_manager(DiscoveryConfig::default());
+        let server = |tls_version| StubServer {
+            tls_version: Some(tls_version),
+            ..StubServer::new(test_features("{}"))
+        };
+        probe.serve("ssl://1.1.1.1:50002", server(TlsVersion::Tls11));
+        probe.serve("ssl://1.1.1.2:50002", server(TlsVersion::Tls13));
+        for ip in &["1.1.1.1", "1.1.1.2"] {
+            discovery
+                .add_default_server(ip.to_string(), vec![Service::Ssl(50002)])
+                .unwrap();
+        }
+        let errors = (0..2)
+            .filter_map(|_| discovery.run_health_check(0).err())
+            .collect::<Vec<_>>();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0].kind(), ErrorKind::IncompatibleServer(_)));
+
+        // the outdated server is not listed, the negotiated version is recorded for the other
+        let healthy = read_lock(&discovery.healthy);
+        assert!(!healthy.contains_key(&ServerAddr::resolve("1.1.1.1").unwrap()));
+        let server = &healthy[&ServerAddr::resolve("1.1.1.2").unwrap()];
+        assert_eq!(server.tls_version, Some(TlsVersion::Tls13));
+    }
+
+    #[test]
+    fn test_min_tls_version_negotiated() {
+        let (discovery, _probe) = stub_manager(DiscoveryConfig {
+            min_tls_version: TlsVersion::Tls13,
+            ..Default::default()
+        });
+        let timeout = Some(Duration::from_secs(5));
+        for (version, accepted) in [
+            (rustls::ProtocolVersion::TLSv1_2, false),
+            (rustls::ProtocolVersion::TLSv1_3, true),
+        ] {
+            let port = connection::tests::tls_server(vec![version]).port();
+            let url = format!("ssl://localhost:{}", port);
+            let client = ElectrumProbe.connect(&url, None, timeout, None, false).unwrap();
+            let result = discovery.verify_tls_version(&*client, Service::Ssl(port));
+            assert_eq!(result.is_ok(), accepted);
+        }
+    }
+
+    #[test]
+    fn test_pinned_readd() {
+        let cooldown = Duration::from_secs(3600);
+        let (discovery, _probe) = stub_manager(DiscoveryConfig {
+            pinned_readd_cooldown: Some(cooldown),
+            ..Default::default()
+        });
+        let hosts = "{\"1.1.1.1\":{\"tcp_port\":50001}}";
+        discovery
+            .add_server_request("1.1.1.1".parse().unwrap(), test_features(hosts))
+            .unwrap();
+        assert_eq!(discovery.pin_server("1.1.1.1"), 1);
+
+        // retried up to the maximum despite never being healthy, then given up on
+        for _ in 0..MAX_CONSECUTIVE_FAILURES {
+            assert!(discovery.run_health_check(0).is_err());
+        }
+        assert!(read_lock(&discovery.queue).is_empty());
+
+        // re-queued once the cooldown passes
+        let dropped_at = lock(&discovery.dropped_pinned)[0].0;
+        discovery.requeue_pinned(dropped_at + cooldown / 2);
+        assert!(read_lock(&discovery.queue).is_empty());
+        discovery.requeue_pinned(dropped_at + cooldown);
+        let queue = read_lock(&discovery.queue);
+        let job = queue.peek().unwrap();
+        assert!(job.is_pinned && job.is_due(Duration::from_secs(3600)));
+        assert_eq!(job.consecutive_failures, 0);
+        assert!(lock(&discovery.dropped_pinned).is_empty());
+    }
+
+    #[test]
+    fn test_normalized_hostnames() {
+        let resolution = Resolution {
+            cnames: vec![],
+            ips: vec!["1.1.1.1".parse().unwrap()],
+        };
+        let resolver = Arc::new(StubResolver::default());
+        resolver.set("example.com", resolution);
+        let discovery = test_manager(DiscoveryConfig::default()).with_resolver(resolver);
+        for hostname in &["example.com.", "Example.COM", "example.com"] {
+            discovery
+                .add_default_server(hostname.to_string(), vec![Service::Tcp(50001)])
+                .unwrap();
+        }
+
+        let status = discovery.server_status();
+        assert_eq!(status.len(), 1);
+        assert_eq!(status[0].hostname, "example.com");
+        assert_eq!(normalize_hostname(" Example.com. "), "example.com");
+    }
+
+    #[test]
+    fn test_latency_method() {
+        let connect_delay = Duration::from_millis(200);
+        let handshake_delay = Duration::from_millis(100);
+        let features_delay = Duration::from_millis(100);
+        let latency = |latency_method| {
+            let (discovery, probe) = stub_manager(DiscoveryConfig {
+                latency_method,
+                ..Default::default()
+            });
+            let server = StubServer {
+                connect_delay,
+                handshake_delay,
+                features_delay,
+                ..StubServer::new(test_features("{}"))
+            };
+            probe.serve("tcp://1.1.1.1:50001", server);
+            discovery
+                .add_default_server("1.1.1.1".into(), vec![Service::Tcp(50001)])
+                .unwrap();
+            assert_eq!(discovery.server_status()[0].latency, None);
+            discovery.run_health_check(0).unwrap();
+            let latency = discovery.server_status()[0].latency.unwrap();
+            assert_eq!(latency.method, latency_method);
+            latency.duration
+        };
+
+        assert_eq!(latency(LatencyMethod::Connect), connect_delay);
+        let handshake = latency(LatencyMethod::Handshake);
+        let established = connect_delay + handshake_delay;
+        assert!(handshake >= established && handshake < established + features_delay);
+        let round_trip = latency(LatencyMethod::RoundTrip);
+        assert!(round_trip >= established + features_delay);
+    }
+
+    #[test]
+    fn test_features_no_response() {
+        let (discovery, probe) = stub_manager(DiscoveryConfig {
+            features_timeout: Duration::from_millis(10),
+            max_features_no_response: Some(2),
+            ..Default::default()
+        });
+        let unresponsive = || StubServer {
+            features_delay: Duration::from_secs(3600),
+            ..StubServer::new(test_features("{}"))
+        };
+        let server = unresponsive();
+        let open = server.open.clone();
+        probe.serve("tcp://1.1.1.1:50001", server);
+        discovery
+            .add_default_server("1.1.1.1".into(), vec![Service::Tcp(50001)])
+            .unwrap();
+
+        let start = Instant::now();
+        let err = discovery.run_health_check(0).unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(60));
+        assert!(matches!(err.kind(), ErrorKind::FeaturesNoResponse));
+        // the connection was closed rather than left lingering
+        assert_eq!(Arc::strong_count(&open), 2);
+        let job = read_lock(&discovery.queue).peek().cloned().unwrap();
+        assert_eq!(job.last_failure, Some(FailureKind::FeaturesNoResponse));
+
+        // other failures in between reset the count
+        let mut refusing = StubServer::new(test_features("{}"));
+        refusing.connect_error = Some(std::io::ErrorKind::ConnectionRefused);
+        probe.serve("tcp://1.1.1.1:50001", refusing);
+        assert!(discovery.run_health_check(0).is_err());
+        probe.serve("tcp://1.1.1.1:50001", unresponsive());
+        assert!(discovery.run_health_check(0).is_err());
+        let job = read_lock(&discovery.queue).peek().cloned().unwrap();
+        assert_eq!(job.consecutive_failures, 3);
+        assert_eq!(job.consecutive_no_response, 1);
+
+        // default servers are otherwise retried, but not after max_features_no_response
+        assert!(discovery.run_health_check(0).is_err());
+        assert!(read_lock(&discovery.queue).is_empty());
+    }
+
+    #[test]
+    fn test_service_transitions() {
+        let (discovery, probe) = stub_manager(DiscoveryConfig::default());
+        let features = test_features("{}");
+        probe.serve("tcp://1.1.1.1:50001", StubServer::new(features.clone()));
+        probe.serve("ssl://1.1.1.1:50002", StubServer::new(features.clone()));
+        discovery
+            .add_default_server(
+                "1.1.1.1".into(),
+                vec![Service::Tcp(50001), Service::Ssl(50002)],
+            )
+            .unwrap();
+        let transitions = |service: &str| {
+            let status = discovery.server_status();
+            let status = status.iter().find(|s| s.service == service).unwrap();
+            (status.became_healthy_at, status.became_unhealthy_at)
+        };
+        let check_all = || {
+            for _ in 0..2 {
+                discovery.run_health_check(0).ok();
+            }
+        };
+
+        check_all();
+        let (ssl_healthy_at, _) = transitions("s50002");
+        let (tcp_healthy_at, tcp_unhealthy_at) = transitions("t50001");
+        assert!(ssl_healthy_at.is_some() && tcp_healthy_at.is_some());
+        assert_eq!(tcp_unhealthy_at, None);
+
+        // the tcp service flaps, the ssl one is unaffected
+        let refusing = StubServer {
+            connect_error: Some(std::io::ErrorKind::ConnectionRefused),
+            ..StubServer::new(features.clone())
+        };
+        probe.serve("tcp://1.1.1.1:50001", refusing);
+        check_all();
+        let (_, tcp_unhealthy_at) = transitions("t50001");
+        assert!(tcp_unhealthy_at.is_some());
+        assert_eq!(transitions("s50002"), (ssl_healthy_at, None));
+
+        thread::sleep(Duration::from_millis(10));
+        probe.serve("tcp://1.1.1.1:50001", StubServer::new(features));
+        check_all();
+        let (tcp_rehealthy_at, unhealthy_at) = transitions("t50001");
+        assert!(tcp_rehealthy_at > tcp_healthy_at);
+        assert_eq!(unhealthy_at, tcp_unhealthy_at);
+        assert_eq!(transitions("s50002"), (ssl_healthy_at, None));
+    }
+
+    #[test]
+    fn test_websocket_services() {
+        for s in &["t50001", "s50002", "w50003", "ws50004"] {
+            assert_eq!(s.parse::<Service>().unwrap().to_string(), *s);
+        }
+
+        let (discovery, probe) = stub_manager(DiscoveryConfig::default());
+        let features = test_features(r#"{ "1.1.1.1": { "ws_port": 50003, "wss_port": 50004 } }"#);
+        probe.serve("ws://1.1.1.1:50003", StubServer::new(features.clone()));
+        probe.serve("wss://1.1.1.1:50004", StubServer::new(features.clone()));
+        discovery
+            .add_server_request("1.1.1.1".parse().unwrap(), features)
+            .unwrap();
+        discovery.run_health_check(0).unwrap();
+        discovery.run_health_check(0).unwrap();
+
+        let mut strs = discovery.get_servers().remove(0).2;
+        strs.sort();
+        assert_eq!(strs, vec!["ca50004", "v1.4", "w50003", "ws50004"]);
+    }
+
+    #[test]
+    fn test_cert_kinds() {
+        let strs = |config: DiscoveryConfig| {
+            let (discovery, probe) = stub_manager(config);
+            let server = |cert_error| StubServer {
+                cert_error,
+                ..StubServer::new(test_features("{}"))
+            };
+            probe.serve("ssl://1.1.1.1:50002", server(None));
+            probe.serve(
+                "ssl://1.1.1.2:50002",
+                server(Some(webpki::Error::UnknownIssuer)),
+            );
+            // certificates failing verification for other reasons fail the check
+            probe.serve(
+                "ssl://1.1.1.3:50002",
+                server(Some(webpki::Error::CertExpired)),
+            );
+            for host in &["1.1.1.1", "1.1.1.2", "1.1.1.3"] {
+                discovery
+                    .add_default_server(host.to_string(), vec![Service::Ssl(50002)])
+                    .unwrap();
+            }
+            for _ in 0..3 {
+                // fails for self-signed servers if their certificate is verified
+                let _ = discovery.run_health_check(0);
+            }
+            let mut servers = discovery
+                .get_servers()
+                .into_iter()
+                .map(|ServerEntry(_, hostname, strs)| (hostname, strs))
+                .collect::<Vec<_>>();
+            servers.sort();
+            servers
+        };
+        let v = format!("v{}", PROTOCOL_VERSION);
+
+        // self-signed certificates are accepted unless required to be CA-signed, and told apart
+        assert_eq!(
+            strs(DiscoveryConfig {
+                cert_verification: CertVerification::None,
+                ..Default::default()
+            }),
+            vec![
+                (
+                    "1.1.1.1".into(),
+                    vec![v.clone(), "s50002".into(), "ca50002".into()]
+                ),
+                (
+                    "1.1.1.2".into(),
+                    vec![v.clone(), "s50002".into(), "cs50002".into()]
+                ),
+            ]
+        );
+        assert_eq!(
+            strs(DiscoveryConfig {
+                cert_verification: CertVerification::None,
+                detect_self_signed: false,
+                ..Default::default()
+            }),
+            vec![
+                ("1.1.1.1".into(), vec![v.clone(), "s50002".into()]),
+                ("1.1.1.2".into(), vec![v.clone(), "s50002".into()]),
+                ("1.1.1.3".into(), vec![v.clone(), "s50002".into()]),
+            ]
+        );
+        assert_eq!(
+            strs(DiscoveryConfig::default()),
+            vec![("1.1.1.1".into(), vec![v, "s50002".into(), "ca50002".into()])]
+        );
+    }
+
+    #[test]
+    fn test_list_servers() {
+        let discovery = test_manager(DiscoveryConfig::default());
+        for i in 1..=9 {
+            discovery
+                .add_default_server(format!("1.1.1.{}", i), vec![Service::Tcp(50001)])
+                .unwrap();
+        }
+        for onion in &ONION_HOSTS[0..3] {
+            discovery
+                .add_default_server(onion.to_string(), vec![Service::Tcp(50001)])
+                .unwrap();
+        }
+        {
+            let mut queue = write_lock(&discovery.queue);
+            let mut jobs = std::mem::take(&mut *queue).into_vec();
+            jobs[0].last_check = Some(Instant::now());
+            jobs[0].last_healthy = jobs[0].last_check;
+            *queue = BinaryHeap::from(jobs);
+        }
+
+        // pages are consistent and non-overlapping, together covering everything
+        let page = |offset| {
+            let page = discovery.list_servers(offset, 5, None);
+            assert_eq!(page.total, 12);
+            page.servers
+                .into_iter()
+                .map(|status| status.hostname)
+                .collect::<Vec<_>>()
+        };
+        let pages = vec![page(0), page(5), page(10)];
+        assert_eq!(
+            pages.iter().map(Vec::len).collect::<Vec<_>>(),
+            vec![5, 5, 2]
+        );
+        let all = pages.concat();
+        let mut sorted = all.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(all, sorted);
+        assert_eq!(page(5), pages[1]);
+        assert!(page(15).is_empty());
+
+        let filtered = |filter| discovery.list_servers(0, 100, Some(filter)).total;
+        assert_eq!(
+            filtered(ServerFilter {
+                transport: Some(Transport::Onion),
+                ..Default::default()
+            }),
+            3
+        );
+        assert_eq!(
+            filtered(ServerFilter {
+                healthy: Some(true),
+                ..Default::default()
+            }),
+            1
+        );
+        assert_eq!(
+            filtered(ServerFilter {
+                hostname: Some("1.1.1.".into()),
+                network: Some(Network::Regtest),
+                ..Default::default()
+            }),
+            9
+        );
+    }
 }
@@ -0,0 +1,152 @@
+use std::collections::BinaryHeap;
+use std::time::Duration;
+
+use crate::electrum::discovery::{
+    lock, normalize_hostname, read_lock, write_lock, AdmissionSource, DiscoveryEvent,
+    DiscoveryManager, Service,
+};
+use crate::electrum::Hostname;
+use crate::errors::Result;
+
+impl DiscoveryManager {
+    /// Queue a server on the operator's request, subject to the same checks as advertised
+    /// servers. Returns the number of services queued.
+    pub fn add_server(&self, hostname: Hostname, services: Vec<Service>) -> Result<usize> {
+        self.add_listed_server(
+            hostname,
+            services,
+            self.our_network,
+            AdmissionSource::Manual,
+            false,
+        )
+    }
+
+    /// Schedule the queued services of the host to be health checked ahead of all others. Returns
+    /// the number of services scheduled.
+    pub fn recheck_server(&self, hostname: &str) -> usize {
+        let hostname = normalize_hostname(hostname);
+        let mut queue = write_lock(&self.queue);
+        let mut jobs = std::mem::take(&mut *queue).into_vec();
+        let mut scheduled = 0;
+        for job in jobs.iter_mut().filter(|job| job.hostname == hostname) {
+            job.recheck = true;
+            scheduled += 1;
+        }
+        *queue = BinaryHeap::from(jobs);
+        scheduled
+    }
+
+    /// Remove the host and all of its services. It may be re-added later on, unlike with
+    /// `ban_server`. Returns whether it was queued.
+    pub fn remove_server(&self, hostname: &str) -> bool {
+        let hostname = normalize_hostname(hostname);
+        let addr = read_lock(&self.queue)
+            .iter()
+            .find(|job| job.hostname == hostname)
+            .map(|job| job.addr.clone());
+        match addr {
+            Some(addr) => {
+                self.drop_server(&addr);
+                self.emit(DiscoveryEvent::Dropped {
+                    hostname,
+                    reason: "removed by the operator".into(),
+                });
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove the host and all of its services, and reject further requests to add it for
+    /// `duration`, or permanently if None. Dual-stack hosts are banned on all of their addresses.
+    pub fn ban_server(
+        &self,
+        hostname: &str,
+        reason: &str,
+        duration: Option<Duration>,
+    ) -> Result<()> {
+        let hostname = normalize_hostname(hostname);
+        let (addrs, _) = self.resolve_all(&hostname)?;
+        let reason = format!("banned by the operator: {}", reason);
+        for addr in &addrs {
+            self.drop_server(addr);
+            self.ban(addr, reason.clone(), duration);
+        }
+        self.emit(DiscoveryEvent::Dropped { hostname, reason });
+        Ok(())
+    }
+
+    /// Lift the ban of the host, on all of its addresses. Returns whether it was banned.
+    pub fn unban_server(&self, hostname: &str) -> Result<bool> {
+        let hostname = normalize_hostname(hostname);
+        let (addrs, _) = self.resolve_all(&hostname)?;
+        let mut was_banned = false;
+        for addr in &addrs {
+            lock(&self.strikes).remove(addr);
+            was_banned |= write_lock(&self.bans).remove(addr).is_some();
+        }
+        Ok(was_banned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::electrum::discovery::test_util::*;
+    use crate::electrum::discovery::*;
+
+    #[test]
+    fn test_admin() {
+        let (discovery, probe) = stub_manager(DiscoveryConfig::default());
+        probe.serve("tcp://1.1.1.1:50001", StubServer::new(test_features("{}")));
+        probe.serve("ssl://1.1.1.1:50002", StubServer::new(test_features("{}")));
+        probe.serve("tcp://1.1.1.2:50001", StubServer::new(test_features("{}")));
+        let services = vec![Service::Tcp(50001), Service::Ssl(50002)];
+        assert_eq!(
+            discovery
+                .add_server("1.1.1.1".into(), services.clone())
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            discovery
+                .add_server("1.1.1.1".into(), services.clone())
+                .unwrap(),
+            0
+        );
+        assert!(discovery
+            .add_server("127.0.0.1".into(), services.clone())
+            .is_err());
+        let status = discovery.server_status();
+        assert!(status.iter().all(|s| s.source == AdmissionSource::Manual));
+
+        // checked services are rescheduled ahead of the others when rechecked
+        discovery
+            .add_default_server("1.1.1.2".into(), vec![Service::Tcp(50001)])
+            .unwrap();
+        discovery.run_health_check(0).unwrap();
+        discovery.run_health_check(0).unwrap();
+        assert_eq!(discovery.recheck_server("1.1.1.1"), 2);
+        assert_eq!(
+            read_lock(&discovery.queue).peek().unwrap().hostname,
+            "1.1.1.1"
+        );
+
+        assert!(discovery.remove_server("1.1.1.1"));
+        assert!(!discovery.remove_server("1.1.1.1"));
+        assert_eq!(discovery.server_status().len(), 1);
+        assert!(discovery.get_servers().iter().all(|s| s.1 != "1.1.1.1"));
+        // removed servers may be re-added, banned ones may not
+        assert_eq!(
+            discovery
+                .add_server("1.1.1.1".into(), services.clone())
+                .unwrap(),
+            2
+        );
+        discovery.ban_server("1.1.1.1", "spam", None).unwrap();
+        assert_eq!(discovery.server_status().len(), 1);
+        let err = discovery
+            .add_server("1.1.1.1".into(), services)
+            .unwrap_err();
+        assert!(err.to_string().contains("banned by the operator: spam"));
+    }
+}
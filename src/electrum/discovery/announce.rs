@@ -0,0 +1,202 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::electrum::discovery::{DiscoveryConfig, ServerAddr};
+use crate::util::LruMap;
+
+/// Throttles our own `server.add_peer` registrations with other servers, so that we remain a
+/// polite participant and don't get rate-limited or banned by them
+#[derive(Debug)]
+pub(super) struct AnnounceThrottle {
+    max_per_window: usize,
+    window: Duration,
+    interval: Duration,
+    /// Times of the registrations made within the last `window`
+    recent: VecDeque<Instant>,
+    /// Time of the last registration made with each server within the last `interval`
+    pub(super) last_announced: LruMap<ServerAddr, Instant>,
+}
+
+impl AnnounceThrottle {
+    pub(super) fn new(config: &DiscoveryConfig) -> Self {
+        AnnounceThrottle {
+            max_per_window: config.announce_max_per_window,
+            window: config.announce_window,
+            interval: config.announce_interval,
+            recent: VecDeque::new(),
+            last_announced: LruMap::new(config.max_tracked_ips),
+        }
+    }
+
+    /// Check whether we may register ourselves with `addr` at `now`, recording the registration if so
+    pub(super) fn try_acquire(&mut self, addr: &ServerAddr, now: Instant) -> bool {
+        let (window, interval) = (self.window, self.interval);
+
+        if let Some(last) = self.last_announced.get(addr) {
+            if now.saturating_duration_since(*last) < interval {
+                return false;
+            }
+        }
+
+        while self
+            .recent
+            .front()
+            .map_or(false, |t| now.saturating_duration_since(*t) >= window)
+        {
+            self.recent.pop_front();
+        }
+        if self.recent.len() >= self.max_per_window {
+            return false;
+        }
+
+        self.recent.push_back(now);
+        self.last_announced
+            .retain(|_, t| now.saturating_duration_since(*t) < interval);
+        self.last_announced.insert(addr.clone(), now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::electrum::discovery::test_util::*;
+    use crate::electrum::discovery::*;
+
+    #[test]
+    fn test_announce_throttle() {
+        let mut throttle = AnnounceThrottle::new(&DiscoveryConfig {
+            announce_max_per_window: 3,
+            announce_window: Duration::from_secs(60),
+            announce_interval: Duration::from_secs(600),
+            ..Default::default()
+        });
+        let addrs = (1..=10)
+            .map(|i| ServerAddr::Clearnet(IpAddr::from([1, 1, 1, i])))
+            .collect::<Vec<_>>();
+        let start = Instant::now();
+
+        // only the first 3 newly discovered servers get registered with in the first window
+        let registered = |throttle: &mut AnnounceThrottle, now| {
+            addrs
+                .iter()
+                .filter(|addr| throttle.try_acquire(addr, now))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(registered(&mut throttle, start), addrs[0..3].to_vec());
+        assert!(registered(&mut throttle, start + Duration::from_secs(30)).is_empty());
+
+        // the next window allows another 3, skipping the servers registered with recently
+        let next = start + Duration::from_secs(60);
+        assert_eq!(registered(&mut throttle, next), addrs[3..6].to_vec());
+
+        // servers can be registered with again once the interval passes
+        let later = start + Duration::from_secs(600);
+        assert_eq!(registered(&mut throttle, later), addrs[0..3].to_vec());
+    }
+
+    #[test]
+    fn test_announce() {
+        let our_features =
+            test_features(r#"{ "example.com": { "tcp_port": 50001, "ssl_port": 50002 } }"#);
+        for &announce in &[true, false] {
+            let probe = Arc::new(StubProbe::default());
+            let config = DiscoveryConfig {
+                health_check_freq: Duration::from_secs(0),
+                ..Default::default()
+            };
+            let discovery = DiscoveryManager::new(
+                Network::Regtest,
+                our_features.clone(),
+                PROTOCOL_VERSION,
+                announce,
+                None,
+                config,
+            )
+            .with_probe(probe.clone());
+            let server = StubServer::new(test_features("{}"));
+            let calls = server.calls.clone();
+            probe.serve("tcp://1.1.1.1:50001", server);
+            discovery
+                .add_default_server("1.1.1.1".into(), vec![Service::Tcp(50001)])
+                .unwrap();
+            discovery.run_health_check(0).unwrap();
+
+            let announced = calls
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(method, _)| method == "server.add_peer")
+                .map(|(_, params)| params[0].clone())
+                .collect::<Vec<_>>();
+            let expected = if announce {
+                vec![json!(our_features)]
+            } else {
+                vec![]
+            };
+            assert_eq!(announced, expected);
+        }
+    }
+
+    #[test]
+    fn test_reciprocity_boost() {
+        let probe = Arc::new(StubProbe::default());
+        let config = DiscoveryConfig {
+            health_check_freq: Duration::from_secs(0),
+            announce_max_per_window: 1,
+            reciprocity_boost: 0.5,
+            ..Default::default()
+        };
+        let discovery = DiscoveryManager::new(
+            Network::Regtest,
+            test_features("{}"),
+            PROTOCOL_VERSION,
+            true,
+            None,
+            config,
+        )
+        .with_probe(probe.clone());
+        for ip in &["1.1.1.1", "1.1.1.2"] {
+            let url = format!("tcp://{}:50001", ip);
+            probe.serve(&url, StubServer::new(test_features("{}")));
+            discovery
+                .add_default_server(ip.to_string(), vec![Service::Tcp(50001)])
+                .unwrap();
+        }
+        discovery.run_health_check(0).unwrap();
+        discovery.run_health_check(0).unwrap();
+
+        // only the first server checked was registered with, so only it reciprocated
+        let reciprocating = read_lock(&discovery.healthy)
+            .values()
+            .filter(|server| server.reciprocates)
+            .map(|server| server.hostname.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(reciprocating.len(), 1);
+        let servers = discovery.get_servers();
+        assert_eq!(servers.len(), 2);
+        assert_eq!(servers[0].1, reciprocating[0]);
+    }
+
+    #[test]
+    fn test_tracking_map_bounded() {
+        let mut throttle = AnnounceThrottle::new(&DiscoveryConfig {
+            announce_max_per_window: usize::MAX,
+            max_tracked_ips: 100,
+            ..Default::default()
+        });
+        let addr = |i: u32| ServerAddr::Clearnet(IpAddr::from(i.to_be_bytes()));
+        let now = Instant::now();
+        for i in 0..10_000 {
+            assert!(throttle.try_acquire(&addr(i), now));
+        }
+        assert_eq!(throttle.last_announced.len(), 100);
+
+        // the least recently used entries were evicted, the most recent ones are still throttled
+        assert!(throttle.last_announced.get(&addr(0)).is_none());
+        assert!(throttle.last_announced.get(&addr(9_999)).is_some());
+        assert!(!throttle.try_acquire(&addr(9_900), now));
+        assert!(throttle.try_acquire(&addr(9_899), now));
+        assert_eq!(throttle.last_announced.len(), 100);
+    }
+}
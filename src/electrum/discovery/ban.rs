@@ -0,0 +1,324 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+use crate::electrum::discovery::{
+    is_remote_addr, lock, normalize_hostname, read_lock, write_lock, DiscoveryManager, ServerAddr,
+};
+use crate::errors::{Error, Result, ResultExt};
+
+/// Which takes precedence when a default server is also banned
+#[derive(Serialize, Eq, PartialEq, Copy, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum BanPrecedence {
+    /// The ban wins, and the default server is not queued
+    Ban,
+    /// The default server is queued regardless of the ban
+    Default,
+}
+
+impl FromStr for BanPrecedence {
+    type Err = Error;
+
+    /// Parse the `--electrum-discovery-ban-precedence` option: `ban` or `default`
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ban" => Ok(BanPrecedence::Ban),
+            "default" => Ok(BanPrecedence::Default),
+            _ => bail!("invalid ban precedence {}", s),
+        }
+    }
+}
+
+/// An IP range in CIDR notation, like `192.0.2.0/24`
+#[derive(Serialize, Eq, PartialEq, Copy, Clone, Debug)]
+pub struct IpRange {
+    pub addr: IpAddr,
+    pub prefix_len: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_matches(
+                u32::from(net).into(),
+                u32::from(*ip).into(),
+                self.prefix_len,
+                32,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(net), u128::from(*ip), self.prefix_len, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = Error;
+
+    /// Parse CIDR notation, or a single IP address as a range of its own
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.trim().splitn(2, '/');
+        let addr: IpAddr = parts
+            .next()
+            .unwrap_or_default()
+            .parse()
+            .chain_err(|| format!("invalid IP range {}", s))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match parts.next() {
+            Some(len) => len
+                .parse()
+                .chain_err(|| format!("invalid IP range {}", s))?,
+            None => max_len,
+        };
+        ensure!(prefix_len <= max_len, "invalid IP range {}", s);
+        Ok(IpRange { addr, prefix_len })
+    }
+}
+
+/// A server banned for misbehaving or on the operator's request. Further requests to add it are
+/// rejected until the ban expires.
+#[derive(Serialize, Clone, Debug)]
+pub struct Ban {
+    pub reason: String,
+    /// When the ban lifts, None if permanent
+    pub expires_at: Option<SystemTime>,
+}
+
+impl Ban {
+    fn is_active(&self, now: SystemTime) -> bool {
+        self.expires_at.map_or(true, |expires_at| now < expires_at)
+    }
+}
+
+fn prefix_matches(net: u128, ip: u128, prefix_len: u8, bits: u8) -> bool {
+    let prefix_len = prefix_len.min(bits);
+    if prefix_len == 0 {
+        return true;
+    }
+    let shift = bits - prefix_len;
+    (net >> shift) == (ip >> shift)
+}
+
+impl DiscoveryManager {
+    /// Check the server against the `banned_hosts` and `banned_ranges`, returning the reason if banned
+    pub(super) fn ban_reason(&self, hostname: &str, addr: &ServerAddr) -> Option<String> {
+        let banned_hosts = &self.config.banned_hosts;
+        if banned_hosts
+            .iter()
+            .any(|banned| normalize_hostname(banned) == hostname)
+        {
+            return Some(format!("{} is banned", hostname));
+        }
+        if let ServerAddr::Clearnet(ip) = addr {
+            if let Some(range) = self.config.banned_ranges.iter().find(|r| r.contains(ip)) {
+                return Some(format!(
+                    "{} is in banned range {}/{}",
+                    ip, range.addr, range.prefix_len
+                ));
+            }
+        }
+        None
+    }
+
+    /// Check the address against what servers may never be reached at, regardless of how they were
+    /// added: non-remote addresses (unless trusted), our own and actively banned ones. Returns the
+    /// reason if rejected. Re-resolved addresses get the same checks as on admission, as
+    /// hostnames can be re-pointed anywhere.
+    pub(super) fn addr_rejection(&self, addr: &ServerAddr) -> Option<String> {
+        if !is_remote_addr(addr, self.config.trust_private_ranges) || self.our_addrs.contains(addr)
+        {
+            return Some(format!("{} is an own or non-remote addr", addr));
+        }
+        self.active_ban(addr)
+            .map(|reason| format!("{} is banned ({})", addr, reason))
+    }
+
+    /// Ban the server for `duration`, or permanently if None
+    pub(super) fn ban(&self, addr: &ServerAddr, reason: String, duration: Option<Duration>) {
+        warn!("banning {}: {}", addr, reason);
+        let expires_at = duration.map(|duration| SystemTime::now() + duration);
+        lock(&self.strikes).remove(addr);
+        write_lock(&self.bans).insert(addr.clone(), Ban { reason, expires_at });
+    }
+
+    /// The reason the server is banned, if it currently is
+    pub(super) fn active_ban(&self, addr: &ServerAddr) -> Option<String> {
+        read_lock(&self.bans)
+            .get(addr)
+            .filter(|ban| ban.is_active(SystemTime::now()))
+            .map(|ban| ban.reason.clone())
+    }
+
+    /// Count a misbehavior of the server, banning it for `ban_duration` once it reaches
+    /// `strikes_before_ban`. Returns whether it was banned.
+    pub(super) fn strike(&self, addr: &ServerAddr, reason: &str) -> bool {
+        let strikes = {
+            let mut strikes = lock(&self.strikes);
+            let count = strikes.get(addr).cloned().unwrap_or(0) + 1;
+            strikes.insert(addr.clone(), count);
+            count
+        };
+        debug!("strike {} for {}: {}", strikes, addr, reason);
+        if strikes < self.config.strikes_before_ban.max(1) {
+            return false;
+        }
+        self.ban(
+            addr,
+            format!("{} ({} times)", reason, strikes),
+            self.config.ban_duration,
+        );
+        true
+    }
+
+    /// Forget the bans that expired by `now`
+    pub(super) fn expire_bans(&self, now: SystemTime) {
+        write_lock(&self.bans).retain(|addr, ban| {
+            let active = ban.is_active(now);
+            if !active {
+                debug!("ban of {} expired", addr);
+            }
+            active
+        });
+    }
+
+    /// Get the currently banned servers by address
+    pub fn bans(&self) -> Vec<(String, Ban)> {
+        let now = SystemTime::now();
+        let mut bans = read_lock(&self.bans)
+            .iter()
+            .filter(|(_, ban)| ban.is_active(now))
+            .map(|(addr, ban)| (addr.to_string(), ban.clone()))
+            .collect::<Vec<_>>();
+        bans.sort_by(|a, b| a.0.cmp(&b.0));
+        bans
+    }
+
+    /// Check whether a default server should be skipped for being banned, according to `ban_precedence`
+    pub(super) fn skip_banned_default(&self, hostname: &str, addr: &ServerAddr) -> bool {
+        let reason = match self.ban_reason(hostname, addr) {
+            Some(reason) => reason,
+            None => return false,
+        };
+        match self.config.ban_precedence {
+            BanPrecedence::Ban => {
+                warn!(
+                    "not queueing banned default server {}: {}",
+                    hostname, reason
+                );
+                true
+            }
+            BanPrecedence::Default => {
+                warn!(
+                    "queueing default server {} despite the ban: {}",
+                    hostname, reason
+                );
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::electrum::discovery::test_util::*;
+    use crate::electrum::discovery::*;
+
+    #[test]
+    fn test_bans() {
+        let discovery = test_manager(DiscoveryConfig {
+            strikes_before_ban: 2,
+            ban_duration: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        });
+        let added_by: IpAddr = "1.1.1.1".parse().unwrap();
+        let request = |hosts: &str| discovery.add_server_request(added_by, test_features(hosts));
+        let mismatching = r#"{"1.1.1.2":{"tcp_port":50001}}"#;
+        let own = r#"{"1.1.1.1":{"tcp_port":50001}}"#;
+
+        // peers advertising mismatching ips are banned after repeated strikes
+        request(mismatching).unwrap();
+        assert!(discovery.bans().is_empty());
+        request(mismatching).unwrap();
+        let bans = discovery.bans();
+        assert_eq!(bans.len(), 1);
+        assert_eq!(bans[0].0, "1.1.1.1");
+        assert!(bans[0].1.reason.contains("mismatching ips"));
+        assert!(bans[0].1.expires_at.is_some());
+        let err = request(own).unwrap_err();
+        assert!(err.to_string().contains("1.1.1.1 is banned"));
+        assert!(read_lock(&discovery.queue).is_empty());
+
+        // until the ban expires
+        discovery.expire_bans(SystemTime::now() + Duration::from_secs(3600));
+        assert!(discovery.bans().is_empty());
+        request(own).unwrap();
+        assert_eq!(read_lock(&discovery.queue).len(), 1);
+
+        // bans made by the operator may be permanent, and lifted manually
+        discovery.ban_server("1.1.1.3", "spam", None).unwrap();
+        discovery.expire_bans(SystemTime::now() + Duration::from_secs(365 * 24 * 3600));
+        assert_eq!(discovery.bans()[0].1.expires_at, None);
+        assert!(discovery.unban_server("1.1.1.3").unwrap());
+        assert!(!discovery.unban_server("1.1.1.3").unwrap());
+        assert!(discovery.bans().is_empty());
+    }
+
+    #[test]
+    fn test_banned_defaults() {
+        let config = DiscoveryConfig {
+            banned_hosts: vec!["1.1.1.1".to_string()].into_iter().collect(),
+            banned_ranges: vec![IpRange {
+                addr: "1.1.2.0".parse().unwrap(),
+                prefix_len: 24,
+            }],
+            ..Default::default()
+        };
+        let add_defaults = |discovery: &DiscoveryManager| {
+            for ip in &["1.1.1.1", "1.1.2.3", "1.1.3.1"] {
+                discovery
+                    .add_default_server(ip.to_string(), vec![Service::Tcp(50001)])
+                    .unwrap();
+            }
+            discovery
+                .server_status()
+                .into_iter()
+                .map(|status| status.hostname)
+                .collect::<Vec<_>>()
+        };
+
+        // the ban wins by default
+        let discovery = test_manager(config.clone());
+        assert_eq!(add_defaults(&discovery), vec!["1.1.3.1"]);
+        // banned hosts are rejected over gossip regardless
+        let hosts = "{\"1.1.2.4\":{\"tcp_port\":50001}}";
+        discovery
+            .add_server_request("1.1.2.4".parse().unwrap(), test_features(hosts))
+            .unwrap();
+        assert_eq!(discovery.server_status().len(), 1);
+
+        let discovery = test_manager(DiscoveryConfig {
+            ban_precedence: BanPrecedence::Default,
+            ..config
+        });
+        assert_eq!(
+            add_defaults(&discovery),
+            vec!["1.1.1.1", "1.1.2.3", "1.1.3.1"]
+        );
+    }
+
+    #[test]
+    fn test_ip_range_parsing() {
+        let range: IpRange = "1.1.2.0/24".parse().unwrap();
+        assert!(range.contains(&"1.1.2.3".parse().unwrap()));
+        assert!(!range.contains(&"1.1.3.1".parse().unwrap()));
+        let range: IpRange = "2001:db8::1".parse().unwrap();
+        assert_eq!(range.prefix_len, 128);
+        assert!(range.contains(&"2001:db8::1".parse().unwrap()));
+        assert!(!range.contains(&"2001:db8::2".parse().unwrap()));
+        for invalid in &["1.1.2.0/33", "2001:db8::/129", "1.1.2/24", "1.1.2.0/x", ""] {
+            assert!(invalid.parse::<IpRange>().is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use std::time::{Duration, Instant};
+
+use crate::chain::Network;
+use crate::electrum::discovery::resolver::clearnet_ips;
+use crate::electrum::discovery::{
+    is_remote_addr, lock, normalize_hostname, write_lock, AdmissionSource, DiscoveryManager,
+    HealthCheck, Service,
+};
+use crate::electrum::Hostname;
+use crate::errors::{Result, ResultExt};
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A peer list entry, in the same format returned by `server.peers.subscribe`
+pub(super) type PeerListEntry = (String, Hostname, Vec<String>);
+
+impl DiscoveryManager {
+    /// Fetch the configured bootstrap peer list and queue its servers, if its due
+    pub(super) fn bootstrap_if_due(&self) -> Result<()> {
+        let url = match self.config.bootstrap_url {
+            Some(ref url) => url,
+            None => return Ok(()),
+        };
+        // the list is fetched over clearnet
+        if self.config.tor_only {
+            return Ok(());
+        }
+        {
+            let mut last_bootstrap = lock(&self.last_bootstrap);
+            if last_bootstrap.map_or(false, |t| t.elapsed() < self.config.bootstrap_interval) {
+                return Ok(());
+            }
+            *last_bootstrap = Some(Instant::now());
+        }
+
+        // the compiled-in default servers remain queued regardless, so a failed fetch
+        // just means we have to make do with them
+        let entries = fetch_peer_list(url)?;
+        let added = entries
+            .into_iter()
+            .map(|(_, hostname, feature_strs)| {
+                self.add_bootstrap_server(hostname, parse_services(&feature_strs))
+                    .map_err(|e| warn!("failed adding bootstrap server: {:?}", e))
+                    .unwrap_or(0)
+            })
+            .sum::<usize>();
+        info!("queued {} services from bootstrap peer list {}", added, url);
+        Ok(())
+    }
+
+    /// Queue the given services of a server from the bootstrap peer list, skipping ones that are
+    /// already queued. Returns the number of services added.
+    pub(super) fn add_bootstrap_server(
+        &self,
+        hostname: Hostname,
+        services: Vec<Service>,
+    ) -> Result<usize> {
+        let is_default = self.config.bootstrap_as_default;
+        let network = self.our_network;
+        self.add_listed_server(
+            hostname,
+            services,
+            network,
+            AdmissionSource::Bootstrap,
+            is_default,
+        )
+    }
+
+    /// Queue the given services of a server from a peer list, skipping ones that are already
+    /// queued. Returns the number of services added.
+    pub(super) fn add_listed_server(
+        &self,
+        hostname: Hostname,
+        services: Vec<Service>,
+        network: Network,
+        source: AdmissionSource,
+        is_default: bool,
+    ) -> Result<usize> {
+        let hostname = normalize_hostname(&hostname);
+        let (addrs, canonical_name) = self.resolve_all(&hostname)?;
+        let addr = addrs[0].clone();
+        if is_default {
+            if self.skip_banned_default(&hostname, &addr) {
+                return Ok(0);
+            }
+        } else if let Some(reason) = self.ban_reason(&hostname, &addr) {
+            bail!(reason);
+        }
+        // peer lists are fetched remotely, so their servers get the same checks as the ones
+        // added via `server.add_peer`, even when treated as default servers
+        ensure!(
+            is_remote_addr(&addr, self.config.trust_private_ranges)
+                && !self.our_addrs.contains(&addr),
+            "own or non-remote server addr"
+        );
+        if let Some(reason) = self.active_ban(&addr) {
+            bail!("banned server {} ({})", hostname, reason);
+        }
+
+        let mut queue = write_lock(&self.queue);
+        let jobs = services
+            .into_iter()
+            .map(|service| {
+                let mut job =
+                    HealthCheck::new(addr.clone(), hostname.clone(), service, network, None);
+                job.source = source;
+                job.canonical_name = canonical_name.clone();
+                job.ips = clearnet_ips(&addrs);
+                job.is_default = is_default;
+                job
+            })
+            .filter(|job| !queue.iter().any(|queued| queued == job))
+            .collect::<Vec<_>>();
+        ensure!(
+            is_default || queue.len() + jobs.len() <= self.config.max_queue_size,
+            "queue size exceeded"
+        );
+        for job in &jobs {
+            self.emit(job.queued_event());
+        }
+        let added = jobs.len();
+        queue.extend(jobs);
+        Ok(added)
+    }
+}
+
+/// Parse the services out of a peer list entry's feature strings, ignoring the other features
+pub(super) fn parse_services(feature_strs: &[String]) -> Vec<Service> {
+    feature_strs.iter().filter_map(|s| s.parse().ok()).collect()
+}
+
+/// Fetch a JSON peer list. TLS certificates are validated for https urls.
+fn fetch_peer_list(url: &str) -> Result<Vec<PeerListEntry>> {
+    if !url.starts_with("https://") {
+        warn!(
+            "fetching bootstrap peer list over an insecure connection: {}",
+            url
+        );
+    }
+    let agent = ureq::AgentBuilder::new().timeout(FETCH_TIMEOUT).build();
+    let body = agent
+        .get(url)
+        .call()
+        .chain_err(|| format!("failed fetching bootstrap peer list from {}", url))?
+        .into_string()
+        .chain_err(|| "failed reading bootstrap peer list")?;
+    serde_json::from_str(&body).chain_err(|| "invalid bootstrap peer list")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::electrum::discovery::test_util::*;
+    use crate::electrum::discovery::*;
+
+    #[test]
+    fn test_bootstrap() {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/peers.json", server.server_addr());
+        let peers = json!([
+            ["1.1.1.1", "1.1.1.1", ["v1.4", "t50001", "s50002"]],
+            [ONION_HOSTS[0], ONION_HOSTS[0], ["v1.4", "p10000", "t50001"]],
+            ["127.0.0.1", "127.0.0.1", ["v1.4", "t50001"]],
+        ]);
+        let responder = thread::spawn(move || {
+            let request = server.recv().unwrap();
+            let response = tiny_http::Response::from_string(peers.to_string());
+            request.respond(response).unwrap();
+        });
+
+        let discovery = test_manager(DiscoveryConfig {
+            bootstrap_url: Some(url),
+            ..Default::default()
+        });
+        discovery.bootstrap_if_due().unwrap();
+        responder.join().unwrap();
+
+        let queue = read_lock(&discovery.queue);
+        let mut services = queue
+            .iter()
+            .map(|job| (job.hostname.as_str(), job.service))
+            .collect::<Vec<_>>();
+        services.sort_by_key(|(hostname, service)| (*hostname, service.to_string()));
+        assert_eq!(
+            services,
+            vec![
+                ("1.1.1.1", Service::Ssl(50002)),
+                ("1.1.1.1", Service::Tcp(50001)),
+                (ONION_HOSTS[0], Service::Tcp(50001)),
+            ]
+        );
+        assert!(queue.iter().all(|job| !job.is_default));
+        drop(queue);
+
+        // not due again until the interval passes
+        discovery.bootstrap_if_due().unwrap();
+    }
+}
@@ -0,0 +1,459 @@
+//! The connections used to probe remote servers: JSON-RPC over TCP or SSL, optionally framed as
+//! WebSocket messages and through a SOCKS5 proxy. Every blocking operation is bounded by the
+//! socket's read and write timeouts, and all of them together by the deadline of the health check,
+//! so that unresponsive or trickling servers are given up on without leaving any threads behind.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rustls::{
+    Certificate, ClientConfig, ClientSession, ProtocolVersion, RootCertStore, ServerCertVerified,
+    ServerCertVerifier, Session, StreamOwned, TLSError,
+};
+use serde_json::Value;
+
+use crate::errors::*;
+use crate::util::{socks, websocket};
+
+use super::probe::{Socks5Proxy, TlsVersion};
+
+/// Replies are rejected above this size, which fits the largest transactions and peer lists
+const MAX_REPLY_SIZE: u64 = 16 * 1024 * 1024;
+
+lazy_static! {
+    static ref VERIFYING_TLS_CONFIG: Arc<ClientConfig> = tls_config(true);
+    static ref NON_VERIFYING_TLS_CONFIG: Arc<ClientConfig> = tls_config(false);
+}
+
+/// The protocols that services are probed over
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(super) enum Protocol {
+    Tcp,
+    Ssl,
+    Ws,
+    Wss,
+}
+
+/// A socket whose reads and writes are each bounded by `timeout`, and fail once `deadline` passes
+struct BoundedSocket {
+    sock: TcpStream,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+}
+
+impl BoundedSocket {
+    fn set_timeouts(&self) -> io::Result<()> {
+        let timeout = bounded_timeout(self.timeout, self.deadline)?;
+        self.sock.set_read_timeout(timeout)?;
+        self.sock.set_write_timeout(timeout)
+    }
+}
+
+impl Read for BoundedSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.set_timeouts()?;
+        self.sock.read(buf)
+    }
+}
+
+impl Write for BoundedSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.set_timeouts()?;
+        self.sock.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sock.flush()
+    }
+}
+
+enum Stream {
+    Tcp(BoundedSocket),
+    Ssl(Box<StreamOwned<ClientSession, BoundedSocket>>),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(sock) => sock.read(buf),
+            Stream::Ssl(tls) => tls.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(sock) => sock.write(buf),
+            Stream::Ssl(tls) => tls.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(sock) => sock.flush(),
+            Stream::Ssl(tls) => tls.flush(),
+        }
+    }
+}
+
+/// An open JSON-RPC connection, sending one request at a time
+pub(super) struct RpcConnection {
+    stream: Mutex<BufReader<Stream>>,
+    websocket: bool,
+    next_id: AtomicUsize,
+    /// The TLS version negotiated for SSL connections
+    pub tls_version: Option<TlsVersion>,
+    /// How long opening the TCP connection took, through the proxy if any, before any TLS or
+    /// websocket handshake
+    pub tcp_connect_time: Duration,
+}
+
+impl RpcConnection {
+    /// Connect to `host:port`. The certificate of SSL services is verified against `host` if
+    /// `verify_certificate` is set. Through a proxy, `host` is resolved by the proxy. Every
+    /// operation fails once `timeout` elapses, and all of them once `deadline` passes, if set.
+    pub fn connect(
+        host: &str,
+        port: u16,
+        protocol: Protocol,
+        socks5: Option<&Socks5Proxy>,
+        timeout: Option<Duration>,
+        deadline: Option<Instant>,
+        verify_certificate: bool,
+    ) -> Result<Self> {
+        let start = Instant::now();
+        let connect_timeout =
+            bounded_timeout(timeout, deadline).chain_err(|| "failed to connect")?;
+        let sock = match socks5 {
+            Some(proxy) => {
+                let credentials = proxy
+                    .credentials
+                    .as_ref()
+                    .map(|(username, password)| (username.as_str(), password.as_str()));
+                socks::connect(proxy.addr, credentials, host, port, connect_timeout)?
+            }
+            None => connect_direct(host, port, connect_timeout)?,
+        };
+        let tcp_connect_time = start.elapsed();
+        let sock = BoundedSocket {
+            sock,
+            timeout,
+            deadline,
+        };
+        let (stream, tls_version) = match protocol {
+            Protocol::Ssl | Protocol::Wss => {
+                let tls = tls_handshake(sock, host, verify_certificate)?;
+                let tls_version = tls.sess.get_protocol_version().and_then(to_tls_version);
+                (Stream::Ssl(Box::new(tls)), tls_version)
+            }
+            Protocol::Tcp | Protocol::Ws => (Stream::Tcp(sock), None),
+        };
+        let mut stream = BufReader::new(stream);
+        let websocket = match protocol {
+            Protocol::Ws | Protocol::Wss => true,
+            Protocol::Tcp | Protocol::Ssl => false,
+        };
+        if websocket {
+            let key = rand::random();
+            websocket::connect(stream.get_mut(), host, key)
+                .and_then(|()| websocket::read_connect_reply(&mut stream, key))
+                .chain_err(|| "websocket handshake failed")?;
+        }
+        Ok(RpcConnection {
+            stream: Mutex::new(stream),
+            websocket,
+            next_id: AtomicUsize::new(0),
+            tls_version,
+            tcp_connect_time,
+        })
+    }
+
+    /// Send the request and wait for its reply, failing if the socket times out in between or the
+    /// deadline passes
+    pub fn request(&self, method: &str, params: &[Value]) -> Result<Value> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let request = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        let mut stream = self.stream.lock().unwrap();
+        let writer = stream.get_mut();
+        let sent = if self.websocket {
+            websocket::write_masked_text(writer, &request.to_string(), rand::random())
+        } else {
+            writer.write_all(format!("{}\n", request).as_bytes())
+        };
+        sent.and_then(|()| writer.flush())
+            .chain_err(|| format!("failed to send {}", method))?;
+        loop {
+            let message = self
+                .read_message(&mut stream)
+                .chain_err(|| format!("failed to read {} reply", method))?;
+            let reply: Value =
+                serde_json::from_str(&message).chain_err(|| format!("invalid {} reply", method))?;
+            // skip notifications, which may be sent at any time
+            if reply.get("id").and_then(Value::as_u64) != Some(id as u64) {
+                continue;
+            }
+            if let Some(error) = reply.get("error").filter(|error| !error.is_null()) {
+                bail!("{} failed: {}", method, error);
+            }
+            return reply
+                .get("result")
+                .cloned()
+                .chain_err(|| format!("missing {} result", method));
+        }
+    }
+
+    /// Read the next line, or the next message of websocket connections
+    fn read_message(&self, stream: &mut BufReader<Stream>) -> Result<String> {
+        let message = if self.websocket {
+            // the server's pings are answered once its message was read, as the stream can't be
+            // written to while it's being read from
+            let mut replies = vec![];
+            let message = websocket::read_server_message(stream, &mut replies);
+            if !replies.is_empty() {
+                let writer = stream.get_mut();
+                writer
+                    .write_all(&replies)
+                    .and_then(|()| writer.flush())
+                    .chain_err(|| "failed to reply to control frames")?;
+            }
+            message?
+        } else {
+            let mut line = String::new();
+            let len = stream
+                .by_ref()
+                .take(MAX_REPLY_SIZE)
+                .read_line(&mut line)
+                .chain_err(|| "failed to read line")?;
+            ensure!((len as u64) < MAX_REPLY_SIZE, "reply too large");
+            Some(line).filter(|_| len > 0)
+        };
+        message.ok_or_else(|| {
+            let eof = io::Error::from(io::ErrorKind::UnexpectedEof);
+            Error::with_chain(eof, "connection closed")
+        })
+    }
+}
+
+fn connect_direct(host: &str, port: u16, timeout: Option<Duration>) -> Result<TcpStream> {
+    let addrs = (host, port)
+        .to_socket_addrs()
+        .chain_err(|| format!("failed to resolve {}", host))?;
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no addresses");
+    for addr in addrs {
+        let result = match timeout {
+            Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+            None => TcpStream::connect(addr),
+        };
+        match result {
+            Ok(sock) => {
+                sock.set_read_timeout(timeout)
+                    .chain_err(|| "failed setting read timeout")?;
+                sock.set_write_timeout(timeout)
+                    .chain_err(|| "failed setting write timeout")?;
+                return Ok(sock);
+            }
+            Err(e) => last_error = e,
+        }
+    }
+    let message = format!("failed to connect to {}:{}", host, port);
+    Err(Error::with_chain(last_error, message))
+}
+
+/// The timeout of the next operation: `timeout`, shortened to the time left until `deadline`.
+/// Fails with a timeout error once the deadline passed.
+fn bounded_timeout(
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+) -> io::Result<Option<Duration>> {
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => return Ok(timeout),
+    };
+    let left = deadline.saturating_duration_since(Instant::now());
+    if left == Duration::from_secs(0) {
+        return Err(io::Error::new(io::ErrorKind::TimedOut, "deadline passed"));
+    }
+    Ok(Some(timeout.map_or(left, |timeout| timeout.min(left))))
+}
+
+/// Complete the TLS handshake, for certificate errors to fail the connection right away
+fn tls_handshake(
+    mut sock: BoundedSocket,
+    host: &str,
+    verify_certificate: bool,
+) -> Result<StreamOwned<ClientSession, BoundedSocket>> {
+    let config = if verify_certificate {
+        &*VERIFYING_TLS_CONFIG
+    } else {
+        &*NON_VERIFYING_TLS_CONFIG
+    };
+    let name = webpki::DNSNameRef::try_from_ascii_str(host)
+        .map_err(|_| format!("invalid TLS server name {}", host))?;
+    let mut session = ClientSession::new(config, name);
+    while session.is_handshaking() {
+        session
+            .complete_io(&mut sock)
+            .chain_err(|| "TLS handshake failed")?;
+    }
+    Ok(StreamOwned::new(session, sock))
+}
+
+fn to_tls_version(version: ProtocolVersion) -> Option<TlsVersion> {
+    match version {
+        ProtocolVersion::TLSv1_0 => Some(TlsVersion::Tls10),
+        ProtocolVersion::TLSv1_1 => Some(TlsVersion::Tls11),
+        ProtocolVersion::TLSv1_2 => Some(TlsVersion::Tls12),
+        ProtocolVersion::TLSv1_3 => Some(TlsVersion::Tls13),
+        _ => None,
+    }
+}
+
+fn tls_config(verify_certificate: bool) -> Arc<ClientConfig> {
+    let mut config = ClientConfig::new();
+    if verify_certificate {
+        config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    } else {
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(NoCertificateVerification));
+    }
+    Arc::new(config)
+}
+
+/// Accepts any certificate, for the services whose certificates aren't verified
+struct NoCertificateVerification;
+
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        _presented_certs: &[Certificate],
+        _dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> std::result::Result<ServerCertVerified, TLSError> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::super::probe::{is_timeout, is_untrusted_certificate};
+    use super::*;
+    use rustls::internal::pemfile::{certs, pkcs8_private_keys};
+    use rustls::{NoClientAuth, ServerConfig, ServerSession};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+
+    const CERT: &str = include_str!("../testdata/localhost.crt");
+    const KEY: &str = include_str!("../testdata/localhost.key");
+
+    /// Serve a single SSL connection for `localhost`, negotiating one of `versions`, and echo
+    /// JSON-RPC requests back as their result
+    pub fn tls_server(versions: Vec<ProtocolVersion>) -> SocketAddr {
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        let chain = certs(&mut CERT.as_bytes()).unwrap();
+        let key = pkcs8_private_keys(&mut KEY.as_bytes()).unwrap().remove(0);
+        config.set_single_cert(chain, key).unwrap();
+        config.versions = versions;
+        let config = Arc::new(config);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (sock, _) = listener.accept().unwrap();
+            let tls = StreamOwned::new(ServerSession::new(&config), sock);
+            let mut reader = BufReader::new(tls);
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                let request: Value = serde_json::from_str(&line).unwrap();
+                let reply = json!({ "id": request["id"], "result": request["params"] });
+                let tls = reader.get_mut();
+                writeln!(tls, "{}", reply)
+                    .and_then(|()| tls.flush())
+                    .unwrap();
+                line.clear();
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_tls_version() {
+        let timeout = Some(Duration::from_secs(5));
+        for (version, expected) in [
+            (ProtocolVersion::TLSv1_2, TlsVersion::Tls12),
+            (ProtocolVersion::TLSv1_3, TlsVersion::Tls13),
+        ] {
+            let port = tls_server(vec![version]).port();
+            let connection = RpcConnection::connect(
+                "localhost",
+                port,
+                Protocol::Ssl,
+                None,
+                timeout,
+                None,
+                false,
+            )
+            .unwrap();
+            assert_eq!(connection.tls_version, Some(expected));
+            let reply = connection.request("echo", &[json!("hello")]).unwrap();
+            assert_eq!(reply, json!(["hello"]));
+        }
+
+        // the self-signed certificate fails verification, as untrusted
+        let port = tls_server(vec![ProtocolVersion::TLSv1_3]).port();
+        let err =
+            RpcConnection::connect("localhost", port, Protocol::Ssl, None, timeout, None, true)
+                .err()
+                .unwrap();
+        assert!(is_untrusted_certificate(&err), "{}", err.display_chain());
+    }
+
+    #[test]
+    fn test_deadline() {
+        // a server trickling its reply a byte at a time, well within the socket timeout
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            while sock.write_all(b" ").is_ok() {
+                thread::sleep(Duration::from_millis(10));
+            }
+        });
+        let timeout = Some(Duration::from_secs(5));
+        let deadline = Instant::now() + Duration::from_millis(200);
+        let connection = RpcConnection::connect(
+            "127.0.0.1",
+            port,
+            Protocol::Tcp,
+            None,
+            timeout,
+            Some(deadline),
+            false,
+        )
+        .unwrap();
+        let err = connection.request("server.features", &[]).unwrap_err();
+        assert!(Instant::now() < deadline + Duration::from_secs(1));
+        assert!(is_timeout(&err), "{}", err.display_chain());
+
+        // no more connections are opened once the deadline passed
+        let err = RpcConnection::connect(
+            "127.0.0.1",
+            port,
+            Protocol::Tcp,
+            None,
+            timeout,
+            Some(deadline),
+            false,
+        )
+        .err()
+        .unwrap();
+        assert!(is_timeout(&err), "{}", err.display_chain());
+    }
+}
@@ -0,0 +1,77 @@
+use crate::chain::Network;
+use crate::electrum::discovery::bootstrap::{parse_services, PeerListEntry};
+use crate::electrum::discovery::{AdmissionSource, DiscoveryManager, ProbeClient};
+use crate::errors::ResultExt;
+
+impl DiscoveryManager {
+    /// Queue the servers listed by a healthy peer's `server.peers.subscribe`, up to
+    /// `max_crawled_per_peer` of them. Returns the number of services added.
+    pub(super) fn crawl(&self, client: &dyn ProbeClient, network: Network) -> usize {
+        let peers = client
+            .call("server.peers.subscribe", &[])
+            .and_then(|peers| {
+                serde_json::from_value::<Vec<PeerListEntry>>(peers)
+                    .chain_err(|| "invalid peer list")
+            });
+        let peers = match peers {
+            Ok(peers) => peers,
+            Err(e) => {
+                debug!("failed crawling peers: {:?}", e);
+                return 0;
+            }
+        };
+        peers
+            .into_iter()
+            .take(self.config.max_crawled_per_peer)
+            .map(|(_, hostname, feature_strs)| {
+                let services = parse_services(&feature_strs);
+                self.add_listed_server(hostname, services, network, AdmissionSource::Crawl, false)
+                    .map_err(|e| debug!("not queueing crawled server: {:?}", e))
+                    .unwrap_or(0)
+            })
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::electrum::discovery::test_util::*;
+    use crate::electrum::discovery::*;
+
+    #[test]
+    fn test_crawl() {
+        let (discovery, probe) = stub_manager(DiscoveryConfig {
+            crawl: true,
+            max_crawled_per_peer: 3,
+            ..Default::default()
+        });
+        let peers = json!([
+            ["1.1.1.1", "1.1.1.1", ["v1.4", "t50001"]],
+            ["1.1.1.2", "1.1.1.2", ["v1.4", "p10000", "t50001", "s50002"]],
+            ["10.0.0.1", "10.0.0.1", ["v1.4", "t50001"]],
+            ["1.1.1.3", "1.1.1.3", ["v1.4", "t50001"]],
+        ]);
+        let server = StubServer::new(test_features("{}")).respond("server.peers.subscribe", peers);
+        probe.serve("tcp://1.1.1.1:50001", server);
+        discovery
+            .add_default_server("1.1.1.1".into(), vec![Service::Tcp(50001)])
+            .unwrap();
+        discovery.run_health_check(0).unwrap();
+
+        // the already queued server isn't duplicated, non-remote ones and ones beyond the
+        // limit are skipped
+        let status = discovery
+            .server_status()
+            .into_iter()
+            .map(|s| (s.hostname, s.service, s.source))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            status,
+            vec![
+                ("1.1.1.1".into(), "t50001".into(), AdmissionSource::Default),
+                ("1.1.1.2".into(), "s50002".into(), AdmissionSource::Crawl),
+                ("1.1.1.2".into(), "t50001".into(), AdmissionSource::Crawl),
+            ]
+        );
+    }
+}
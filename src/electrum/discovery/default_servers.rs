@@ -1,447 +1,329 @@
+use std::collections::{BTreeMap, BinaryHeap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
 use crate::chain::Network;
-use crate::electrum::discovery::{DiscoveryManager, Service};
+use crate::electrum::discovery::{
+    normalize_hostname, write_lock, AdmissionSource, DiscoveryManager, Service,
+};
+use crate::electrum::Hostname;
+use crate::errors::{Result, ResultExt};
+
+type DefaultServers = &'static [(&'static str, &'static [Service])];
+
+#[cfg(not(feature = "liquid"))]
+const DEFAULT_PORTS: &[Service] = &[Service::Tcp(50001), Service::Ssl(50002)];
 
-pub fn add_default_servers(discovery: &DiscoveryManager, network: Network) {
+#[cfg(not(feature = "liquid"))]
+const BITCOIN_SERVERS: DefaultServers = &[
+    (
+        "81-7-10-251.blue.kundencontroller.de",
+        &[Service::Ssl(50002)],
+    ),
+    ("E-X.not.fyi", DEFAULT_PORTS),
+    ("VPS.hsmiths.com", DEFAULT_PORTS),
+    ("b.ooze.cc", DEFAULT_PORTS),
+    ("bitcoin.corgi.party", DEFAULT_PORTS),
+    ("bitcoins.sk", DEFAULT_PORTS),
+    ("btc.cihar.com", DEFAULT_PORTS),
+    ("btc.xskyx.net", DEFAULT_PORTS),
+    ("currentlane.lovebitco.in", DEFAULT_PORTS),
+    ("daedalus.bauerj.eu", DEFAULT_PORTS),
+    (
+        "electrum.jochen-hoenicke.de",
+        &[Service::Tcp(50003), Service::Ssl(50005)],
+    ),
+    ("dragon085.startdedicated.de", &[Service::Ssl(50002)]),
+    ("e-1.claudioboxx.com", DEFAULT_PORTS),
+    ("e.keff.org", DEFAULT_PORTS),
+    ("electrum-server.ninja", DEFAULT_PORTS),
+    ("electrum-unlimited.criptolayer.net", &[Service::Ssl(50002)]),
+    ("electrum.eff.ro", DEFAULT_PORTS),
+    ("electrum.festivaldelhumor.org", DEFAULT_PORTS),
+    ("electrum.hsmiths.com", DEFAULT_PORTS),
+    ("electrum.leblancnet.us", DEFAULT_PORTS),
+    ("electrum.mindspot.org", &[Service::Ssl(50002)]),
+    ("electrum.qtornado.com", DEFAULT_PORTS),
+    ("electrum.taborsky.cz", &[Service::Ssl(50002)]),
+    ("electrum.villocq.com", DEFAULT_PORTS),
+    ("electrum2.eff.ro", DEFAULT_PORTS),
+    ("electrum2.villocq.com", DEFAULT_PORTS),
+    ("electrumx.bot.nu", DEFAULT_PORTS),
+    ("electrumx.ddns.net", DEFAULT_PORTS),
+    ("electrumx.ftp.sh", &[Service::Ssl(50002)]),
+    ("electrumx.ml", DEFAULT_PORTS),
+    ("electrumx.soon.it", DEFAULT_PORTS),
+    ("elx01.knas.systems", DEFAULT_PORTS),
+    ("enode.duckdns.org", DEFAULT_PORTS),
+    ("fedaykin.goip.de", DEFAULT_PORTS),
+    ("fn.48.org", &[Service::Tcp(50003), Service::Ssl(50002)]),
+    ("helicarrier.bauerj.eu", DEFAULT_PORTS),
+    ("icarus.tetradrachm.net", DEFAULT_PORTS),
+    ("electrum.emzy.de", DEFAULT_PORTS),
+    ("ndnd.selfhost.eu", DEFAULT_PORTS),
+    ("orannis.com", DEFAULT_PORTS),
+    ("rbx.curalle.ovh", &[Service::Ssl(50002)]),
+    ("tardis.bauerj.eu", DEFAULT_PORTS),
+    ("technetium.network", &[Service::Ssl(50002)]),
+    ("tomscryptos.com", DEFAULT_PORTS),
+    ("ulrichard.ch", DEFAULT_PORTS),
+    ("vmd27610.contaboserver.net", DEFAULT_PORTS),
+    ("vmd30612.contaboserver.net", DEFAULT_PORTS),
+    (
+        "wsw6tua3xl24gsmi264zaep6seppjyrkyucpsmuxnjzyt3f3j6swshad.onion",
+        DEFAULT_PORTS,
+    ),
+    ("xray587.startdedicated.de", &[Service::Ssl(50002)]),
+    ("yuio.top", DEFAULT_PORTS),
+    (
+        "bitcoin.dragon.zone",
+        &[Service::Tcp(50003), Service::Ssl(50004)],
+    ),
+    ("ecdsa.net", &[Service::Tcp(50001), Service::Ssl(110)]),
+    ("btc.usebsv.com", &[Service::Ssl(50006)]),
+    ("e2.keff.org", DEFAULT_PORTS),
+    ("electrum.hodlister.co", &[Service::Ssl(50002)]),
+    ("electrum3.hodlister.co", &[Service::Ssl(50002)]),
+    ("electrum5.hodlister.co", &[Service::Ssl(50002)]),
+    ("electrumx.electricnewyear.net", &[Service::Ssl(50002)]),
+    (
+        "fortress.qtornado.com",
+        &[Service::Tcp(50001), Service::Ssl(443)],
+    ),
+    (
+        "green-gold.westeurope.cloudapp.azure.com",
+        &[Service::Tcp(56001), Service::Ssl(56002)],
+    ),
+    ("electrumx.erbium.eu", DEFAULT_PORTS),
+];
+
+#[cfg(not(feature = "liquid"))]
+const TESTNET_SERVERS: DefaultServers = &[
+    (
+        "testnet.hsmiths.com",
+        &[Service::Tcp(53011), Service::Ssl(53012)],
+    ),
+    (
+        "testnet.qtornado.com",
+        &[Service::Tcp(51001), Service::Ssl(51002)],
+    ),
+    ("testnet1.bauerj.eu", DEFAULT_PORTS),
+    ("tn.not.fyi", &[Service::Tcp(55001), Service::Ssl(55002)]),
+    (
+        "bitcoin.cluelessperson.com",
+        &[Service::Tcp(51001), Service::Ssl(51002)],
+    ),
+];
+
+/// The compiled-in default servers for the network
+fn compiled_default_servers(network: Network) -> DefaultServers {
     match network {
         #[cfg(not(feature = "liquid"))]
-        Network::Bitcoin => {
-            discovery
-                .add_default_server(
-                    "3smoooajg7qqac2y.onion".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "81-7-10-251.blue.kundencontroller.de".into(),
-                    vec![Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "E-X.not.fyi".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "VPS.hsmiths.com".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "b.ooze.cc".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "bauerjda5hnedjam.onion".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "bauerjhejlv6di7s.onion".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "bitcoin.corgi.party".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "bitcoin3nqy3db7c.onion".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "bitcoins.sk".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "btc.cihar.com".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "btc.xskyx.net".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "currentlane.lovebitco.in".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "daedalus.bauerj.eu".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "electrum.jochen-hoenicke.de".into(),
-                    vec![Service::Tcp(50003), Service::Ssl(50005)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "dragon085.startdedicated.de".into(),
-                    vec![Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "e-1.claudioboxx.com".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "e.keff.org".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "electrum-server.ninja".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "electrum-unlimited.criptolayer.net".into(),
-                    vec![Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "electrum.eff.ro".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "electrum.festivaldelhumor.org".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "electrum.hsmiths.com".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "electrum.leblancnet.us".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server("electrum.mindspot.org".into(), vec![Service::Ssl(50002)])
-                .ok();
-            discovery
-                .add_default_server(
-                    "electrum.qtornado.com".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server("electrum.taborsky.cz".into(), vec![Service::Ssl(50002)])
-                .ok();
-            discovery
-                .add_default_server(
-                    "electrum.villocq.com".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "electrum2.eff.ro".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "electrum2.villocq.com".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "electrumx.bot.nu".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "electrumx.ddns.net".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server("electrumx.ftp.sh".into(), vec![Service::Ssl(50002)])
-                .ok();
-            discovery
-                .add_default_server(
-                    "electrumx.ml".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "electrumx.soon.it".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server("electrumxhqdsmlu.onion".into(), vec![Service::Tcp(50001)])
-                .ok();
-            discovery
-                .add_default_server(
-                    "elx01.knas.systems".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "enode.duckdns.org".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "fedaykin.goip.de".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "fn.48.org".into(),
-                    vec![Service::Tcp(50003), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "helicarrier.bauerj.eu".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "hsmiths4fyqlw5xw.onion".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "hsmiths5mjk6uijs.onion".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "icarus.tetradrachm.net".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "electrum.emzy.de".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "ndnd.selfhost.eu".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server("ndndword5lpb7eex.onion".into(), vec![Service::Tcp(50001)])
-                .ok();
-            discovery
-                .add_default_server(
-                    "orannis.com".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "ozahtqwp25chjdjd.onion".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "qtornadoklbgdyww.onion".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server("rbx.curalle.ovh".into(), vec![Service::Ssl(50002)])
-                .ok();
-            discovery
-                .add_default_server("s7clinmo4cazmhul.onion".into(), vec![Service::Tcp(50001)])
-                .ok();
-            discovery
-                .add_default_server(
-                    "tardis.bauerj.eu".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server("technetium.network".into(), vec![Service::Ssl(50002)])
-                .ok();
-            discovery
-                .add_default_server(
-                    "tomscryptos.com".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "ulrichard.ch".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "vmd27610.contaboserver.net".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "vmd30612.contaboserver.net".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "wsw6tua3xl24gsmi264zaep6seppjyrkyucpsmuxnjzyt3f3j6swshad.onion".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "xray587.startdedicated.de".into(),
-                    vec![Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "yuio.top".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "bitcoin.dragon.zone".into(),
-                    vec![Service::Tcp(50003), Service::Ssl(50004)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "ecdsa.net".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(110)],
-                )
-                .ok();
-            discovery
-                .add_default_server("btc.usebsv.com".into(), vec![Service::Ssl(50006)])
-                .ok();
-            discovery
-                .add_default_server(
-                    "e2.keff.org".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server("electrum.hodlister.co".into(), vec![Service::Ssl(50002)])
-                .ok();
-            discovery
-                .add_default_server("electrum3.hodlister.co".into(), vec![Service::Ssl(50002)])
-                .ok();
-            discovery
-                .add_default_server("electrum5.hodlister.co".into(), vec![Service::Ssl(50002)])
-                .ok();
-            discovery
-                .add_default_server(
-                    "electrumx.electricnewyear.net".into(),
-                    vec![Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "fortress.qtornado.com".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(443)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "green-gold.westeurope.cloudapp.azure.com".into(),
-                    vec![Service::Tcp(56001), Service::Ssl(56002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "electrumx.erbium.eu".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-        }
+        Network::Bitcoin => BITCOIN_SERVERS,
         #[cfg(not(feature = "liquid"))]
-        Network::Testnet => {
-            discovery
-                .add_default_server(
-                    "hsmithsxurybd7uh.onion".into(),
-                    vec![Service::Tcp(53011), Service::Ssl(53012)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "testnet.hsmiths.com".into(),
-                    vec![Service::Tcp(53011), Service::Ssl(53012)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "testnet.qtornado.com".into(),
-                    vec![Service::Tcp(51001), Service::Ssl(51002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "testnet1.bauerj.eu".into(),
-                    vec![Service::Tcp(50001), Service::Ssl(50002)],
-                )
-                .ok();
-            discovery
-                .add_default_server(
-                    "tn.not.fyi".into(),
-                    vec![Service::Tcp(55001), Service::Ssl(55002)],
-                )
-                .ok();
+        Network::Testnet => TESTNET_SERVERS,
+        _ => &[],
+    }
+}
+
+/// Read a default servers file, see `DiscoveryConfig::default_servers_path`
+fn read_default_servers(path: &Path) -> Result<Vec<(Hostname, Vec<Service>)>> {
+    let contents = fs::read_to_string(path)
+        .chain_err(|| format!("failed reading default servers from {:?}", path))?;
+    let servers: BTreeMap<Hostname, Vec<String>> =
+        serde_json::from_str(&contents).chain_err(|| "invalid default servers file")?;
+    servers
+        .into_iter()
+        .map(|(hostname, services)| {
+            let services = services
+                .iter()
+                .map(|s| s.parse())
+                .collect::<Result<Vec<Service>>>()?;
+            Ok((hostname, services))
+        })
+        .collect()
+}
+
+/// The default servers of the network: the ones read from `default_servers_path` for our network,
+/// or the configured ones for networks that have them, or the compiled-in ones otherwise
+fn default_servers_for(
+    discovery: &DiscoveryManager,
+    network: Network,
+) -> Vec<(Hostname, Vec<Service>)> {
+    let config = discovery.config();
+    if let Some(path) = &config.default_servers_path {
+        if network == discovery.our_network {
+            match read_default_servers(path) {
+                Ok(servers) => return servers,
+                Err(e) => warn!("failed loading default servers, ignoring the file: {:?}", e),
+            }
+        }
+    }
+    match config.default_servers.get(&network) {
+        Some(servers) => servers.clone(),
+        None => compiled_default_servers(network)
+            .iter()
+            .map(|(hostname, services)| (hostname.to_string(), services.to_vec()))
+            .collect(),
+    }
+}
+
+/// Queue the default servers of each of the networks, see `default_servers_for`
+pub fn add_default_servers(discovery: &DiscoveryManager, networks: &[Network]) {
+    for &network in networks {
+        for (hostname, services) in default_servers_for(discovery, network) {
             discovery
-                .add_default_server(
-                    "bitcoin.cluelessperson.com".into(),
-                    vec![Service::Tcp(51001), Service::Ssl(51002)],
-                )
+                .add_default_server_for(network, hostname, services)
                 .ok();
         }
+    }
+}
+
+impl DiscoveryManager {
+    /// Re-read the default servers file if a reload was requested, by SIGHUP
+    pub(super) fn reload_default_servers_if_requested(&self) {
+        if self.reload_requested.swap(false, Ordering::Relaxed) {
+            if let Err(e) = self.reload_default_servers() {
+                warn!("failed reloading default servers: {:?}", e);
+            }
+        }
+    }
 
-        _ => (),
+    /// Re-read the default servers file and queue the newly listed servers. Servers no longer
+    /// listed stop being treated as default servers, and get given up on like others when failing.
+    fn reload_default_servers(&self) -> Result<()> {
+        let path = match &self.config.default_servers_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let servers = read_default_servers(path)?;
+        let listed = servers
+            .iter()
+            .map(|(hostname, _)| normalize_hostname(hostname))
+            .collect::<HashSet<_>>();
+        {
+            let mut queue = write_lock(&self.queue);
+            let mut jobs = std::mem::take(&mut *queue).into_vec();
+            for job in &mut jobs {
+                if job.source == AdmissionSource::Default && job.network == self.our_network {
+                    job.is_default = listed.contains(&job.hostname);
+                }
+            }
+            *queue = BinaryHeap::from(jobs);
+        }
+        for (hostname, services) in servers {
+            if let Err(e) = self.add_default_server_for(self.our_network, hostname, services) {
+                warn!("failed adding default server: {:?}", e);
+            }
+        }
+        info!("reloaded default servers from {:?}", path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::electrum::discovery::test_util::*;
+    use crate::electrum::discovery::*;
+
+    #[test]
+    fn test_default_servers_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("servers.json");
+        let write = |contents: &str| std::fs::write(&path, contents).unwrap();
+        write(r#"{"1.1.1.1": ["t50001", "s50002"], "1.1.1.2": ["t50001"]}"#);
+        let discovery = test_manager(DiscoveryConfig {
+            default_servers_path: Some(path.clone()),
+            ..Default::default()
+        });
+        let defaults = || {
+            let mut defaults = read_lock(&discovery.queue)
+                .iter()
+                .filter(|job| job.is_default)
+                .map(|job| (job.hostname.clone(), job.service))
+                .collect::<Vec<_>>();
+            defaults.sort_by_key(|(hostname, service)| (hostname.clone(), service.to_string()));
+            defaults
+        };
+        assert_eq!(
+            defaults(),
+            vec![
+                ("1.1.1.1".into(), Service::Ssl(50002)),
+                ("1.1.1.1".into(), Service::Tcp(50001)),
+                ("1.1.1.2".into(), Service::Tcp(50001)),
+            ]
+        );
+
+        // only re-read when requested, queueing the new servers and demoting the unlisted ones
+        write(r#"{"1.1.1.2": ["t50001"], "1.1.1.3": ["t50001"]}"#);
+        discovery.reload_default_servers_if_requested();
+        assert_eq!(defaults().len(), 3);
+        discovery
+            .reload_requested
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        discovery.reload_default_servers_if_requested();
+        assert_eq!(
+            defaults(),
+            vec![
+                ("1.1.1.2".into(), Service::Tcp(50001)),
+                ("1.1.1.3".into(), Service::Tcp(50001)),
+            ]
+        );
+        assert_eq!(read_lock(&discovery.queue).len(), 4);
+
+        // invalid files are ignored
+        write("{");
+        let discovery = test_manager(DiscoveryConfig {
+            default_servers_path: Some(path.clone()),
+            ..Default::default()
+        });
+        assert!(discovery.server_status().is_empty());
+    }
+
+    #[test]
+    fn test_per_network_defaults() {
+        let mut default_servers = HashMap::new();
+        default_servers.insert(
+            Network::Regtest,
+            vec![("1.1.1.1".to_string(), vec![Service::Tcp(50001)])],
+        );
+        default_servers.insert(
+            Network::Signet,
+            vec![(ONION_HOSTS[0].to_string(), vec![Service::Ssl(50002)])],
+        );
+        let (discovery, probe) = stub_manager(DiscoveryConfig {
+            extra_networks: vec![Network::Signet],
+            default_servers,
+            ..Default::default()
+        });
+
+        let defaults = discovery
+            .server_status()
+            .into_iter()
+            .map(|status| (status.hostname, status.network))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            defaults,
+            vec![
+                ("1.1.1.1".to_string(), Network::Regtest),
+                (ONION_HOSTS[0].to_string(), Network::Signet),
+            ]
+        );
+
+        // servers are checked against their own network, and only ours are advertised
+        let mut features = test_features("{}");
+        features.genesis_hash = genesis_hash(Network::Signet);
+        let hosts = "{\"1.1.1.2\":{\"tcp_port\":50001}}";
+        features.hosts = serde_json::from_str(hosts).unwrap();
+        probe.serve("tcp://1.1.1.2:50001", StubServer::new(features.clone()));
+        discovery
+            .add_server_request("1.1.1.2".parse().unwrap(), features)
+            .unwrap();
+        for _ in 0..3 {
+            discovery.run_health_check(0).ok();
+        }
+        assert!(discovery.get_servers().is_empty());
+        assert_eq!(discovery.get_servers_for(Network::Signet).len(), 1);
     }
 }
@@ -0,0 +1,324 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::net::IpAddr;
+use std::path::Path;
+
+use crate::electrum::discovery::{read_lock, DiscoveryManager, ServerAddr, ServerEntry};
+use crate::errors::{Result, ResultExt};
+
+/// Maps IPs to the autonomous system (ASN) announcing them, like a local copy of an IP-to-ASN
+/// database. Used to avoid advertising many servers hosted by a single provider.
+pub trait AsnLookup: fmt::Debug + Send + Sync {
+    fn asn(&self, ip: IpAddr) -> Option<u32>;
+}
+
+/// Knows no ASNs, leaving only subnets and address families to diversify over
+#[derive(Debug, Default)]
+pub struct NoAsnLookup;
+
+impl AsnLookup for NoAsnLookup {
+    fn asn(&self, _ip: IpAddr) -> Option<u32> {
+        None
+    }
+}
+
+/// Looks up ASNs in an IP-to-ASN database in the TSV format of https://iptoasn.com, with one
+/// `range_start range_end as_number country_code as_description` line per range, like
+/// `ip2asn-combined.tsv`. Ranges announced by no AS have AS number 0.
+#[derive(Debug, Default)]
+pub struct Ip2AsnLookup {
+    /// Non-overlapping IPv4 and IPv6 ranges (`start`, `end`, `asn`), sorted by start
+    v4: Vec<(u32, u32, u32)>,
+    v6: Vec<(u128, u128, u32)>,
+}
+
+impl Ip2AsnLookup {
+    /// Read the database from a file, which may take a few seconds for the full one
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let file = File::open(path).chain_err(|| format!("failed to open {}", path.display()))?;
+        Self::from_reader(BufReader::new(file))
+            .chain_err(|| format!("failed to read {}", path.display()))
+    }
+
+    pub fn from_reader(reader: impl BufRead) -> Result<Self> {
+        let mut lookup = Ip2AsnLookup::default();
+        for (index, line) in reader.lines().enumerate() {
+            let line = line.chain_err(|| "failed to read line")?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut fields = line.split('\t');
+            let mut next_ip = || -> Result<IpAddr> {
+                let field = fields.next().unwrap_or_default();
+                field
+                    .parse()
+                    .chain_err(|| format!("invalid IP {:?} on line {}", field, index + 1))
+            };
+            let (start, end) = (next_ip()?, next_ip()?);
+            let asn = fields
+                .next()
+                .and_then(|field| field.parse::<u32>().ok())
+                .chain_err(|| format!("invalid AS number on line {}", index + 1))?;
+            if asn == 0 {
+                continue;
+            }
+            match (start, end) {
+                (IpAddr::V4(start), IpAddr::V4(end)) => {
+                    lookup.v4.push((start.into(), end.into(), asn))
+                }
+                (IpAddr::V6(start), IpAddr::V6(end)) => {
+                    lookup.v6.push((start.into(), end.into(), asn))
+                }
+                _ => bail!("mixed address families on line {}", index + 1),
+            }
+        }
+        lookup.v4.sort_unstable();
+        lookup.v6.sort_unstable();
+        Ok(lookup)
+    }
+}
+
+impl AsnLookup for Ip2AsnLookup {
+    fn asn(&self, ip: IpAddr) -> Option<u32> {
+        match ip {
+            IpAddr::V4(ip) => find_range(&self.v4, u32::from(ip)),
+            IpAddr::V6(ip) => find_range(&self.v6, u128::from(ip)),
+        }
+    }
+}
+
+/// Find the ASN of the last range starting at or before `ip`, if it contains it
+fn find_range<T: Ord + Copy>(ranges: &[(T, T, u32)], ip: T) -> Option<u32> {
+    let index = match ranges.binary_search_by(|(start, _, _)| start.cmp(&ip)) {
+        Ok(index) => index,
+        Err(0) => return None,
+        Err(index) => index - 1,
+    };
+    let (_, end, asn) = ranges[index];
+    Some(asn).filter(|_| ip <= end)
+}
+
+impl DiscoveryManager {
+    /// Check whether the servers meet the `min_advertised_servers` and `min_advertised_subnets`
+    /// diversity thresholds
+    pub(super) fn is_diverse(&self, servers: &[ServerEntry]) -> bool {
+        let config = &self.config;
+        if servers.len() < config.min_advertised_servers {
+            return false;
+        }
+        if config.min_advertised_subnets == 0 {
+            return true;
+        }
+        let subnets = servers
+            .iter()
+            .filter_map(|ServerEntry(addr, _, _)| match addr {
+                ServerAddr::Clearnet(ip) => Some(subnet(ip)),
+                // the location of onion servers is unknown, they don't add to subnet diversity
+                ServerAddr::Onion(_) => None,
+            })
+            .collect::<HashSet<_>>();
+        subnets.len() >= config.min_advertised_subnets
+    }
+
+    pub(super) fn lookup_asn(&self, addr: &ServerAddr) -> Option<u32> {
+        match addr {
+            ServerAddr::Clearnet(ip) => self.asn_lookup.asn(*ip),
+            ServerAddr::Onion(_) => None,
+        }
+    }
+
+    /// Pick `count` of the servers at the `ranked` indexes, next to the `included` ones. Each pick
+    /// goes to the address family (IPv4, IPv6 or onion) picked the least so far, and to a server on
+    /// a subnet and ASN not picked yet, in order of rank. Once no server adds to the diversity,
+    /// the rest are picked by rank alone.
+    pub(super) fn pick_diverse(
+        &self,
+        servers: &[ServerEntry],
+        mut ranked: Vec<usize>,
+        included: &HashSet<ServerAddr>,
+        count: usize,
+    ) -> HashSet<usize> {
+        let healthy = read_lock(&self.healthy);
+        let asn_of = |addr: &ServerAddr| healthy.get(addr).and_then(|server| server.asn);
+        let mut mix = Mix::default();
+        for ServerEntry(addr, _, _) in servers.iter().filter(|entry| included.contains(&entry.0)) {
+            mix.add(addr, asn_of(addr));
+        }
+
+        let mut picked = HashSet::new();
+        while picked.len() < count && !ranked.is_empty() {
+            let position = ranked
+                .iter()
+                .map(|index| &servers[*index].0)
+                .enumerate()
+                .filter(|(_, addr)| mix.is_new(addr, asn_of(addr)))
+                .min_by_key(|(position, addr)| (mix.family_count(addr), *position))
+                .map_or(0, |(position, _)| position);
+            let index = ranked.remove(position);
+            mix.add(&servers[index].0, asn_of(&servers[index].0));
+            picked.insert(index);
+        }
+        picked
+    }
+}
+
+/// The address families, subnets and ASNs of the servers picked so far
+#[derive(Default)]
+struct Mix {
+    families: HashMap<&'static str, usize>,
+    subnets: HashSet<(bool, u32)>,
+    asns: HashSet<u32>,
+}
+
+impl Mix {
+    fn add(&mut self, addr: &ServerAddr, asn: Option<u32>) {
+        *self.families.entry(family(addr)).or_default() += 1;
+        if let ServerAddr::Clearnet(ip) = addr {
+            self.subnets.insert(subnet(ip));
+        }
+        self.asns.extend(asn);
+    }
+
+    /// Whether the server is on a subnet and ASN not in the mix yet. Onion servers are, as far
+    /// as we can tell.
+    fn is_new(&self, addr: &ServerAddr, asn: Option<u32>) -> bool {
+        let new_subnet = match addr {
+            ServerAddr::Clearnet(ip) => !self.subnets.contains(&subnet(ip)),
+            ServerAddr::Onion(_) => true,
+        };
+        new_subnet && asn.map_or(true, |asn| !self.asns.contains(&asn))
+    }
+
+    fn family_count(&self, addr: &ServerAddr) -> usize {
+        self.families.get(family(addr)).copied().unwrap_or(0)
+    }
+}
+
+/// The /16 subnet of IPv4 addresses, or the /32 subnet of IPv6 ones
+fn subnet(ip: &IpAddr) -> (bool, u32) {
+    match ip {
+        IpAddr::V4(ip) => (false, u32::from(*ip) >> 16),
+        IpAddr::V6(ip) => (true, (u128::from(*ip) >> 96) as u32),
+    }
+}
+
+fn family(addr: &ServerAddr) -> &'static str {
+    match addr {
+        ServerAddr::Clearnet(IpAddr::V4(_)) => "ipv4",
+        ServerAddr::Clearnet(IpAddr::V6(_)) => "ipv6",
+        ServerAddr::Onion(_) => "onion",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::electrum::discovery::test_util::*;
+    use crate::electrum::discovery::*;
+
+    #[test]
+    fn test_ip2asn_lookup() {
+        let tsv = "1.0.0.0\t1.0.0.255\t13335\tUS\tCLOUDFLARENET\n\
+                   1.0.1.0\t1.0.3.255\t0\tNone\tNot routed\n\
+                   1.0.4.0\t1.0.7.255\t38803\tAU\tWPL-AS-AP\n\
+                   2606:4700::\t2606:4700:ffff:ffff:ffff:ffff:ffff:ffff\t13335\tUS\tCLOUDFLARENET\n";
+        let lookup = Ip2AsnLookup::from_reader(tsv.as_bytes()).unwrap();
+        let asn = |ip: &str| lookup.asn(ip.parse().unwrap());
+        assert_eq!(asn("1.0.0.0"), Some(13335));
+        assert_eq!(asn("1.0.0.255"), Some(13335));
+        assert_eq!(asn("1.0.2.1"), None);
+        assert_eq!(asn("1.0.5.1"), Some(38803));
+        assert_eq!(asn("1.0.8.0"), None);
+        assert_eq!(asn("0.255.255.255"), None);
+        assert_eq!(asn("2606:4700::1111"), Some(13335));
+        assert_eq!(asn("2606:4701::1"), None);
+
+        assert!(Ip2AsnLookup::from_reader("1.0.0.0\t1.0.0.255\tAS1\n".as_bytes()).is_err());
+        assert!(Ip2AsnLookup::from_reader("1.0.0.0\t::1\t1\n".as_bytes()).is_err());
+    }
+
+    #[derive(Debug)]
+    struct StubAsnLookup;
+
+    impl AsnLookup for StubAsnLookup {
+        fn asn(&self, ip: IpAddr) -> Option<u32> {
+            match ip.to_string().as_str() {
+                "3.3.3.3" => Some(2),
+                _ => Some(1),
+            }
+        }
+    }
+
+    #[test]
+    fn test_sampling_diversity() {
+        let sampled = |discovery: &DiscoveryManager| {
+            discovery
+                .get_servers()
+                .into_iter()
+                .map(|ServerEntry(addr, _, _)| addr)
+                .collect::<Vec<_>>()
+        };
+
+        // one server of each address family
+        let (discovery, _) = stub_manager(DiscoveryConfig {
+            sample_size: Some(3),
+            ..Default::default()
+        });
+        for host in &[
+            "1.1.1.1",
+            "1.1.1.2",
+            "1.1.1.3",
+            "2606:4700::1111",
+            ONION_HOSTS[0],
+        ] {
+            add_healthy(&discovery, host, Service::Tcp(50001));
+        }
+        for _ in 0..20 {
+            let families = sampled(&discovery)
+                .iter()
+                .map(|addr| match addr {
+                    ServerAddr::Clearnet(ip) => ip.is_ipv6() as usize,
+                    ServerAddr::Onion(_) => 2,
+                })
+                .collect::<HashSet<_>>();
+            assert_eq!(families.len(), 3);
+        }
+
+        // spread over subnets and ASNs
+        let (discovery, _) = stub_manager(DiscoveryConfig {
+            sample_size: Some(2),
+            ..Default::default()
+        });
+        let discovery = discovery.with_asn_lookup(Arc::new(StubAsnLookup));
+        for host in &["1.1.1.1", "1.1.1.2", "2.2.2.2", "3.3.3.3"] {
+            add_healthy(&discovery, host, Service::Tcp(50001));
+        }
+        // 3.3.3.3 is the only server on another ASN, and always paired with one on the other
+        for _ in 0..20 {
+            let addrs = sampled(&discovery);
+            assert_eq!(addrs.len(), 2);
+            assert!(addrs.contains(&ServerAddr::resolve("3.3.3.3").unwrap()));
+        }
+    }
+
+    #[test]
+    fn test_min_diversity() {
+        let (discovery, _) = stub_manager(DiscoveryConfig {
+            min_advertised_servers: 3,
+            min_advertised_subnets: 2,
+            ..Default::default()
+        });
+        add_healthy(&discovery, "1.1.1.1", Service::Tcp(50001));
+        add_healthy(&discovery, "1.1.2.2", Service::Tcp(50001));
+        assert!(discovery.get_servers().is_empty());
+
+        // enough servers, but all within the same subnet
+        add_healthy(&discovery, "1.1.3.3", Service::Tcp(50001));
+        assert!(discovery.get_servers().is_empty());
+        assert_eq!(discovery.get_servers_for(Network::Regtest).len(), 3);
+
+        add_healthy(&discovery, "2.2.2.2", Service::Tcp(50001));
+        assert_eq!(discovery.get_servers().len(), 4);
+    }
+}
@@ -0,0 +1,209 @@
+use std::net::IpAddr;
+
+use tokio::sync::broadcast::{
+    self,
+    error::{RecvError, TryRecvError},
+};
+
+use crate::electrum::discovery::{DiscoveryManager, HealthCheck, Service};
+use crate::electrum::Hostname;
+
+/// A change in the state of discovery, as streamed to subscribers
+#[derive(Serialize, Clone, PartialEq, Eq, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DiscoveryEvent {
+    /// A service was queued for health checking
+    Queued {
+        hostname: Hostname,
+        service: Service,
+    },
+    /// A service was health checked, successfully if `error` is unset. `added_by` is the IP of the
+    /// peer that advertised the server, unset for default servers.
+    Checked {
+        hostname: Hostname,
+        service: Service,
+        added_by: Option<IpAddr>,
+        error: Option<String>,
+    },
+    /// A service passed its health check and is now listed
+    Healthy {
+        hostname: Hostname,
+        service: Service,
+    },
+    /// A previously healthy service failed its health check and is no longer listed
+    Unhealthy {
+        hostname: Hostname,
+        service: Service,
+    },
+    /// A service was given up on after failing its health checks, and is no longer queued
+    Removed {
+        hostname: Hostname,
+        service: Service,
+    },
+    /// A server and all of its services were dropped
+    Dropped { hostname: Hostname, reason: String },
+}
+
+/// A subscription to discovery events, obtained via `DiscoveryManager::subscribe`. Subscribers
+/// that lag too far behind miss events rather than stalling discovery.
+#[derive(Debug)]
+pub struct DiscoveryEvents(broadcast::Receiver<DiscoveryEvent>);
+
+impl DiscoveryEvents {
+    /// Wait for the next event, or None once the discovery manager is gone
+    pub async fn recv(&mut self) -> Option<DiscoveryEvent> {
+        loop {
+            match self.0.recv().await {
+                Ok(event) => return Some(event),
+                Err(RecvError::Lagged(missed)) => lagged(missed),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// Get the next event if there is one available, without waiting
+    pub fn try_recv(&mut self) -> Option<DiscoveryEvent> {
+        loop {
+            match self.0.try_recv() {
+                Ok(event) => return Some(event),
+                Err(TryRecvError::Lagged(missed)) => lagged(missed),
+                Err(TryRecvError::Empty) | Err(TryRecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+fn lagged(missed: u64) {
+    warn!(
+        "discovery event subscriber lagging behind, missed {} events",
+        missed
+    );
+}
+
+impl DiscoveryManager {
+    /// Subscribe to discovery events, starting from the next one
+    pub fn subscribe(&self) -> DiscoveryEvents {
+        DiscoveryEvents(self.events.subscribe())
+    }
+
+    pub(super) fn emit(&self, event: DiscoveryEvent) {
+        // fails if there are no subscribers, which is fine
+        self.events.send(event).ok();
+    }
+}
+
+impl HealthCheck {
+    pub(super) fn queued_event(&self) -> DiscoveryEvent {
+        DiscoveryEvent::Queued {
+            hostname: self.hostname.clone(),
+            service: self.service,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::electrum::discovery::test_util::*;
+    use crate::electrum::discovery::*;
+
+    #[test]
+    fn test_events() {
+        let (discovery, probe) = stub_manager(DiscoveryConfig::default());
+        let mut events = discovery.subscribe();
+        probe.serve("tcp://1.1.1.1:50001", StubServer::new(test_features("{}")));
+        discovery
+            .add_default_server("1.1.1.1".into(), vec![Service::Tcp(50001)])
+            .unwrap();
+        discovery.run_health_check(0).unwrap();
+
+        let mut features = test_features("{}");
+        features.genesis_hash = genesis_hash(Network::Testnet);
+        probe.serve("tcp://1.1.1.1:50001", StubServer::new(features));
+        assert!(discovery.run_health_check(0).is_err());
+
+        let hostname = "1.1.1.1".to_string();
+        let service = Service::Tcp(50001);
+        let received = std::iter::from_fn(|| events.try_recv()).collect::<Vec<_>>();
+        assert_eq!(
+            received,
+            vec![
+                DiscoveryEvent::Queued {
+                    hostname: hostname.clone(),
+                    service
+                },
+                DiscoveryEvent::Checked {
+                    hostname: hostname.clone(),
+                    service,
+                    added_by: None,
+                    error: None,
+                },
+                DiscoveryEvent::Healthy {
+                    hostname: hostname.clone(),
+                    service
+                },
+                DiscoveryEvent::Checked {
+                    hostname: hostname.clone(),
+                    service,
+                    added_by: None,
+                    error: Some("Incompatible Electrum server: incompatible networks".into()),
+                },
+                DiscoveryEvent::Dropped {
+                    hostname,
+                    reason: "incompatible networks".into()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_events_added_by() {
+        let (discovery, probe) = stub_manager(DiscoveryConfig {
+            log_connection_attempts: true,
+            ..Default::default()
+        });
+        let mut events = discovery.subscribe();
+        let added_by: IpAddr = "1.1.1.1".parse().unwrap();
+        let features = test_features(r#"{ "1.1.1.1": { "tcp_port": 50001 } }"#);
+        probe.serve("tcp://1.1.1.1:50001", StubServer::new(features.clone()));
+        discovery.add_server_request(added_by, features).unwrap();
+        discovery.run_health_check(0).unwrap();
+
+        let checked = std::iter::from_fn(|| events.try_recv())
+            .filter(|event| matches!(event, DiscoveryEvent::Checked { .. }))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            checked,
+            vec![DiscoveryEvent::Checked {
+                hostname: "1.1.1.1".into(),
+                service: Service::Tcp(50001),
+                added_by: Some(added_by),
+                error: None,
+            }]
+        );
+        let event = serde_json::to_value(&checked[0]).unwrap();
+        assert_eq!(event["type"], "checked");
+        assert_eq!(event["added_by"], "1.1.1.1");
+    }
+
+    #[test]
+    fn test_lagging_events() {
+        let discovery = test_manager(DiscoveryConfig {
+            event_capacity: 2,
+            ..Default::default()
+        });
+        let mut events = discovery.subscribe();
+        for ip in &["1.1.1.1", "1.1.1.2", "1.1.1.3"] {
+            discovery
+                .add_default_server(ip.to_string(), vec![Service::Tcp(50001)])
+                .unwrap();
+        }
+        // the oldest event is missed, discovery isn't held back
+        let hostnames = std::iter::from_fn(|| events.try_recv())
+            .map(|event| match event {
+                DiscoveryEvent::Queued { hostname, .. } => hostname,
+                event => panic!("unexpected {:?}", event),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(hostnames, vec!["1.1.1.2", "1.1.1.3"]);
+    }
+}
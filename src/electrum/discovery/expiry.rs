@@ -0,0 +1,109 @@
+use std::collections::{BinaryHeap, HashSet};
+use std::net::IpAddr;
+use std::time::Instant;
+
+use crate::electrum::discovery::{
+    write_lock, AdmissionSource, DiscoveryEvent, DiscoveryManager, HealthCheck, ServerAddr, Service,
+};
+
+impl DiscoveryManager {
+    /// Refresh the advertisement time of the queued services re-advertised by a peer other than
+    /// the one that originally advertised them, keeping them from expiring
+    pub(super) fn refresh_advertised(
+        &self,
+        queue: &mut BinaryHeap<HealthCheck>,
+        added_by: IpAddr,
+        advertised: &HashSet<(ServerAddr, Service)>,
+        now: Instant,
+    ) {
+        if self.config.gossip_lifetime.is_none() || advertised.is_empty() {
+            return;
+        }
+        let mut jobs = std::mem::take(queue).into_vec();
+        for job in &mut jobs {
+            if job.added_by != Some(added_by)
+                && advertised.contains(&(job.addr.clone(), job.service))
+            {
+                job.advertised_at = now;
+            }
+        }
+        *queue = BinaryHeap::from(jobs);
+    }
+
+    /// Forget gossip-learned services that weren't re-advertised within `gossip_lifetime` and
+    /// aren't currently healthy
+    pub(super) fn expire_gossip(&self, now: Instant) {
+        let lifetime = match self.config.gossip_lifetime {
+            Some(lifetime) => lifetime,
+            None => return,
+        };
+        let mut queue = write_lock(&self.queue);
+        let (expired, jobs): (Vec<_>, Vec<_>) =
+            std::mem::take(&mut *queue).into_iter().partition(|job| {
+                job.source == AdmissionSource::Gossip
+                    && !job.is_pinned
+                    && !job.is_healthy()
+                    && now.saturating_duration_since(job.advertised_at) >= lifetime
+            });
+        *queue = BinaryHeap::from(jobs);
+        drop(queue);
+
+        for job in expired {
+            debug!("expiring gossip-learned {} {:?}", job.hostname, job.service);
+            self.emit(DiscoveryEvent::Removed {
+                hostname: job.hostname,
+                service: job.service,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::electrum::discovery::test_util::*;
+    use crate::electrum::discovery::*;
+
+    #[test]
+    fn test_gossip_lifetime() {
+        let lifetime = Duration::from_secs(3600);
+        let (discovery, probe) = stub_manager(DiscoveryConfig {
+            gossip_lifetime: Some(lifetime),
+            ..Default::default()
+        });
+        let features = test_features(r#"{ "1.1.1.1": { "tcp_port": 50001 } }"#);
+        probe.serve("tcp://1.1.1.1:50001", StubServer::new(features.clone()));
+        discovery
+            .add_server_request("1.1.1.1".parse().unwrap(), features.clone())
+            .unwrap();
+        discovery.run_health_check(0).unwrap();
+
+        // healthy servers are exempt
+        discovery.expire_gossip(Instant::now() + lifetime * 2);
+        assert_eq!(read_lock(&discovery.queue).len(), 1);
+
+        let refusing = StubServer {
+            connect_error: Some(std::io::ErrorKind::ConnectionRefused),
+            ..StubServer::new(features.clone())
+        };
+        probe.serve("tcp://1.1.1.1:50001", refusing);
+        assert!(discovery.run_health_check(0).is_err());
+        discovery.expire_gossip(Instant::now() + lifetime / 2);
+        assert_eq!(read_lock(&discovery.queue).len(), 1);
+
+        // re-advertising by another peer extends the lifetime
+        let later = Instant::now() + lifetime / 2;
+        discovery.refresh_advertised(
+            &mut write_lock(&discovery.queue),
+            "1.1.1.2".parse().unwrap(),
+            &vec![(ServerAddr::resolve("1.1.1.1").unwrap(), Service::Tcp(50001))]
+                .into_iter()
+                .collect(),
+            later,
+        );
+        discovery.expire_gossip(Instant::now() + lifetime);
+        assert_eq!(read_lock(&discovery.queue).len(), 1);
+
+        discovery.expire_gossip(later + lifetime);
+        assert!(read_lock(&discovery.queue).is_empty());
+    }
+}
@@ -0,0 +1,147 @@
+use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+
+use crate::chain::{deserialize, Network, Transaction, Txid};
+use crate::electrum::discovery::{DiscoveryManager, ProbeClient};
+use crate::errors::{Result, ResultExt};
+use crate::util::electrum_merkle::get_merkle_root_from_branch;
+
+/// Known-good data used to verify that servers serve correct transactions and merkle proofs
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct VerificationData {
+    pub transactions: Vec<KnownTransaction>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct KnownTransaction {
+    pub txid: Txid,
+    /// The height of the block confirming the transaction
+    pub height: usize,
+    /// The merkle root of the block confirming the transaction
+    pub merkle_root: Sha256dHash,
+}
+
+#[derive(Deserialize)]
+struct MerkleResponse {
+    block_height: usize,
+    merkle: Vec<Sha256dHash>,
+    pos: usize,
+}
+
+impl DiscoveryManager {
+    /// Verify that the server serves the known-good transactions and merkle proofs configured
+    /// for our network. Skipped when there are none configured.
+    pub(super) fn verify_fixtures(&self, client: &dyn ProbeClient, network: Network) -> Result<()> {
+        let fixtures = match self.config.verification_fixtures.get(&network) {
+            Some(fixtures) => fixtures,
+            None => return Ok(()),
+        };
+
+        for known in &fixtures.transactions {
+            let raw_tx = client.call("blockchain.transaction.get", &[json!(known.txid)])?;
+            let raw_tx = hex::decode(raw_tx.as_str().chain_err(|| "non-string transaction")?)
+                .chain_err(|| "non-hex transaction")?;
+            let tx: Transaction = deserialize(&raw_tx).chain_err(|| "invalid transaction")?;
+            ensure!(
+                tx.txid() == known.txid,
+                "served wrong transaction for {}",
+                known.txid
+            );
+
+            let merkle = client.call(
+                "blockchain.transaction.get_merkle",
+                &[json!(known.txid), json!(known.height)],
+            )?;
+            let merkle: MerkleResponse =
+                serde_json::from_value(merkle).chain_err(|| "invalid merkle proof")?;
+            let root = get_merkle_root_from_branch(
+                Sha256dHash::from(known.txid),
+                &merkle.merkle,
+                merkle.pos,
+            );
+            ensure!(
+                merkle.block_height == known.height && root == known.merkle_root,
+                "served invalid merkle proof for {}",
+                known.txid
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::electrum::discovery::test_util::*;
+    use crate::electrum::discovery::*;
+
+    #[test]
+    fn test_verification_fixtures() {
+        use bitcoin::consensus::serialize;
+        use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+
+        let block = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Regtest);
+        let tx = &block.txdata[0];
+        let known = KnownTransaction {
+            txid: tx.txid(),
+            height: 0,
+            merkle_root: Sha256dHash::from(block.header.merkle_root),
+        };
+        let mut fixtures = HashMap::new();
+        fixtures.insert(
+            Network::Regtest,
+            VerificationData {
+                transactions: vec![known.clone()],
+            },
+        );
+        let config = DiscoveryConfig {
+            verification_fixtures: fixtures,
+            ..Default::default()
+        };
+
+        let server = |merkle: serde_json::Value| {
+            StubServer::new(test_features("{}"))
+                .respond(
+                    "blockchain.transaction.get",
+                    json!(hex::encode(serialize(tx))),
+                )
+                .respond(
+                    "blockchain.transaction.get_merkle",
+                    json!({ "block_height": 0, "merkle": merkle, "pos": 0 }),
+                )
+        };
+
+        // a server serving the right transaction and merkle proof passes the deep check
+        let (discovery, probe) = stub_manager(config.clone());
+        let good = server(json!([]));
+        probe.serve("tcp://1.1.1.1:50001", good.clone());
+        discovery
+            .add_default_server("1.1.1.1".into(), vec![Service::Tcp(50001)])
+            .unwrap();
+        discovery.run_health_check(0).unwrap();
+        assert_eq!(discovery.get_servers().len(), 1);
+        let calls = good.calls.lock().unwrap();
+        assert_eq!(
+            calls[0],
+            ("blockchain.transaction.get".into(), vec![json!(known.txid)])
+        );
+        assert_eq!(calls[1].1, vec![json!(known.txid), json!(0)]);
+
+        // an invalid merkle proof fails it
+        let (discovery, probe) = stub_manager(config.clone());
+        probe.serve("tcp://1.1.1.1:50001", server(json!([known.merkle_root])));
+        discovery
+            .add_default_server("1.1.1.1".into(), vec![Service::Tcp(50001)])
+            .unwrap();
+        assert!(discovery.run_health_check(0).is_err());
+        assert!(discovery.get_servers().is_empty());
+
+        // deep checks are skipped for networks without fixtures
+        let (discovery, probe) = stub_manager_for(Network::Testnet, config);
+        let unchecked = StubServer::new(discovery.our_features().clone());
+        probe.serve("tcp://1.1.1.1:50001", unchecked.clone());
+        discovery
+            .add_default_server("1.1.1.1".into(), vec![Service::Tcp(50001)])
+            .unwrap();
+        discovery.run_health_check(0).unwrap();
+        assert!(unchecked.calls.lock().unwrap().is_empty());
+    }
+}
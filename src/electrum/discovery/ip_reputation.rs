@@ -0,0 +1,90 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::time::Instant;
+
+use crate::electrum::discovery::{lock, DiscoveryManager};
+
+/// A source of IP reputation (like a feed of known-malicious addresses), consulted when admitting
+/// servers advertised via `server.add_peer`
+pub trait IpReputation: fmt::Debug + Send + Sync {
+    fn is_bad(&self, ip: IpAddr) -> bool;
+}
+
+/// Considers all IPs good
+#[derive(Debug, Default)]
+pub struct NoIpReputation;
+
+impl IpReputation for NoIpReputation {
+    fn is_bad(&self, _ip: IpAddr) -> bool {
+        false
+    }
+}
+
+impl DiscoveryManager {
+    /// Check the IP against our IP reputation source, reusing lookups made within the last
+    /// `ip_reputation_cache_ttl`
+    pub(super) fn is_bad_ip(&self, ip: IpAddr, now: Instant) -> bool {
+        let ttl = self.config.ip_reputation_cache_ttl;
+        let cached = lock(&self.ip_reputation_cache)
+            .get(&ip)
+            .filter(|(looked_up, _)| now.saturating_duration_since(*looked_up) < ttl)
+            .map(|(_, is_bad)| *is_bad);
+        if let Some(is_bad) = cached {
+            return is_bad;
+        }
+        let is_bad = self.ip_reputation.is_bad(ip);
+        lock(&self.ip_reputation_cache).insert(ip, (now, is_bad));
+        is_bad
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::electrum::discovery::test_util::*;
+    use crate::electrum::discovery::*;
+
+    /// An IP reputation source flagging a fixed set of IPs, counting lookups
+    #[derive(Debug, Default)]
+    struct StubIpReputation {
+        bad: HashSet<IpAddr>,
+        lookups: Mutex<usize>,
+    }
+
+    impl IpReputation for StubIpReputation {
+        fn is_bad(&self, ip: IpAddr) -> bool {
+            *self.lookups.lock().unwrap() += 1;
+            self.bad.contains(&ip)
+        }
+    }
+
+    #[test]
+    fn test_ip_reputation() {
+        let ip_reputation = Arc::new(StubIpReputation {
+            bad: vec!["1.1.1.2".parse().unwrap()].into_iter().collect(),
+            ..Default::default()
+        });
+        let discovery =
+            test_manager(DiscoveryConfig::default()).with_ip_reputation(ip_reputation.clone());
+        let add_peer = |ip: &str| {
+            let hosts = format!("{{\"{}\":{{\"tcp_port\":50001}}}}", ip);
+            discovery.add_server_request(ip.parse().unwrap(), test_features(&hosts))
+        };
+        add_peer("1.1.1.1").unwrap();
+        add_peer("1.1.1.2").unwrap();
+        add_peer("1.1.1.2").unwrap();
+
+        let hostnames = discovery
+            .server_status()
+            .into_iter()
+            .map(|status| status.hostname)
+            .collect::<Vec<_>>();
+        assert_eq!(hostnames, vec!["1.1.1.1"]);
+        // the repeated lookup was served from the cache
+        assert_eq!(*ip_reputation.lookups.lock().unwrap(), 2);
+
+        let start = Instant::now();
+        let ttl = DiscoveryConfig::default().ip_reputation_cache_ttl;
+        assert!(discovery.is_bad_ip("1.1.1.2".parse().unwrap(), start + ttl));
+        assert_eq!(*ip_reputation.lookups.lock().unwrap(), 3);
+    }
+}
@@ -0,0 +1,100 @@
+use std::fmt;
+
+use crate::electrum::discovery::{DiscoveryManager, ProbeClient};
+use crate::errors::{Result, ResultExt};
+
+/// The source of our own chain tip height, that the peers' tips are compared against
+pub trait ChainTip: fmt::Debug + Send + Sync {
+    fn height(&self) -> Option<usize>;
+}
+
+/// Doesn't know our tip, disabling the height lag check
+#[derive(Debug, Default)]
+pub struct NoChainTip;
+
+impl ChainTip for NoChainTip {
+    fn height(&self) -> Option<usize> {
+        None
+    }
+}
+
+impl DiscoveryManager {
+    /// Verify that the server's tip is at most `max_height_lag` blocks behind ours, returning how
+    /// far behind it is. None if the check is disabled or we don't know our own tip.
+    pub(super) fn verify_height_lag(&self, client: &dyn ProbeClient) -> Result<Option<usize>> {
+        let (max_lag, our_height) = match (self.config.max_height_lag, self.chain_tip.height()) {
+            (Some(max_lag), Some(our_height)) => (max_lag, our_height),
+            _ => return Ok(None),
+        };
+        let height = client
+            .tip_height()
+            .chain_err(|| "server did not report its tip height")?;
+        let lag = our_height.saturating_sub(height);
+        ensure!(
+            lag <= max_lag,
+            "server tip {} is {} blocks behind ours (max {})",
+            height,
+            lag,
+            max_lag
+        );
+        Ok(Some(lag))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::electrum::discovery::test_util::*;
+    use crate::electrum::discovery::*;
+
+    #[test]
+    fn test_height_lag() {
+        #[derive(Debug)]
+        struct Tip(usize);
+        impl ChainTip for Tip {
+            fn height(&self) -> Option<usize> {
+                Some(self.0)
+            }
+        }
+        let (discovery, probe) = stub_manager(DiscoveryConfig {
+            max_height_lag: Some(2),
+            ..Default::default()
+        });
+        let discovery = discovery.with_chain_tip(Arc::new(Tip(100)));
+        let server = |height: Option<usize>| {
+            let server = StubServer::new(test_features("{}"));
+            match height {
+                Some(height) => server.respond(
+                    "blockchain.headers.subscribe",
+                    json!({ "height": height, "hex": "" }),
+                ),
+                None => server,
+            }
+        };
+        probe.serve("tcp://1.1.1.1:50001", server(Some(98)));
+        probe.serve("tcp://1.1.1.2:50001", server(Some(97)));
+        probe.serve("tcp://1.1.1.3:50001", server(Some(101)));
+        probe.serve("tcp://1.1.1.4:50001", server(None));
+        for ip in &["1.1.1.1", "1.1.1.2", "1.1.1.3", "1.1.1.4"] {
+            discovery
+                .add_default_server(ip.to_string(), vec![Service::Tcp(50001)])
+                .unwrap();
+        }
+        let passed = (0..4)
+            .filter(|_| discovery.run_health_check(0).is_ok())
+            .count();
+        assert_eq!(passed, 2);
+
+        // the lagging server and the one not reporting its tip are not listed
+        let mut hosts = discovery
+            .server_status()
+            .into_iter()
+            .filter(|status| status.healthy)
+            .map(|status| (status.hostname, status.metrics.height_lag))
+            .collect::<Vec<_>>();
+        hosts.sort();
+        assert_eq!(
+            hosts,
+            vec![("1.1.1.1".into(), Some(2)), ("1.1.1.3".into(), Some(0))]
+        );
+    }
+}
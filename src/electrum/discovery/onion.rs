@@ -0,0 +1,105 @@
+use crypto::digest::Digest;
+use crypto::sha3::Sha3;
+
+use crate::errors::{Result, ResultExt};
+
+const V2_ONION_LEN: usize = 16; // base32 of the truncated key hash
+const V3_ONION_LEN: usize = 56; // base32 of the 32 bytes public key, 2 bytes checksum and version byte
+const V3_VERSION: u8 = 3;
+
+/// Validate that the host is a well-formed v3 onion address, rejecting deprecated v2 ones
+pub(super) fn validate_onion(host: &str) -> Result<()> {
+    let label = &host[..host.len() - ".onion".len()];
+    ensure!(
+        label.len() != V2_ONION_LEN,
+        "{} is a deprecated v2 onion address",
+        host
+    );
+    ensure!(
+        label.len() == V3_ONION_LEN,
+        "{} is not a valid onion address (invalid length)",
+        host
+    );
+    let decoded = base32_decode(label)
+        .chain_err(|| format!("{} is not a valid onion address (invalid charset)", host))?;
+    let (pubkey, checksum, version) = (&decoded[..32], &decoded[32..34], decoded[34]);
+    ensure!(
+        version == V3_VERSION,
+        "{} is not a valid onion address (unsupported version {})",
+        host,
+        version
+    );
+
+    let mut hash = [0u8; 32];
+    let mut sha3 = Sha3::sha3_256();
+    sha3.input(b".onion checksum");
+    sha3.input(pubkey);
+    sha3.input(&[version]);
+    sha3.result(&mut hash);
+    ensure!(
+        checksum[..] == hash[..2],
+        "{} is not a valid onion address (checksum mismatch)",
+        host
+    );
+    Ok(())
+}
+
+/// Decode unpadded RFC 4648 base32, as used by onion addresses
+fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in encoded.bytes() {
+        let value = match c.to_ascii_lowercase() {
+            c @ b'a'..=b'z' => c - b'a',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::electrum::discovery::test_util::*;
+    use crate::electrum::discovery::*;
+
+    #[test]
+    fn test_onion_validation() {
+        for onion in &ONION_HOSTS {
+            assert_eq!(
+                ServerAddr::resolve(onion).unwrap(),
+                ServerAddr::Onion(onion.to_string())
+            );
+        }
+        let v3 = ONION_HOSTS[0];
+        let invalid = [
+            // deprecated v2
+            "3smoooajg7qqac2y.onion".to_string(),
+            // invalid length
+            v3[1..].to_string(),
+            // invalid charset (0, 1, 8 and 9 are not base32)
+            v3.replacen('a', "1", 1),
+            // checksum mismatch
+            v3.replacen('e', "f", 1),
+            // unsupported version (the last character encodes the low bits of the version byte)
+            v3.replace("37ead", "37eae"),
+        ];
+        for onion in &invalid {
+            assert!(ServerAddr::resolve(onion).is_err(), "{}", onion);
+        }
+
+        let discovery = test_manager(DiscoveryConfig::default());
+        let hosts = format!("{{\"{}\":{{\"tcp_port\":50001}}}}", invalid[3]);
+        discovery
+            .add_server_request("1.1.1.1".parse().unwrap(), test_features(&hosts))
+            .unwrap();
+        assert!(read_lock(&discovery.queue).is_empty());
+    }
+}
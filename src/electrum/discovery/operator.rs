@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+
+use crate::electrum::discovery::{DiscoveryManager, ServerEntry};
+
+impl DiscoveryManager {
+    /// Look up the operator of the hostname in the configured `operators`, matching the hostname
+    /// itself or any of its parent domains (the most specific match wins)
+    pub(super) fn operator_of(&self, hostname: &str) -> Option<&str> {
+        let operators = &self.config.operators;
+        if operators.is_empty() {
+            return None;
+        }
+        let mut domain = hostname;
+        loop {
+            if let Some(operator) = operators.get(domain) {
+                return Some(operator);
+            }
+            domain = &domain[domain.find('.')? + 1..];
+        }
+    }
+
+    /// Keep at most `max_servers_per_operator` servers of each operator, preferring the ones listed
+    /// first. Servers with no known operator are not grouped.
+    pub(super) fn cap_per_operator(&self, entries: Vec<ServerEntry>) -> Vec<ServerEntry> {
+        let max = match self.config.max_servers_per_operator {
+            Some(max) => max,
+            None => return entries,
+        };
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        entries
+            .into_iter()
+            .filter(
+                |ServerEntry(_, hostname, _)| match self.operator_of(hostname) {
+                    Some(operator) => {
+                        let count = counts.entry(operator).or_insert(0);
+                        *count += 1;
+                        *count <= max
+                    }
+                    None => true,
+                },
+            )
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::electrum::discovery::test_util::*;
+    use crate::electrum::discovery::*;
+
+    #[test]
+    fn test_operator_grouping() {
+        let operators = vec![
+            ("example.com", "alice"),
+            ("1.1.1.3", "alice"),
+            ("1.1.1.4", "bob"),
+            ("1.1.1.5", "bob"),
+        ];
+        let (discovery, _) = stub_manager(DiscoveryConfig {
+            operators: operators
+                .into_iter()
+                .map(|(host, operator)| (host.into(), operator.into()))
+                .collect(),
+            max_servers_per_operator: Some(1),
+            ..Default::default()
+        });
+        assert_eq!(discovery.operator_of("a.example.com"), Some("alice"));
+        assert_eq!(discovery.operator_of("example.org"), None);
+
+        for ip in &["1.1.1.3", "1.1.1.4", "1.1.1.5", "1.1.1.6", "1.1.1.7"] {
+            add_healthy(&discovery, ip, Service::Tcp(50001));
+        }
+        let hostnames = discovery
+            .get_servers()
+            .into_iter()
+            .map(|ServerEntry(_, hostname, _)| hostname)
+            .collect::<Vec<_>>();
+        // one server of each operator, and all of the ungrouped ones
+        assert_eq!(hostnames.len(), 4);
+        assert!(hostnames.contains(&"1.1.1.3".to_string()));
+        assert_eq!(
+            hostnames
+                .iter()
+                .filter(|h| *h == "1.1.1.4" || *h == "1.1.1.5")
+                .count(),
+            1
+        );
+        assert!(hostnames.contains(&"1.1.1.6".to_string()));
+        assert!(hostnames.contains(&"1.1.1.7".to_string()));
+    }
+}
@@ -0,0 +1,381 @@
+use std::collections::BinaryHeap;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::time::{Instant, SystemTime};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::chain::Network;
+use crate::electrum::discovery::{
+    lock, read_lock, write_lock, AdmissionSource, DiscoveryConfig, DiscoveryManager, HealthCheck,
+    Server, ServerAddr, Service,
+};
+use crate::electrum::{Hostname, ProtocolVersion, ServerFeatures};
+use crate::errors::{Result, ResultExt};
+
+/// The discovery state persisted across restarts by `save_to`/`load_from`
+#[derive(Serialize, Deserialize)]
+struct SavedState {
+    queue: Vec<HealthCheck>,
+    healthy: Vec<SavedServer>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedServer {
+    addr: ServerAddr,
+    hostname: Hostname,
+    network: Network,
+    features: ServerFeatures,
+    /// The healthy services, with the pruning limit each reported
+    services: Vec<(Service, Option<usize>)>,
+}
+
+/// The persisted form of a `HealthCheck`. Times are stored as wall-clock times, and the transient
+/// `last_check`/`last_failure` are left out.
+#[derive(Serialize, Deserialize)]
+struct SavedHealthCheck {
+    addr: ServerAddr,
+    hostname: Hostname,
+    service: Service,
+    network: Network,
+    source: AdmissionSource,
+    canonical_name: Option<Hostname>,
+    is_default: bool,
+    is_pinned: bool,
+    added_by: Option<IpAddr>,
+    last_healthy: Option<SystemTime>,
+    consecutive_failures: usize,
+}
+
+impl DiscoveryManager {
+    /// Save the queued health check jobs and the healthy servers to `path`, for `load_from`
+    pub fn save_to(&self, path: &Path) -> Result<()> {
+        let queue = read_lock(&self.queue).iter().cloned().collect();
+        let healthy = read_lock(&self.healthy)
+            .iter()
+            .map(|(addr, server)| SavedServer {
+                addr: addr.clone(),
+                hostname: server.hostname.clone(),
+                network: server.network,
+                features: server.features.clone(),
+                services: server.services.iter().map(|(s, p)| (*s, *p)).collect(),
+            })
+            .collect();
+        let contents = serde_json::to_string(&SavedState { queue, healthy })
+            .chain_err(|| "failed serializing discovery state")?;
+
+        // write to a temporary file first, so that a crash mid-write doesn't lose the previous state
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, contents).chain_err(|| format!("failed writing {:?}", tmp_path))?;
+        fs::rename(&tmp_path, path).chain_err(|| format!("failed writing {:?}", path))?;
+        Ok(())
+    }
+
+    /// Save the state to `state_path`, if set, like when shutting down
+    pub fn save_state(&self) -> Result<()> {
+        match self.config.state_path {
+            Some(ref path) => self.save_to(path),
+            None => Ok(()),
+        }
+    }
+
+    /// Save the state to `state_path` if it's due
+    pub(super) fn save_state_if_due(&self) -> Result<()> {
+        let path = match self.config.state_path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        {
+            let mut last_state_save = lock(&self.last_state_save);
+            if last_state_save.map_or(false, |t| t.elapsed() < self.config.state_save_interval) {
+                return Ok(());
+            }
+            *last_state_save = Some(Instant::now());
+        }
+        self.save_to(path)
+    }
+
+    /// Restore the state saved to `state_path`, if any, merged with the default servers. A
+    /// missing or unreadable state file is logged and otherwise ignored.
+    pub fn with_saved_state(self) -> Self {
+        let path = match self.config.state_path {
+            Some(ref path) if path.exists() => path,
+            _ => return self,
+        };
+        match read_state(path) {
+            Ok(state) => self.restore(state),
+            Err(e) => warn!("not restoring discovery state: {:?}", e),
+        }
+        self
+    }
+
+    /// Create a manager with the state saved by `save_to`, merged with the default servers
+    pub fn load_from(
+        path: &Path,
+        our_network: Network,
+        our_features: ServerFeatures,
+        our_version: ProtocolVersion,
+        announce: bool,
+        tor_proxy: Option<SocketAddr>,
+        config: DiscoveryConfig,
+    ) -> Result<Self> {
+        let state = read_state(path)?;
+        let discovery = Self::new(
+            our_network,
+            our_features,
+            our_version,
+            announce,
+            tor_proxy,
+            config,
+        );
+        discovery.restore(state);
+        Ok(discovery)
+    }
+
+    fn restore(&self, state: SavedState) {
+        let mut queue = write_lock(&self.queue);
+        let mut jobs = std::mem::take(&mut *queue).into_vec();
+        for job in state.queue {
+            // matching `should_retry`, non-default servers that never once succeeded don't get another chance
+            if job.last_healthy.is_none() && !job.is_default && !job.is_pinned {
+                continue;
+            }
+            if !job.is_default && jobs.len() >= self.config.max_queue_size {
+                debug!("queue size exceeded, not restoring {:?}", job);
+                continue;
+            }
+            // the saved job replaces the freshly queued default one, to keep its history
+            jobs.retain(|queued| *queued != job);
+            jobs.push(job);
+        }
+
+        let freq = self.config.health_check_freq;
+        let mut healthy = write_lock(&self.healthy);
+        for saved in state.healthy {
+            let addr = saved.addr;
+            let mut server = Server::new(saved.hostname, saved.network, saved.features);
+            server.asn = self.lookup_asn(&addr);
+            for (service, pruning) in saved.services {
+                let job = jobs.iter_mut().find(|job| {
+                    job.addr == addr
+                        && job.service == service
+                        && job.last_healthy.map_or(false, |t| t.elapsed() < freq)
+                });
+                // consider the service checked when it was last healthy, so that it stays listed
+                // and is rescheduled as if we were never restarted. services that weren't healthy
+                // within the last `health_check_freq` stay unchecked and unlisted until rechecked.
+                if let Some(job) = job {
+                    job.last_check = job.last_healthy;
+                    server.services.insert(service, pruning);
+                    server.scores.insert(service, job.metrics.score());
+                }
+            }
+            if !server.services.is_empty() {
+                healthy.insert(addr, server);
+            }
+        }
+
+        info!(
+            "restored {} queued services and {} healthy servers",
+            jobs.len(),
+            healthy.len()
+        );
+        *queue = BinaryHeap::from(jobs);
+    }
+}
+
+fn read_state(path: &Path) -> Result<SavedState> {
+    let contents = fs::read(path).chain_err(|| format!("failed reading {:?}", path))?;
+    serde_json::from_slice(&contents).chain_err(|| format!("invalid discovery state in {:?}", path))
+}
+
+impl Serialize for HealthCheck {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let now = (Instant::now(), SystemTime::now());
+        SavedHealthCheck {
+            addr: self.addr.clone(),
+            hostname: self.hostname.clone(),
+            service: self.service,
+            network: self.network,
+            source: self.source,
+            canonical_name: self.canonical_name.clone(),
+            is_default: self.is_default,
+            is_pinned: self.is_pinned,
+            added_by: self.added_by,
+            last_healthy: self
+                .last_healthy
+                .and_then(|t| now.1.checked_sub(now.0.saturating_duration_since(t))),
+            consecutive_failures: self.consecutive_failures,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for HealthCheck {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let saved = SavedHealthCheck::deserialize(deserializer)?;
+        let now = (Instant::now(), SystemTime::now());
+        // times before the monotonic clock's start (like before a reboot) can't be represented,
+        // treat them as never healthy rather than as healthy just now
+        let last_healthy = saved.last_healthy.and_then(|t| {
+            let age = now.1.duration_since(t).unwrap_or_default();
+            now.0.checked_sub(age)
+        });
+        let mut job = HealthCheck::new(
+            saved.addr,
+            saved.hostname,
+            saved.service,
+            saved.network,
+            saved.added_by,
+        );
+        job.source = saved.source;
+        job.canonical_name = saved.canonical_name;
+        job.is_default = saved.is_default;
+        job.is_pinned = saved.is_pinned;
+        job.last_healthy = last_healthy;
+        job.consecutive_failures = saved.consecutive_failures;
+        Ok(job)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::electrum::discovery::test_util::*;
+    use crate::electrum::discovery::*;
+
+    #[test]
+    fn test_saved_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = DiscoveryConfig {
+            state_path: Some(dir.path().join("discovery-state.json")),
+            state_save_interval: Duration::from_secs(3600),
+            ..Default::default()
+        };
+        // nothing to restore yet
+        let (discovery, probe) = stub_manager(config.clone());
+        let discovery = discovery.with_saved_state();
+        probe.serve("tcp://1.1.1.1:50001", StubServer::new(test_features("{}")));
+        discovery
+            .add_default_server("1.1.1.1".into(), vec![Service::Tcp(50001)])
+            .unwrap();
+        discovery.run_health_check(0).unwrap();
+        discovery.save_state_if_due().unwrap();
+        assert!(dir.path().join("discovery-state.json").exists());
+
+        let (restored, _) = stub_manager(config);
+        let restored = restored.with_saved_state();
+        assert_eq!(restored.get_servers().len(), 1);
+        assert_eq!(read_lock(&restored.queue).len(), 1);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let (discovery, probe) = stub_manager(DiscoveryConfig::default());
+        probe.serve("tcp://1.1.1.1:50001", StubServer::new(test_features("{}")));
+        probe.serve("tcp://1.1.1.2:50001", StubServer::new(test_features("{}")));
+        discovery
+            .add_default_server("1.1.1.1".into(), vec![Service::Tcp(50001)])
+            .unwrap();
+        discovery
+            .add_default_server(ONION_HOSTS[0].into(), vec![Service::Tcp(50001)])
+            .unwrap();
+        let add_peer = |ip: &str| {
+            let hosts = format!("{{\"{}\":{{\"tcp_port\":50001}}}}", ip);
+            discovery
+                .add_server_request(ip.parse().unwrap(), test_features(&hosts))
+                .unwrap();
+        };
+        add_peer("1.1.1.2");
+        for _ in 0..3 {
+            discovery.run_health_check(0).ok();
+        }
+        // queued but never checked, so dropped on load
+        add_peer("1.1.1.3");
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("discovery.json");
+        discovery.save_to(&path).unwrap();
+        let loaded = DiscoveryManager::load_from(
+            &path,
+            Network::Regtest,
+            test_features("{}"),
+            PROTOCOL_VERSION,
+            false,
+            None,
+            DiscoveryConfig::default(),
+        )
+        .unwrap();
+
+        let status = |discovery: &DiscoveryManager| {
+            discovery
+                .server_status()
+                .into_iter()
+                .map(|s| (s.hostname, s.source, s.healthy, s.consecutive_failures))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            status(&loaded),
+            vec![
+                ("1.1.1.1".into(), AdmissionSource::Default, true, 0),
+                ("1.1.1.2".into(), AdmissionSource::Gossip, true, 0),
+                (ONION_HOSTS[0].into(), AdmissionSource::Default, false, 1),
+            ]
+        );
+        let mut healthy = loaded
+            .get_servers()
+            .into_iter()
+            .map(|ServerEntry(_, hostname, _)| hostname)
+            .collect::<Vec<_>>();
+        healthy.sort();
+        assert_eq!(healthy, vec!["1.1.1.1", "1.1.1.2"]);
+
+        // reloading doesn't duplicate the default servers
+        loaded
+            .add_default_server("1.1.1.1".into(), vec![Service::Tcp(50001)])
+            .unwrap();
+        assert_eq!(loaded.server_status().len(), 3);
+    }
+
+    #[test]
+    fn test_load_stale_healthy() {
+        let (discovery, probe) = stub_manager(DiscoveryConfig::default());
+        probe.serve("tcp://1.1.1.1:50001", StubServer::new(test_features("{}")));
+        discovery
+            .add_default_server("1.1.1.1".into(), vec![Service::Tcp(50001)])
+            .unwrap();
+        discovery.run_health_check(0).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("discovery.json");
+        discovery.save_to(&path).unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+
+        // last healthy longer than a check interval ago, so not listed until checked again
+        let config = DiscoveryConfig {
+            health_check_freq: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let loaded = DiscoveryManager::load_from(
+            &path,
+            Network::Regtest,
+            test_features("{}"),
+            PROTOCOL_VERSION,
+            false,
+            None,
+            config,
+        )
+        .unwrap();
+        assert!(loaded.get_servers().is_empty());
+        let queue = read_lock(&loaded.queue);
+        let job = queue.peek().unwrap();
+        assert!(job.last_check.is_none() && job.last_healthy.is_some());
+        assert!(job.is_due(Duration::from_secs(3600)));
+    }
+}
@@ -0,0 +1,388 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
+
+use rustls::TLSError;
+use serde_json::Value;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime as DateTime;
+use url::{Host, Url};
+
+use super::connection::{Protocol, RpcConnection};
+use crate::electrum::ServerFeatures;
+use crate::errors::{Error, Result, ResultExt};
+
+/// Opens connections to remote servers for health checking. Abstracted so that the
+/// network can be substituted with stub servers.
+pub trait Probe: fmt::Debug + Send + Sync {
+    /// Connect to the server at `url`, optionally through a SOCKS5 proxy. Requests fail once
+    /// `timeout` elapses without a response or once `deadline` passes, if set. The certificate of
+    /// SSL services is verified against the url's hostname if `verify_certificate` is set.
+    fn connect(
+        &self,
+        url: &str,
+        socks5: Option<&Socks5Proxy>,
+        timeout: Option<Duration>,
+        deadline: Option<Instant>,
+        verify_certificate: bool,
+    ) -> Result<Box<dyn ProbeClient>>;
+}
+
+/// Which SSL services to verify the certificates of. Onion services commonly use self-signed
+/// certificates, and are already authenticated by their onion address.
+#[derive(Serialize, Eq, PartialEq, Copy, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum CertVerification {
+    All,
+    ClearnetOnly,
+    None,
+}
+
+impl FromStr for CertVerification {
+    type Err = Error;
+
+    /// Parse the `--electrum-discovery-cert-verification` option: `all`, `clearnet` or `none`
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "all" => Ok(CertVerification::All),
+            "clearnet" => Ok(CertVerification::ClearnetOnly),
+            "none" => Ok(CertVerification::None),
+            _ => bail!("invalid certificate verification {}", s),
+        }
+    }
+}
+
+/// The kind of certificate presented by an SSL service
+#[derive(Serialize, Eq, PartialEq, Copy, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum CertKind {
+    /// Verified against the CA roots and the server's hostname
+    CaSigned,
+    /// Not issued by a trusted CA, like self-signed certificates. Certificates failing verification
+    /// otherwise (like expired ones, or ones issued for another hostname) fail the check instead.
+    SelfSigned,
+}
+
+impl CertKind {
+    /// The prefix of the feature string advertising the kind in `server.peers.subscribe`, which is
+    /// followed by the SSL port (like `ca50002`). Unlike `s`, it isn't parsed as a service by wallets.
+    pub(super) fn feature_prefix(self) -> &'static str {
+        match self {
+            CertKind::CaSigned => "ca",
+            CertKind::SelfSigned => "cs",
+        }
+    }
+}
+
+/// A SOCKS5 proxy to connect through, with optional username/password authentication
+#[derive(Clone, Debug, PartialEq)]
+pub struct Socks5Proxy {
+    pub addr: SocketAddr,
+    pub credentials: Option<(String, String)>,
+}
+
+/// An open connection to a remote Electrum server
+pub trait ProbeClient {
+    fn features(&self) -> Result<ServerFeatures>;
+
+    fn add_peer(&self, features: &ServerFeatures) -> Result<bool>;
+
+    /// Issue a raw RPC call
+    fn call(&self, method: &str, params: &[Value]) -> Result<Value>;
+
+    /// The server's current time, as reported in its `server.banner` (like with the `$TIME`
+    /// variable of our banner templates). None if the banner doesn't contain an RFC 3339 time.
+    fn server_time(&self) -> Option<SystemTime> {
+        let banner = self.call("server.banner", &[]).ok()?;
+        parse_banner_time(banner.as_str()?)
+    }
+
+    /// The server's tip height, via `blockchain.headers.subscribe`
+    fn tip_height(&self) -> Option<usize> {
+        let tip = self.call("blockchain.headers.subscribe", &[]).ok()?;
+        Some(tip.get("height")?.as_u64()? as usize)
+    }
+
+    /// The TLS version negotiated for SSL connections. None for plain connections, or if the
+    /// client doesn't expose it.
+    fn tls_version(&self) -> Option<TlsVersion> {
+        None
+    }
+
+    /// How long opening the TCP connection took, before any TLS or websocket handshake. None if
+    /// the client doesn't expose it.
+    fn tcp_connect_time(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[derive(Serialize, Eq, PartialEq, Ord, PartialOrd, Copy, Clone, Debug)]
+pub enum TlsVersion {
+    #[serde(rename = "TLSv1.0")]
+    Tls10,
+    #[serde(rename = "TLSv1.1")]
+    Tls11,
+    #[serde(rename = "TLSv1.2")]
+    Tls12,
+    #[serde(rename = "TLSv1.3")]
+    Tls13,
+}
+
+impl fmt::Display for TlsVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let version = match self {
+            TlsVersion::Tls10 => "1.0",
+            TlsVersion::Tls11 => "1.1",
+            TlsVersion::Tls12 => "1.2",
+            TlsVersion::Tls13 => "1.3",
+        };
+        write!(f, "TLSv{}", version)
+    }
+}
+
+/// Probes servers over the network, over TCP, SSL or WebSocket (with or without SSL)
+#[derive(Debug, Default)]
+pub struct ElectrumProbe;
+
+impl Probe for ElectrumProbe {
+    fn connect(
+        &self,
+        url: &str,
+        socks5: Option<&Socks5Proxy>,
+        timeout: Option<Duration>,
+        deadline: Option<Instant>,
+        verify_certificate: bool,
+    ) -> Result<Box<dyn ProbeClient>> {
+        let url = Url::parse(url).chain_err(|| format!("invalid url {}", url))?;
+        let host = match url
+            .host()
+            .chain_err(|| format!("missing host in {}", url))?
+        {
+            Host::Domain(domain) => domain.to_string(),
+            Host::Ipv4(ip) => ip.to_string(),
+            Host::Ipv6(ip) => ip.to_string(),
+        };
+        let port = url
+            .port()
+            .chain_err(|| format!("missing port in {}", url))?;
+        let protocol = match url.scheme() {
+            "tcp" => Protocol::Tcp,
+            "ssl" => Protocol::Ssl,
+            "ws" => Protocol::Ws,
+            "wss" => Protocol::Wss,
+            scheme => bail!("unsupported scheme {}", scheme),
+        };
+        let connection = RpcConnection::connect(
+            &host,
+            port,
+            protocol,
+            socks5,
+            timeout,
+            deadline,
+            verify_certificate,
+        )?;
+        Ok(Box::new(connection))
+    }
+}
+
+impl ProbeClient for RpcConnection {
+    fn features(&self) -> Result<ServerFeatures> {
+        let mut features = self.request("server.features", &[])?;
+        // the hosts are taken from the server's registration instead
+        if let Some(features) = features.as_object_mut() {
+            features.insert("hosts".into(), json!({}));
+        }
+        serde_json::from_value(features).chain_err(|| "invalid server.features reply")
+    }
+
+    fn add_peer(&self, features: &ServerFeatures) -> Result<bool> {
+        let reply = self.request("server.add_peer", &[json!(features)])?;
+        reply
+            .as_bool()
+            .chain_err(|| "invalid server.add_peer reply")
+    }
+
+    fn call(&self, method: &str, params: &[Value]) -> Result<Value> {
+        self.request(method, params)
+    }
+
+    fn tls_version(&self) -> Option<TlsVersion> {
+        self.tls_version
+    }
+
+    fn tcp_connect_time(&self) -> Option<Duration> {
+        Some(self.tcp_connect_time)
+    }
+}
+
+/// Check whether the error was caused by the remote end abruptly closing the connection
+pub(super) fn is_connection_reset(e: &Error) -> bool {
+    has_io_error(e, |kind| match kind {
+        io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::BrokenPipe
+        | io::ErrorKind::UnexpectedEof => true,
+        _ => false,
+    })
+}
+
+/// Check whether the error was caused by the remote end not responding in time
+pub(super) fn is_timeout(e: &Error) -> bool {
+    has_io_error(e, |kind| match kind {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => true,
+        _ => false,
+    })
+}
+
+/// Check whether the certificate failed verification for not being issued by a trusted CA, as is
+/// the case for self-signed certificates: either as an unknown issuer, or as a CA certificate used
+/// as its own end-entity certificate (which `openssl req -x509` generates by default)
+pub(super) fn is_untrusted_certificate(e: &Error) -> bool {
+    io_errors(e)
+        .filter_map(|e| e.get_ref()?.downcast_ref::<TLSError>())
+        .any(|e| match e {
+            TLSError::WebPKIError(webpki::Error::UnknownIssuer)
+            | TLSError::WebPKIError(webpki::Error::CAUsedAsEndEntity) => true,
+            _ => false,
+        })
+}
+
+/// Check whether any of the error's causes is an IO error of a matching kind
+fn has_io_error(e: &Error, matches: fn(io::ErrorKind) -> bool) -> bool {
+    io_errors(e).any(|e| matches(e.kind()))
+}
+
+/// The IO errors among the error's causes
+fn io_errors(e: &Error) -> impl Iterator<Item = &io::Error> {
+    let e: &(dyn std::error::Error + 'static) = e;
+    std::iter::successors(Some(e), |e| e.source()).filter_map(|e| e.downcast_ref::<io::Error>())
+}
+
+/// The first RFC 3339 time found in a banner, ignoring surrounding punctuation
+fn parse_banner_time(banner: &str) -> Option<SystemTime> {
+    banner
+        .split_whitespace()
+        .map(|word| word.trim_matches(|c: char| !c.is_ascii_alphanumeric()))
+        .find_map(|word| DateTime::parse(word, &Rfc3339).ok())
+        .map(SystemTime::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::{genesis_hash, Network};
+    use crate::util::websocket;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::UNIX_EPOCH;
+
+    /// Serve a single connection, replying to each request with the reply of its method after
+    /// sending a notification
+    fn stub_server(replies: Vec<(&'static str, Value)>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("tcp://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (sock, _) = listener.accept().unwrap();
+            let mut writer = sock.try_clone().unwrap();
+            for line in BufReader::new(sock).lines() {
+                let request: Value = serde_json::from_str(&line.unwrap()).unwrap();
+                let method = request["method"].as_str().unwrap();
+                let notification =
+                    json!({ "method": "blockchain.headers.subscribe", "params": [] });
+                let reply = match replies.iter().find(|(m, _)| *m == method) {
+                    Some((_, result)) => json!({ "id": request["id"], "result": result }),
+                    None => json!({ "id": request["id"], "error": "unknown method" }),
+                };
+                writeln!(writer, "{}\n{}", notification, reply).unwrap();
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn test_rpc_connection() {
+        let features = json!({
+            "hosts": { "example.com": { "tcp_port": "invalid" } },
+            "genesis_hash": "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206",
+            "server_version": "electrs-esplora 9",
+            "protocol_min": "1.4",
+            "protocol_max": "1.4.2",
+            "pruning": null,
+            "hash_function": "sha256"
+        });
+        let url = stub_server(vec![
+            ("server.features", features),
+            ("server.add_peer", json!(true)),
+            (
+                "server.banner",
+                json!("Welcome! Server time: 2023-11-14T22:13:20Z."),
+            ),
+        ]);
+        let timeout = Some(Duration::from_secs(5));
+        let client = ElectrumProbe
+            .connect(&url, None, timeout, None, false)
+            .unwrap();
+
+        let features = client.features().unwrap();
+        assert!(features.hosts.is_empty());
+        assert_eq!(features.server_version, "electrs-esplora 9");
+        assert_eq!(features.genesis_hash, genesis_hash(Network::Regtest));
+        assert!(client.add_peer(&features).unwrap());
+        let time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(client.server_time(), Some(time));
+        let err = client.call("server.unknown", &[]).unwrap_err();
+        assert!(err.to_string().contains("unknown method"));
+    }
+
+    #[test]
+    fn test_websocket_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (sock, _) = listener.accept().unwrap();
+            let mut writer = sock.try_clone().unwrap();
+            let mut reader = BufReader::new(sock);
+            websocket::accept(&mut reader, &mut writer).unwrap();
+            while let Ok(Some(message)) = websocket::read_message(&mut reader, &mut writer) {
+                let request: Value = serde_json::from_str(&message).unwrap();
+                let reply = json!({ "id": request["id"], "result": request["method"] });
+                websocket::write_text(&mut writer, &reply.to_string()).unwrap();
+            }
+        });
+        let timeout = Some(Duration::from_secs(5));
+        let client = ElectrumProbe
+            .connect(&url, None, timeout, None, false)
+            .unwrap();
+        for method in &["server.ping", "server.version"] {
+            assert_eq!(client.call(method, &[]).unwrap(), json!(method));
+        }
+    }
+
+    #[test]
+    fn test_connection_errors() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let err = ElectrumProbe
+            .connect(&format!("tcp://{}", addr), None, None, None, false)
+            .err()
+            .unwrap();
+        assert!(!is_timeout(&err));
+
+        // the connection is closed before the server replies
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("tcp://{}", listener.local_addr().unwrap());
+        thread::spawn(move || drop(listener.accept().unwrap()));
+        let client = ElectrumProbe
+            .connect(&url, None, None, None, false)
+            .unwrap();
+        assert!(is_connection_reset(&client.features().unwrap_err()));
+
+        assert!(ElectrumProbe
+            .connect("http://1.1.1.1:50001", None, None, None, false)
+            .is_err());
+    }
+}
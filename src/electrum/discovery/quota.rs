@@ -0,0 +1,113 @@
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::time::Instant;
+
+use crate::electrum::discovery::{lock, DiscoveryManager, HealthCheck};
+use crate::errors::Result;
+
+impl DiscoveryManager {
+    /// Count a `server.add_peer` request from `added_by` towards its quota, failing if it already
+    /// made `max_requests_per_ip` requests within the last `request_window`
+    pub(super) fn check_request_quota(&self, added_by: IpAddr, now: Instant) -> Result<()> {
+        let max = match self.config.max_requests_per_ip {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+        let window = self.config.request_window;
+        let mut requests = lock(&self.requests_by_ip);
+        let mut recent: VecDeque<Instant> = requests.get(&added_by).cloned().unwrap_or_default();
+        while recent
+            .front()
+            .map_or(false, |t| now.saturating_duration_since(*t) >= window)
+        {
+            recent.pop_front();
+        }
+        let allowed = recent.len() < max;
+        if allowed {
+            recent.push_back(now);
+        }
+        let count = recent.len();
+        requests.insert(added_by, recent);
+        ensure!(
+            allowed,
+            "too many requests from {} ({} within {:?})",
+            added_by,
+            count,
+            window
+        );
+        Ok(())
+    }
+
+    /// Fail if adding `new_jobs` would leave more than `max_pending_per_ip` jobs requested by
+    /// `added_by` that are not (yet) healthy
+    pub(super) fn check_pending_quota<'a>(
+        &self,
+        added_by: IpAddr,
+        queue: impl Iterator<Item = &'a HealthCheck>,
+        new_jobs: usize,
+    ) -> Result<()> {
+        let max = match self.config.max_pending_per_ip {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+        let pending = queue
+            .filter(|job| job.added_by == Some(added_by) && !job.is_healthy())
+            .count();
+        ensure!(
+            pending + new_jobs <= max,
+            "too many pending servers from {} ({} + {} > {})",
+            added_by,
+            pending,
+            new_jobs,
+            max
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::electrum::discovery::test_util::*;
+    use crate::electrum::discovery::*;
+
+    #[test]
+    fn test_per_ip_quotas() {
+        let added_by: IpAddr = "1.1.1.1".parse().unwrap();
+        let onion = |i: usize| {
+            test_features(&format!(
+                "{{\"{}\":{{\"tcp_port\":50001}}}}",
+                ONION_HOSTS[i]
+            ))
+        };
+
+        let discovery = test_manager(DiscoveryConfig {
+            max_pending_per_ip: Some(2),
+            ..Default::default()
+        });
+        discovery.add_server_request(added_by, onion(0)).unwrap();
+        discovery.add_server_request(added_by, onion(1)).unwrap();
+        assert!(discovery.add_server_request(added_by, onion(2)).is_err());
+        // other IPs have their own quota
+        discovery
+            .add_server_request("1.1.1.2".parse().unwrap(), onion(2))
+            .unwrap();
+        assert_eq!(read_lock(&discovery.queue).len(), 3);
+
+        let discovery = test_manager(DiscoveryConfig {
+            max_requests_per_ip: Some(2),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        discovery.check_request_quota(added_by, now).unwrap();
+        discovery.check_request_quota(added_by, now).unwrap();
+        assert!(discovery.check_request_quota(added_by, now).is_err());
+        assert!(discovery
+            .check_request_quota("1.1.1.2".parse().unwrap(), now)
+            .is_ok());
+        // rejected requests don't count towards the quota
+        let later = now + Duration::from_secs(3600);
+        discovery.check_request_quota(added_by, later).unwrap();
+        discovery.check_request_quota(added_by, later).unwrap();
+        assert!(discovery.check_request_quota(added_by, later).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+use std::time::{Duration, SystemTime};
+
+use crate::electrum::discovery::{lock, read_lock, write_lock, DiscoveryManager, ServerAddr};
+
+const DECAY_INTERVAL: Duration = Duration::from_secs(3600); // decay reputation every hour
+const MIN_WEIGHT: f64 = 0.01; // forget servers whose reputation decayed below this weight
+
+/// A server's long-term reputation, earned through successful health checks and decaying
+/// over time so that stale reputation fades away
+#[derive(Clone, Debug)]
+pub(super) struct Reputation {
+    pub(super) weight: f64,
+    /// When the weight was last brought up to date with the decay
+    pub(super) updated: SystemTime,
+}
+
+impl Reputation {
+    pub(super) fn new(weight: f64, updated: SystemTime) -> Self {
+        Reputation { weight, updated }
+    }
+
+    /// Apply the decay accumulated since the last update, halving the weight every `half_life`
+    pub(super) fn decay(&mut self, now: SystemTime, half_life: Duration) {
+        let elapsed = now.duration_since(self.updated).unwrap_or_default();
+        if half_life > Duration::from_secs(0) {
+            self.weight *= 0.5f64.powf(elapsed.as_secs_f64() / half_life.as_secs_f64());
+        }
+        self.updated = self.updated.max(now);
+    }
+}
+
+impl DiscoveryManager {
+    /// Credit a successful health check to the server's reputation
+    pub(super) fn record_reputation(&self, addr: &ServerAddr, now: SystemTime) {
+        let half_life = self.config.reputation_half_life;
+        let mut reputation = write_lock(&self.reputation);
+        let entry = reputation
+            .entry(addr.clone())
+            .or_insert_with(|| Reputation::new(0.0, now));
+        entry.decay(now, half_life);
+        entry.weight += 1.0;
+    }
+
+    /// The server's current reputation weight, or zero for unknown servers
+    pub(super) fn reputation_weight(&self, addr: &ServerAddr) -> f64 {
+        read_lock(&self.reputation)
+            .get(addr)
+            .map_or(0.0, |reputation| reputation.weight)
+    }
+
+    /// Decay the reputation of all servers, if its due
+    pub(super) fn decay_reputation_if_due(&self) {
+        {
+            let mut last_decay = lock(&self.last_decay);
+            if last_decay.map_or(false, |t| t.elapsed() < DECAY_INTERVAL) {
+                return;
+            }
+            *last_decay = Some(std::time::Instant::now());
+        }
+        self.decay_reputation(SystemTime::now());
+    }
+
+    /// Decay the reputation of all servers up to `now`, forgetting ones that faded away
+    pub(super) fn decay_reputation(&self, now: SystemTime) {
+        let half_life = self.config.reputation_half_life;
+        let mut reputation = write_lock(&self.reputation);
+        reputation.retain(|_, reputation| {
+            reputation.decay(now, half_life);
+            reputation.weight >= MIN_WEIGHT
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::electrum::discovery::test_util::*;
+    use crate::electrum::discovery::*;
+
+    #[test]
+    fn test_reputation_decay() {
+        let discovery = test_manager(DiscoveryConfig {
+            reputation_half_life: Duration::from_secs(30 * 86400),
+            ..Default::default()
+        });
+        let addr = ServerAddr::resolve("1.1.1.1").unwrap();
+        let start = std::time::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        for _ in 0..4 {
+            discovery.record_reputation(&addr, start);
+        }
+        assert_eq!(discovery.reputation_weight(&addr), 4.0);
+
+        // halved after 30 days of absence, quartered after 60
+        discovery.decay_reputation(start + Duration::from_secs(30 * 86400));
+        assert!((discovery.reputation_weight(&addr) - 2.0).abs() < 1e-9);
+        discovery.decay_reputation(start + Duration::from_secs(60 * 86400));
+        assert!((discovery.reputation_weight(&addr) - 1.0).abs() < 1e-9);
+
+        // returning after a long absence only partially restores it
+        discovery.record_reputation(&addr, start + Duration::from_secs(90 * 86400));
+        assert!((discovery.reputation_weight(&addr) - 1.5).abs() < 1e-9);
+
+        // and it's eventually forgotten entirely
+        discovery.decay_reputation(start + Duration::from_secs(500 * 86400));
+        assert!(read_lock(&discovery.reputation).is_empty());
+    }
+}
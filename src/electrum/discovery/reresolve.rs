@@ -0,0 +1,266 @@
+use std::collections::{BinaryHeap, HashMap};
+use std::net::IpAddr;
+use std::time::Instant;
+
+use crate::electrum::discovery::resolver::clearnet_ips;
+use crate::electrum::discovery::{
+    lock, read_lock, write_lock, BanPrecedence, DiscoveryEvent, DiscoveryManager, HealthCheck,
+    ServerAddr,
+};
+use crate::electrum::Hostname;
+
+impl DiscoveryManager {
+    /// Re-resolve the hostnames of queued servers if it's due, see `reresolve`
+    pub(super) fn reresolve_if_due(&self) {
+        let interval = match self.config.reresolve_interval {
+            Some(interval) => interval,
+            None => return,
+        };
+        {
+            let mut last_reresolve = lock(&self.last_reresolve);
+            if last_reresolve.map_or(false, |t| t.elapsed() < interval) {
+                return;
+            }
+            *last_reresolve = Some(Instant::now());
+        }
+        self.reresolve();
+    }
+
+    /// Re-resolve the hostnames of queued clearnet servers, following their IP changes. The
+    /// admission checks are re-applied to the new IP, evicting servers that moved to a banned,
+    /// own or non-remote one. Servers that still resolve to their current IP keep it, even if
+    /// it's no longer the first.
+    fn reresolve(&self) {
+        let hostnames = read_lock(&self.queue)
+            .iter()
+            .filter(|job| job.hostname.parse::<IpAddr>().is_err())
+            .filter_map(|job| match job.addr {
+                ServerAddr::Clearnet(_) => {
+                    Some((job.hostname.clone(), (job.addr.clone(), job.is_default)))
+                }
+                ServerAddr::Onion(_) => None,
+            })
+            .collect::<HashMap<Hostname, _>>();
+
+        for (hostname, (addr, is_default)) in hostnames {
+            let addrs = match self.resolve_all(&hostname) {
+                Ok((addrs, _)) => addrs,
+                Err(e) => {
+                    debug!("failed re-resolving {}: {:?}", hostname, e);
+                    continue;
+                }
+            };
+            let ips = clearnet_ips(&addrs);
+            if addrs.contains(&addr) {
+                self.readdress(&addr, addr.clone(), ips);
+                continue;
+            }
+            let new_addr = addrs[0].clone();
+            let reason = self.addr_rejection(&new_addr).or_else(|| {
+                self.ban_reason(&hostname, &new_addr)
+                    .filter(|_| !is_default || self.config.ban_precedence == BanPrecedence::Ban)
+            });
+            match reason {
+                Some(reason) => {
+                    warn!(
+                        "evicting {}: re-resolved to {}, {}",
+                        hostname, new_addr, reason
+                    );
+                    self.drop_server(&addr);
+                    self.emit(DiscoveryEvent::Dropped { hostname, reason });
+                }
+                None => {
+                    info!("{} re-resolved from {} to {}", hostname, addr, new_addr);
+                    self.readdress(&addr, new_addr, ips);
+                }
+            }
+        }
+    }
+
+    /// Re-resolve the hostname of a failing default server every `reresolve_after_failures`
+    /// consecutive failures, moving it (and its other services) to the new IP if it changed. The
+    /// job gets a fresh start on its new IP.
+    pub(super) fn reresolve_failing(&self, job: &mut HealthCheck) {
+        match self.config.reresolve_after_failures {
+            Some(threshold) if threshold > 0 && job.consecutive_failures % threshold == 0 => (),
+            _ => return,
+        }
+        let addrs = match self.resolve_all(&job.hostname) {
+            Ok((addrs, _)) => addrs,
+            Err(e) => {
+                debug!("failed re-resolving {}: {:?}", job.hostname, e);
+                return;
+            }
+        };
+        let ips = clearnet_ips(&addrs);
+        if addrs.contains(&job.addr) {
+            job.ips = ips;
+            return;
+        }
+        let new_addr = addrs[0].clone();
+        if let Some(reason) = self.addr_rejection(&new_addr) {
+            warn!("not moving {} from {}: {}", job.hostname, job.addr, reason);
+            return;
+        }
+        if self.skip_banned_default(&job.hostname, &new_addr) {
+            return;
+        }
+        info!(
+            "{} re-resolved from {} to {} after {} failures",
+            job.hostname, job.addr, new_addr, job.consecutive_failures
+        );
+        self.readdress(&job.addr, new_addr.clone(), ips.clone());
+        job.addr = new_addr;
+        job.ips = ips;
+        job.consecutive_failures = 0;
+        job.recheck = true;
+    }
+
+    fn readdress(&self, addr: &ServerAddr, new_addr: ServerAddr, ips: Vec<IpAddr>) {
+        {
+            let mut healthy = write_lock(&self.healthy);
+            if let Some(server) = healthy.remove(addr) {
+                healthy.insert(new_addr.clone(), server);
+            }
+        }
+        let mut queue = write_lock(&self.queue);
+        let mut jobs = std::mem::take(&mut *queue).into_vec();
+        for job in &mut jobs {
+            if job.addr == *addr {
+                job.addr = new_addr.clone();
+                job.ips = ips.clone();
+            }
+        }
+        *queue = BinaryHeap::from(jobs);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::electrum::discovery::test_util::*;
+    use crate::electrum::discovery::*;
+
+    #[test]
+    fn test_reresolve_failing_default() {
+        let (discovery, probe) = stub_manager(DiscoveryConfig {
+            reresolve_after_failures: Some(2),
+            ..Default::default()
+        });
+        let resolver = Arc::new(StubResolver::default());
+        let discovery = discovery.with_resolver(resolver.clone());
+        let resolve_to = |ip: &str| Resolution {
+            cnames: vec![],
+            ips: vec![ip.parse().unwrap()],
+        };
+        resolver.set("electrum.example.com", resolve_to("1.1.1.1"));
+        discovery
+            .add_default_server("electrum.example.com".into(), vec![Service::Tcp(50001)])
+            .unwrap();
+        probe.serve("tcp://1.1.1.2:50001", StubServer::new(test_features("{}")));
+
+        // the server moved, but it's only re-resolved once the threshold is crossed
+        resolver.set("electrum.example.com", resolve_to("1.1.1.2"));
+        assert!(discovery.run_health_check(0).is_err());
+        assert_eq!(discovery.server_status()[0].addr, "1.1.1.1");
+        assert!(discovery.run_health_check(0).is_err());
+        let status = discovery.server_status();
+        assert_eq!(status[0].addr, "1.1.1.2");
+        assert_eq!(status[0].consecutive_failures, 0);
+
+        discovery.run_health_check(0).unwrap();
+        assert_eq!(discovery.get_servers().len(), 1);
+    }
+
+    #[test]
+    fn test_reresolve_into_banned_range() {
+        let (discovery, probe) = stub_manager(DiscoveryConfig {
+            banned_ranges: vec![IpRange {
+                addr: "10.0.0.0".parse().unwrap(),
+                prefix_len: 8,
+            }],
+            reresolve_interval: Some(Duration::from_secs(0)),
+            ..Default::default()
+        });
+        let resolver = Arc::new(StubResolver::default());
+        let discovery = discovery.with_resolver(resolver.clone());
+        let resolve_to = |ip: &str| Resolution {
+            cnames: vec![],
+            ips: vec![ip.parse().unwrap()],
+        };
+        resolver.set("electrum.example.com", resolve_to("1.1.1.1"));
+        discovery
+            .add_server_request(
+                "1.1.1.1".parse().unwrap(),
+                test_features(r#"{ "electrum.example.com": { "tcp_port": 50001 } }"#),
+            )
+            .unwrap();
+        probe.serve("tcp://1.1.1.1:50001", StubServer::new(test_features("{}")));
+        discovery.run_health_check(0).unwrap();
+        assert_eq!(discovery.get_servers().len(), 1);
+
+        // moving to an allowed IP is followed
+        resolver.set("electrum.example.com", resolve_to("1.1.1.2"));
+        discovery.reresolve_if_due();
+        let addr = ServerAddr::Clearnet("1.1.1.2".parse().unwrap());
+        assert_eq!(read_lock(&discovery.queue).peek().unwrap().addr, addr);
+        assert!(read_lock(&discovery.healthy).contains_key(&addr));
+
+        // moving into a banned range evicts the server
+        let mut events = discovery.subscribe();
+        resolver.set("electrum.example.com", resolve_to("10.1.1.1"));
+        discovery.reresolve_if_due();
+        assert!(read_lock(&discovery.queue).is_empty());
+        assert!(discovery.get_servers().is_empty());
+        assert!(matches!(
+            events.try_recv(),
+            Some(DiscoveryEvent::Dropped { hostname, .. }) if hostname == "electrum.example.com"
+        ));
+    }
+
+    #[test]
+    fn test_reresolve_into_local_addr() {
+        let (discovery, probe) = stub_manager(DiscoveryConfig {
+            reresolve_interval: Some(Duration::from_secs(0)),
+            reresolve_after_failures: Some(1),
+            ..Default::default()
+        });
+        let resolver = Arc::new(StubResolver::default());
+        let discovery = discovery.with_resolver(resolver.clone());
+        let resolve_to = |ip: &str| Resolution {
+            cnames: vec![],
+            ips: vec![ip.parse().unwrap()],
+        };
+        resolver.set("electrum.example.com", resolve_to("1.1.1.1"));
+        resolver.set("default.example.com", resolve_to("1.1.1.2"));
+        discovery
+            .add_server_request(
+                "1.1.1.1".parse().unwrap(),
+                test_features(r#"{ "electrum.example.com": { "tcp_port": 50001 } }"#),
+            )
+            .unwrap();
+        discovery
+            .add_default_server("default.example.com".into(), vec![Service::Tcp(50001)])
+            .unwrap();
+        probe.serve("tcp://1.1.1.1:50001", StubServer::new(test_features("{}")));
+        probe.serve(
+            "tcp://127.0.0.1:50001",
+            StubServer::new(test_features("{}")),
+        );
+
+        // failing default servers aren't moved to a local address
+        resolver.set("default.example.com", resolve_to("127.0.0.1"));
+        for _ in 0..2 {
+            discovery.run_health_check(0).ok();
+        }
+        let status = discovery.server_status();
+        let default = status.iter().find(|s| s.hostname == "default.example.com");
+        assert_eq!(default.unwrap().addr, "1.1.1.2");
+
+        // and queued servers get evicted, like those moving into a banned range
+        resolver.set("electrum.example.com", resolve_to("10.0.0.1"));
+        discovery.reresolve_if_due();
+        let status = discovery.server_status();
+        assert!(status.iter().all(|s| s.hostname != "electrum.example.com"));
+        assert!(discovery.get_servers().is_empty());
+    }
+}
@@ -0,0 +1,204 @@
+use std::fmt;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::electrum::discovery::{DiscoveryManager, ServerAddr, Socks5Proxy};
+use crate::electrum::Hostname;
+use crate::errors::{Result, ResultExt};
+use crate::util::socks;
+
+/// Resolves hostnames to IP addresses. Abstracted so that DNS can be substituted in tests.
+pub trait Resolver: fmt::Debug + Send + Sync {
+    fn resolve(&self, hostname: &str) -> Result<Resolution>;
+}
+
+/// The outcome of resolving a hostname
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Resolution {
+    /// The chain of aliases (CNAME records) followed, excluding the hostname itself. Empty if the
+    /// hostname is not an alias, or if the resolver can't tell.
+    pub cnames: Vec<Hostname>,
+    pub ips: Vec<IpAddr>,
+}
+
+impl Resolution {
+    /// The name the hostname finally resolved through, if it's an alias
+    pub fn canonical_name(&self) -> Option<&Hostname> {
+        self.cnames.last()
+    }
+}
+
+/// Resolves hostnames using the system resolver, which does not expose CNAME chains
+#[derive(Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, hostname: &str) -> Result<Resolution> {
+        let ips = format!("{}:1", hostname)
+            .to_socket_addrs()
+            .chain_err(|| "hostname resolution failed")?
+            .map(|addr| addr.ip())
+            .collect();
+        Ok(Resolution {
+            cnames: vec![],
+            ips,
+        })
+    }
+}
+
+/// Resolves hostnames through tor's SOCKS5 proxy, so that no DNS queries leave over clearnet in
+/// `tor_only` mode. Tor reports a single address per hostname, and no CNAME chains.
+#[derive(Debug)]
+pub(super) struct TorResolver {
+    pub proxy: Socks5Proxy,
+    pub timeout: Duration,
+}
+
+impl Resolver for TorResolver {
+    fn resolve(&self, hostname: &str) -> Result<Resolution> {
+        let credentials = self
+            .proxy
+            .credentials
+            .as_ref()
+            .map(|(username, password)| (username.as_str(), password.as_str()));
+        let ip = socks::resolve(self.proxy.addr, credentials, hostname, Some(self.timeout))
+            .chain_err(|| "hostname resolution failed")?;
+        Ok(Resolution {
+            cnames: vec![],
+            ips: vec![ip],
+        })
+    }
+}
+
+/// The IPs among the resolved addresses
+pub(super) fn clearnet_ips(addrs: &[ServerAddr]) -> Vec<IpAddr> {
+    addrs
+        .iter()
+        .filter_map(|addr| match addr {
+            ServerAddr::Clearnet(ip) => Some(*ip),
+            ServerAddr::Onion(_) => None,
+        })
+        .collect()
+}
+
+impl DiscoveryManager {
+    /// Resolve the hostname using our resolver, returning its first address along with the
+    /// canonical name it resolved through, see `resolve_all`
+    pub(super) fn resolve(&self, hostname: &str) -> Result<(ServerAddr, Option<Hostname>)> {
+        let (mut addrs, canonical_name) = self.resolve_all(hostname)?;
+        Ok((addrs.swap_remove(0), canonical_name))
+    }
+
+    /// Resolve the hostname using our resolver, returning all of its (A and AAAA) addresses along
+    /// with the canonical name it resolved through, if it's an alias. Rejects aliases unless
+    /// `accept_cname_hosts` is set. Onion hosts and IP addresses resolve to themselves.
+    pub(super) fn resolve_all(
+        &self,
+        hostname: &str,
+    ) -> Result<(Vec<ServerAddr>, Option<Hostname>)> {
+        let (addr, resolution) = ServerAddr::resolve_with(hostname, &*self.resolver)?;
+        let canonical_name = resolution.canonical_name().cloned();
+        if let Some(canonical_name) = &canonical_name {
+            debug!(
+                "{} resolved to {} via {} -> {}",
+                hostname,
+                addr,
+                hostname,
+                resolution.cnames.join(" -> ")
+            );
+            ensure!(
+                self.config.accept_cname_hosts,
+                "{} is an alias of {}, CNAME-fronted hosts are not accepted",
+                hostname,
+                canonical_name
+            );
+        }
+        let addrs = if resolution.ips.is_empty() {
+            vec![addr]
+        } else {
+            resolution
+                .ips
+                .into_iter()
+                .map(ServerAddr::Clearnet)
+                .collect()
+        };
+        Ok((addrs, canonical_name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::electrum::discovery::test_util::*;
+    use crate::electrum::discovery::*;
+
+    #[test]
+    fn test_cname_resolution() {
+        let resolution = Resolution {
+            cnames: vec!["lb.example.net".into(), "edge-1.cdn.example".into()],
+            ips: vec!["1.1.1.2".parse().unwrap(), "1.1.1.1".parse().unwrap()],
+        };
+        let resolver = Arc::new(StubResolver::default());
+        resolver.set("electrum.example.com", resolution);
+
+        let discovery = test_manager(DiscoveryConfig::default()).with_resolver(resolver.clone());
+        discovery
+            .add_default_server("electrum.example.com".into(), vec![Service::Tcp(50001)])
+            .unwrap();
+        let status = discovery.server_status();
+        assert_eq!(status.len(), 1);
+        assert_eq!(status[0].addr, "1.1.1.2");
+        assert_eq!(
+            status[0].canonical_name.as_deref(),
+            Some("edge-1.cdn.example")
+        );
+
+        // rejected when aliases are not accepted, while other hosts still resolve
+        let discovery = test_manager(DiscoveryConfig {
+            accept_cname_hosts: false,
+            ..Default::default()
+        })
+        .with_resolver(resolver);
+        assert!(discovery
+            .add_default_server("electrum.example.com".into(), vec![Service::Tcp(50001)])
+            .is_err());
+        discovery
+            .add_default_server("1.1.1.1".into(), vec![Service::Tcp(50001)])
+            .unwrap();
+        assert_eq!(discovery.server_status()[0].canonical_name, None);
+    }
+
+    #[test]
+    fn test_dual_stack_resolution() {
+        let ipv4: IpAddr = "1.1.1.1".parse().unwrap();
+        let ipv6: IpAddr = "2606:4700::1111".parse().unwrap();
+        let resolver = Arc::new(StubResolver::default());
+        resolver.set(
+            "electrum.example.com",
+            Resolution {
+                cnames: vec![],
+                ips: vec![ipv4, ipv6],
+            },
+        );
+        let discovery = test_manager(DiscoveryConfig::default()).with_resolver(resolver);
+        let features = test_features(r#"{"electrum.example.com": {"tcp_port": 50001}}"#);
+
+        // announced over ipv6, the second address, and checked over it
+        discovery
+            .add_server_request("1.0.0.1".parse().unwrap(), features.clone())
+            .unwrap();
+        assert!(discovery.server_status().is_empty());
+        discovery.add_server_request(ipv6, features).unwrap();
+        let status = discovery.server_status();
+        assert_eq!(status.len(), 1);
+        assert_eq!(status[0].addr, "2606:4700::1111");
+        assert_eq!(status[0].ips, vec![ipv4, ipv6]);
+
+        // bans cover both addresses
+        discovery
+            .ban_server("electrum.example.com", "test", None)
+            .unwrap();
+        assert!(discovery.server_status().is_empty());
+        assert!(discovery.active_ban(&ServerAddr::Clearnet(ipv4)).is_some());
+        assert!(discovery.active_ban(&ServerAddr::Clearnet(ipv6)).is_some());
+    }
+}
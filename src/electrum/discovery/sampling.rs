@@ -0,0 +1,114 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+use rand::Rng;
+
+use crate::electrum::discovery::{read_lock, DiscoveryManager, ServerAddr, ServerEntry};
+
+/// The weight servers with no reputation are sampled with, for them to still have a chance
+const MIN_SAMPLE_WEIGHT: f64 = 0.001;
+
+impl DiscoveryManager {
+    /// Pick `sample_size` of the servers at random, weighted by their reputation and latency, and
+    /// always including the default servers if `sample_include_defaults` is set. Diverse servers are
+    /// preferred if `sample_diversity` is set, see `pick_diverse`. The servers keep their relative
+    /// order.
+    pub(super) fn sample(&self, servers: Vec<ServerEntry>) -> Vec<ServerEntry> {
+        let size = match self.config.sample_size {
+            Some(size) if size < servers.len() => size,
+            _ => return servers,
+        };
+
+        let mut defaults = HashSet::new();
+        let mut latencies: HashMap<ServerAddr, f64> = HashMap::new();
+        for job in read_lock(&self.queue).iter() {
+            if job.is_default && self.config.sample_include_defaults {
+                defaults.insert(job.addr.clone());
+            }
+            if let Some(latency) = job.latency {
+                let secs = latency.duration.as_secs_f64();
+                let min = latencies.entry(job.addr.clone()).or_insert(secs);
+                *min = min.min(secs);
+            }
+        }
+
+        // weighted sampling without replacement, by picking the highest random keys u^(1/weight)
+        let healthy = read_lock(&self.healthy);
+        let mut rng = rand::thread_rng();
+        let mut keys = servers
+            .iter()
+            .enumerate()
+            .filter(|(_, ServerEntry(addr, _, _))| !defaults.contains(addr))
+            .map(|(index, ServerEntry(addr, _, _))| {
+                let weight = healthy
+                    .get(addr)
+                    .map_or(0.0, |server| self.server_weight(addr, server));
+                let latency = latencies.get(addr).copied().unwrap_or(0.0);
+                let weight = (weight / (1.0 + latency)).max(MIN_SAMPLE_WEIGHT);
+                (rng.gen::<f64>().powf(1.0 / weight), index)
+            })
+            .collect::<Vec<_>>();
+        drop(healthy);
+        keys.sort_by(|(a, _), (b, _)| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+
+        let sampled_defaults = servers.len() - keys.len();
+        let count = size.saturating_sub(sampled_defaults);
+        let ranked = keys.into_iter().map(|(_, index)| index);
+        let picked = if self.config.sample_diversity {
+            self.pick_diverse(&servers, ranked.collect(), &defaults, count)
+        } else {
+            ranked.take(count).collect::<HashSet<_>>()
+        };
+        servers
+            .into_iter()
+            .enumerate()
+            .filter(|(index, ServerEntry(addr, _, _))| {
+                picked.contains(index) || defaults.contains(addr)
+            })
+            .map(|(_, entry)| entry)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::electrum::discovery::test_util::*;
+    use crate::electrum::discovery::*;
+
+    #[test]
+    fn test_sampling() {
+        let (discovery, _) = stub_manager(DiscoveryConfig {
+            sample_size: Some(2),
+            sample_include_defaults: true,
+            ..Default::default()
+        });
+        discovery
+            .add_default_server("1.1.1.1".into(), vec![Service::Tcp(50001)])
+            .unwrap();
+        for ip in &["1.1.1.1", "1.1.1.2", "1.1.1.3"] {
+            add_healthy(&discovery, ip, Service::Tcp(50001));
+        }
+        let now = SystemTime::now();
+        for _ in 0..100 {
+            discovery.record_reputation(&ServerAddr::resolve("1.1.1.2").unwrap(), now);
+        }
+        discovery.record_reputation(&ServerAddr::resolve("1.1.1.3").unwrap(), now);
+
+        // the default server is always included, the heavier server is picked more often
+        let mut picked = HashMap::new();
+        for _ in 0..200 {
+            let hostnames = discovery
+                .get_servers()
+                .into_iter()
+                .map(|ServerEntry(_, hostname, _)| hostname)
+                .collect::<Vec<_>>();
+            assert_eq!(hostnames.len(), 2);
+            assert!(hostnames.contains(&"1.1.1.1".to_string()));
+            for hostname in hostnames {
+                *picked.entry(hostname).or_insert(0) += 1;
+            }
+        }
+        assert!(picked["1.1.1.2"] > 150);
+        assert!(picked.get("1.1.1.3").map_or(0, |n| *n) < 50);
+    }
+}
@@ -0,0 +1,109 @@
+use std::time::Duration;
+
+use crate::electrum::discovery::{DiscoveryManager, HealthCheck};
+
+const FAILURE_RATE_ALPHA: f64 = 0.1; // weight of the latest check in the failure rate moving average
+
+/// Performance metrics of a single service, accumulated over its health checks
+#[derive(Serialize, Default, Copy, Clone, Debug)]
+pub struct ServiceMetrics {
+    /// The time taken to connect on the last successful health check
+    pub connect_time: Option<Duration>,
+    /// The time taken to respond to `server.features` on the last successful health check
+    pub handshake_time: Option<Duration>,
+    /// How many blocks the server's tip was behind ours on the last successful health check, if
+    /// checked (see `max_height_lag`)
+    pub height_lag: Option<usize>,
+    /// Exponential moving average of the health check failures, between 0 and 1
+    pub failure_rate: f64,
+    /// The number of health checks the metrics were accumulated over
+    pub checks: usize,
+}
+
+impl ServiceMetrics {
+    pub(super) fn record_success(
+        &mut self,
+        connect_time: Duration,
+        handshake_time: Duration,
+        height_lag: Option<usize>,
+    ) {
+        self.connect_time = Some(connect_time);
+        self.handshake_time = Some(handshake_time);
+        self.height_lag = height_lag;
+        self.record(false);
+    }
+
+    pub(super) fn record_failure(&mut self) {
+        self.record(true);
+    }
+
+    fn record(&mut self, failed: bool) {
+        let sample = if failed { 1.0 } else { 0.0 };
+        self.failure_rate += FAILURE_RATE_ALPHA * (sample - self.failure_rate);
+        self.checks += 1;
+    }
+
+    /// A score between 0 and 1, decreasing with the failure rate and the time taken to connect
+    /// and respond (halved at one second)
+    pub fn score(&self) -> f64 {
+        let response_time =
+            self.connect_time.unwrap_or_default() + self.handshake_time.unwrap_or_default();
+        (1.0 - self.failure_rate) / (1.0 + response_time.as_secs_f64())
+    }
+}
+
+impl DiscoveryManager {
+    /// Whether the service scored below `min_score` over at least `min_score_checks` health checks.
+    /// Default and pinned servers are never considered poor.
+    pub(super) fn is_poor(&self, job: &HealthCheck) -> bool {
+        !job.is_default
+            && !job.is_pinned
+            && job.metrics.checks >= self.config.min_score_checks
+            && self
+                .config
+                .min_score
+                .map_or(false, |min_score| job.metrics.score() < min_score)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::electrum::discovery::test_util::*;
+    use crate::electrum::discovery::*;
+
+    #[test]
+    fn test_min_score() {
+        let (discovery, probe) = stub_manager(DiscoveryConfig {
+            min_score: Some(0.75),
+            min_score_checks: 3,
+            ..Default::default()
+        });
+        probe.serve("tcp://1.1.1.1:50001", StubServer::new(test_features("{}")));
+        discovery
+            .add_server_request(
+                "1.1.1.1".parse().unwrap(),
+                test_features(r#"{"1.1.1.1":{"tcp_port":50001}}"#),
+            )
+            .unwrap();
+        discovery.run_health_check(0).unwrap();
+        let status = &discovery.server_status()[0];
+        assert_eq!(status.metrics.checks, 1);
+        assert_eq!(status.metrics.failure_rate, 0.0);
+        assert!(status.metrics.connect_time.is_some() && status.metrics.handshake_time.is_some());
+        assert!(status.score > 0.9);
+
+        // the failure rate goes 0.1, 0.19, 0.271, falling below the minimum score on the 3rd
+        // failure, well before MAX_CONSECUTIVE_FAILURES
+        let mut server = StubServer::new(test_features("{}"));
+        server.connect_error = Some(std::io::ErrorKind::ConnectionRefused);
+        probe.serve("tcp://1.1.1.1:50001", server);
+        for _ in 0..2 {
+            discovery.run_health_check(0).unwrap_err();
+            assert_eq!(read_lock(&discovery.queue).len(), 1);
+        }
+        let status = &discovery.server_status()[0];
+        assert!((status.metrics.failure_rate - 0.19).abs() < 1e-9);
+        discovery.run_health_check(0).unwrap_err();
+        assert!(read_lock(&discovery.queue).is_empty());
+    }
+}
@@ -0,0 +1,148 @@
+use std::time::{Duration, Instant};
+
+use error_chain::ChainedError;
+
+use crate::electrum::discovery::{DiscoveryManager, Service};
+use crate::electrum::Hostname;
+use crate::errors::Result;
+
+/// A stage of the discovery pipeline, as exercised by `DiscoveryManager::self_test`
+#[derive(Serialize, Eq, PartialEq, Copy, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SelfTestStage {
+    Resolve,
+    Connect,
+    Features,
+    Compatibility,
+}
+
+/// The outcome of a single self-test stage
+#[derive(Serialize, Debug)]
+pub struct StageReport {
+    pub stage: SelfTestStage,
+    pub duration: Duration,
+    /// None if the stage passed
+    pub error: Option<String>,
+}
+
+/// The outcome of a self-test. Stages are run in order up to the first failing one.
+#[derive(Serialize, Debug)]
+pub struct SelfTestReport {
+    /// The server tested against, None if no `self_test_server` is configured
+    pub server: Option<String>,
+    pub stages: Vec<StageReport>,
+}
+
+impl SelfTestReport {
+    /// Whether all stages ran and passed
+    pub fn passed(&self) -> bool {
+        self.stages.last().map_or(false, |last| {
+            last.stage == SelfTestStage::Compatibility && last.error.is_none()
+        })
+    }
+
+    fn run<T>(&mut self, stage: SelfTestStage, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        let start = Instant::now();
+        let result = f();
+        self.stages.push(StageReport {
+            stage,
+            duration: start.elapsed(),
+            error: result.as_ref().err().map(|e| e.display_chain().to_string()),
+        });
+        result
+    }
+}
+
+impl DiscoveryManager {
+    /// Exercise the discovery pipeline end-to-end against the configured `self_test_server`,
+    /// reporting the outcome and timing of each stage
+    pub fn self_test(&self) -> SelfTestReport {
+        let (hostname, service) = match self.config.self_test_server {
+            Some((ref hostname, service)) => (hostname, service),
+            None => {
+                return SelfTestReport {
+                    server: None,
+                    stages: vec![],
+                }
+            }
+        };
+        let mut report = SelfTestReport {
+            server: Some(format!("{} {}", hostname, service)),
+            stages: vec![],
+        };
+        // failures are recorded in the report
+        self.run_self_test(hostname, service, &mut report).ok();
+        report
+    }
+
+    fn run_self_test(
+        &self,
+        hostname: &Hostname,
+        service: Service,
+        report: &mut SelfTestReport,
+    ) -> Result<()> {
+        let (addr, _) = report.run(SelfTestStage::Resolve, || self.resolve(hostname))?;
+        let deadline = self.check_deadline(Instant::now());
+        let (client, _) = report.run(SelfTestStage::Connect, || {
+            self.connect(&addr, hostname, service, deadline)
+        })?;
+        let features = report.run(SelfTestStage::Features, || client.features())?;
+        report.run(SelfTestStage::Compatibility, || {
+            self.verify_compatibility(&features, self.our_network)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::electrum::discovery::test_util::*;
+    use crate::electrum::discovery::*;
+
+    #[test]
+    fn test_self_test() {
+        let (discovery, probe) = stub_manager(DiscoveryConfig {
+            self_test_server: Some(("1.1.1.1".into(), Service::Tcp(50001))),
+            ..Default::default()
+        });
+        let stages = |report: &SelfTestReport| {
+            report
+                .stages
+                .iter()
+                .map(|stage| (stage.stage, stage.error.is_none()))
+                .collect::<Vec<_>>()
+        };
+
+        // the connection is refused before the server is served
+        let report = discovery.self_test();
+        assert!(!report.passed());
+        assert_eq!(
+            stages(&report),
+            vec![
+                (SelfTestStage::Resolve, true),
+                (SelfTestStage::Connect, false)
+            ]
+        );
+
+        let mut features = test_features("{}");
+        features.hash_function = "sha512".into();
+        probe.serve("tcp://1.1.1.1:50001", StubServer::new(features));
+        let report = discovery.self_test();
+        assert!(!report.passed());
+        assert_eq!(
+            stages(&report),
+            vec![
+                (SelfTestStage::Resolve, true),
+                (SelfTestStage::Connect, true),
+                (SelfTestStage::Features, true),
+                (SelfTestStage::Compatibility, false),
+            ]
+        );
+        let error = report.stages[3].error.as_ref().unwrap();
+        assert!(error.contains("incompatible hash function"));
+
+        probe.serve("tcp://1.1.1.1:50001", StubServer::new(test_features("{}")));
+        let report = discovery.self_test();
+        assert!(report.passed());
+        assert_eq!(report.stages.len(), 4);
+    }
+}
@@ -0,0 +1,110 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::electrum::discovery::{lock, DiscoveryManager};
+use crate::errors::{Result, ResultExt};
+
+const SNAPSHOT_PREFIX: &str = "servers-";
+const SNAPSHOT_SUFFIX: &str = ".json";
+
+impl DiscoveryManager {
+    /// Write a snapshot of the advertised server list if one is configured and due
+    pub(super) fn write_snapshot_if_due(&self) -> Result<()> {
+        let dir = match self.config.snapshot_dir {
+            Some(ref dir) => dir,
+            None => return Ok(()),
+        };
+        {
+            let mut last_snapshot = lock(&self.last_snapshot);
+            if last_snapshot.map_or(false, |t| t.elapsed() < self.config.snapshot_interval) {
+                return Ok(());
+            }
+            *last_snapshot = Some(Instant::now());
+        }
+        let path = self.write_snapshot(dir, SystemTime::now())?;
+        debug!("wrote server list snapshot to {:?}", path);
+        prune_snapshots(dir, self.config.snapshot_retain)
+    }
+
+    /// Write the `get_servers()` output to a file named after the given timestamp
+    pub(super) fn write_snapshot(&self, dir: &Path, time: SystemTime) -> Result<PathBuf> {
+        let millis = time
+            .duration_since(UNIX_EPOCH)
+            .chain_err(|| "system time before unix epoch")?
+            .as_millis();
+        // zero-padded so that lexicographic ordering matches chronological ordering
+        let path = dir.join(format!(
+            "{}{:015}{}",
+            SNAPSHOT_PREFIX, millis, SNAPSHOT_SUFFIX
+        ));
+        let contents = serde_json::to_string_pretty(&self.get_servers())
+            .chain_err(|| "failed serializing server list")?;
+        fs::create_dir_all(dir).chain_err(|| format!("failed creating {:?}", dir))?;
+        fs::write(&path, contents).chain_err(|| format!("failed writing {:?}", path))?;
+        Ok(path)
+    }
+}
+
+/// Delete all but the `retain` most recent snapshots in `dir`
+pub(super) fn prune_snapshots(dir: &Path, retain: usize) -> Result<()> {
+    let mut snapshots = fs::read_dir(dir)
+        .chain_err(|| format!("failed reading {:?}", dir))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .map_or(false, |name| {
+                    name.starts_with(SNAPSHOT_PREFIX) && name.ends_with(SNAPSHOT_SUFFIX)
+                })
+        })
+        .collect::<Vec<_>>();
+    snapshots.sort();
+
+    let excess = snapshots.len().saturating_sub(retain);
+    for path in snapshots.into_iter().take(excess) {
+        fs::remove_file(&path).chain_err(|| format!("failed removing {:?}", path))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::electrum::discovery::test_util::*;
+    use crate::electrum::discovery::*;
+
+    #[test]
+    fn test_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let discovery = test_manager(DiscoveryConfig {
+            snapshot_dir: Some(dir.path().to_path_buf()),
+            snapshot_retain: 2,
+            ..Default::default()
+        });
+        add_healthy(&discovery, "1.1.1.1", Service::Tcp(50001));
+        add_healthy(&discovery, ONION_HOSTS[0], Service::Ssl(50002));
+
+        let start = std::time::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let paths = (0..3)
+            .map(|i| {
+                discovery
+                    .write_snapshot(dir.path(), start + Duration::from_secs(60 * i))
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let contents: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&paths[2]).unwrap()).unwrap();
+        assert_eq!(contents, json!(discovery.get_servers()));
+        assert_eq!(contents.as_array().unwrap().len(), 2);
+
+        super::prune_snapshots(dir.path(), 2).unwrap();
+        assert!(!paths[0].exists());
+        assert!(paths[1].exists() && paths[2].exists());
+
+        // the periodic job writes a new snapshot and prunes down to the retention limit
+        discovery.write_snapshot_if_due().unwrap();
+        assert!(!paths[1].exists());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+}
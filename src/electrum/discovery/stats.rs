@@ -0,0 +1,244 @@
+use std::fmt;
+use std::time::Duration;
+
+use crate::electrum::discovery::{lock, read_lock, DiscoveryManager, HealthCheck, Latency};
+use crate::metrics::{
+    CounterVec, Gauge, GaugeVec, HistogramOpts, HistogramVec, MetricOpts, Metrics,
+};
+
+/// Cumulative time spent by the health check workers, as returned by `stats`. Comparing the busy
+/// and idle time tells whether discovery could use more workers.
+#[derive(Serialize, Default, Clone, Debug)]
+pub struct DiscoveryStats {
+    /// Time spent running health checks that passed
+    pub healthy_time: Duration,
+    /// Time spent running health checks that failed
+    pub unhealthy_time: Duration,
+    /// Time spent sleeping between health checks
+    pub idle_time: Duration,
+}
+
+/// The prometheus metrics discovery is exported as
+pub(super) struct DiscoveryMetrics {
+    /// The time budget, by state
+    pub(super) time: GaugeVec,
+    pub(super) queue_size: Gauge,
+    pub(super) healthy_servers: Gauge,
+    /// Health checks run, by result
+    pub(super) checks: CounterVec,
+    /// `server.add_peer` requests, by `RequestOutcome`
+    pub(super) requests: CounterVec,
+    /// Latency of the healthy services, by scheme
+    pub(super) latency: HistogramVec,
+}
+
+/// What came of a `server.add_peer` request
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum RequestOutcome {
+    /// Some of its services were queued for health checks
+    Accepted,
+    /// It was valid, but all of its servers were skipped or already queued
+    Ignored,
+    /// It failed, like for exceeding the quotas or being incompatible
+    Rejected,
+}
+
+impl RequestOutcome {
+    fn label(self) -> &'static str {
+        match self {
+            RequestOutcome::Accepted => "accepted",
+            RequestOutcome::Ignored => "ignored",
+            RequestOutcome::Rejected => "rejected",
+        }
+    }
+}
+
+impl fmt::Debug for DiscoveryMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DiscoveryMetrics")
+    }
+}
+
+impl DiscoveryManager {
+    /// Export the time budget, queue and health check statistics as `electrum_discovery_*`
+    /// prometheus metrics
+    pub fn with_metrics(mut self, metrics: &Metrics) -> Self {
+        self.metrics = Some(DiscoveryMetrics {
+            time: metrics.gauge_vec(
+                MetricOpts::new(
+                    "electrum_discovery_time",
+                    "Time spent by discovery workers (seconds), by state",
+                ),
+                &["state"],
+            ),
+            queue_size: metrics.gauge(MetricOpts::new(
+                "electrum_discovery_queue_size",
+                "# of services queued for health checks",
+            )),
+            healthy_servers: metrics.gauge(MetricOpts::new(
+                "electrum_discovery_healthy_servers",
+                "# of healthy servers",
+            )),
+            checks: metrics.counter_vec(
+                MetricOpts::new(
+                    "electrum_discovery_checks",
+                    "# of health checks run, by result",
+                ),
+                &["result"],
+            ),
+            requests: metrics.counter_vec(
+                MetricOpts::new(
+                    "electrum_discovery_requests",
+                    "# of server.add_peer requests, by result",
+                ),
+                &["result"],
+            ),
+            latency: metrics.histogram_vec(
+                HistogramOpts::new(
+                    "electrum_discovery_latency",
+                    "Latency of healthy services (seconds), by scheme",
+                ),
+                &["scheme"],
+            ),
+        });
+        self
+    }
+
+    /// Get the cumulative time spent by the health check workers
+    pub fn stats(&self) -> DiscoveryStats {
+        lock(&self.stats).clone()
+    }
+
+    pub(super) fn record_check_time(&self, healthy: bool, elapsed: Duration) {
+        let mut stats = lock(&self.stats);
+        let (state, total) = if healthy {
+            ("healthy", &mut stats.healthy_time)
+        } else {
+            ("unhealthy", &mut stats.unhealthy_time)
+        };
+        *total += elapsed;
+        self.set_time_metric(state, *total);
+    }
+
+    pub(super) fn record_idle_time(&self, elapsed: Duration) {
+        let mut stats = lock(&self.stats);
+        stats.idle_time += elapsed;
+        self.set_time_metric("idle", stats.idle_time);
+    }
+
+    fn set_time_metric(&self, state: &str, total: Duration) {
+        if let Some(metrics) = &self.metrics {
+            metrics
+                .time
+                .with_label_values(&[state])
+                .set(total.as_secs_f64());
+        }
+    }
+
+    /// Count a health check, along with the latency measured if it passed
+    pub(super) fn record_check_metrics(&self, job: &HealthCheck, latency: Option<Latency>) {
+        if let Some(metrics) = &self.metrics {
+            let result = if latency.is_some() { "ok" } else { "failed" };
+            metrics.checks.with_label_values(&[result]).inc();
+            if let Some(latency) = latency {
+                metrics
+                    .latency
+                    .with_label_values(&[job.service.scheme()])
+                    .observe(latency.duration.as_secs_f64());
+            }
+        }
+    }
+
+    /// Count a `server.add_peer` request
+    pub(super) fn record_request_metrics(&self, outcome: RequestOutcome) {
+        if let Some(metrics) = &self.metrics {
+            metrics.requests.with_label_values(&[outcome.label()]).inc();
+        }
+    }
+
+    /// Bring the queue size and healthy server count gauges up to date
+    pub(super) fn update_gauges(&self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.queue_size.set(read_lock(&self.queue).len() as i64);
+            metrics
+                .healthy_servers
+                .set(read_lock(&self.healthy).len() as i64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::electrum::discovery::test_util::*;
+    use crate::electrum::discovery::*;
+
+    #[test]
+    fn test_metrics() {
+        use crate::metrics::{CounterVec, Metrics};
+
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let (discovery, probe) = stub_manager(DiscoveryConfig {
+            max_requests_per_ip: Some(1),
+            ..Default::default()
+        });
+        let discovery = discovery.with_metrics(&metrics);
+        probe.serve("tcp://1.1.1.1:50001", StubServer::new(test_features("{}")));
+        let hosts = r#"{"1.1.1.1":{"tcp_port":50001},"1.1.1.2":{"tcp_port":50001}}"#;
+        discovery
+            .add_server_request("1.1.1.1".parse().unwrap(), test_features(hosts))
+            .unwrap();
+        discovery
+            .add_server_request("1.1.1.1".parse().unwrap(), test_features(hosts))
+            .unwrap_err();
+        // only advertising another host, so nothing gets queued
+        let hosts = r#"{"1.1.1.4":{"tcp_port":50001}}"#;
+        discovery
+            .add_server_request("1.1.1.2".parse().unwrap(), test_features(hosts))
+            .unwrap();
+        discovery
+            .add_default_server("1.1.1.3".into(), vec![Service::Tcp(50001)])
+            .unwrap();
+        let passed = (0..2)
+            .filter(|_| discovery.run_health_check(0).is_ok())
+            .count();
+        assert_eq!(passed, 1);
+        discovery.update_gauges();
+
+        let metrics = discovery.metrics.as_ref().unwrap();
+        let count = |counter: &CounterVec, label| counter.with_label_values(&[label]).get();
+        assert_eq!(count(&metrics.requests, "accepted"), 1);
+        assert_eq!(count(&metrics.requests, "rejected"), 1);
+        assert_eq!(count(&metrics.requests, "ignored"), 1);
+        assert_eq!(count(&metrics.checks, "ok"), 1);
+        assert_eq!(count(&metrics.checks, "failed"), 1);
+        let latency = metrics.latency.with_label_values(&["tcp"]);
+        assert_eq!(latency.get_sample_count(), 1);
+        // the unreachable default server stays queued to be retried
+        assert_eq!(metrics.queue_size.get(), 2);
+        assert_eq!(metrics.healthy_servers.get(), 1);
+    }
+
+    #[test]
+    fn test_time_stats() {
+        let (discovery, probe) = stub_manager(DiscoveryConfig::default());
+        probe.serve("tcp://1.1.1.1:50001", StubServer::new(test_features("{}")));
+        for ip in &["1.1.1.1", "1.1.1.2"] {
+            discovery
+                .add_default_server(ip.to_string(), vec![Service::Tcp(50001)])
+                .unwrap();
+        }
+        let stats = discovery.stats();
+        assert_eq!(stats.healthy_time, Duration::from_secs(0));
+        assert_eq!(stats.unhealthy_time, Duration::from_secs(0));
+
+        for _ in 0..2 {
+            discovery.run_health_check(0).ok();
+        }
+        let stats = discovery.stats();
+        assert!(stats.healthy_time > Duration::from_secs(0));
+        assert!(stats.unhealthy_time > Duration::from_secs(0));
+
+        discovery.record_idle_time(Duration::from_secs(1));
+        assert_eq!(discovery.stats().idle_time, Duration::from_secs(1));
+    }
+}
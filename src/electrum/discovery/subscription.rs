@@ -0,0 +1,89 @@
+use serde_json::Value;
+
+use crate::electrum::discovery::{DiscoveryManager, ProbeClient};
+
+/// The scripthash subscribed to when verifying subscription support. Its history doesn't matter,
+/// only that the server acknowledges the subscription with a well-formed status.
+const PROBE_SCRIPTHASH: &str = "8b01df4e368ea28f8dc0423bcf7a4923e3a12d307c875e47a0cfbf90b5c39161";
+
+impl DiscoveryManager {
+    /// Verify that the server handles `blockchain.scripthash.subscribe`, by checking that it responds
+    /// with a well-formed status (null or a hex-encoded hash). None if `verify_subscriptions` is unset.
+    pub(super) fn verify_subscriptions(&self, client: &dyn ProbeClient) -> Option<bool> {
+        if !self.config.verify_subscriptions {
+            return None;
+        }
+        let status = client.call(
+            "blockchain.scripthash.subscribe",
+            &[json!(PROBE_SCRIPTHASH)],
+        );
+        Some(match status {
+            Ok(Value::Null) => true,
+            Ok(Value::String(status)) => {
+                status.len() == 64 && status.chars().all(|c| c.is_ascii_hexdigit())
+            }
+            Ok(status) => {
+                debug!("invalid scripthash status: {}", status);
+                false
+            }
+            Err(e) => {
+                debug!("scripthash subscription failed: {:?}", e);
+                false
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::electrum::discovery::test_util::*;
+    use crate::electrum::discovery::*;
+
+    #[test]
+    fn test_verify_subscriptions() {
+        let (discovery, probe) = stub_manager(DiscoveryConfig {
+            verify_subscriptions: true,
+            ..Default::default()
+        });
+        let server = StubServer::new(test_features("{}"));
+        let status = "a5e3ac8c3a35a8e3f4c1cf1c6c2aa9b9cf4d50d5e02c8e0e2e1ba2bd1e80b1d4";
+        let subscribe = "blockchain.scripthash.subscribe";
+        probe.serve(
+            "tcp://1.1.1.1:50001",
+            server.clone().respond(subscribe, json!(null)),
+        );
+        probe.serve(
+            "tcp://1.1.1.2:50001",
+            server.clone().respond(subscribe, json!(status)),
+        );
+        probe.serve(
+            "tcp://1.1.1.3:50001",
+            server.clone().respond(subscribe, json!([])),
+        );
+        probe.serve("tcp://1.1.1.4:50001", server);
+        for ip in &["1.1.1.1", "1.1.1.2", "1.1.1.3", "1.1.1.4"] {
+            discovery
+                .add_default_server(ip.to_string(), vec![Service::Tcp(50001)])
+                .unwrap();
+        }
+        for _ in 0..4 {
+            discovery.run_health_check(0).unwrap();
+        }
+
+        // servers with broken subscriptions remain healthy, but are flagged and not listed
+        let healthy = read_lock(&discovery.healthy);
+        let subscriptions = |ip: &str| healthy[&ServerAddr::resolve(ip).unwrap()].subscriptions;
+        assert_eq!(subscriptions("1.1.1.1"), Some(true));
+        assert_eq!(subscriptions("1.1.1.2"), Some(true));
+        assert_eq!(subscriptions("1.1.1.3"), Some(false));
+        assert_eq!(subscriptions("1.1.1.4"), Some(false));
+        drop(healthy);
+        let mut listed = discovery
+            .get_servers()
+            .into_iter()
+            .map(|ServerEntry(_, hostname, _)| hostname)
+            .collect::<Vec<_>>();
+        listed.sort();
+        assert_eq!(listed, vec!["1.1.1.1", "1.1.1.2"]);
+    }
+}
@@ -0,0 +1,217 @@
+//! Test doubles shared by the tests of the discovery modules: stub servers served by a stub
+//! probe, and managers probing through it.
+
+use rustls::TLSError;
+
+use crate::electrum::discovery::*;
+
+pub const PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion::new(1, 4);
+
+pub const ONION_HOSTS: [&str; 3] = [
+    "aeaqcaibaeaqcaibaeaqcaibaeaqcaibaeaqcaibaeaqcaibaea37ead.onion",
+    "aibaeaqcaibaeaqcaibaeaqcaibaeaqcaibaeaqcaibaeaqcaibejsqd.onion",
+    "ambqgaydambqgaydambqgaydambqgaydambqgaydambqgaydambqoyyd.onion",
+];
+
+pub fn test_features(hosts: &str) -> ServerFeatures {
+    ServerFeatures {
+        hosts: serde_json::from_str(hosts).unwrap(),
+        server_version: "electrs-esplora 9".into(),
+        genesis_hash: genesis_hash(Network::Regtest),
+        protocol_min: PROTOCOL_VERSION,
+        protocol_max: PROTOCOL_VERSION,
+        hash_function: "sha256".into(),
+        pruning: None,
+    }
+}
+
+/// A stub server, as served by `StubProbe`
+#[derive(Clone, Debug)]
+pub struct StubServer {
+    pub features: ServerFeatures,
+    /// Fail connecting with this error
+    pub connect_error: Option<std::io::ErrorKind>,
+    /// The TLS version negotiated with this server
+    pub tls_version: Option<TlsVersion>,
+    /// Fail connecting with this error when verifying the certificate
+    pub cert_error: Option<webpki::Error>,
+    /// How long connecting, the handshakes that follow and responding to `server.features` take
+    pub connect_delay: Duration,
+    pub handshake_delay: Duration,
+    pub features_delay: Duration,
+    /// The timeout the connection was opened with
+    pub timeout: Option<Duration>,
+    /// Shared by all open connections to this server, to tell whether they were closed
+    pub open: Arc<()>,
+    /// Responses to raw calls, by method
+    pub responses: HashMap<String, serde_json::Value>,
+    /// A log of the raw calls and `server.add_peer` registrations made
+    pub calls: Arc<Mutex<Vec<(String, Vec<serde_json::Value>)>>>,
+}
+
+impl StubServer {
+    pub fn new(features: ServerFeatures) -> Self {
+        StubServer {
+            features,
+            connect_error: None,
+            tls_version: None,
+            cert_error: None,
+            connect_delay: Duration::from_secs(0),
+            handshake_delay: Duration::from_secs(0),
+            features_delay: Duration::from_secs(0),
+            timeout: None,
+            open: Arc::new(()),
+            responses: HashMap::new(),
+            calls: Default::default(),
+        }
+    }
+
+    pub fn respond(mut self, method: &str, response: serde_json::Value) -> Self {
+        self.responses.insert(method.into(), response);
+        self
+    }
+}
+
+impl ProbeClient for StubServer {
+    fn features(&self) -> Result<ServerFeatures> {
+        if let Some(timeout) = self.timeout.filter(|t| *t < self.features_delay) {
+            thread::sleep(timeout);
+            let timed_out = std::io::Error::from(std::io::ErrorKind::TimedOut);
+            return Err(Error::with_chain(timed_out, "no reply"));
+        }
+        thread::sleep(self.features_delay);
+        Ok(self.features.clone())
+    }
+
+    fn add_peer(&self, features: &ServerFeatures) -> Result<bool> {
+        let mut calls = self.calls.lock().unwrap();
+        calls.push(("server.add_peer".into(), vec![json!(features)]));
+        Ok(true)
+    }
+
+    fn tls_version(&self) -> Option<TlsVersion> {
+        self.tls_version
+    }
+
+    fn tcp_connect_time(&self) -> Option<Duration> {
+        Some(self.connect_delay)
+    }
+
+    fn call(&self, method: &str, params: &[serde_json::Value]) -> Result<serde_json::Value> {
+        let mut calls = self.calls.lock().unwrap();
+        calls.push((method.into(), params.to_vec()));
+        let response = self.responses.get(method).cloned();
+        Ok(response.chain_err(|| format!("unknown method {}", method))?)
+    }
+}
+
+/// A probe serving stub servers by url, refusing connections to any other url
+#[derive(Debug, Default)]
+pub struct StubProbe {
+    pub servers: Mutex<HashMap<String, StubServer>>,
+    /// A log of the urls connected to, with the proxy used
+    pub connections: Mutex<Vec<(String, Option<Socks5Proxy>)>>,
+    /// Whether the certificate was verified on the last connection to each url
+    pub verified: Mutex<HashMap<String, bool>>,
+}
+
+impl StubProbe {
+    pub fn serve(&self, url: &str, server: StubServer) {
+        self.servers.lock().unwrap().insert(url.into(), server);
+    }
+}
+
+impl Probe for StubProbe {
+    fn connect(
+        &self,
+        url: &str,
+        socks5: Option<&Socks5Proxy>,
+        timeout: Option<Duration>,
+        _deadline: Option<Instant>,
+        verify_certificate: bool,
+    ) -> Result<Box<dyn ProbeClient>> {
+        let mut connections = self.connections.lock().unwrap();
+        connections.push((url.into(), socks5.cloned()));
+        lock(&self.verified).insert(url.into(), verify_certificate);
+        let server = self.servers.lock().unwrap().get(url).cloned();
+        let mut server = server.chain_err(|| "connection refused")?;
+        server.timeout = timeout;
+        if let Some(kind) = server.connect_error {
+            return Err(Error::with_chain(
+                std::io::Error::from(kind),
+                "connect failed",
+            ));
+        }
+        if let Some(e) = server.cert_error.filter(|_| verify_certificate) {
+            let e = std::io::Error::new(std::io::ErrorKind::InvalidData, TLSError::WebPKIError(e));
+            return Err(Error::with_chain(e, "TLS handshake failed"));
+        }
+        thread::sleep(server.connect_delay + server.handshake_delay);
+        Ok(Box::new(server))
+    }
+}
+
+pub fn stub_manager(config: DiscoveryConfig) -> (DiscoveryManager, Arc<StubProbe>) {
+    stub_manager_for(Network::Regtest, config)
+}
+
+pub fn add_healthy(discovery: &DiscoveryManager, hostname: &str, service: Service) {
+    let addr = ServerAddr::resolve(hostname).unwrap();
+    let job = HealthCheck::new(addr, hostname.into(), service, Network::Regtest, None);
+    let result = CheckResult {
+        features: test_features("{}"),
+        clock_skew: None,
+        tls_version: None,
+        cert_kind: None,
+        subscriptions: None,
+        latency: LatencyMethod::RoundTrip.measure(Duration::from_secs(0), Duration::from_secs(0)),
+        connect_time: Duration::from_secs(0),
+        handshake_time: Duration::from_secs(0),
+        height_lag: None,
+        reciprocates: false,
+    };
+    discovery.save_healthy_service(&job, result);
+}
+
+pub fn stub_manager_for(
+    network: Network,
+    config: DiscoveryConfig,
+) -> (DiscoveryManager, Arc<StubProbe>) {
+    let probe = Arc::new(StubProbe::default());
+    let config = DiscoveryConfig {
+        health_check_freq: Duration::from_secs(0),
+        ..config
+    };
+    let mut features = test_features("{}");
+    features.genesis_hash = genesis_hash(network);
+    let discovery = DiscoveryManager::new(network, features, PROTOCOL_VERSION, false, None, config);
+    (discovery.with_probe(probe.clone()), probe)
+}
+
+pub fn test_manager(config: DiscoveryConfig) -> DiscoveryManager {
+    DiscoveryManager::new(
+        Network::Regtest,
+        test_features("{}"),
+        PROTOCOL_VERSION,
+        false,
+        None,
+        config,
+    )
+}
+
+/// A resolver serving resolutions by hostname
+#[derive(Debug, Default)]
+pub struct StubResolver(Mutex<HashMap<String, Resolution>>);
+
+impl StubResolver {
+    pub fn set(&self, hostname: &str, resolution: Resolution) {
+        self.0.lock().unwrap().insert(hostname.into(), resolution);
+    }
+}
+
+impl Resolver for StubResolver {
+    fn resolve(&self, hostname: &str) -> Result<Resolution> {
+        let resolution = self.0.lock().unwrap().get(hostname).cloned();
+        Ok(resolution.chain_err(|| "NXDOMAIN")?)
+    }
+}
@@ -0,0 +1,102 @@
+use std::collections::BinaryHeap;
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use crate::electrum::discovery::{lock, write_lock, DiscoveryManager, Transport};
+
+const TOR_PROBE_INTERVAL: Duration = Duration::from_secs(60);
+const TOR_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl DiscoveryManager {
+    /// Probe the tor proxy for reachability if it's due, see `set_tor_reachable`
+    pub(super) fn probe_tor_proxy_if_due(&self) {
+        let proxy = match self.tor_proxy {
+            Some(proxy) => proxy,
+            None => return,
+        };
+        {
+            let mut last_tor_probe = lock(&self.last_tor_probe);
+            if last_tor_probe.map_or(false, |t| t.elapsed() < TOR_PROBE_INTERVAL) {
+                return;
+            }
+            *last_tor_probe = Some(Instant::now());
+        }
+        let reachable = TcpStream::connect_timeout(&proxy, TOR_PROBE_TIMEOUT).is_ok();
+        self.set_tor_reachable(reachable);
+    }
+
+    /// Record the tor proxy reachability. When it recovers from being unreachable, all onion
+    /// services are rescheduled for an immediate re-check (if `recheck_onions_on_tor_recovery` is set),
+    /// so that the onion servers we list repopulate quickly.
+    pub(super) fn set_tor_reachable(&self, reachable: bool) {
+        let was_reachable = lock(&self.tor_reachable).replace(reachable);
+        match (was_reachable, reachable) {
+            (Some(false), true) => {
+                info!("tor proxy recovered");
+                if self.config.recheck_onions_on_tor_recovery {
+                    self.recheck_onions();
+                }
+            }
+            (Some(true), false) | (None, false) => warn!("tor proxy is unreachable"),
+            _ => (),
+        }
+    }
+
+    fn recheck_onions(&self) {
+        let mut queue = write_lock(&self.queue);
+        let mut jobs = std::mem::take(&mut *queue).into_vec();
+        for job in &mut jobs {
+            if job.addr.transport() == Transport::Onion {
+                job.recheck = true;
+            }
+        }
+        *queue = BinaryHeap::from(jobs);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::electrum::discovery::test_util::*;
+    use crate::electrum::discovery::*;
+
+    #[test]
+    fn test_tor_recovery() {
+        let discovery = test_manager(DiscoveryConfig {
+            health_check_freq: Duration::from_secs(3600),
+            ..Default::default()
+        });
+        discovery
+            .add_default_server("1.1.1.1".into(), vec![Service::Tcp(50001)])
+            .unwrap();
+        for onion in &ONION_HOSTS[0..2] {
+            discovery
+                .add_default_server(onion.to_string(), vec![Service::Tcp(50001)])
+                .unwrap();
+        }
+        // all checked recently, so none are due
+        {
+            let mut queue = write_lock(&discovery.queue);
+            let mut jobs = std::mem::take(&mut *queue).into_vec();
+            for job in &mut jobs {
+                job.last_check = Some(Instant::now());
+            }
+            *queue = BinaryHeap::from(jobs);
+        }
+        assert!(discovery.pop_due_job(0).is_none());
+
+        // tor going down and staying down doesn't affect scheduling
+        discovery.set_tor_reachable(false);
+        discovery.set_tor_reachable(false);
+        assert!(discovery.pop_due_job(0).is_none());
+
+        // once it recovers, the onion services are due right away
+        discovery.set_tor_reachable(true);
+        let mut due = vec![];
+        while let Some(job) = discovery.pop_due_job(0) {
+            discovery.finish_job(job.addr.transport());
+            due.push(job.hostname);
+        }
+        due.sort();
+        assert_eq!(due, vec![ONION_HOSTS[0], ONION_HOSTS[1]]);
+    }
+}
@@ -0,0 +1,215 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
+use std::time::Duration;
+
+use crate::electrum::discovery::{onion, DiscoveryManager, ServerAddr};
+use crate::electrum::{Hostname, ServerPorts};
+use crate::errors::{Result, ResultExt};
+
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An ephemeral onion service created through the tor control port. Tor removes it once the
+/// control connection closes, which happens when this is dropped.
+#[derive(Debug)]
+pub struct OnionService {
+    pub hostname: Hostname,
+    /// The port the service is reachable at, forwarded to the same port of our Electrum listener
+    pub port: u16,
+    _control: TorControl,
+}
+
+impl OnionService {
+    /// Create an onion service pointing at our Electrum listener, via the tor control port at
+    /// `control_addr`. Authenticates using the cookie file if tor requires it.
+    pub fn create(control_addr: SocketAddr, electrum_addr: SocketAddr) -> Result<Self> {
+        let mut control = TorControl::connect(control_addr)?;
+        control.authenticate()?;
+
+        // listeners bound to all interfaces are reachable by tor over loopback
+        let mut target = electrum_addr;
+        if target.ip().is_unspecified() {
+            target.set_ip(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        }
+        let port = target.port();
+        let reply = control.command(&format!(
+            "ADD_ONION NEW:ED25519-V3 Flags=DiscardPK Port={},{}",
+            port, target
+        ))?;
+        let service_id = reply
+            .iter()
+            .find_map(|line| line.strip_prefix("ServiceID="))
+            .chain_err(|| "missing ServiceID in ADD_ONION reply")?;
+        let hostname = format!("{}.onion", service_id);
+        onion::validate_onion(&hostname)?;
+        info!("created onion service {}:{} -> {}", hostname, port, target);
+
+        Ok(OnionService {
+            hostname,
+            port,
+            _control: control,
+        })
+    }
+}
+
+impl DiscoveryManager {
+    /// Advertise the onion service in our features, keeping it alive for as long as we're running
+    pub fn with_onion_service(mut self, service: OnionService) -> Self {
+        self.our_features.hosts.insert(
+            service.hostname.clone(),
+            ServerPorts {
+                tcp_port: Some(service.port),
+                ssl_port: None,
+                ws_port: None,
+                wss_port: None,
+            },
+        );
+        self.our_addrs
+            .insert(ServerAddr::Onion(service.hostname.clone()));
+        self.onion_service = Some(service);
+        self
+    }
+
+    /// The onion service created for us, if any
+    pub fn onion_service(&self) -> Option<&OnionService> {
+        self.onion_service.as_ref()
+    }
+}
+
+/// A connection to the tor control port, speaking the subset of the control protocol needed to
+/// create onion services
+#[derive(Debug)]
+struct TorControl {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl TorControl {
+    fn connect(addr: SocketAddr) -> Result<Self> {
+        let stream = TcpStream::connect_timeout(&addr, CONTROL_TIMEOUT)
+            .chain_err(|| format!("failed connecting to the tor control port at {}", addr))?;
+        stream
+            .set_read_timeout(Some(CONTROL_TIMEOUT))
+            .chain_err(|| "failed setting tor control timeout")?;
+        let reader = stream
+            .try_clone()
+            .chain_err(|| "failed cloning tor control stream")?;
+        Ok(TorControl {
+            reader: BufReader::new(reader),
+            writer: stream,
+        })
+    }
+
+    /// Send the command and read its reply, returning the reply lines without their status code.
+    /// Fails unless the reply status is 250 (OK).
+    fn command(&mut self, command: &str) -> Result<Vec<String>> {
+        write!(self.writer, "{}\r\n", command).chain_err(|| "failed writing to tor control")?;
+        let mut lines = vec![];
+        loop {
+            let mut line = String::new();
+            let read = self
+                .reader
+                .read_line(&mut line)
+                .chain_err(|| "failed reading from tor control")?;
+            ensure!(read > 0, "tor control connection closed");
+            let line = line.trim_end();
+            ensure!(line.len() >= 4, "invalid tor control reply: {}", line);
+            let (status, separator, text) = (&line[..3], &line[3..4], &line[4..]);
+            ensure!(status == "250", "tor control error: {}", line);
+            lines.push(text.to_string());
+            // a space marks the end of the reply, '-' and '+' are followed by more lines
+            if separator == " " {
+                return Ok(lines);
+            }
+        }
+    }
+
+    /// Authenticate using the method tor asks for. Only the NULL (no authentication) and COOKIE
+    /// methods are supported.
+    fn authenticate(&mut self) -> Result<()> {
+        let info = self.command("PROTOCOLINFO 1")?;
+        let auth = info
+            .iter()
+            .find_map(|line| line.strip_prefix("AUTH "))
+            .chain_err(|| "missing AUTH in PROTOCOLINFO reply")?;
+        let methods = auth
+            .split(' ')
+            .find_map(|field| field.strip_prefix("METHODS="))
+            .unwrap_or("")
+            .split(',')
+            .collect::<Vec<_>>();
+
+        if methods.contains(&"NULL") {
+            self.command("AUTHENTICATE")?;
+        } else if methods.contains(&"COOKIE") {
+            let cookie_file = auth
+                .split("COOKIEFILE=\"")
+                .nth(1)
+                .and_then(|path| path.split('"').next())
+                .chain_err(|| "missing COOKIEFILE in PROTOCOLINFO reply")?;
+            let cookie = fs::read(cookie_file)
+                .chain_err(|| format!("failed reading tor control cookie {}", cookie_file))?;
+            self.command(&format!("AUTHENTICATE {}", hex::encode(cookie)))?;
+        } else {
+            bail!(
+                "unsupported tor control authentication methods {:?}",
+                methods
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::electrum::discovery::test_util::*;
+    use crate::electrum::discovery::*;
+
+    #[test]
+    fn test_onion_service() {
+        use std::io::{BufRead, BufReader, Write};
+        use std::net::TcpListener;
+
+        // a stub tor control port, not requiring authentication
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let control_addr = listener.local_addr().unwrap();
+        let service_id = ONION_HOSTS[0].trim_end_matches(".onion").to_string();
+        let commands = Arc::new(Mutex::new(vec![]));
+        let received = commands.clone();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            for line in BufReader::new(stream).lines() {
+                let line = line.unwrap();
+                let reply = if line.starts_with("PROTOCOLINFO") {
+                    "250-PROTOCOLINFO 1\r\n250-AUTH METHODS=NULL\r\n250 OK\r\n".to_string()
+                } else if line.starts_with("ADD_ONION") {
+                    format!("250-ServiceID={}\r\n250 OK\r\n", service_id)
+                } else {
+                    "250 OK\r\n".to_string()
+                };
+                received.lock().unwrap().push(line);
+                writer.write_all(reply.as_bytes()).unwrap();
+            }
+        });
+
+        let service = OnionService::create(control_addr, "0.0.0.0:50001".parse().unwrap()).unwrap();
+        assert_eq!(service.hostname, ONION_HOSTS[0]);
+        assert_eq!(
+            *commands.lock().unwrap(),
+            vec![
+                "PROTOCOLINFO 1",
+                "AUTHENTICATE",
+                "ADD_ONION NEW:ED25519-V3 Flags=DiscardPK Port=50001,127.0.0.1:50001",
+            ]
+        );
+
+        // advertised in our features, and never listed as a peer of ours
+        let discovery = test_manager(DiscoveryConfig::default()).with_onion_service(service);
+        let ports = &discovery.our_features().hosts[ONION_HOSTS[0]];
+        assert_eq!((ports.tcp_port, ports.ssl_port), (Some(50001), None));
+        assert!(discovery
+            .our_addrs
+            .contains(&ServerAddr::Onion(ONION_HOSTS[0].into())));
+    }
+}
@@ -1,12 +1,21 @@
+mod banner;
+mod rate_limit;
+mod resume;
 mod server;
+mod tls;
 pub use server::RPC;
 
-#[cfg(feature = "electrum-discovery")]
-mod client;
 #[cfg(feature = "electrum-discovery")]
 mod discovery;
 #[cfg(feature = "electrum-discovery")]
-pub use {client::Client, discovery::DiscoveryManager};
+pub use discovery::{
+    AdmissionSource, AsnLookup, Ban, BanPrecedence, CertKind, CertVerification, ChainTip,
+    DiscoveryConfig, DiscoveryEvent, DiscoveryEvents, DiscoveryManager, DiscoveryStats,
+    ElectrumProbe, Ip2AsnLookup, IpRange, IpReputation, Latency, LatencyMethod, NoAsnLookup,
+    NoChainTip, NoIpReputation, OnionService, Probe, ProbeClient, RequestLimitPolicy, Resolution,
+    Resolver, SelfTestReport, SelfTestStage, ServerFilter, ServerPage, Service, ServiceMetrics,
+    ServiceStatus, Socks5Proxy, StageReport, SystemResolver, TlsVersion, Transport,
+};
 
 use std::cmp::Ordering;
 use std::collections::HashMap;
@@ -31,7 +40,7 @@ pub type Hostname = String;
 
 pub type ServerHosts = HashMap<Hostname, ServerPorts>;
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub struct ServerFeatures {
     pub hosts: ServerHosts,
     pub genesis_hash: BlockHash,
@@ -42,10 +51,14 @@ pub struct ServerFeatures {
     pub hash_function: String,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub struct ServerPorts {
     tcp_port: Option<Port>,
     ssl_port: Option<Port>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ws_port: Option<Port>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    wss_port: Option<Port>,
 }
 
 #[derive(Eq, PartialEq, Debug, Clone, Default)]
@@ -76,19 +89,21 @@ impl PartialOrd for ProtocolVersion {
 
 impl FromStr for ProtocolVersion {
     type Err = crate::errors::Error;
+    /// Parse the `major.minor` format. A trailing patch component (like `1.4.2`) is accepted but
+    /// ignored, as it isn't relevant for compatibility.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut iter = s.split('.');
+        let parts = s.split('.').collect::<Vec<_>>();
+        ensure!(
+            (2..=3).contains(&parts.len())
+                && parts
+                    .iter()
+                    .all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit())),
+            "invalid protocol version {:?}, expected major.minor",
+            s
+        );
         Ok(Self {
-            major: iter
-                .next()
-                .chain_err(|| "missing major")?
-                .parse()
-                .chain_err(|| "invalid major")?,
-            minor: iter
-                .next()
-                .chain_err(|| "missing minor")?
-                .parse()
-                .chain_err(|| "invalid minor")?,
+            major: parts[0].parse().chain_err(|| "invalid major")?,
+            minor: parts[1].parse().chain_err(|| "invalid minor")?,
         })
     }
 }
@@ -0,0 +1,329 @@
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::errors::*;
+use crate::util::LruMap;
+
+/// The JSON-RPC error code ElectrumX returns when clients exceed their limits
+pub const EXCESSIVE_RESOURCE_USAGE: i32 = -101;
+
+/// Limits on the resources used by clients, unlimited if unset
+#[derive(Clone, Debug, Default)]
+pub struct RateLimitConfig {
+    /// Requests per second per IP, with bursts of up to a second's worth
+    pub requests_per_sec: Option<f64>,
+    /// Connections per IP
+    pub max_connections: Option<usize>,
+    /// Requests being processed at once per IP, across its connections
+    pub max_in_flight: Option<usize>,
+    /// Subscriptions (to scripthashes and outpoints) per connection
+    pub max_subscriptions: Option<usize>,
+    /// Subscriptions per IP, across its connections
+    pub max_subscriptions_per_ip: Option<usize>,
+    /// Client IPs tracked at once, beyond which the least recently active ones are forgotten
+    /// (and tracked afresh on their next request)
+    pub max_tracked_ips: Option<usize>,
+}
+
+/// Tracks the resources used by each client IP, shared by all its connections
+#[derive(Debug)]
+pub struct RateLimits {
+    config: RateLimitConfig,
+    usage: Mutex<LruMap<IpAddr, Usage>>,
+}
+
+#[derive(Debug)]
+struct Usage {
+    connections: usize,
+    /// Requests that can be made right away, replenished at `requests_per_sec`
+    tokens: f64,
+    refilled_at: Instant,
+    in_flight: usize,
+    subscriptions: usize,
+}
+
+impl Usage {
+    fn new(connections: usize, tokens: f64) -> Self {
+        Usage {
+            connections,
+            tokens,
+            refilled_at: Instant::now(),
+            in_flight: 0,
+            subscriptions: 0,
+        }
+    }
+}
+
+/// A request being processed, until dropped
+pub struct InFlight {
+    limits: Arc<RateLimits>,
+    ip: IpAddr,
+}
+
+impl RateLimits {
+    pub fn new(config: RateLimitConfig) -> Self {
+        let max_tracked_ips = config.max_tracked_ips.unwrap_or(usize::MAX);
+        RateLimits {
+            config,
+            usage: Mutex::new(LruMap::new(max_tracked_ips)),
+        }
+    }
+
+    fn burst(&self) -> f64 {
+        self.config
+            .requests_per_sec
+            .map_or(0.0, |rate| rate.max(1.0))
+    }
+
+    /// Account for a new connection, failing if the IP has too many already
+    pub fn connect(&self, ip: IpAddr) -> Result<()> {
+        let burst = self.burst();
+        let mut usage = self.usage.lock().unwrap();
+        // checked before tracking the IP, as rejected connections are never disconnected
+        let connections = usage.get(&ip).map_or(0, |usage| usage.connections);
+        if let Some(max_connections) = self.config.max_connections {
+            ensure!(
+                connections < max_connections,
+                ErrorKind::RateLimited("too many connections from this IP".into())
+            );
+        }
+        match usage.get_mut(&ip) {
+            Some(usage) => usage.connections += 1,
+            None => {
+                usage.insert(ip, Usage::new(1, burst));
+            }
+        }
+        Ok(())
+    }
+
+    /// The usage of a connected IP, tracked afresh if it was evicted in the meantime. Its other
+    /// connections and requests then go uncounted, so releasing them must not underflow.
+    fn connected<'a>(
+        usage: &'a mut LruMap<IpAddr, Usage>,
+        ip: IpAddr,
+        burst: f64,
+    ) -> &'a mut Usage {
+        if usage.get(&ip).is_none() {
+            usage.insert(ip, Usage::new(1, burst));
+        }
+        usage.get_mut(&ip).unwrap()
+    }
+
+    /// Release the connection along with its subscriptions
+    pub fn disconnect(&self, ip: IpAddr, subscriptions: usize) {
+        let mut usage = self.usage.lock().unwrap();
+        if let Some(ip_usage) = usage.get_mut(&ip) {
+            ip_usage.connections = ip_usage.connections.saturating_sub(1);
+            ip_usage.subscriptions = ip_usage.subscriptions.saturating_sub(subscriptions);
+            if ip_usage.connections == 0 {
+                usage.remove(&ip);
+            }
+        }
+    }
+
+    /// Account for a request, failing if the IP made too many
+    pub fn start_request(self: &Arc<Self>, ip: IpAddr) -> Result<InFlight> {
+        self.start_request_at(ip, Instant::now())
+    }
+
+    fn start_request_at(self: &Arc<Self>, ip: IpAddr, now: Instant) -> Result<InFlight> {
+        let burst = self.burst();
+        let mut usage = self.usage.lock().unwrap();
+        let usage = Self::connected(&mut usage, ip, burst);
+        if let Some(rate) = self.config.requests_per_sec {
+            let elapsed = now.saturating_duration_since(usage.refilled_at);
+            usage.tokens = (usage.tokens + elapsed.as_secs_f64() * rate).min(burst);
+            usage.refilled_at = now;
+            ensure!(
+                usage.tokens >= 1.0,
+                ErrorKind::RateLimited("too many requests".into())
+            );
+            usage.tokens -= 1.0;
+        }
+        if let Some(max_in_flight) = self.config.max_in_flight {
+            ensure!(
+                usage.in_flight < max_in_flight,
+                ErrorKind::RateLimited("too many concurrent requests".into())
+            );
+        }
+        usage.in_flight += 1;
+        Ok(InFlight {
+            limits: Arc::clone(self),
+            ip,
+        })
+    }
+
+    /// Account for a new subscription of a connection already holding `subscriptions`
+    pub fn add_subscription(&self, ip: IpAddr, subscriptions: usize) -> Result<()> {
+        if let Some(max_subscriptions) = self.config.max_subscriptions {
+            ensure!(
+                subscriptions < max_subscriptions,
+                ErrorKind::RateLimited("too many subscriptions".into())
+            );
+        }
+        let burst = self.burst();
+        let mut usage = self.usage.lock().unwrap();
+        let usage = Self::connected(&mut usage, ip, burst);
+        if let Some(max_subscriptions) = self.config.max_subscriptions_per_ip {
+            ensure!(
+                usage.subscriptions < max_subscriptions,
+                ErrorKind::RateLimited("too many subscriptions from this IP".into())
+            );
+        }
+        usage.subscriptions += 1;
+        Ok(())
+    }
+
+    pub fn remove_subscription(&self, ip: IpAddr) {
+        if let Some(usage) = self.usage.lock().unwrap().get_mut(&ip) {
+            usage.subscriptions = usage.subscriptions.saturating_sub(1);
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if let Some(usage) = self.limits.usage.lock().unwrap().get_mut(&self.ip) {
+            usage.in_flight = usage.in_flight.saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_rate_limits() {
+        let limits = Arc::new(RateLimits::new(RateLimitConfig {
+            requests_per_sec: Some(2.0),
+            max_connections: Some(2),
+            max_in_flight: Some(2),
+            max_subscriptions: Some(2),
+            max_subscriptions_per_ip: Some(3),
+            max_tracked_ips: None,
+        }));
+        let (ip, other_ip) = ("1.1.1.1".parse().unwrap(), "1.1.1.2".parse().unwrap());
+        fn limited<T>(result: Result<T>) -> bool {
+            match result {
+                Err(e) => matches!(e.kind(), ErrorKind::RateLimited(_)),
+                Ok(_) => false,
+            }
+        }
+        limits.connect(ip).unwrap();
+        limits.connect(ip).unwrap();
+        assert!(limited(limits.connect(ip)));
+        limits.connect(other_ip).unwrap();
+
+        // bursts of a second's worth of requests, replenished over time
+        let now = Instant::now();
+        let first = limits.start_request_at(ip, now).unwrap();
+        let second = limits.start_request_at(ip, now).unwrap();
+        drop((first, second));
+        assert!(limited(limits.start_request_at(ip, now)));
+        assert!(limits.start_request_at(other_ip, now).is_ok());
+        let later = now + Duration::from_millis(500);
+        assert!(limits.start_request_at(ip, later).is_ok());
+        assert!(limited(limits.start_request_at(ip, later)));
+
+        // concurrent requests across connections of the same IP
+        let later = later + Duration::from_secs(10);
+        let first = limits.start_request_at(ip, later).unwrap();
+        let _second = limits.start_request_at(ip, later).unwrap();
+        let later = later + Duration::from_secs(10);
+        assert!(limited(limits.start_request_at(ip, later)));
+        drop(first);
+        assert!(limits.start_request_at(ip, later).is_ok());
+
+        // subscriptions per connection and per IP
+        assert!(limits.add_subscription(ip, 0).is_ok());
+        assert!(limits.add_subscription(ip, 1).is_ok());
+        assert!(limited(limits.add_subscription(ip, 2)));
+        assert!(limits.add_subscription(ip, 0).is_ok());
+        assert!(limited(limits.add_subscription(ip, 1)));
+        limits.remove_subscription(ip);
+        assert!(limits.add_subscription(ip, 1).is_ok());
+        limits.disconnect(ip, 2);
+        assert!(limits.add_subscription(ip, 0).is_ok());
+
+        // forgotten once all their connections are closed
+        limits.disconnect(ip, 2);
+        assert!(limits.usage.lock().unwrap().get(&ip).is_none());
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        let limits = RateLimits::new(RateLimitConfig {
+            max_connections: Some(1),
+            ..Default::default()
+        });
+        let ip = "1.1.1.1".parse().unwrap();
+        limits.connect(ip).unwrap();
+        assert!(limits.connect(ip).is_err());
+        // a connection is accepted again once the previous one is closed
+        limits.disconnect(ip, 0);
+        limits.connect(ip).unwrap();
+        limits.disconnect(ip, 0);
+
+        // rejected connections aren't tracked, as they're never disconnected
+        let none = RateLimits::new(RateLimitConfig {
+            max_connections: Some(0),
+            ..Default::default()
+        });
+        assert!(none.connect(ip).is_err());
+        assert!(none.usage.lock().unwrap().is_empty());
+        assert!(limits.usage.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_tracked_ips_bounded() {
+        let limits = Arc::new(RateLimits::new(RateLimitConfig {
+            requests_per_sec: Some(1.0),
+            max_tracked_ips: Some(100),
+            ..Default::default()
+        }));
+        let ip = |i: u32| IpAddr::from(i.to_be_bytes());
+        let now = Instant::now();
+        for i in 0..10_000 {
+            limits.connect(ip(i)).unwrap();
+            drop(limits.start_request_at(ip(i), now).unwrap());
+        }
+        assert_eq!(limits.usage.lock().unwrap().len(), 100);
+
+        // the most recently active IPs are still limited, evicted ones are tracked afresh
+        assert!(limits.start_request_at(ip(9_999), now).is_err());
+        assert!(limits.start_request_at(ip(0), now).is_ok());
+        assert_eq!(limits.usage.lock().unwrap().len(), 100);
+        limits.disconnect(ip(0), 0);
+        assert!(limits.usage.lock().unwrap().get(&ip(0)).is_none());
+    }
+
+    #[test]
+    fn test_retracked_in_flight() {
+        let limits = Arc::new(RateLimits::new(RateLimitConfig {
+            max_in_flight: Some(1),
+            max_tracked_ips: Some(1),
+            ..Default::default()
+        }));
+        let (ip, other_ip) = ("1.1.1.1".parse().unwrap(), "1.1.1.2".parse().unwrap());
+        limits.connect(ip).unwrap();
+        limits.connect(ip).unwrap();
+        let in_flight = limits.start_request(ip).unwrap();
+
+        // evicted by another IP, then tracked afresh with a single connection and no request
+        limits.connect(other_ip).unwrap();
+        assert!(limits.usage.lock().unwrap().get(&ip).is_none());
+        let retracked = limits.start_request(ip).unwrap();
+        drop(in_flight);
+        drop(retracked);
+        assert_eq!(limits.usage.lock().unwrap().get(&ip).unwrap().in_flight, 0);
+        drop(limits.start_request(ip).unwrap());
+
+        // releasing the uncounted connection doesn't underflow either
+        limits.disconnect(ip, 0);
+        limits.disconnect(ip, 0);
+        assert!(limits.usage.lock().unwrap().get(&ip).is_none());
+    }
+}
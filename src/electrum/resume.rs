@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+use rand::Rng;
+
+use crate::chain::OutPoint;
+use crate::errors::*;
+
+/// Subscription sets remembered at once, beyond which the oldest are forgotten
+const MAX_SAVED: usize = 10_000;
+/// Random bytes of a token, hex-encoded
+const TOKEN_LEN: usize = 16;
+
+/// The subscriptions of disconnected clients, kept for a while under the resume token they were
+/// issued so that they can restore them with a single request when reconnecting
+#[derive(Debug)]
+pub struct ResumeTokens {
+    ttl: Duration,
+    saved: Mutex<HashMap<String, (Subscriptions, Instant)>>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Subscriptions {
+    pub script_hashes: Vec<Sha256dHash>,
+    pub outpoints: Vec<OutPoint>,
+}
+
+impl ResumeTokens {
+    /// Keep the subscriptions for `ttl` after the client disconnected
+    pub fn new(ttl: Duration) -> Self {
+        ResumeTokens {
+            ttl,
+            saved: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// A new random token, unguessable so that clients can't take over each other's subscriptions
+    pub fn issue(&self) -> String {
+        let token: [u8; TOKEN_LEN] = rand::thread_rng().gen();
+        hex::encode(token)
+    }
+
+    /// Check that the token is one we could have issued, before looking it up
+    pub fn validate(token: &str) -> Result<()> {
+        ensure!(
+            token.len() == TOKEN_LEN * 2 && token.bytes().all(|b| b.is_ascii_hexdigit()),
+            "invalid resume token"
+        );
+        Ok(())
+    }
+
+    /// Remember the subscriptions of a client disconnecting with the token
+    pub fn save(&self, token: String, subscriptions: Subscriptions) {
+        let mut saved = self.saved.lock().unwrap();
+        let ttl = self.ttl;
+        saved.retain(|_, (_, saved_at)| saved_at.elapsed() < ttl);
+        if saved.len() >= MAX_SAVED {
+            let oldest = saved
+                .iter()
+                .min_by_key(|(_, (_, saved_at))| *saved_at)
+                .map(|(token, _)| token.clone());
+            if let Some(oldest) = oldest {
+                saved.remove(&oldest);
+            }
+        }
+        saved.insert(token, (subscriptions, Instant::now()));
+    }
+
+    /// The subscriptions saved under the token, which can only be resumed once
+    pub fn take(&self, token: &str) -> Option<Subscriptions> {
+        let (subscriptions, saved_at) = self.saved.lock().unwrap().remove(token)?;
+        if saved_at.elapsed() < self.ttl {
+            Some(subscriptions)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resume() {
+        let tokens = ResumeTokens::new(Duration::from_secs(60));
+        let token = tokens.issue();
+        assert_eq!(token.len(), 32);
+        assert_ne!(token, tokens.issue());
+        ResumeTokens::validate(&token).unwrap();
+        assert!(ResumeTokens::validate("unknown").is_err());
+        assert!(ResumeTokens::validate(&format!("x{}", &token[1..])).is_err());
+
+        let subscriptions = Subscriptions {
+            script_hashes: vec![Sha256dHash::default()],
+            outpoints: vec![],
+        };
+        tokens.save(token.clone(), subscriptions);
+        assert_eq!(tokens.take("unknown"), None);
+        assert_eq!(
+            tokens.take(&token),
+            Some(Subscriptions {
+                script_hashes: vec![Sha256dHash::default()],
+                outpoints: vec![],
+            })
+        );
+        // tokens are single-use
+        assert_eq!(tokens.take(&token), None);
+
+        let expired = ResumeTokens::new(Duration::from_secs(0));
+        expired.save(token.clone(), Subscriptions::default());
+        assert_eq!(expired.take(&token), None);
+    }
+}
@@ -1,9 +1,17 @@
 use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Write};
-use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
-use std::sync::mpsc::{Sender, SyncSender, TrySendError};
+use std::convert::TryInto;
+use std::fs;
+use std::hash::Hash;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use bitcoin::hashes::sha256d::Hash as Sha256dHash;
 use crypto::digest::Digest;
@@ -12,29 +20,76 @@ use error_chain::ChainedError;
 use hex;
 use serde_json::{from_str, Value};
 
-#[cfg(not(feature = "liquid"))]
-use bitcoin::consensus::encode::serialize;
 #[cfg(feature = "liquid")]
 use elements::encode::serialize;
+#[cfg(not(feature = "liquid"))]
+use {
+    crate::account::{Account, AccountScan, DEFAULT_GAP_LIMIT},
+    crate::chain::{deserialize, Transaction},
+    crate::util::{is_coinbase, ScriptToAsm},
+    bitcoin::consensus::encode::serialize,
+    bitcoin::util::address::AddressType,
+    std::str::FromStr,
+};
 
-use crate::chain::Txid;
+use crate::chain::{address, Network, OutPoint, Txid};
 use crate::config::Config;
+use crate::electrum::banner::{Banner, BannerVars};
+use crate::electrum::rate_limit::{RateLimitConfig, RateLimits, EXCESSIVE_RESOURCE_USAGE};
+use crate::electrum::resume::{ResumeTokens, Subscriptions};
+use crate::electrum::tls::{TlsAcceptor, TlsStream};
 use crate::electrum::{get_electrum_height, ProtocolVersion};
 use crate::errors::*;
-use crate::metrics::{Gauge, HistogramOpts, HistogramVec, MetricOpts, Metrics};
-use crate::new_index::{Query, Utxo};
+use crate::metrics::{CounterVec, Gauge, HistogramOpts, HistogramVec, MetricOpts, Metrics};
+use crate::new_index::{compute_script_hash, Query, Utxo};
 use crate::util::electrum_merkle::{get_header_merkle_proof, get_id_from_pos, get_tx_merkle_proof};
 use crate::util::{
-    create_socket, full_hash, spawn_thread, BlockId, BoolThen, Channel, FullHash, HeaderEntry,
-    SyncChannel,
+    create_socket, full_hash, proxy_protocol, spawn_thread, websocket, BlockId, BoolThen, Channel,
+    FullHash, HeaderEntry, SyncChannel,
 };
 
 const ELECTRS_VERSION: &str = env!("CARGO_PKG_VERSION");
-const PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion::new(1, 4);
+const PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion::new(1, 5);
+const PROTOCOL_VERSION_MIN: ProtocolVersion = ProtocolVersion::new(1, 4);
 const MAX_HEADERS: usize = 2016;
 
+// JSON-RPC error codes, along with those ElectrumX defines for application errors
+const PARSE_ERROR: i32 = -32700;
+const INVALID_REQUEST: i32 = -32600;
+const METHOD_NOT_FOUND: i32 = -32601;
+const BAD_REQUEST: i32 = 1;
+const DAEMON_ERROR: i32 = 2;
+
+/// How long to wait for more update notifications before notifying the clients, so that those
+/// arriving in quick succession (like for a new block and the mempool changes it causes) result in
+/// a single round of status updates
+const NOTIFICATION_WINDOW: Duration = Duration::from_millis(250);
+
+/// Sent to clients as a `server.banner` notification when shutting down
+const SHUTDOWN_NOTICE: &str = "Server shutting down, please reconnect later";
+
+#[cfg(feature = "electrum-discovery")]
+use crate::electrum::{
+    ChainTip, DiscoveryConfig, DiscoveryManager, Ip2AsnLookup, OnionService, ServerFeatures,
+};
+
+/// Our indexed chain tip, that discovery compares the servers' tips against
+#[cfg(feature = "electrum-discovery")]
+struct IndexedTip(Arc<Query>);
+
+#[cfg(feature = "electrum-discovery")]
+impl std::fmt::Debug for IndexedTip {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "IndexedTip")
+    }
+}
+
 #[cfg(feature = "electrum-discovery")]
-use crate::electrum::{DiscoveryManager, ServerFeatures};
+impl ChainTip for IndexedTip {
+    fn height(&self) -> Option<usize> {
+        Some(self.0.chain().best_height())
+    }
+}
 
 // TODO: Sha256dHash should be a generic hash-container (since script hash is single SHA256)
 fn hash_from_value(val: Option<&Value>) -> Result<Sha256dHash> {
@@ -44,12 +99,65 @@ fn hash_from_value(val: Option<&Value>) -> Result<Sha256dHash> {
     Ok(script_hash)
 }
 
+/// The scripthash of the address, which must be for our network
+fn script_hash_from_address(val: Option<&Value>, network: Network) -> Result<Sha256dHash> {
+    let addr = str_from_value(val, "address")?;
+    #[cfg(not(feature = "liquid"))]
+    let (addr, is_expected_net) = {
+        let addr = address::Address::from_str(&addr).chain_err(|| "invalid address")?;
+        // Testnet, Regtest and Signet all share the same version bytes
+        let addr_network = Network::from(addr.network);
+        let is_expected_net = addr_network == network
+            || (addr_network == Network::Testnet
+                && matches!(network, Network::Regtest | Network::Signet));
+        (addr, is_expected_net)
+    };
+    #[cfg(feature = "liquid")]
+    let (addr, is_expected_net) = {
+        let addr = address::Address::parse_with_params(&addr, network.address_params())
+            .chain_err(|| "invalid address")?;
+        let is_expected_net = addr.params == network.address_params();
+        (addr, is_expected_net)
+    };
+    ensure!(is_expected_net, "address on invalid network");
+    let script_hash = compute_script_hash(&addr.script_pubkey());
+    Ok(<Sha256dHash as bitcoin::hashes::Hash>::from_inner(
+        script_hash,
+    ))
+}
+
+/// The params of the legacy `blockchain.address.*` methods, with the address replaced by its
+/// scripthash for the matching `blockchain.scripthash.*` ones
+fn address_params(params: &[Value], network: Network, enabled: bool) -> Result<Vec<Value>> {
+    ensure!(enabled, "blockchain.address methods are disabled");
+    let script_hash = script_hash_from_address(params.get(0), network)?;
+    let mut params = params.to_vec();
+    params[0] = json!(script_hash.to_string());
+    Ok(params)
+}
+
+/// The raw transactions given as params, at least one
+fn txs_from_params(params: &[Value]) -> Result<Vec<String>> {
+    ensure!(!params.is_empty(), "missing tx");
+    params
+        .iter()
+        .map(|tx| Ok(tx.as_str().chain_err(|| "non-string tx")?.to_string()))
+        .collect()
+}
+
 fn usize_from_value(val: Option<&Value>, name: &str) -> Result<usize> {
     let val = val.chain_err(|| format!("missing {}", name))?;
     let val = val.as_u64().chain_err(|| format!("non-integer {}", name))?;
     Ok(val as usize)
 }
 
+#[cfg(feature = "electrum-discovery")]
+fn str_from_value(val: Option<&Value>, name: &str) -> Result<String> {
+    let val = val.chain_err(|| format!("missing {}", name))?;
+    let val = val.as_str().chain_err(|| format!("non-string {}", name))?;
+    Ok(val.to_string())
+}
+
 fn usize_from_value_or(val: Option<&Value>, name: &str, default: usize) -> Result<usize> {
     if val.is_none() {
         return Ok(default);
@@ -70,6 +178,59 @@ fn bool_from_value_or(val: Option<&Value>, name: &str, default: bool) -> Result<
     bool_from_value(val, name)
 }
 
+fn u32_from_value(val: Option<&Value>, name: &str) -> Result<u32> {
+    let val = usize_from_value(val, name)?;
+    Ok(val
+        .try_into()
+        .chain_err(|| format!("{} out of range", name))?)
+}
+
+/// Parse the protocol version requested in `server.version`, either a single version or a
+/// `[min, max]` range. Defaults to the minimal version we support.
+fn protocol_range_from_value(val: Option<&Value>) -> Result<(ProtocolVersion, ProtocolVersion)> {
+    let parse = |val: &Value| -> Result<ProtocolVersion> {
+        val.as_str()
+            .chain_err(|| "non-string protocol version")?
+            .parse()
+    };
+    match val {
+        None => Ok((PROTOCOL_VERSION_MIN, PROTOCOL_VERSION_MIN)),
+        Some(Value::Array(range)) => {
+            ensure!(range.len() == 2, "invalid protocol version range");
+            Ok((parse(&range[0])?, parse(&range[1])?))
+        }
+        Some(version) => {
+            let version = parse(version)?;
+            Ok((version.clone(), version))
+        }
+    }
+}
+
+/// Negotiate the highest protocol version supported by both sides, given the version requested
+/// in `server.version`
+fn negotiate_version(val: Option<&Value>) -> Result<ProtocolVersion> {
+    let (client_min, client_max) = protocol_range_from_value(val)?;
+    let version = client_max.min(PROTOCOL_VERSION);
+    ensure!(
+        version >= client_min && version >= PROTOCOL_VERSION_MIN,
+        "unsupported protocol version {}-{}, supported: {}-{}",
+        client_min,
+        client_max,
+        PROTOCOL_VERSION_MIN,
+        PROTOCOL_VERSION
+    );
+    Ok(version)
+}
+
+/// Release the memory of a map that shrank to a fraction of its capacity, like the subscriptions
+/// of wallets that rotate through many addresses and unsubscribe from the used ones. Removing
+/// entries alone doesn't free any.
+fn shrink_to_len<K: Eq + Hash, V>(map: &mut HashMap<K, V>) {
+    if map.len() < map.capacity() / 4 {
+        map.shrink_to_fit();
+    }
+}
+
 // TODO: implement caching and delta updates
 fn get_status_hash(txs: Vec<(Txid, Option<BlockId>)>, query: &Query) -> Option<FullHash> {
     if txs.is_empty() {
@@ -91,37 +252,133 @@ fn get_status_hash(txs: Vec<(Txid, Option<BlockId>)>, query: &Query) -> Option<F
     }
 }
 
+/// How JSON-RPC messages are delimited on the wire
+#[derive(Clone, Copy, Debug)]
+enum Framing {
+    /// Newline-delimited, over plain TCP
+    Lines,
+    /// One message per WebSocket text frame
+    WebSocket,
+}
+
+/// A client connection, either plain or with TLS terminated by us, or over a unix socket
+enum Stream {
+    Plain(TcpStream),
+    Tls(TlsStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(match self {
+            Stream::Plain(stream) => Stream::Plain(stream.try_clone()?),
+            Stream::Tls(stream) => Stream::Tls(stream.try_clone()?),
+            Stream::Unix(stream) => Stream::Unix(stream.try_clone()?),
+        })
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.shutdown(how),
+            Stream::Tls(stream) => stream.shutdown(how),
+            Stream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
 struct Connection {
     query: Arc<Query>,
     last_header_entry: Option<HeaderEntry>,
     status_hashes: HashMap<Sha256dHash, Value>, // ScriptHash -> StatusHash
-    stream: TcpStream,
+    outpoint_statuses: HashMap<OutPoint, Value>,
+    stream: Stream,
     addr: SocketAddr,
+    framing: Framing,
+    admin: bool,
     chan: SyncChannel<Message>,
     stats: Arc<Stats>,
+    limits: Arc<RateLimits>,
+    banner: Arc<Banner>,
+    status_cache: Arc<StatusCache>,
+    sessions: Arc<Sessions>,
+    resume_tokens: Option<Arc<ResumeTokens>>,
+    resume_token: Option<String>,
     txs_limit: usize,
+    max_batch_size: usize,
+    idle_timeout: Option<Duration>,
+    last_request_at: Instant,
     #[cfg(feature = "electrum-discovery")]
     discovery: Option<Arc<DiscoveryManager>>,
 }
 
 impl Connection {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         query: Arc<Query>,
-        stream: TcpStream,
+        stream: Stream,
         addr: SocketAddr,
+        framing: Framing,
+        admin: bool,
         stats: Arc<Stats>,
+        limits: Arc<RateLimits>,
+        banner: Arc<Banner>,
+        status_cache: Arc<StatusCache>,
+        sessions: Arc<Sessions>,
+        resume_tokens: Option<Arc<ResumeTokens>>,
         txs_limit: usize,
+        max_batch_size: usize,
+        idle_timeout: Option<Duration>,
         #[cfg(feature = "electrum-discovery")] discovery: Option<Arc<DiscoveryManager>>,
     ) -> Connection {
         Connection {
             query,
             last_header_entry: None, // disable header subscription for now
             status_hashes: HashMap::new(),
+            outpoint_statuses: HashMap::new(),
             stream,
             addr,
+            framing,
+            admin,
             chan: SyncChannel::new(10),
             stats,
+            limits,
+            banner,
+            status_cache,
+            sessions,
+            resume_tokens,
+            resume_token: None,
             txs_limit,
+            max_batch_size,
+            idle_timeout,
+            last_request_at: Instant::now(),
             #[cfg(feature = "electrum-discovery")]
             discovery,
         }
@@ -135,15 +392,23 @@ impl Connection {
         Ok(result)
     }
 
-    fn server_version(&self) -> Result<Value> {
+    fn server_version(&self, params: &[Value]) -> Result<Value> {
         Ok(json!([
             format!("electrs-esplora {}", ELECTRS_VERSION),
-            PROTOCOL_VERSION
+            negotiate_version(params.get(1))?
         ]))
     }
 
     fn server_banner(&self) -> Result<Value> {
-        Ok(json!(self.query.config().electrum_banner.clone()))
+        let config = self.query.config();
+        let version = format!("electrs-esplora {}", ELECTRS_VERSION);
+        let network = format!("{:?}", config.network_type).to_lowercase();
+        Ok(json!(self.banner.render(&BannerVars {
+            version: &version,
+            tip_height: self.query.chain().best_height(),
+            network: &network,
+            donation_address: config.electrum_donation_address.as_deref(),
+        })))
     }
 
     #[cfg(feature = "electrum-discovery")]
@@ -156,7 +421,7 @@ impl Connection {
     }
 
     fn server_donation_address(&self) -> Result<Value> {
-        Ok(Value::Null)
+        Ok(json!(self.query.config().electrum_donation_address))
     }
 
     fn server_peers_subscribe(&self) -> Result<Value> {
@@ -189,8 +454,179 @@ impl Connection {
         Ok(json!(true))
     }
 
+    /// The `admin.*` methods are only served on the `--electrum-admin-addr` listener. Client
+    /// addresses can't tell the operator apart, as local proxies and the unix socket make any
+    /// client appear to connect from localhost.
+    fn ensure_admin(&self) -> Result<()> {
+        ensure!(self.admin, "admin methods are unavailable");
+        Ok(())
+    }
+
+    fn admin_sessions(&self) -> Result<Value> {
+        self.ensure_admin()?;
+        Ok(json!(self.sessions.list()))
+    }
+
+    fn admin_disconnect(&self, params: &[Value]) -> Result<Value> {
+        self.ensure_admin()?;
+        let id = usize_from_value(params.get(0), "session id")? as u64;
+        let disconnected = self.sessions.disconnect(id);
+        if disconnected {
+            self.stats.disconnects.with_label_values(&["admin"]).inc();
+        }
+        Ok(json!(disconnected))
+    }
+
+    /// How far the index is behind bitcoind
+    fn admin_sync_status(&self) -> Result<Value> {
+        self.ensure_admin()?;
+        let info = self.query.blockchain_info()?;
+        let tip = self.query.chain().best_header();
+        Ok(json!({
+            "indexed_height": tip.height(),
+            "indexed_hash": tip.hash(),
+            "daemon_height": info.blocks,
+            "daemon_headers": info.headers,
+            "initial_block_download": info.initialblockdownload.unwrap_or(false),
+            "verification_progress": info.verificationprogress,
+            "synced": tip.hash().to_string() == info.bestblockhash,
+            "mempool_txs": self.query.mempool().txids().len(),
+        }))
+    }
+
+    #[cfg(feature = "electrum-discovery")]
+    fn admin_discovery(&self) -> Result<&Arc<DiscoveryManager>> {
+        self.ensure_admin()?;
+        self.discovery
+            .as_ref()
+            .chain_err(|| "discovery is disabled")
+    }
+
+    #[cfg(feature = "electrum-discovery")]
+    fn admin_discovery_queue(&self) -> Result<Value> {
+        Ok(json!(self.admin_discovery()?.server_status()))
+    }
+
+    /// The discovery manager, for the `discovery.*` admin methods. Like the `admin.*` methods,
+    /// they're only served on the `--electrum-admin-addr` listener.
+    #[cfg(feature = "electrum-discovery")]
+    fn discovery_admin(&self) -> Result<&Arc<DiscoveryManager>> {
+        ensure!(self.admin, "discovery admin methods are unavailable");
+        self.discovery
+            .as_ref()
+            .chain_err(|| "discovery is disabled")
+    }
+
+    #[cfg(feature = "electrum-discovery")]
+    fn discovery_list(&self) -> Result<Value> {
+        let discovery = self.discovery_admin()?;
+        Ok(json!({
+            "queue": discovery.server_status(),
+            "advertised": discovery.get_servers(),
+            "banned": discovery.bans(),
+        }))
+    }
+
+    #[cfg(feature = "electrum-discovery")]
+    fn discovery_add(&self, params: &[Value]) -> Result<Value> {
+        let discovery = self.discovery_admin()?;
+        let hostname = str_from_value(params.get(0), "hostname")?;
+        let services = params
+            .get(1)
+            .and_then(Value::as_array)
+            .chain_err(|| "missing services")?
+            .iter()
+            .map(|service| service.as_str().chain_err(|| "non-string service")?.parse())
+            .collect::<Result<Vec<_>>>()?;
+        Ok(json!(discovery.add_server(hostname, services)?))
+    }
+
+    #[cfg(feature = "electrum-discovery")]
+    fn discovery_check(&self, params: &[Value]) -> Result<Value> {
+        let hostname = str_from_value(params.get(0), "hostname")?;
+        Ok(json!(self.discovery_admin()?.recheck_server(&hostname)))
+    }
+
+    #[cfg(feature = "electrum-discovery")]
+    fn discovery_pin(&self, params: &[Value]) -> Result<Value> {
+        let hostname = str_from_value(params.get(0), "hostname")?;
+        Ok(json!(self.discovery_admin()?.pin_server(&hostname)))
+    }
+
+    #[cfg(feature = "electrum-discovery")]
+    fn discovery_remove(&self, params: &[Value]) -> Result<Value> {
+        let hostname = str_from_value(params.get(0), "hostname")?;
+        Ok(json!(self.discovery_admin()?.remove_server(&hostname)))
+    }
+
+    #[cfg(feature = "electrum-discovery")]
+    fn discovery_ban(&self, params: &[Value]) -> Result<Value> {
+        let (hostname, reason, duration) = ban_from_params(params)?;
+        self.discovery_admin()?
+            .ban_server(&hostname, reason, duration)?;
+        Ok(json!(true))
+    }
+
+    #[cfg(feature = "electrum-discovery")]
+    fn discovery_unban(&self, params: &[Value]) -> Result<Value> {
+        let hostname = str_from_value(params.get(0), "hostname")?;
+        Ok(json!(self.discovery_admin()?.unban_server(&hostname)?))
+    }
+
+    fn resume_tokens(&self) -> Result<&Arc<ResumeTokens>> {
+        self.resume_tokens
+            .as_ref()
+            .chain_err(|| "session resumption is disabled")
+    }
+
+    /// The token to restore this session's subscriptions with after reconnecting, with
+    /// `server.session.resume`. The subscriptions are saved under it when disconnecting.
+    fn server_session_token(&mut self) -> Result<Value> {
+        if self.resume_token.is_none() {
+            self.resume_token = Some(self.resume_tokens()?.issue());
+        }
+        Ok(json!(self.resume_token))
+    }
+
+    /// Subscribe again to everything the disconnected session with the token was subscribed to,
+    /// returning the current statuses. The token is used up, and the session carries on with a
+    /// new one returned along with the statuses.
+    fn server_session_resume(&mut self, params: &[Value]) -> Result<Value> {
+        let token = str_from_value(params.get(0), "token")?;
+        ResumeTokens::validate(&token)?;
+        let resume_tokens = Arc::clone(self.resume_tokens()?);
+        let subscriptions = resume_tokens
+            .take(&token)
+            .chain_err(|| "unknown or expired resume token")?;
+        // subscriptions that fail now (like over the limits) are reported, without losing the rest
+        let mut failed = serde_json::Map::new();
+        let mut script_hashes = serde_json::Map::new();
+        for script_hash in subscriptions.script_hashes {
+            match self.subscribe_script_hash(script_hash) {
+                Ok(status) => script_hashes.insert(script_hash.to_string(), status),
+                Err(e) => failed.insert(script_hash.to_string(), json!(e.to_string())),
+            };
+        }
+        let mut outpoints = serde_json::Map::new();
+        for outpoint in subscriptions.outpoints {
+            let key = format!("{}:{}", outpoint.txid, outpoint.vout);
+            match self.subscribe_outpoint(outpoint) {
+                Ok(status) => outpoints.insert(key, status),
+                Err(e) => failed.insert(key, json!(e.to_string())),
+            };
+        }
+        let token = resume_tokens.issue();
+        self.resume_token = Some(token.clone());
+        Ok(json!({
+            "token": token,
+            "scripthashes": script_hashes,
+            "outpoints": outpoints,
+            "failed": failed,
+        }))
+    }
+
     fn mempool_get_fee_histogram(&self) -> Result<Value> {
-        Ok(json!(&self.query.mempool().backlog_stats().fee_histogram))
+        Ok(json!(self.query.mempool().electrum_fee_histogram()))
     }
 
     fn blockchain_block_header(&self, params: &[Value]) -> Result<Value> {
@@ -231,24 +667,9 @@ impl Connection {
             })
             .collect();
 
-        if count == 0 || cp_height == 0 {
-            return Ok(json!({
-                "count": headers.len(),
-                "hex": headers.join(""),
-                "max": MAX_HEADERS,
-            }));
-        }
-
-        let (branch, root) =
-            get_header_merkle_proof(self.query.chain(), start_height + (count - 1), cp_height)?;
-
-        Ok(json!({
-            "count": headers.len(),
-            "hex": headers.join(""),
-            "max": MAX_HEADERS,
-            "root": root,
-            "branch" : branch,
-        }))
+        block_headers_reply(start_height, headers, cp_height, |height| {
+            get_header_merkle_proof(self.query.chain(), height, cp_height)
+        })
     }
 
     fn blockchain_estimatefee(&self, params: &[Value]) -> Result<Value> {
@@ -267,8 +688,25 @@ impl Connection {
         Ok(json!(relayfee / 100_000f64))
     }
 
+    fn subscription_count(&self) -> usize {
+        self.status_hashes.len() + self.outpoint_statuses.len()
+    }
+
+    fn address_params(&self, params: &[Value]) -> Result<Vec<Value>> {
+        let enabled = self.query.config().electrum_address_methods;
+        address_params(params, self.query.network(), enabled)
+    }
+
     fn blockchain_scripthash_subscribe(&mut self, params: &[Value]) -> Result<Value> {
         let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
+        self.subscribe_script_hash(script_hash)
+    }
+
+    fn subscribe_script_hash(&mut self, script_hash: Sha256dHash) -> Result<Value> {
+        if !self.status_hashes.contains_key(&script_hash) {
+            self.limits
+                .add_subscription(self.addr.ip(), self.subscription_count())?;
+        }
 
         let history_txids = get_history(&self.query, &script_hash[..], self.txs_limit)?;
         let status_hash = get_status_hash(history_txids, &self.query)
@@ -280,6 +718,46 @@ impl Connection {
         Ok(status_hash)
     }
 
+    /// Drop the subscription, returning whether it existed
+    fn blockchain_scripthash_unsubscribe(&mut self, params: &[Value]) -> Result<Value> {
+        let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
+        let subscribed = self.status_hashes.remove(&script_hash).is_some();
+        if subscribed {
+            self.stats.subscriptions.dec();
+            self.limits.remove_subscription(self.addr.ip());
+            shrink_to_len(&mut self.status_hashes);
+        }
+        Ok(json!(subscribed))
+    }
+
+    fn blockchain_outpoint_subscribe(&mut self, params: &[Value]) -> Result<Value> {
+        let outpoint = outpoint_from_params(params)?;
+        self.subscribe_outpoint(outpoint)
+    }
+
+    fn subscribe_outpoint(&mut self, outpoint: OutPoint) -> Result<Value> {
+        if !self.outpoint_statuses.contains_key(&outpoint) {
+            self.limits
+                .add_subscription(self.addr.ip(), self.subscription_count())?;
+        }
+        let status = get_outpoint_status(&self.query, &outpoint);
+        if let None = self.outpoint_statuses.insert(outpoint, status.clone()) {
+            self.stats.subscriptions.inc();
+        }
+        Ok(status)
+    }
+
+    fn blockchain_outpoint_unsubscribe(&mut self, params: &[Value]) -> Result<Value> {
+        let outpoint = outpoint_from_params(params)?;
+        let subscribed = self.outpoint_statuses.remove(&outpoint).is_some();
+        if subscribed {
+            self.stats.subscriptions.dec();
+            self.limits.remove_subscription(self.addr.ip());
+            shrink_to_len(&mut self.outpoint_statuses);
+        }
+        Ok(json!(subscribed))
+    }
+
     #[cfg(not(feature = "liquid"))]
     fn blockchain_scripthash_get_balance(&self, params: &[Value]) -> Result<Value> {
         let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
@@ -294,8 +772,11 @@ impl Connection {
     fn blockchain_scripthash_get_history(&self, params: &[Value]) -> Result<Value> {
         let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
         let history_txids = get_history(&self.query, &script_hash[..], self.txs_limit)?;
+        Ok(self.history_json(history_txids))
+    }
 
-        Ok(json!(history_txids
+    fn history_json(&self, history_txids: Vec<(Txid, Option<BlockId>)>) -> Value {
+        json!(history_txids
             .into_iter()
             .map(|(txid, blockid)| {
                 let is_mempool = blockid.is_none();
@@ -306,7 +787,7 @@ impl Connection {
                 let height = get_electrum_height(blockid, has_unconfirmed_parents);
                 GetHistoryResult { txid, height, fee }
             })
-            .collect::<Vec<_>>()))
+            .collect::<Vec<_>>())
     }
 
     fn blockchain_scripthash_listunspent(&self, params: &[Value]) -> Result<Value> {
@@ -337,16 +818,75 @@ impl Connection {
         )))
     }
 
+    /// Scan the account given by the descriptor or xpub and the optional gap limit
+    #[cfg(not(feature = "liquid"))]
+    fn account_params(&self, params: &[Value]) -> Result<AccountScan> {
+        let descriptor = params.get(0).chain_err(|| "missing descriptor")?;
+        let descriptor = descriptor.as_str().chain_err(|| "non-string descriptor")?;
+        let gap_limit = match params.get(1) {
+            Some(gap_limit) => gap_limit
+                .as_u64()
+                .and_then(|gap_limit| gap_limit.try_into().ok())
+                .chain_err(|| "invalid gap_limit")?,
+            None => DEFAULT_GAP_LIMIT,
+        };
+        Account::parse(descriptor, self.query.network())?.scan(&self.query, gap_limit)
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    fn blockchain_account_get_balance(&self, params: &[Value]) -> Result<Value> {
+        let (confirmed, unconfirmed) = self.account_params(params)?.balance(&self.query);
+        Ok(json!({
+            "confirmed": confirmed,
+            "unconfirmed": unconfirmed,
+        }))
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    fn blockchain_account_get_history(&self, params: &[Value]) -> Result<Value> {
+        let history_txids = self
+            .account_params(params)?
+            .history_txids(&self.query, self.txs_limit)?;
+        Ok(self.history_json(history_txids))
+    }
+
+    /// The unspent outputs of the account, with the chain and index of the script they belong to
+    #[cfg(not(feature = "liquid"))]
+    fn blockchain_account_listunspent(&self, params: &[Value]) -> Result<Value> {
+        let scan = self.account_params(params)?;
+        let utxos = scan.utxos(&self.query)?;
+
+        Ok(json!(utxos
+            .into_iter()
+            .map(|(script, utxo)| json!({
+                "height": utxo.confirmed.map_or(0, |b| b.height),
+                "tx_pos": utxo.vout,
+                "tx_hash": utxo.txid,
+                "value": utxo.value,
+                "chain": script.chain,
+                "index": script.index,
+            }))
+            .collect::<Vec<_>>()))
+    }
+
     fn blockchain_transaction_broadcast(&self, params: &[Value]) -> Result<Value> {
         let tx = params.get(0).chain_err(|| "missing tx")?;
         let tx = tx.as_str().chain_err(|| "non-string tx")?.to_string();
         let txid = self.query.broadcast_raw(&tx)?;
+        self.status_cache.invalidate();
         if let Err(e) = self.chan.sender().try_send(Message::PeriodicUpdate) {
             warn!("failed to issue PeriodicUpdate after broadcast: {}", e);
         }
         Ok(json!(txid))
     }
 
+    /// Run the transactions through bitcoind's `testmempoolaccept` without broadcasting them, for
+    /// wallets to validate RBF bumps and packages of dependent transactions before sending
+    fn blockchain_transaction_check(&self, params: &[Value]) -> Result<Value> {
+        let txs = txs_from_params(params)?;
+        Ok(json!(self.query.test_mempool_accept(&txs)?))
+    }
+
     fn blockchain_transaction_get(&self, params: &[Value]) -> Result<Value> {
         let tx_hash = Txid::from(hash_from_value(params.get(0)).chain_err(|| "bad tx_hash")?);
         let verbose = match params.get(1) {
@@ -354,15 +894,18 @@ impl Connection {
             None => false,
         };
 
-        // FIXME: implement verbose support
-        if verbose {
-            bail!("verbose transactions are currently unsupported");
-        }
-
         let tx = self
             .query
             .lookup_raw_txn(&tx_hash)
             .chain_err(|| "missing transaction")?;
+
+        #[cfg(not(feature = "liquid"))]
+        if verbose {
+            return verbose_transaction(&self.query, &tx);
+        }
+        #[cfg(feature = "liquid")]
+        ensure!(!verbose, "verbose transactions are unsupported on liquid");
+
         Ok(json!(hex::encode(tx)))
     }
 
@@ -402,47 +945,102 @@ impl Connection {
     }
 
     fn handle_command(&mut self, method: &str, params: &[Value], id: &Value) -> Result<Value> {
-        let timer = self
-            .stats
-            .latency
-            .with_label_values(&[method])
-            .start_timer();
+        let _in_flight = match RateLimits::start_request(&self.limits, self.addr.ip()) {
+            Ok(in_flight) => in_flight,
+            Err(e) => {
+                debug!("[{}] rpc #{} {} refused: {}", self.addr, id, method, e);
+                return Ok(error_reply(id, &e));
+            }
+        };
+        let started_at = Instant::now();
         let result = match method {
+            #[cfg(not(feature = "liquid"))]
+            "blockchain.account.get_balance" => self.blockchain_account_get_balance(&params),
+            #[cfg(not(feature = "liquid"))]
+            "blockchain.account.get_history" => self.blockchain_account_get_history(&params),
+            #[cfg(not(feature = "liquid"))]
+            "blockchain.account.listunspent" => self.blockchain_account_listunspent(&params),
+            #[cfg(not(feature = "liquid"))]
+            "blockchain.address.get_balance" => self
+                .address_params(params)
+                .and_then(|params| self.blockchain_scripthash_get_balance(&params)),
+            "blockchain.address.get_history" => self
+                .address_params(params)
+                .and_then(|params| self.blockchain_scripthash_get_history(&params)),
+            "blockchain.address.listunspent" => self
+                .address_params(params)
+                .and_then(|params| self.blockchain_scripthash_listunspent(&params)),
             "blockchain.block.header" => self.blockchain_block_header(&params),
             "blockchain.block.headers" => self.blockchain_block_headers(&params),
             "blockchain.estimatefee" => self.blockchain_estimatefee(&params),
             "blockchain.headers.subscribe" => self.blockchain_headers_subscribe(),
+            "blockchain.outpoint.subscribe" => self.blockchain_outpoint_subscribe(&params),
+            "blockchain.outpoint.unsubscribe" => self.blockchain_outpoint_unsubscribe(&params),
             "blockchain.relayfee" => self.blockchain_relayfee(),
             #[cfg(not(feature = "liquid"))]
             "blockchain.scripthash.get_balance" => self.blockchain_scripthash_get_balance(&params),
             "blockchain.scripthash.get_history" => self.blockchain_scripthash_get_history(&params),
             "blockchain.scripthash.listunspent" => self.blockchain_scripthash_listunspent(&params),
             "blockchain.scripthash.subscribe" => self.blockchain_scripthash_subscribe(&params),
+            "blockchain.scripthash.unsubscribe" => self.blockchain_scripthash_unsubscribe(&params),
             "blockchain.transaction.broadcast" => self.blockchain_transaction_broadcast(&params),
+            "blockchain.transaction.check" => self.blockchain_transaction_check(&params),
             "blockchain.transaction.get" => self.blockchain_transaction_get(&params),
             "blockchain.transaction.get_merkle" => self.blockchain_transaction_get_merkle(&params),
             "blockchain.transaction.id_from_pos" => {
                 self.blockchain_transaction_id_from_pos(&params)
             }
             "mempool.get_fee_histogram" => self.mempool_get_fee_histogram(),
+            "admin.sessions" => self.admin_sessions(),
+            "admin.disconnect" => self.admin_disconnect(&params),
+            "admin.sync_status" => self.admin_sync_status(),
+            #[cfg(feature = "electrum-discovery")]
+            "admin.discovery_queue" => self.admin_discovery_queue(),
             "server.banner" => self.server_banner(),
             "server.donation_address" => self.server_donation_address(),
             "server.peers.subscribe" => self.server_peers_subscribe(),
             "server.ping" => Ok(Value::Null),
-            "server.version" => self.server_version(),
+            "server.session.resume" => self.server_session_resume(&params),
+            "server.session.token" => self.server_session_token(),
+            "server.version" => self.server_version(&params),
 
             #[cfg(feature = "electrum-discovery")]
             "server.features" => self.server_features(),
             #[cfg(feature = "electrum-discovery")]
             "server.add_peer" => self.server_add_peer(&params),
 
-            &_ => bail!("unknown method {} {:?}", method, params),
+            #[cfg(feature = "electrum-discovery")]
+            "discovery.list" => self.discovery_list(),
+            #[cfg(feature = "electrum-discovery")]
+            "discovery.add" => self.discovery_add(&params),
+            #[cfg(feature = "electrum-discovery")]
+            "discovery.check" => self.discovery_check(&params),
+            #[cfg(feature = "electrum-discovery")]
+            "discovery.pin" => self.discovery_pin(&params),
+            #[cfg(feature = "electrum-discovery")]
+            "discovery.remove" => self.discovery_remove(&params),
+            #[cfg(feature = "electrum-discovery")]
+            "discovery.ban" => self.discovery_ban(&params),
+            #[cfg(feature = "electrum-discovery")]
+            "discovery.unban" => self.discovery_unban(&params),
+
+            &_ => Err(ErrorKind::MethodNotFound(method.to_string()).into()),
         };
-        timer.observe_duration();
+        // unknown methods aren't labelled by name, as clients can make up any number of them
+        let label = match &result {
+            Err(e) if matches!(e.kind(), ErrorKind::MethodNotFound(_)) => "unknown",
+            _ => method,
+        };
+        self.stats
+            .latency
+            .with_label_values(&[label])
+            .observe(started_at.elapsed().as_secs_f64());
+        self.stats.requests.with_label_values(&[label]).inc();
         // TODO: return application errors should be sent to the client
         Ok(match result {
             Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
             Err(e) => {
+                self.stats.errors.with_label_values(&[label]).inc();
                 warn!(
                     "rpc #{} {} {:?} failed: {}",
                     id,
@@ -450,7 +1048,7 @@ impl Connection {
                     params,
                     e.display_chain()
                 );
-                json!({"jsonrpc": "2.0", "id": id, "error": format!("{}", e)})
+                error_reply(id, &e)
             }
         })
     }
@@ -475,9 +1073,12 @@ impl Connection {
             }
         }
         for (script_hash, status_hash) in self.status_hashes.iter_mut() {
-            let history_txids = get_history(&self.query, &script_hash[..], self.txs_limit)?;
-            let new_status_hash = get_status_hash(history_txids, &self.query)
-                .map_or(Value::Null, |h| json!(hex::encode(full_hash(&h[..]))));
+            let (query, txs_limit) = (&self.query, self.txs_limit);
+            let new_status_hash = self.status_cache.get_or_compute(script_hash, || {
+                let history_txids = get_history(query, &script_hash[..], txs_limit)?;
+                Ok(get_status_hash(history_txids, query)
+                    .map_or(Value::Null, |h| json!(hex::encode(full_hash(&h[..])))))
+            })?;
             if new_status_hash == *status_hash {
                 continue;
             }
@@ -487,40 +1088,104 @@ impl Connection {
                 "params": [script_hash, new_status_hash]}));
             *status_hash = new_status_hash;
         }
+        for (outpoint, status) in self.outpoint_statuses.iter_mut() {
+            let new_status = get_outpoint_status(&self.query, outpoint);
+            if new_status == *status {
+                continue;
+            }
+            result.push(json!({
+                "jsonrpc": "2.0",
+                "method": "blockchain.outpoint.subscribe",
+                "params": [[outpoint.txid, outpoint.vout], new_status]}));
+            *status = new_status;
+        }
         timer.observe_duration();
         Ok(result)
     }
 
+    /// Send the values with a single write, rather than one per value
     fn send_values(&mut self, values: &[Value]) -> Result<()> {
+        if values.is_empty() {
+            return Ok(());
+        }
+        let mut buf = vec![];
         for value in values {
-            let line = value.to_string() + "\n";
-            self.stream
-                .write_all(line.as_bytes())
-                .chain_err(|| format!("failed to send {}", value))?;
+            match self.framing {
+                Framing::Lines => {
+                    buf.extend(value.to_string().as_bytes());
+                    buf.push(b'\n');
+                }
+                Framing::WebSocket => websocket::write_text(&mut buf, &value.to_string())
+                    .chain_err(|| format!("failed to frame {}", value))?,
+            }
         }
-        Ok(())
+        self.stream
+            .write_all(&buf)
+            .chain_err(|| format!("failed to send {} values", values.len()))
     }
 
-    fn handle_replies(&mut self) -> Result<()> {
+    fn handle_request(&mut self, cmd: &Value) -> Result<Value> {
         let empty_params = json!([]);
+        match (
+            cmd.get("method"),
+            cmd.get("params").unwrap_or_else(|| &empty_params),
+            cmd.get("id"),
+        ) {
+            (Some(&Value::String(ref method)), &Value::Array(ref params), Some(ref id)) => {
+                self.handle_command(method, params, id)
+            }
+            _ => {
+                let id = cmd.get("id").unwrap_or(&Value::Null);
+                let e = ErrorKind::InvalidRequest(format!("invalid command: {}", cmd)).into();
+                Ok(error_reply(id, &e))
+            }
+        }
+    }
+
+    /// Handle a batch of requests, replying with their responses in the same order
+    fn handle_batch(&mut self, batch: &[Value]) -> Value {
+        let max_batch_size = self.max_batch_size;
+        batch_reply(batch, max_batch_size, |cmd| self.handle_request(cmd))
+    }
+
+    fn handle_replies(&mut self, session: &Session) -> Result<()> {
         loop {
-            let msg = self.chan.receiver().recv().chain_err(|| "channel closed")?;
+            let deadline = idle_deadline(
+                self.idle_timeout,
+                self.last_request_at,
+                self.subscription_count(),
+            );
+            let msg = match deadline {
+                // disconnect idle clients once due, rather than on the next periodic update
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    match self.chan.receiver().recv_timeout(timeout) {
+                        Ok(msg) => msg,
+                        Err(RecvTimeoutError::Timeout) => {
+                            info!("[{}] disconnecting idle peer", self.addr);
+                            self.stats.disconnects.with_label_values(&["idle"]).inc();
+                            return Ok(());
+                        }
+                        Err(RecvTimeoutError::Disconnected) => bail!("channel closed"),
+                    }
+                }
+                None => self.chan.receiver().recv().chain_err(|| "channel closed")?,
+            };
             trace!("RPC {:?}", msg);
             match msg {
                 Message::Request(line) => {
-                    let cmd: Value = from_str(&line).chain_err(|| "invalid JSON format")?;
-                    let reply = match (
-                        cmd.get("method"),
-                        cmd.get("params").unwrap_or_else(|| &empty_params),
-                        cmd.get("id"),
-                    ) {
-                        (
-                            Some(&Value::String(ref method)),
-                            &Value::Array(ref params),
-                            Some(ref id),
-                        ) => self.handle_command(method, params, id)?,
-                        _ => bail!("invalid command: {}", cmd),
+                    self.last_request_at = Instant::now();
+                    let reply = match from_str::<Value>(&line) {
+                        Ok(Value::Array(ref batch)) => self.handle_batch(batch),
+                        Ok(ref cmd) => self.handle_request(cmd)?,
+                        Err(e) => {
+                            debug!("[{}] invalid JSON: {}", self.addr, e);
+                            parse_error_reply()
+                        }
                     };
+                    session
+                        .subscriptions
+                        .store(self.subscription_count(), Ordering::Relaxed);
                     self.send_values(&[reply])?
                 }
                 Message::PeriodicUpdate => {
@@ -529,12 +1194,47 @@ impl Connection {
                         .chain_err(|| "failed to update subscriptions")?;
                     self.send_values(&values)?
                 }
+                Message::Control(frame) => self
+                    .stream
+                    .write_all(&frame)
+                    .chain_err(|| "failed to send control frame")?,
                 Message::Done => return Ok(()),
+                Message::Shutdown => {
+                    self.send_shutdown_notice()?;
+                    return Ok(());
+                }
             }
         }
     }
 
-    fn handle_requests(mut reader: BufReader<TcpStream>, tx: SyncSender<Message>) -> Result<()> {
+    /// Keep the subscriptions under the session's resume token, if it was issued one
+    fn save_subscriptions(&mut self) {
+        if let (Some(resume_tokens), Some(token)) = (&self.resume_tokens, self.resume_token.take())
+        {
+            if self.subscription_count() > 0 {
+                resume_tokens.save(
+                    token,
+                    Subscriptions {
+                        script_hashes: self.status_hashes.keys().cloned().collect(),
+                        outpoints: self.outpoint_statuses.keys().cloned().collect(),
+                    },
+                );
+            }
+        }
+    }
+
+    fn send_shutdown_notice(&mut self) -> Result<()> {
+        let notice =
+            json!({"jsonrpc": "2.0", "method": "server.banner", "params": [SHUTDOWN_NOTICE]});
+        self.send_values(&[notice])?;
+        if let Framing::WebSocket = self.framing {
+            websocket::write_close(&mut self.stream, websocket::CLOSE_GOING_AWAY)
+                .chain_err(|| "failed to send close frame")?;
+        }
+        Ok(())
+    }
+
+    fn handle_requests(mut reader: BufReader<Stream>, tx: SyncSender<Message>) -> Result<()> {
         loop {
             let mut line = Vec::<u8>::new();
             reader
@@ -562,22 +1262,70 @@ impl Connection {
         }
     }
 
+    /// Read WebSocket messages, each holding one or more newline-delimited requests
+    fn handle_websocket_requests(
+        mut reader: BufReader<Stream>,
+        tx: SyncSender<Message>,
+    ) -> Result<()> {
+        // control frames are answered by the replying thread, not to interleave with its replies
+        let mut control_replies = ControlReplies(tx.clone());
+        while let Some(message) = websocket::read_message(&mut reader, &mut control_replies)? {
+            for req in message.lines().filter(|line| !line.trim().is_empty()) {
+                tx.send(Message::Request(req.to_string()))
+                    .chain_err(|| "channel closed")?;
+            }
+        }
+        tx.send(Message::Done).chain_err(|| "channel closed")?;
+        Ok(())
+    }
+
     pub fn run(mut self) {
+        if let Err(e) = self.limits.connect(self.addr.ip()) {
+            warn!("[{}] rejecting connection: {}", self.addr, e);
+            self.stats
+                .disconnects
+                .with_label_values(&["connection_limit"])
+                .inc();
+            let _ = self.stream.shutdown(Shutdown::Both);
+            return;
+        }
+        let mut reader = BufReader::new(self.stream.try_clone().expect("failed to clone stream"));
+        if let Framing::WebSocket = self.framing {
+            if let Err(e) = websocket::accept(&mut reader, &mut self.stream) {
+                warn!("[{}] websocket handshake failed: {}", self.addr, e);
+                self.limits.disconnect(self.addr.ip(), 0);
+                let _ = self.stream.shutdown(Shutdown::Both);
+                return;
+            }
+        }
+        let session = self.sessions.add(
+            self.addr,
+            self.framing,
+            self.stream.try_clone().expect("failed to clone stream"),
+        );
         self.stats.clients.inc();
-        let reader = BufReader::new(self.stream.try_clone().expect("failed to clone TcpStream"));
         let tx = self.chan.sender();
-        let child = spawn_thread("reader", || Connection::handle_requests(reader, tx));
-        if let Err(e) = self.handle_replies() {
+        let child = match self.framing {
+            Framing::Lines => spawn_thread("reader", || Connection::handle_requests(reader, tx)),
+            Framing::WebSocket => spawn_thread("reader", || {
+                Connection::handle_websocket_requests(reader, tx)
+            }),
+        };
+        if let Err(e) = self.handle_replies(&session) {
             error!(
                 "[{}] connection handling failed: {}",
                 self.addr,
                 e.display_chain().to_string()
             );
         }
+        self.sessions.remove(session.id);
+        self.save_subscriptions();
         self.stats.clients.dec();
         self.stats
             .subscriptions
-            .sub(self.status_hashes.len() as i64);
+            .sub(self.subscription_count() as i64);
+        self.limits
+            .disconnect(self.addr.ip(), self.subscription_count());
 
         debug!("[{}] shutting down connection", self.addr);
         let _ = self.stream.shutdown(Shutdown::Both);
@@ -598,6 +1346,252 @@ fn get_history(
     Ok(history_txids)
 }
 
+/// Reply with the error, using the codes (and for some, the messages) of ElectrumX so that clients
+/// can tell them apart
+fn error_reply(id: &Value, e: &Error) -> Value {
+    let (code, message) = match e.kind() {
+        ErrorKind::TooPopular => (BAD_REQUEST, "history too large".to_string()),
+        ErrorKind::RateLimited(_) => (EXCESSIVE_RESOURCE_USAGE, e.to_string()),
+        ErrorKind::InvalidRequest(_) => (INVALID_REQUEST, e.to_string()),
+        ErrorKind::MethodNotFound(_) => (METHOD_NOT_FOUND, e.to_string()),
+        ErrorKind::Connection(_) => (DAEMON_ERROR, e.to_string()),
+        _ => (BAD_REQUEST, e.to_string()),
+    };
+    json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}})
+}
+
+/// Wait for the next notification, coalescing the periodic ones arriving within `window` after
+/// it. None once the channel is closed.
+fn next_notification(receiver: &Receiver<Notification>, window: Duration) -> Option<Notification> {
+    let mut msg = receiver.recv().ok()?;
+    if let Notification::Periodic = msg {
+        let deadline = Instant::now() + window;
+        while let Ok(next) =
+            receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        {
+            if let Notification::Exit = next {
+                msg = next;
+                break;
+            }
+        }
+    }
+    Some(msg)
+}
+
+/// When a client that last made a request at `last_request_at` is considered idle, unless it has
+/// subscriptions to keep alive
+fn idle_deadline(
+    idle_timeout: Option<Duration>,
+    last_request_at: Instant,
+    subscriptions: usize,
+) -> Option<Instant> {
+    idle_timeout
+        .filter(|_| subscriptions == 0)
+        .map(|timeout| last_request_at + timeout)
+}
+
+/// Reply to each request of the batch in order, the ones that fail with their own error object
+fn batch_reply<F>(batch: &[Value], max_batch_size: usize, mut handle: F) -> Value
+where
+    F: FnMut(&Value) -> Result<Value>,
+{
+    if batch.is_empty() || batch.len() > max_batch_size {
+        let e = format!("batch size must be between 1 and {}", max_batch_size);
+        return error_reply(&Value::Null, &ErrorKind::InvalidRequest(e).into());
+    }
+    let replies: Vec<Value> = batch
+        .iter()
+        .map(|cmd| {
+            handle(cmd).unwrap_or_else(|e| error_reply(cmd.get("id").unwrap_or(&Value::Null), &e))
+        })
+        .collect();
+    json!(replies)
+}
+
+/// The hostname, reason and optional duration (in seconds) of a peer ban
+#[cfg(feature = "electrum-discovery")]
+fn ban_from_params(params: &[Value]) -> Result<(String, &str, Option<Duration>)> {
+    let hostname = str_from_value(params.get(0), "hostname")?;
+    let reason = params
+        .get(1)
+        .and_then(Value::as_str)
+        .unwrap_or("unspecified");
+    let duration = params
+        .get(2)
+        .and_then(Value::as_u64)
+        .map(Duration::from_secs);
+    Ok((hostname, reason, duration))
+}
+
+fn parse_error_reply() -> Value {
+    json!({"jsonrpc": "2.0", "id": null, "error": {"code": PARSE_ERROR, "message": "invalid JSON"}})
+}
+
+/// The outpoint of the `blockchain.outpoint.*` methods. Their optional `spk_hint` param (the
+/// output's scriptPubKey) is validated but unused, as spends are looked up by outpoint directly.
+fn outpoint_from_params(params: &[Value]) -> Result<OutPoint> {
+    let txid = Txid::from(hash_from_value(params.get(0)).chain_err(|| "bad tx_hash")?);
+    let vout = u32_from_value(params.get(1), "txout_idx")?;
+    if let Some(spk_hint) = params.get(2) {
+        let spk_hint = str_from_value(Some(spk_hint), "spk_hint")?;
+        hex::decode(spk_hint).chain_err(|| "non-hex spk_hint")?;
+    }
+    Ok(OutPoint { txid, vout })
+}
+
+/// The status of an outpoint, as returned by `blockchain.outpoint.subscribe`: the height of the
+/// funding transaction and of the spending one (if any), using the mempool height conventions of
+/// `get_history`. Empty if the funding transaction is unknown.
+fn get_outpoint_status(query: &Query, outpoint: &OutPoint) -> Value {
+    let height = |txid: &Txid, blockid: Option<BlockId>| {
+        let has_unconfirmed_parents = blockid.is_none() && query.has_unconfirmed_parents(txid);
+        get_electrum_height(blockid, has_unconfirmed_parents)
+    };
+    let funding = query.chain().tx_confirming_block(&outpoint.txid);
+    if funding.is_none() && query.mempool().lookup_txn(&outpoint.txid).is_none() {
+        return json!({});
+    }
+    let mut status = json!({ "height": height(&outpoint.txid, funding) });
+    if let Some(spend) = query.lookup_spend(outpoint) {
+        status["spender_txhash"] = json!(spend.txid);
+        status["spender_height"] = json!(height(&spend.txid, spend.confirmed));
+    }
+    status
+}
+
+/// The `blockchain.block.headers` reply for the `headers` found from `start_height`, along with the
+/// `prove`n merkle branch of the last one to the root at `cp_height` if set
+fn block_headers_reply<F>(
+    start_height: usize,
+    headers: Vec<String>,
+    cp_height: usize,
+    prove: F,
+) -> Result<Value>
+where
+    F: FnOnce(usize) -> Result<(Vec<Sha256dHash>, Sha256dHash)>,
+{
+    if headers.is_empty() || cp_height == 0 {
+        return Ok(json!({
+            "count": headers.len(),
+            "hex": headers.join(""),
+            "max": MAX_HEADERS,
+        }));
+    }
+
+    // the proof is for the last header returned, which precedes the requested range's end if
+    // it goes past our tip
+    let last_height = start_height + headers.len() - 1;
+    let (branch, root) = prove(last_height)?;
+
+    Ok(json!({
+        "count": headers.len(),
+        "hex": headers.join(""),
+        "max": MAX_HEADERS,
+        "root": root,
+        "branch" : branch,
+    }))
+}
+
+/// Decode the transaction in the format of bitcoind's verbose `getrawtransaction`, with the
+/// confirmation details taken from our index
+#[cfg(not(feature = "liquid"))]
+fn verbose_transaction(query: &Query, raw: &[u8]) -> Result<Value> {
+    let tx: Transaction = deserialize(raw).chain_err(|| "invalid transaction")?;
+    let confirmed = query
+        .chain()
+        .tx_confirming_block(&tx.txid())
+        .map(|blockid| {
+            let confirmations = query.chain().best_height() + 1 - blockid.height;
+            (blockid, confirmations)
+        });
+    Ok(decode_transaction(&tx, raw, query.network(), confirmed))
+}
+
+/// The verbose `getrawtransaction` JSON of the transaction, along with its confirming block and
+/// number of confirmations if confirmed
+#[cfg(not(feature = "liquid"))]
+fn decode_transaction(
+    tx: &Transaction,
+    raw: &[u8],
+    network: Network,
+    confirmed: Option<(BlockId, usize)>,
+) -> Value {
+    let vin = tx
+        .input
+        .iter()
+        .map(|txin| {
+            let script_sig = &txin.script_sig;
+            let mut vin = if is_coinbase(txin) {
+                json!({ "coinbase": hex::encode(script_sig.as_bytes()) })
+            } else {
+                json!({
+                    "txid": txin.previous_output.txid,
+                    "vout": txin.previous_output.vout,
+                    "scriptSig": { "asm": script_sig.to_asm(), "hex": hex::encode(script_sig.as_bytes()) },
+                })
+            };
+            if !txin.witness.is_empty() {
+                vin["txinwitness"] = json!(txin.witness.iter().map(hex::encode).collect::<Vec<_>>());
+            }
+            vin["sequence"] = json!(txin.sequence);
+            vin
+        })
+        .collect::<Vec<_>>();
+
+    let vout = tx
+        .output
+        .iter()
+        .enumerate()
+        .map(|(n, txout)| {
+            let script = &txout.script_pubkey;
+            let address = bitcoin::Address::from_script(script, network.into());
+            let script_type = match address.as_ref().and_then(|a| a.address_type()) {
+                Some(AddressType::P2pkh) => "pubkeyhash",
+                Some(AddressType::P2sh) => "scripthash",
+                Some(AddressType::P2wpkh) => "witness_v0_keyhash",
+                Some(AddressType::P2wsh) => "witness_v0_scripthash",
+                Some(AddressType::P2tr) => "witness_v1_taproot",
+                _ if script.is_p2pk() => "pubkey",
+                _ if script.is_op_return() => "nulldata",
+                _ => "nonstandard",
+            };
+            let mut script_pubkey = json!({
+                "asm": script.to_asm(),
+                "hex": hex::encode(script.as_bytes()),
+                "type": script_type,
+            });
+            if let Some(address) = address {
+                script_pubkey["address"] = json!(address.to_string());
+            }
+            json!({
+                "value": txout.value as f64 / 100_000_000f64,
+                "n": n,
+                "scriptPubKey": script_pubkey,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut result = json!({
+        "txid": tx.txid(),
+        "hash": tx.wtxid(),
+        "version": tx.version,
+        "size": raw.len(),
+        "vsize": (tx.weight() + 3) / 4,
+        "weight": tx.weight(),
+        "locktime": tx.lock_time,
+        "vin": vin,
+        "vout": vout,
+        "hex": hex::encode(raw),
+    });
+    if let Some((blockid, confirmations)) = confirmed {
+        result["blockhash"] = json!(blockid.hash);
+        result["confirmations"] = json!(confirmations);
+        result["time"] = json!(blockid.time);
+        result["blocktime"] = json!(blockid.time);
+    }
+    result
+}
+
 #[derive(Serialize, Debug)]
 struct GetHistoryResult {
     #[serde(rename = "tx_hash")]
@@ -611,7 +1605,27 @@ struct GetHistoryResult {
 pub enum Message {
     Request(String),
     PeriodicUpdate,
+    /// A websocket control frame, like a pong, to send as is
+    Control(Vec<u8>),
     Done,
+    /// Reply to the requests received so far, then tell the client we're shutting down and close
+    Shutdown,
+}
+
+/// Sends each websocket control frame written to it to the replying thread
+struct ControlReplies(SyncSender<Message>);
+
+impl Write for ControlReplies {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .send(Message::Control(buf.to_vec()))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "channel closed"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 pub enum Notification {
@@ -619,6 +1633,35 @@ pub enum Notification {
     Exit,
 }
 
+/// Bind a unix socket at `path` that is never accessible with wider permissions than `mode`.
+/// It is bound in a private directory next to `path` and moved into place once restricted, as
+/// binding in place would create it per the umask until `set_permissions`.
+fn bind_unix_with_mode(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    let name = path.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "unix socket path has no file name",
+        )
+    })?;
+    let mut dir_name = std::ffi::OsString::from(".");
+    dir_name.push(name);
+    dir_name.push(".tmp");
+    let dir = path.with_file_name(dir_name);
+    if dir.exists() {
+        // left by a previous execution
+        fs::remove_dir_all(&dir)?;
+    }
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let tmp_path = dir.join("socket");
+    let bound = UnixListener::bind(&tmp_path).and_then(|listener| {
+        fs::set_permissions(&tmp_path, fs::Permissions::from_mode(mode))?;
+        fs::rename(&tmp_path, path)?;
+        Ok(listener)
+    });
+    fs::remove_dir_all(&dir).ok();
+    bound
+}
+
 pub struct RPC {
     notification: Sender<Notification>,
     server: Option<thread::JoinHandle<()>>, // so we can join the server while dropping this ojbect
@@ -626,21 +1669,156 @@ pub struct RPC {
 
 struct Stats {
     latency: HistogramVec,
+    requests: CounterVec,
+    errors: CounterVec,
     clients: Gauge,
     subscriptions: Gauge,
+    disconnects: CounterVec,
+}
+
+/// Scripthash statuses computed since the last update, shared by connections so that those
+/// subscribed to the same scripthashes don't compute their statuses again
+struct StatusCache {
+    generation: AtomicU64,
+    statuses: Mutex<(u64, HashMap<Sha256dHash, Value>)>, // (generation, ScriptHash -> StatusHash)
+}
+
+impl StatusCache {
+    fn new() -> Self {
+        StatusCache {
+            generation: AtomicU64::new(0),
+            statuses: Mutex::new((0, HashMap::new())),
+        }
+    }
+
+    /// Forget the cached statuses, once the chain or mempool are updated
+    fn invalidate(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn get_or_compute(
+        &self,
+        script_hash: &Sha256dHash,
+        compute: impl FnOnce() -> Result<Value>,
+    ) -> Result<Value> {
+        let generation = self.generation.load(Ordering::SeqCst);
+        {
+            let mut statuses = self.statuses.lock().unwrap();
+            if statuses.0 != generation {
+                *statuses = (generation, HashMap::new());
+            }
+            if let Some(status) = statuses.1.get(script_hash) {
+                return Ok(status.clone());
+            }
+        }
+        // computed without holding the lock, and only cached if no update happened meanwhile
+        let status = compute()?;
+        let mut statuses = self.statuses.lock().unwrap();
+        if statuses.0 == generation {
+            statuses.1.insert(*script_hash, status.clone());
+        }
+        Ok(status)
+    }
+}
+
+/// A connected client, as listed by `admin.sessions`
+struct Session {
+    id: u64,
+    addr: SocketAddr,
+    framing: Framing,
+    connected_at: Instant,
+    subscriptions: AtomicUsize,
+    stream: Stream, // a clone of the connection's stream, for disconnecting it
+}
+
+/// The connected clients, for the `admin.*` methods
+struct Sessions {
+    next_id: AtomicU64,
+    sessions: Mutex<HashMap<u64, Arc<Session>>>,
+}
+
+impl Sessions {
+    fn new() -> Self {
+        Sessions {
+            next_id: AtomicU64::new(0),
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn add(&self, addr: SocketAddr, framing: Framing, stream: Stream) -> Arc<Session> {
+        let session = Arc::new(Session {
+            id: self.next_id.fetch_add(1, Ordering::SeqCst),
+            addr,
+            framing,
+            connected_at: Instant::now(),
+            subscriptions: AtomicUsize::new(0),
+            stream,
+        });
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.insert(session.id, Arc::clone(&session));
+        session
+    }
+
+    fn remove(&self, id: u64) {
+        self.sessions.lock().unwrap().remove(&id);
+    }
+
+    fn list(&self) -> Vec<Value> {
+        let sessions = self.sessions.lock().unwrap();
+        let mut sessions: Vec<&Arc<Session>> = sessions.values().collect();
+        sessions.sort_by_key(|session| session.id);
+        sessions
+            .into_iter()
+            .map(|session| {
+                json!({
+                    "id": session.id,
+                    "addr": session.addr.to_string(),
+                    "framing": format!("{:?}", session.framing).to_lowercase(),
+                    "connected_secs": session.connected_at.elapsed().as_secs(),
+                    "subscriptions": session.subscriptions.load(Ordering::Relaxed),
+                })
+            })
+            .collect()
+    }
+
+    /// Close the connections of all sessions, returning how many there were
+    fn disconnect_all(&self) -> usize {
+        let sessions = self.sessions.lock().unwrap();
+        for session in sessions.values() {
+            let _ = session.stream.shutdown(Shutdown::Both);
+        }
+        sessions.len()
+    }
+
+    /// Close the connection of the session, which then winds down like any other disconnecting
+    /// client. Returns whether the session was found.
+    fn disconnect(&self, id: u64) -> bool {
+        let session = self.sessions.lock().unwrap().get(&id).cloned();
+        match session {
+            Some(session) => {
+                info!("[{}] disconnecting session {}", session.addr, id);
+                let _ = session.stream.shutdown(Shutdown::Both);
+                true
+            }
+            None => false,
+        }
+    }
 }
 
 impl RPC {
     fn start_notifier(
         notification: Channel<Notification>,
         senders: Arc<Mutex<Vec<SyncSender<Message>>>>,
-        acceptor: Sender<Option<(TcpStream, SocketAddr)>>,
+        status_cache: Arc<StatusCache>,
+        acceptor: Sender<Option<(Stream, SocketAddr, Framing, bool)>>,
     ) {
         spawn_thread("notification", move || {
-            for msg in notification.receiver().iter() {
+            let receiver = notification.receiver();
+            while let Some(msg) = next_notification(receiver, NOTIFICATION_WINDOW) {
                 let mut senders = senders.lock().unwrap();
                 match msg {
                     Notification::Periodic => {
+                        status_cache.invalidate();
                         for sender in senders.split_off(0) {
                             if let Err(TrySendError::Disconnected(_)) =
                                 sender.try_send(Message::PeriodicUpdate)
@@ -656,9 +1834,17 @@ impl RPC {
         });
     }
 
-    fn start_acceptor(addr: SocketAddr) -> Channel<Option<(TcpStream, SocketAddr)>> {
-        let chan = Channel::unbounded();
-        let acceptor = chan.sender();
+    /// Accept connections on `addr`, terminating TLS for them if `tls` is set. Connections from
+    /// the `trusted_proxies` are attributed to the client address of their PROXY header. The
+    /// connections of `admin` listeners may use the `admin.*` methods.
+    fn start_acceptor(
+        addr: SocketAddr,
+        framing: Framing,
+        tls: Option<Arc<TlsAcceptor>>,
+        admin: bool,
+        trusted_proxies: Vec<IpAddr>,
+        acceptor: Sender<Option<(Stream, SocketAddr, Framing, bool)>>,
+    ) {
         spawn_thread("acceptor", move || {
             let socket = create_socket(&addr);
             socket.listen(511).expect("setting backlog failed");
@@ -667,16 +1853,87 @@ impl RPC {
                 .expect("cannot set nonblocking to false");
             let listener = TcpListener::from(socket);
 
-            info!("Electrum RPC server running on {}", addr);
+            let ssl = if tls.is_some() { ", SSL" } else { "" };
+            let admin_note = if admin { ", admin" } else { "" };
+            info!(
+                "Electrum RPC server running on {} ({:?}{}{})",
+                addr, framing, ssl, admin_note
+            );
+            let trusted_proxies = Arc::new(trusted_proxies);
             loop {
-                let (stream, addr) = listener.accept().expect("accept failed");
+                let (stream, peer_addr) = listener.accept().expect("accept failed");
                 stream
                     .set_nonblocking(false)
                     .expect("failed to set connection as blocking");
-                acceptor.send(Some((stream, addr))).expect("send failed");
+                let (tls, acceptor) = (tls.clone(), acceptor.clone());
+                let forward = move |stream: TcpStream, addr: SocketAddr| {
+                    let stream = match &tls {
+                        Some(tls) => Stream::Tls(tls.accept(stream)),
+                        None => Stream::Plain(stream),
+                    };
+                    acceptor.send(Some((stream, addr, framing, admin))).is_ok()
+                };
+                if !trusted_proxies.contains(&peer_addr.ip()) {
+                    // the server stopped, closing the listener
+                    if !forward(stream, peer_addr) {
+                        break;
+                    }
+                    continue;
+                }
+                // wait on the PROXY header from a thread of its own, so that a slow proxy doesn't
+                // hold up accepting other connections
+                let trusted_proxies = Arc::clone(&trusted_proxies);
+                spawn_thread("proxy-header", move || {
+                    match proxy_protocol::client_addr(&stream, peer_addr, &trusted_proxies) {
+                        Ok(addr) => {
+                            forward(stream, addr);
+                        }
+                        Err(e) => warn!(
+                            "[{}] rejecting connection: {}",
+                            peer_addr,
+                            e.display_chain()
+                        ),
+                    }
+                });
+            }
+        });
+    }
+
+    /// Accept connections on the unix socket at `path`, created with the `mode` permissions if set
+    fn start_unix_acceptor(
+        path: PathBuf,
+        mode: Option<u32>,
+        acceptor: Sender<Option<(Stream, SocketAddr, Framing, bool)>>,
+    ) {
+        spawn_thread("unix-acceptor", move || {
+            if let Ok(meta) = fs::metadata(&path) {
+                // Cleanup socket file left by previous execution
+                if meta.file_type().is_socket() {
+                    fs::remove_file(&path).ok();
+                }
+            }
+            let listener = match mode {
+                Some(mode) => bind_unix_with_mode(&path, mode),
+                None => UnixListener::bind(&path),
+            }
+            .unwrap_or_else(|e| panic!("failed to bind unix socket {:?}: {}", path, e));
+
+            info!(
+                "Electrum RPC server running on unix socket {}",
+                path.display()
+            );
+            // unix socket clients are local, and exempt from the per-IP limits
+            let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+            for stream in listener.incoming() {
+                let stream = stream.expect("accept failed");
+                if acceptor
+                    .send(Some((Stream::Unix(stream), addr, Framing::Lines, false)))
+                    .is_err()
+                {
+                    break;
+                }
             }
         });
-        chan
     }
 
     pub fn start(config: Arc<Config>, query: Arc<Query>, metrics: &Metrics) -> RPC {
@@ -685,60 +1942,238 @@ impl RPC {
                 HistogramOpts::new("electrum_rpc", "Electrum RPC latency (seconds)"),
                 &["method"],
             ),
+            requests: metrics.counter_vec(
+                MetricOpts::new("electrum_requests", "# of Electrum RPC requests"),
+                &["method"],
+            ),
+            errors: metrics.counter_vec(
+                MetricOpts::new("electrum_errors", "# of failed Electrum RPC requests"),
+                &["method"],
+            ),
             clients: metrics.gauge(MetricOpts::new("electrum_clients", "# of Electrum clients")),
             subscriptions: metrics.gauge(MetricOpts::new(
                 "electrum_subscriptions",
                 "# of Electrum subscriptions",
             )),
+            disconnects: metrics.counter_vec(
+                MetricOpts::new(
+                    "electrum_disconnects",
+                    "# of Electrum clients disconnected by the server",
+                ),
+                &["reason"],
+            ),
         });
         stats.clients.set(0);
         stats.subscriptions.set(0);
 
         let notification = Channel::unbounded();
 
+        let tls = config.electrum_tls_cert.as_ref().map(|cert_path| {
+            let key_path = config.electrum_tls_key.as_ref().expect("missing TLS key");
+            let tls = TlsAcceptor::new(cert_path, key_path).unwrap_or_else(|e| {
+                panic!("failed to load TLS certificate: {}", e.display_chain())
+            });
+            Arc::new(tls)
+        });
+
         // Discovery is enabled when electrum-public-hosts is set
         #[cfg(feature = "electrum-discovery")]
-        let discovery = config.electrum_public_hosts.clone().map(|hosts| {
+        let discovery = config.electrum_public_hosts.clone().map(|mut hosts| {
             use crate::chain::genesis_hash;
+            if config.tor_only {
+                hosts.retain(|host, _| {
+                    let is_onion = host.ends_with(".onion");
+                    if !is_onion {
+                        warn!("not advertising clearnet host {} in tor-only mode", host);
+                    }
+                    is_onion
+                });
+            }
+            // advertise our SSL listeners, unless the hosts are reachable at other ports (like
+            // those of a proxy)
+            for ports in hosts.values_mut() {
+                if ports.ssl_port.is_none() {
+                    ports.ssl_port = config.electrum_ssl_addr.map(|addr| addr.port());
+                }
+                if ports.wss_port.is_none() {
+                    ports.wss_port = config.electrum_wss_addr.map(|addr| addr.port());
+                }
+            }
             let features = ServerFeatures {
                 hosts,
                 server_version: format!("electrs-esplora {}", ELECTRS_VERSION),
                 genesis_hash: genesis_hash(config.network_type),
-                protocol_min: PROTOCOL_VERSION,
+                protocol_min: PROTOCOL_VERSION_MIN,
                 protocol_max: PROTOCOL_VERSION,
                 hash_function: "sha256".into(),
                 pruning: None,
             };
-            let discovery = Arc::new(DiscoveryManager::new(
+            let mut discovery = DiscoveryManager::new(
                 config.network_type,
                 features,
                 PROTOCOL_VERSION,
                 config.electrum_announce,
                 config.tor_proxy,
-            ));
+                DiscoveryConfig {
+                    default_servers_path: config.electrum_default_servers.clone(),
+                    cert_verification: config.electrum_discovery_cert_verification,
+                    health_check_freq: config.electrum_discovery_check_freq,
+                    max_consecutive_failures: config.electrum_discovery_max_failures,
+                    max_queue_size: config.electrum_discovery_max_queue,
+                    max_servers_per_request: config.electrum_discovery_max_servers_per_request,
+                    max_services_per_request: config.electrum_discovery_max_services_per_request,
+                    interleave_transports: config.electrum_discovery_interleave,
+                    snapshot_dir: config.electrum_discovery_snapshot_dir.clone(),
+                    snapshot_interval: config.electrum_discovery_snapshot_interval,
+                    snapshot_retain: config.electrum_discovery_snapshot_retain,
+                    bootstrap_url: config.electrum_discovery_bootstrap_url.clone(),
+                    bootstrap_interval: config.electrum_discovery_bootstrap_interval,
+                    bootstrap_as_default: config.electrum_discovery_bootstrap_as_default,
+                    health_check_workers: config.electrum_discovery_workers,
+                    max_concurrent_onion_checks: config.electrum_discovery_max_onion_checks,
+                    request_limit_policy: config.electrum_discovery_request_limit_policy,
+                    trust_private_ranges: config.electrum_discovery_trust_private_ranges,
+                    require_self_advertised: config.electrum_discovery_require_self_advertised,
+                    pinned_readd_cooldown: config.electrum_discovery_pinned_readd_cooldown,
+                    verify_subscriptions: config.electrum_discovery_verify_subscriptions,
+                    banned_hosts: config.electrum_discovery_banned_hosts.clone(),
+                    banned_ranges: config.electrum_discovery_banned_ranges.clone(),
+                    ban_precedence: config.electrum_discovery_ban_precedence,
+                    latency_method: config.electrum_discovery_latency_method,
+                    reresolve_interval: config.electrum_discovery_reresolve_interval,
+                    reresolve_after_failures: config.electrum_discovery_reresolve_after_failures,
+                    gossip_lifetime: config.electrum_discovery_gossip_lifetime,
+                    crawl: config.electrum_discovery_crawl,
+                    max_crawled_per_peer: config.electrum_discovery_max_crawled_per_peer,
+                    sample_size: config.electrum_discovery_sample_size,
+                    sample_include_defaults: config.electrum_discovery_sample_include_defaults,
+                    max_requests_per_ip: config.electrum_discovery_max_requests_per_ip,
+                    request_window: config.electrum_discovery_request_window,
+                    max_pending_per_ip: config.electrum_discovery_max_pending_per_ip,
+                    min_score: config.electrum_discovery_min_score,
+                    min_score_checks: config.electrum_discovery_min_score_checks,
+                    features_timeout: config.electrum_discovery_timeout,
+                    max_features_no_response: config.electrum_discovery_max_features_no_response,
+                    max_tracked_ips: config.electrum_max_tracked_ips,
+                    state_path: Some(config.db_path.join("discovery-state.json")),
+                    tor_only: config.tor_only,
+                    ..Default::default()
+                },
+            )
+            .with_saved_state()
+            .with_chain_tip(Arc::new(IndexedTip(Arc::clone(&query))))
+            .with_metrics(metrics);
+            if let Some(path) = &config.electrum_discovery_asn_db {
+                let lookup = Ip2AsnLookup::from_file(path).unwrap_or_else(|e| {
+                    panic!("failed to load the ASN database: {}", e.display_chain())
+                });
+                discovery = discovery.with_asn_lookup(Arc::new(lookup));
+            }
+            if let Some(control_addr) = config.tor_control {
+                match OnionService::create(control_addr, config.electrum_rpc_addr) {
+                    Ok(service) => discovery = discovery.with_onion_service(service),
+                    Err(e) => warn!("failed creating onion service: {}", e.display_chain()),
+                }
+            }
+            let discovery = Arc::new(discovery);
             DiscoveryManager::spawn_jobs_thread(Arc::clone(&discovery));
             discovery
         });
 
         let rpc_addr = config.electrum_rpc_addr;
+        let ws_addr = config.electrum_ws_addr;
+        let ssl_addr = config.electrum_ssl_addr;
+        let wss_addr = config.electrum_wss_addr;
+        let admin_addr = config.electrum_admin_addr;
+        let trusted_proxies = config.trusted_proxies.clone();
+        let socket_file = config.electrum_socket_file.clone();
+        let socket_mode = config.electrum_socket_mode;
         let txs_limit = config.electrum_txs_limit;
+        let max_batch_size = config.electrum_max_batch_size;
+        let idle_timeout = config.electrum_idle_timeout;
+        let drain_timeout = config.electrum_drain_timeout;
+        let limits = Arc::new(RateLimits::new(RateLimitConfig {
+            requests_per_sec: config.electrum_rate_limit,
+            max_connections: config.electrum_max_connections_per_ip,
+            max_in_flight: config.electrum_max_in_flight,
+            max_subscriptions: config.electrum_max_subscriptions,
+            max_subscriptions_per_ip: config.electrum_max_subscriptions_per_ip,
+            max_tracked_ips: Some(config.electrum_max_tracked_ips),
+        }));
+        // unix socket clients are only told apart by their (shared) placeholder address, so they
+        // are kept out of the per-IP limits of local TCP clients and only limited per connection
+        let unix_limits = Arc::new(RateLimits::new(RateLimitConfig {
+            max_subscriptions: config.electrum_max_subscriptions,
+            ..Default::default()
+        }));
+        let banner = Arc::new(Banner::new(
+            config.electrum_banner.clone(),
+            config.electrum_banner_file.clone(),
+        ));
+        let status_cache = Arc::new(StatusCache::new());
+        let sessions = Arc::new(Sessions::new());
+        let resume_tokens = config
+            .electrum_resume_ttl
+            .map(|ttl| Arc::new(ResumeTokens::new(ttl)));
 
         RPC {
             notification: notification.sender(),
             server: Some(spawn_thread("rpc", move || {
                 let senders = Arc::new(Mutex::new(Vec::<SyncSender<Message>>::new()));
 
-                let acceptor = RPC::start_acceptor(rpc_addr);
-                RPC::start_notifier(notification, senders.clone(), acceptor.sender());
+                let acceptor = Channel::unbounded();
+                let listeners = vec![
+                    (Some(rpc_addr), Framing::Lines, None, false),
+                    (ws_addr, Framing::WebSocket, None, false),
+                    (ssl_addr, Framing::Lines, tls.clone(), false),
+                    (wss_addr, Framing::WebSocket, tls.clone(), false),
+                    (admin_addr, Framing::Lines, None, true),
+                ];
+                for (addr, framing, tls, admin) in listeners {
+                    if let Some(addr) = addr {
+                        // the operator connects to the admin listener directly
+                        let trusted_proxies = if admin {
+                            vec![]
+                        } else {
+                            trusted_proxies.clone()
+                        };
+                        RPC::start_acceptor(
+                            addr,
+                            framing,
+                            tls,
+                            admin,
+                            trusted_proxies,
+                            acceptor.sender(),
+                        );
+                    }
+                }
+                if let Some(path) = socket_file {
+                    RPC::start_unix_acceptor(path, socket_mode, acceptor.sender());
+                }
+                RPC::start_notifier(
+                    notification,
+                    senders.clone(),
+                    Arc::clone(&status_cache),
+                    acceptor.sender(),
+                );
 
                 let mut threads = HashMap::new();
                 let (garbage_sender, garbage_receiver) = crossbeam_channel::unbounded();
 
-                while let Some((stream, addr)) = acceptor.receiver().recv().unwrap() {
+                while let Some((stream, addr, framing, admin)) = acceptor.receiver().recv().unwrap()
+                {
                     // explicitely scope the shadowed variables for the new thread
                     let query = Arc::clone(&query);
                     let senders = Arc::clone(&senders);
                     let stats = Arc::clone(&stats);
+                    let limits = match stream {
+                        Stream::Unix(_) => Arc::clone(&unix_limits),
+                        _ => Arc::clone(&limits),
+                    };
+                    let banner = Arc::clone(&banner);
+                    let status_cache = Arc::clone(&status_cache);
+                    let sessions = Arc::clone(&sessions);
+                    let resume_tokens = resume_tokens.clone();
                     let garbage_sender = garbage_sender.clone();
                     #[cfg(feature = "electrum-discovery")]
                     let discovery = discovery.clone();
@@ -749,8 +2184,17 @@ impl RPC {
                             query,
                             stream,
                             addr,
+                            framing,
+                            admin,
                             stats,
+                            limits,
+                            banner,
+                            status_cache,
+                            sessions,
+                            resume_tokens,
                             txs_limit,
+                            max_batch_size,
+                            idle_timeout,
                             #[cfg(feature = "electrum-discovery")]
                             discovery,
                         );
@@ -772,15 +2216,43 @@ impl RPC {
                     }
                 }
 
-                trace!("closing {} RPC connections", senders.lock().unwrap().len());
+                // connections accepted meanwhile are closed rather than served
+                for (stream, _, _, _) in acceptor.receiver().try_iter().flatten() {
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+
+                // let the connections reply to the requests they already received. connections
+                // too busy to take the message are disconnected once the drain timeout is over.
+                info!("draining {} RPC connections", threads.len());
                 for sender in senders.lock().unwrap().iter() {
-                    let _ = sender.send(Message::Done);
+                    let _ = sender.try_send(Message::Shutdown);
+                }
+                let deadline = Instant::now() + drain_timeout;
+                while !threads.is_empty() {
+                    match garbage_receiver.recv_deadline(deadline) {
+                        Ok(id) => {
+                            if let Some(thread) = threads.remove(&id) {
+                                if let Err(error) = thread.join() {
+                                    error!("failed to join {:?}: {:?}", id, error);
+                                }
+                            }
+                        }
+                        Err(_) => break,
+                    }
+                }
+                if !threads.is_empty() {
+                    // the remaining threads are left to be stopped along with the process
+                    let disconnected = sessions.disconnect_all();
+                    warn!(
+                        "disconnected {} RPC connections that didn't drain in time",
+                        disconnected
+                    );
                 }
 
-                for (id, thread) in threads {
-                    trace!("joining {:?}", id);
-                    if let Err(error) = thread.join() {
-                        error!("failed to join {:?}: {:?}", id, error);
+                #[cfg(feature = "electrum-discovery")]
+                if let Some(discovery) = discovery {
+                    if let Err(e) = discovery.save_state() {
+                        warn!("failed saving discovery state: {}", e.display_chain());
                     }
                 }
 
@@ -804,3 +2276,307 @@ impl Drop for RPC {
         trace!("RPC server is stopped");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+
+    #[test]
+    fn test_negotiate_version() {
+        let negotiate = |val: Value| negotiate_version(Some(&val)).map(|v| v.to_string());
+
+        // the minimal version when none is requested
+        assert_eq!(negotiate_version(None).unwrap(), PROTOCOL_VERSION_MIN);
+        assert_eq!(negotiate(json!("1.4")).unwrap(), "1.4");
+        assert_eq!(negotiate(json!("1.4.2")).unwrap(), "1.4");
+        // the highest version in common within ranges
+        assert_eq!(negotiate(json!(["1.4", "1.5"])).unwrap(), "1.5");
+        assert_eq!(negotiate(json!(["1.2", "1.9"])).unwrap(), "1.5");
+        assert_eq!(negotiate(json!(["1.2", "1.4"])).unwrap(), "1.4");
+
+        assert!(negotiate(json!("1.2")).is_err());
+        assert!(negotiate(json!(["1.6", "1.9"])).is_err());
+        assert!(negotiate(json!(["1.2", "1.3"])).is_err());
+        assert!(negotiate(json!(["1.4"])).is_err());
+        assert!(negotiate(json!(1.4)).is_err());
+    }
+
+    #[test]
+    fn test_status_cache() {
+        let cache = StatusCache::new();
+        let (hash, other) = (Sha256dHash::default(), Sha256dHash::hash(b"other"));
+        let computed = AtomicUsize::new(0);
+        let compute = |status: Value| -> Result<Value> {
+            computed.fetch_add(1, Ordering::SeqCst);
+            Ok(status)
+        };
+
+        // computed once, then shared until invalidated
+        assert_eq!(
+            cache.get_or_compute(&hash, || compute(json!("a"))).unwrap(),
+            json!("a")
+        );
+        assert_eq!(
+            cache.get_or_compute(&hash, || compute(json!("b"))).unwrap(),
+            json!("a")
+        );
+        assert_eq!(
+            cache
+                .get_or_compute(&other, || compute(json!("c")))
+                .unwrap(),
+            json!("c")
+        );
+        assert_eq!(computed.load(Ordering::SeqCst), 2);
+        cache.invalidate();
+        assert_eq!(
+            cache.get_or_compute(&hash, || compute(json!("b"))).unwrap(),
+            json!("b")
+        );
+        assert_eq!(computed.load(Ordering::SeqCst), 3);
+
+        // failures aren't cached
+        assert!(cache.get_or_compute(&other, || bail!("failed")).is_err());
+        assert_eq!(
+            cache
+                .get_or_compute(&other, || compute(json!("d")))
+                .unwrap(),
+            json!("d")
+        );
+
+        // a status computed while the cache is invalidated is returned, but not cached
+        let stale = cache.get_or_compute(&hash, || {
+            cache.invalidate();
+            Ok(json!("stale"))
+        });
+        assert_eq!(stale.unwrap(), json!("stale"));
+        assert_eq!(
+            cache.get_or_compute(&hash, || compute(json!("e"))).unwrap(),
+            json!("e")
+        );
+    }
+
+    #[test]
+    fn test_next_notification() {
+        let window = Duration::from_millis(50);
+        let (sender, receiver) = std::sync::mpsc::channel();
+
+        // periodic notifications within the window are coalesced
+        for _ in 0..3 {
+            sender.send(Notification::Periodic).unwrap();
+        }
+        assert!(matches!(
+            next_notification(&receiver, window),
+            Some(Notification::Periodic)
+        ));
+        assert!(receiver.try_recv().is_err());
+
+        // but the ones after it aren't
+        sender.send(Notification::Periodic).unwrap();
+        let sent = sender.clone();
+        let later = thread::spawn(move || {
+            thread::sleep(window * 4);
+            sent.send(Notification::Periodic).unwrap();
+        });
+        assert!(matches!(
+            next_notification(&receiver, window),
+            Some(Notification::Periodic)
+        ));
+        later.join().unwrap();
+        assert!(matches!(
+            next_notification(&receiver, window),
+            Some(Notification::Periodic)
+        ));
+
+        // exiting isn't delayed by the pending updates
+        sender.send(Notification::Periodic).unwrap();
+        sender.send(Notification::Exit).unwrap();
+        sender.send(Notification::Periodic).unwrap();
+        assert!(matches!(
+            next_notification(&receiver, window),
+            Some(Notification::Exit)
+        ));
+
+        drop(sender);
+        assert!(receiver.try_recv().is_ok());
+        assert!(next_notification(&receiver, window).is_none());
+    }
+
+    #[test]
+    fn test_idle_deadline() {
+        let now = Instant::now();
+        let timeout = Duration::from_secs(60);
+        assert_eq!(idle_deadline(Some(timeout), now, 0), Some(now + timeout));
+        // subscribed clients and disabled timeouts are never idle
+        assert_eq!(idle_deadline(Some(timeout), now, 1), None);
+        assert_eq!(idle_deadline(None, now, 0), None);
+    }
+
+    #[test]
+    #[cfg(not(feature = "liquid"))]
+    fn test_decode_transaction() {
+        use bitcoin::blockdata::constants::genesis_block;
+        use bitcoin::{Address, Script, TxIn, TxOut, Witness};
+
+        let block = genesis_block(bitcoin::Network::Regtest);
+        let coinbase = &block.txdata[0];
+        let blockid = BlockId {
+            height: 0,
+            hash: block.block_hash(),
+            time: block.header.time,
+        };
+        let raw = serialize(coinbase);
+        let decoded = decode_transaction(coinbase, &raw, Network::Regtest, Some((blockid, 3)));
+        assert_eq!(decoded["txid"], json!(coinbase.txid()));
+        assert_eq!(decoded["hex"], json!(hex::encode(&raw)));
+        assert!(decoded["vin"][0]["coinbase"].is_string());
+        assert_eq!(decoded["vout"][0]["value"], json!(50.0));
+        assert_eq!(decoded["vout"][0]["scriptPubKey"]["type"], "pubkey");
+        assert!(decoded["vout"][0]["scriptPubKey"].get("address").is_none());
+        assert_eq!(decoded["blockhash"], json!(block.block_hash()));
+        assert_eq!(decoded["confirmations"], 3);
+        assert_eq!(decoded["blocktime"], block.header.time);
+
+        // a segwit spend, unconfirmed
+        let address = Address::p2wsh(&Script::new(), bitcoin::Network::Regtest);
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: bitcoin::OutPoint::new(coinbase.txid(), 0),
+                script_sig: Script::new(),
+                sequence: 0xffff_fffd,
+                witness: Witness::from_vec(vec![vec![1, 2], vec![3]]),
+            }],
+            output: vec![TxOut {
+                value: 1_000,
+                script_pubkey: address.script_pubkey(),
+            }],
+        };
+        let raw = serialize(&tx);
+        let decoded = decode_transaction(&tx, &raw, Network::Regtest, None);
+        let vin = &decoded["vin"][0];
+        assert_eq!(vin["txid"], json!(coinbase.txid()));
+        assert_eq!(vin["vout"], 0);
+        assert_eq!(vin["txinwitness"], json!(["0102", "03"]));
+        assert_eq!(vin["sequence"], 0xffff_fffdu32);
+        let vout = &decoded["vout"][0];
+        assert_eq!(vout["value"], json!(0.00001));
+        assert_eq!(vout["scriptPubKey"]["type"], "witness_v0_scripthash");
+        assert_eq!(vout["scriptPubKey"]["address"], address.to_string());
+        assert_eq!(decoded["hash"], json!(tx.wtxid()));
+        assert_ne!(decoded["hash"], decoded["txid"]);
+        assert!(decoded.get("confirmations").is_none());
+    }
+
+    #[test]
+    #[cfg(not(feature = "liquid"))]
+    fn test_address_params() {
+        let genesis = json!("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa");
+        let params = address_params(&[genesis.clone(), json!(true)], Network::Bitcoin, true);
+        let script_hash = "8b01df4e368ea28f8dc0423bcf7a4923e3a12d307c875e47a0cfbf90b5c39161";
+        assert_eq!(params.unwrap(), vec![json!(script_hash), json!(true)]);
+        let params = address_params(&[genesis.clone()], Network::Bitcoin, true).unwrap();
+        assert_eq!(
+            hash_from_value(params.get(0)).unwrap().to_string(),
+            script_hash
+        );
+
+        // testnet addresses are valid on regtest and signet, but not on mainnet
+        let testnet = json!("mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfn");
+        assert!(address_params(&[testnet.clone()], Network::Regtest, true).is_ok());
+        assert!(address_params(&[testnet.clone()], Network::Signet, true).is_ok());
+        assert!(address_params(&[testnet], Network::Bitcoin, true).is_err());
+        assert!(address_params(&[genesis.clone()], Network::Testnet, true).is_err());
+
+        assert!(address_params(&[json!("invalid")], Network::Bitcoin, true).is_err());
+        assert!(address_params(&[], Network::Bitcoin, true).is_err());
+        assert!(address_params(&[genesis], Network::Bitcoin, false).is_err());
+    }
+
+    #[test]
+    fn test_txs_from_params() {
+        let txs = txs_from_params(&[json!("0100"), json!("0200")]).unwrap();
+        assert_eq!(txs, vec!["0100", "0200"]);
+        assert!(txs_from_params(&[]).is_err());
+        assert!(txs_from_params(&[json!("0100"), json!(2)]).is_err());
+    }
+
+    #[test]
+    fn test_block_headers_reply() {
+        type Proof = Result<(Vec<Sha256dHash>, Sha256dHash)>;
+        let headers = vec!["aa".to_string(), "bb".to_string()];
+        let branch = vec![Sha256dHash::hash(b"sibling")];
+        let root = Sha256dHash::hash(b"root");
+        let prove = |height: usize| -> Proof {
+            assert_eq!(height, 11);
+            Ok((branch.clone(), root))
+        };
+
+        // the last returned header is proven, like when the requested range goes past the tip
+        let reply = block_headers_reply(10, headers.clone(), 20, prove).unwrap();
+        assert_eq!(reply["count"], 2);
+        assert_eq!(reply["hex"], "aabb");
+        assert_eq!(reply["root"], json!(root));
+        assert_eq!(reply["branch"], json!(branch));
+
+        let unproven = |_: usize| -> Proof { bail!("unexpected proof") };
+        let reply = block_headers_reply(10, headers, 0, unproven).unwrap();
+        assert!(reply.get("root").is_none());
+        let reply = block_headers_reply(10, vec![], 20, unproven).unwrap();
+        assert_eq!(reply["count"], 0);
+        assert!(reply.get("branch").is_none());
+
+        assert!(
+            block_headers_reply(10, vec!["aa".into()], 5, |_| bail!("cp_height < height")).is_err()
+        );
+    }
+
+    #[test]
+    fn test_batch_reply() {
+        let handle = |cmd: &Value| -> Result<Value> {
+            match cmd["method"].as_str() {
+                Some("ok") => Ok(json!({"jsonrpc": "2.0", "id": cmd["id"], "result": true})),
+                Some("busy") => bail!(ErrorKind::RateLimited("too many requests".into())),
+                _ => bail!("failed"),
+            }
+        };
+        let batch = vec![
+            json!({"id": 1, "method": "ok"}),
+            json!({"id": 2, "method": "busy"}),
+            json!({"id": 3, "method": "fail"}),
+            json!({"id": 4, "method": "ok"}),
+        ];
+        // a failing request doesn't affect the others
+        let reply = batch_reply(&batch, 10, handle);
+        let replies = reply.as_array().unwrap();
+        assert_eq!(replies.len(), 4);
+        assert_eq!(replies[0]["result"], json!(true));
+        assert_eq!(replies[1]["id"], json!(2));
+        assert_eq!(replies[1]["error"]["code"], json!(EXCESSIVE_RESOURCE_USAGE));
+        assert_eq!(replies[2]["id"], json!(3));
+        assert_eq!(replies[2]["error"]["code"], json!(BAD_REQUEST));
+        assert_eq!(replies[3]["id"], json!(4));
+        assert_eq!(replies[3]["result"], json!(true));
+
+        // batches out of bounds get a single error
+        for batch in &[vec![], batch.clone()] {
+            let reply = batch_reply(batch, 3, handle);
+            assert_eq!(reply["id"], Value::Null);
+            assert_eq!(reply["error"]["code"], json!(INVALID_REQUEST));
+        }
+    }
+
+    #[test]
+    fn test_bind_unix_with_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("electrum.sock");
+        let _listener = bind_unix_with_mode(&path, 0o600).unwrap();
+        let meta = fs::metadata(&path).unwrap();
+        assert!(meta.file_type().is_socket());
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        // the private directory it was bound in is gone
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+        UnixStream::connect(&path).unwrap();
+    }
+}
@@ -0,0 +1,276 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use error_chain::ChainedError;
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::{NoClientAuth, ServerConfig, ServerSession, Session};
+
+use crate::errors::*;
+
+/// Terminates TLS for the Electrum listeners, using a PEM certificate chain and private key.
+/// These are re-read on SIGHUP, so that renewed certificates (like those of Let's Encrypt) can be
+/// picked up by a deploy hook without restarting. The reload happens lazily, when accepting the
+/// next connection after the signal, so it doesn't affect the established ones.
+///
+/// The TLS streams are implemented over rustls sessions directly, rather than with its
+/// `StreamOwned`, as connections are read and written from separate threads.
+pub struct TlsAcceptor {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    config: RwLock<Arc<ServerConfig>>,
+    reload_requested: Arc<AtomicBool>,
+}
+
+impl TlsAcceptor {
+    pub fn new(cert_path: &Path, key_path: &Path) -> Result<Self> {
+        let reload_requested = Arc::new(AtomicBool::new(false));
+        if let Err(e) =
+            signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&reload_requested))
+        {
+            warn!("failed registering the SIGHUP handler: {:?}", e);
+        }
+        Ok(TlsAcceptor {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            config: RwLock::new(load_config(cert_path, key_path)?),
+            reload_requested,
+        })
+    }
+
+    /// Start a TLS session over the accepted connection. The handshake happens on first use.
+    /// Reloads the certificate first if SIGHUP was received since the last connection.
+    pub fn accept(&self, sock: TcpStream) -> TlsStream {
+        if self.reload_requested.swap(false, Ordering::SeqCst) {
+            match load_config(&self.cert_path, &self.key_path) {
+                Ok(config) => {
+                    info!("reloaded TLS certificate {:?}", self.cert_path);
+                    *self.config.write().unwrap() = config;
+                }
+                Err(e) => warn!("keeping the current TLS certificate: {}", e.display_chain()),
+            }
+        }
+        let session = ServerSession::new(&self.config.read().unwrap());
+        TlsStream {
+            session: Arc::new(Mutex::new(session)),
+            sock,
+        }
+    }
+}
+
+fn load_config(cert_path: &Path, key_path: &Path) -> Result<Arc<ServerConfig>> {
+    let open = |path: &Path| -> Result<BufReader<File>> {
+        let file = File::open(path).chain_err(|| format!("failed to open {:?}", path))?;
+        Ok(BufReader::new(file))
+    };
+    let chain = certs(&mut open(cert_path)?)
+        .map_err(|()| format!("invalid certificate {:?}", cert_path))?;
+    ensure!(!chain.is_empty(), "no certificate found in {:?}", cert_path);
+    let mut keys = pkcs8_private_keys(&mut open(key_path)?)
+        .map_err(|()| format!("invalid private key {:?}", key_path))?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut open(key_path)?)
+            .map_err(|()| format!("invalid private key {:?}", key_path))?;
+    }
+    let key = keys
+        .into_iter()
+        .next()
+        .chain_err(|| format!("no private key found in {:?}", key_path))?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(chain, key)
+        .chain_err(|| "invalid certificate or private key")?;
+    Ok(Arc::new(config))
+}
+
+/// A TLS connection, which can be cloned to read and write from separate threads (as
+/// connections are handled). Reads from the socket happen without holding the session lock, so
+/// that blocking on them doesn't hold up writes.
+pub struct TlsStream {
+    session: Arc<Mutex<ServerSession>>,
+    sock: TcpStream,
+}
+
+impl TlsStream {
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(TlsStream {
+            session: Arc::clone(&self.session),
+            sock: self.sock.try_clone()?,
+        })
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        let mut session = self.session.lock().unwrap();
+        session.send_close_notify();
+        let _ = session.write_tls(&mut &self.sock);
+        self.sock.shutdown(how)
+    }
+
+    /// Send out the pending TLS records, like handshake messages or encrypted data
+    fn flush_tls(&self, session: &mut ServerSession) -> io::Result<()> {
+        while session.wants_write() {
+            session.write_tls(&mut &self.sock)?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut records = [0u8; 16 * 1024];
+        loop {
+            match self.session.lock().unwrap().read(buf) {
+                Ok(0) => (),
+                Ok(len) => return Ok(len),
+                // sent once the client closes the session
+                Err(e) if e.kind() == io::ErrorKind::ConnectionAborted => return Ok(0),
+                Err(e) => return Err(e),
+            }
+            let len = (&self.sock).read(&mut records)?;
+            if len == 0 {
+                return Ok(0);
+            }
+            let mut session = self.session.lock().unwrap();
+            let mut pending = &records[..len];
+            while !pending.is_empty() {
+                session.read_tls(&mut pending)?;
+                session
+                    .process_new_packets()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            }
+            self.flush_tls(&mut session)?;
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut session = self.session.lock().unwrap();
+        let len = session.write(buf)?;
+        self.flush_tls(&mut session)?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut session = self.session.lock().unwrap();
+        session.flush()?;
+        self.flush_tls(&mut session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::{
+        Certificate, ClientConfig, ClientSession, RootCertStore, ServerCertVerified,
+        ServerCertVerifier, StreamOwned, TLSError,
+    };
+    use std::fs;
+    use std::io::BufRead;
+    use std::net::TcpListener;
+    use std::thread;
+
+    const CERT: &str = include_str!("testdata/localhost.crt");
+    const KEY: &str = include_str!("testdata/localhost.key");
+    const RENEWED_CERT: &str = include_str!("testdata/renewed.crt");
+    const RENEWED_KEY: &str = include_str!("testdata/renewed.key");
+
+    /// Accepts the self-signed test certificates, which the tests tell apart themselves
+    struct AcceptAny;
+
+    impl ServerCertVerifier for AcceptAny {
+        fn verify_server_cert(
+            &self,
+            _roots: &RootCertStore,
+            _presented_certs: &[Certificate],
+            _dns_name: webpki::DNSNameRef,
+            _ocsp_response: &[u8],
+        ) -> std::result::Result<ServerCertVerified, TLSError> {
+            Ok(ServerCertVerified::assertion())
+        }
+    }
+
+    fn write_pem(dir: &Path, cert: &str, key: &str) -> (PathBuf, PathBuf) {
+        let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+        fs::write(&cert_path, cert).unwrap();
+        fs::write(&key_path, key).unwrap();
+        (cert_path, key_path)
+    }
+
+    /// Exchange a line each way over a connection accepted by `acceptor`, returning the
+    /// certificate the client was presented
+    fn exchange(acceptor: &TlsAcceptor) -> Certificate {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut config = ClientConfig::new();
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(AcceptAny));
+            let name = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
+            let session = ClientSession::new(&Arc::new(config), name);
+            let mut tls = StreamOwned::new(session, TcpStream::connect(addr).unwrap());
+            tls.write_all(b"ping\n").unwrap();
+            let mut line = String::new();
+            BufReader::new(&mut tls).read_line(&mut line).unwrap();
+            assert_eq!(line, "pong\n");
+            tls.sess.get_peer_certificates().unwrap().remove(0)
+        });
+
+        let (sock, _) = listener.accept().unwrap();
+        let mut tls = acceptor.accept(sock);
+        let mut reader = BufReader::new(tls.try_clone().unwrap());
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "ping\n");
+        tls.write_all(b"pong\n").unwrap();
+        tls.flush().unwrap();
+        client.join().unwrap()
+    }
+
+    fn der(pem: &str) -> Certificate {
+        certs(&mut pem.as_bytes()).unwrap().remove(0)
+    }
+
+    #[test]
+    fn test_handshake() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert_path, key_path) = write_pem(dir.path(), CERT, KEY);
+        let acceptor = TlsAcceptor::new(&cert_path, &key_path).unwrap();
+        assert_eq!(exchange(&acceptor), der(CERT));
+
+        // plaintext clients fail the handshake
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let (sock, _) = listener.accept().unwrap();
+        let mut tls = acceptor.accept(sock);
+        assert!(tls.read(&mut [0u8; 16]).is_err());
+
+        let missing = dir.path().join("missing.pem");
+        assert!(TlsAcceptor::new(&missing, &key_path).is_err());
+        assert!(TlsAcceptor::new(&key_path, &key_path).is_err());
+    }
+
+    #[test]
+    fn test_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert_path, key_path) = write_pem(dir.path(), CERT, KEY);
+        let acceptor = TlsAcceptor::new(&cert_path, &key_path).unwrap();
+
+        // renewed files are only picked up once reloading is requested, on the next accept
+        write_pem(dir.path(), RENEWED_CERT, RENEWED_KEY);
+        assert_eq!(exchange(&acceptor), der(CERT));
+        acceptor.reload_requested.store(true, Ordering::SeqCst);
+        assert_eq!(exchange(&acceptor), der(RENEWED_CERT));
+
+        // invalid files keep the current certificate
+        fs::write(&cert_path, "invalid").unwrap();
+        acceptor.reload_requested.store(true, Ordering::SeqCst);
+        assert_eq!(exchange(&acceptor), der(RENEWED_CERT));
+    }
+}
@@ -19,18 +19,37 @@ error_chain! {
             display("Too many history entries")
         }
 
+        RateLimited(msg: String) {
+            description("Excessive resource usage")
+            display("Excessive resource usage: {}", msg)
+        }
+
+        InvalidRequest(msg: String) {
+            description("Invalid request")
+            display("Invalid request: {}", msg)
+        }
+
+        MethodNotFound(method: String) {
+            description("Unknown method")
+            display("unknown method {}", method)
+        }
+
         #[cfg(feature = "electrum-discovery")]
-        ElectrumClient(e: electrum_client::Error) {
-            description("Electrum client error")
-            display("Electrum client error: {:?}", e)
+        IncompatibleServer(msg: String) {
+            description("Incompatible Electrum server")
+            display("Incompatible Electrum server: {}", msg)
         }
 
-    }
-}
+        #[cfg(feature = "electrum-discovery")]
+        HandshakeReset {
+            description("Connection reset during handshake")
+            display("Connection reset during handshake")
+        }
 
-#[cfg(feature = "electrum-discovery")]
-impl From<electrum_client::Error> for Error {
-    fn from(e: electrum_client::Error) -> Self {
-        Error::from(ErrorKind::ElectrumClient(e))
+        #[cfg(feature = "electrum-discovery")]
+        FeaturesNoResponse {
+            description("No response to server.features")
+            display("No response to server.features")
+        }
     }
 }
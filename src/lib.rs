@@ -22,6 +22,9 @@ extern crate serde_json;
 #[macro_use]
 extern crate lazy_static;
 
+#[cfg(not(feature = "liquid"))]
+pub mod account;
+pub mod broadcast;
 pub mod chain;
 pub mod config;
 pub mod daemon;
@@ -20,7 +20,7 @@ use crate::new_index::{
     compute_script_hash, schema::FullHash, ChainQuery, FundingInfo, ScriptStats, SpendingInfo,
     SpendingInput, TxHistoryInfo, Utxo,
 };
-use crate::util::fees::{make_fee_histogram, TxFeeInfo};
+use crate::util::fees::{make_fee_histogram, FeeHistogram, TxFeeInfo};
 use crate::util::{extract_tx_prevouts, full_hash, has_prevout, is_spendable, Bytes};
 
 #[cfg(feature = "liquid")]
@@ -38,6 +38,8 @@ pub struct Mempool {
     edges: HashMap<OutPoint, (Txid, u32)>,          // OutPoint -> (spending_txid, spending_vin)
     recent: ArrayDeque<[TxOverview; RECENT_TXS_SIZE], Wrapping>, // The N most recent txs to enter the mempool
     backlog_stats: (BacklogStats, Instant),
+    fee_histogram: FeeHistogram,
+    electrum_fee_histogram: Vec<(f64, u64)>,
 
     // monitoring
     latency: HistogramVec, // mempool requests latency
@@ -75,6 +77,8 @@ impl Mempool {
                 BacklogStats::default(),
                 Instant::now() - Duration::from_secs(BACKLOG_STATS_TTL),
             ),
+            fee_histogram: FeeHistogram::default(),
+            electrum_fee_histogram: vec![],
             latency: metrics.histogram_vec(
                 HistogramOpts::new("mempool_latency", "Mempool requests latency (in seconds)"),
                 &["part"],
@@ -151,6 +155,13 @@ impl Mempool {
             .collect()
     }
 
+    /// The funding and spending entries of the scripthash's mempool history
+    pub fn history_entries(&self, scripthash: &[u8]) -> &[TxHistoryInfo] {
+        self.history
+            .get(scripthash)
+            .map_or(&[], |entries| &entries[..])
+    }
+
     pub fn history_txids(&self, scripthash: &[u8], limit: usize) -> Vec<Txid> {
         let _timer = self
             .latency
@@ -276,6 +287,11 @@ impl Mempool {
         &self.backlog_stats.0
     }
 
+    /// The fee histogram of the electrum `mempool.get_fee_histogram` method, as of the last update
+    pub fn electrum_fee_histogram(&self) -> &[(f64, u64)] {
+        &self.electrum_fee_histogram
+    }
+
     pub fn update(&mut self, daemon: &Daemon) -> Result<()> {
         let _timer = self.latency.with_label_values(&["update"]).start_timer();
         let new_txids = daemon
@@ -302,6 +318,8 @@ impl Mempool {
             .with_label_values(&["txs"])
             .set(self.txstore.len() as f64);
 
+        self.electrum_fee_histogram = self.fee_histogram.compact();
+
         // Update cached backlog stats (if expired)
         if self.backlog_stats.1.elapsed() > Duration::from_secs(BACKLOG_STATS_TTL) {
             let _timer = self
@@ -344,7 +362,7 @@ impl Mempool {
                 return;
             }
         };
-        for txid in txids {
+        for &txid in &txids {
             let tx = self.txstore.get(&txid).expect("missing mempool tx");
             let txid_bytes = full_hash(&txid[..]);
             let prevouts = extract_tx_prevouts(&tx, &txos, false);
@@ -418,6 +436,8 @@ impl Mempool {
                 &mut self.asset_issuance,
             );
         }
+
+        self.update_fee_histogram(txids);
     }
 
     pub fn lookup_txo(&self, outpoint: &OutPoint) -> Result<TxOut> {
@@ -475,7 +495,15 @@ impl Mempool {
             .observe(to_remove.len() as f64);
         let _timer = self.latency.with_label_values(&["remove"]).start_timer();
 
+        // the effective fee rates of the remaining relatives change along
+        let relatives: Vec<Txid> = to_remove
+            .iter()
+            .flat_map(|txid| self.relatives(txid))
+            .filter(|txid| !to_remove.contains(txid))
+            .collect();
+
         for txid in &to_remove {
+            self.fee_histogram.remove(*txid);
             self.txstore
                 .remove(*txid)
                 .unwrap_or_else(|| panic!("missing mempool tx {}", txid));
@@ -501,6 +529,118 @@ impl Mempool {
 
         self.edges
             .retain(|_outpoint, (txid, _vin)| !to_remove.contains(txid));
+
+        self.update_fee_histogram(relatives);
+    }
+
+    /// The in-mempool parents and children of the transaction
+    fn relatives(&self, txid: &Txid) -> Vec<Txid> {
+        let tx = match self.txstore.get(txid) {
+            Some(tx) => tx,
+            None => return vec![],
+        };
+        let parents = tx
+            .input
+            .iter()
+            .map(|txin| txin.previous_output.txid)
+            .filter(|parent| self.txstore.contains_key(parent));
+        let children = (0..tx.output.len() as u32).filter_map(|vout| {
+            let (child, _vin) = self.edges.get(&OutPoint { txid: *txid, vout })?;
+            Some(*child)
+        });
+        parents.chain(children).unique().collect()
+    }
+
+    /// The ancestors of each transaction of the cluster, including itself. Each set is built once
+    /// from those of the transaction's parents, which are handled first, rather than walking up
+    /// the whole ancestry of every transaction.
+    fn cluster_ancestors(&self, cluster: &HashSet<Txid>) -> HashMap<Txid, HashSet<Txid>> {
+        let parents = |txid: &Txid| -> Vec<Txid> {
+            let tx = match self.txstore.get(txid) {
+                Some(tx) => tx,
+                None => return vec![],
+            };
+            tx.input
+                .iter()
+                .map(|txin| txin.previous_output.txid)
+                .filter(|parent| cluster.contains(parent))
+                .unique()
+                .collect()
+        };
+        let mut ancestors: HashMap<Txid, HashSet<Txid>> = HashMap::with_capacity(cluster.len());
+        for txid in cluster {
+            let mut stack = vec![*txid];
+            while let Some(&txid) = stack.last() {
+                if ancestors.contains_key(&txid) {
+                    stack.pop();
+                    continue;
+                }
+                let parents = parents(&txid);
+                let pending: Vec<Txid> = parents
+                    .iter()
+                    .filter(|parent| !ancestors.contains_key(*parent))
+                    .copied()
+                    .collect();
+                if pending.is_empty() {
+                    let mut own: HashSet<Txid> = parents
+                        .iter()
+                        .flat_map(|parent| ancestors[parent].iter().copied())
+                        .collect();
+                    own.insert(txid);
+                    ancestors.insert(txid, own);
+                    stack.pop();
+                } else {
+                    stack.extend(pending);
+                }
+            }
+        }
+        ancestors
+    }
+
+    /// Recompute the effective fee rates of the transactions and of those related to them
+    /// (directly or not), moving them to the matching fee histogram bins.
+    ///
+    /// A transaction is expected to be mined along with its ancestors once their combined fee
+    /// rate is high enough, so its effective fee rate is the lowest of its own and of this
+    /// package's. Children can pay for their parents (CPFP), which are then mined at the highest
+    /// effective fee rate among themselves and their descendants.
+    fn update_fee_histogram(&mut self, txids: Vec<Txid>) {
+        let _timer = self
+            .latency
+            .with_label_values(&["update_fee_histogram"])
+            .start_timer();
+
+        // find the clusters of related transactions
+        let mut cluster = HashSet::new();
+        let mut queue = txids;
+        while let Some(txid) = queue.pop() {
+            if self.feeinfo.contains_key(&txid) && cluster.insert(txid) {
+                queue.extend(self.relatives(&txid));
+            }
+        }
+
+        let ancestors = self.cluster_ancestors(&cluster);
+        let mut effective_rates: HashMap<Txid, f32> = HashMap::new();
+        for (txid, ancestors) in &ancestors {
+            let (fee, vsize) = ancestors
+                .iter()
+                .map(|ancestor| &self.feeinfo[ancestor])
+                .fold((0, 0), |(fee, vsize), feeinfo| {
+                    (fee + feeinfo.fee, vsize + feeinfo.vsize as u64)
+                });
+            let package_rate = fee as f32 / vsize as f32;
+            let rate = package_rate.min(self.feeinfo[txid].fee_per_vbyte);
+            for ancestor in ancestors {
+                let effective_rate = effective_rates.entry(*ancestor).or_insert(rate);
+                *effective_rate = effective_rate.max(rate);
+            }
+        }
+
+        for txid in cluster {
+            let vsize = self.feeinfo[&txid].vsize;
+            self.fee_histogram
+                .insert(txid, effective_rates[&txid], vsize);
+        }
     }
 
     #[cfg(feature = "liquid")]
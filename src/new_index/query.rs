@@ -1,12 +1,15 @@
 use rayon::prelude::*;
+use serde_json::Value;
 
+use std::cmp;
 use std::collections::{BTreeSet, HashMap};
 use std::sync::{Arc, RwLock, RwLockReadGuard};
 use std::time::{Duration, Instant};
 
-use crate::chain::{Network, OutPoint, Transaction, TxOut, Txid};
+use crate::broadcast::Broadcaster;
+use crate::chain::{self, Network, OutPoint, Transaction, TxOut, Txid};
 use crate::config::Config;
-use crate::daemon::Daemon;
+use crate::daemon::{BlockchainInfo, Daemon};
 use crate::errors::*;
 use crate::new_index::{ChainQuery, Mempool, ScriptStats, SpendingInput, Utxo};
 use crate::util::{is_spendable, BlockId, Bytes, TransactionStatus};
@@ -29,6 +32,7 @@ pub struct Query {
     mempool: Arc<RwLock<Mempool>>,
     daemon: Arc<Daemon>,
     config: Arc<Config>,
+    broadcaster: Broadcaster,
     cached_estimates: RwLock<(HashMap<u16, f64>, Option<Instant>)>,
     cached_relayfee: RwLock<Option<f64>>,
     #[cfg(feature = "liquid")]
@@ -47,6 +51,7 @@ impl Query {
             chain,
             mempool,
             daemon,
+            broadcaster: Broadcaster::new(&config),
             config,
             cached_estimates: RwLock::new((HashMap::new(), None)),
             cached_relayfee: RwLock::new(None),
@@ -69,8 +74,12 @@ impl Query {
         self.mempool.read().unwrap()
     }
 
+    pub fn blockchain_info(&self) -> Result<BlockchainInfo> {
+        self.daemon.getblockchaininfo()
+    }
+
     pub fn broadcast_raw(&self, txhex: &str) -> Result<Txid> {
-        let txid = self.daemon.broadcast_raw(txhex)?;
+        let txid = self.broadcaster.broadcast(&self.daemon, txhex)?;
         self.mempool
             .write()
             .unwrap()
@@ -78,6 +87,17 @@ impl Query {
         Ok(txid)
     }
 
+    /// Check whether bitcoind would accept the transactions (like RBF replacements or packages of
+    /// dependent transactions) without broadcasting them. The results of `testmempoolaccept` are
+    /// extended with the `fee_rate` and `effective_fee_rate` (of the package) in sat/vB.
+    pub fn test_mempool_accept(&self, txhexes: &[String]) -> Result<Vec<Value>> {
+        let mut results = self.daemon.test_mempool_accept(txhexes)?;
+        for result in &mut results {
+            add_fee_rates(result);
+        }
+        Ok(results)
+    }
+
     pub fn utxo(&self, scripthash: &[u8]) -> Result<Vec<Utxo>> {
         let mut utxos = self.chain.utxo(scripthash, self.config.utxos_limit)?;
         let mempool = self.mempool();
@@ -86,6 +106,49 @@ impl Query {
         Ok(utxos)
     }
 
+    /// The page of the scripthash's unspent outputs matching the filter, along with the number of
+    /// matching outputs. Only the outputs of the page get formatted as `Utxo` objects.
+    ///
+    /// The confirmed outputs are taken from the (cached) utxo set of the scripthash, as telling
+    /// which of the outputs in the history index are unspent requires replaying all of it. All of
+    /// them are visited for the total to be counted, but only those up to the end of the page are
+    /// kept around for sorting.
+    pub fn utxo_page(&self, scripthash: &[u8], filter: &UtxoFilter) -> Result<(usize, Vec<Utxo>)> {
+        let confirmed = self.chain.utxo_set(scripthash, self.config.utxos_limit)?;
+        let mempool = self.mempool();
+        let mut mempool_utxos: HashMap<OutPoint, Utxo> = if filter.confirmed_only {
+            HashMap::new()
+        } else {
+            mempool
+                .utxo(scripthash)
+                .into_iter()
+                .map(|utxo| (OutPoint::from(&utxo), utxo))
+                .collect()
+        };
+
+        let candidates = confirmed
+            .into_iter()
+            .filter(|(outpoint, _)| !mempool.has_spend(outpoint))
+            .map(|(outpoint, (blockid, value))| (outpoint, Some(blockid), value))
+            .chain(
+                mempool_utxos
+                    .iter()
+                    .map(|(outpoint, utxo)| (*outpoint, None, utxo.value)),
+            );
+        let (total, page) = filter.select_page(candidates);
+
+        let page = page
+            .into_iter()
+            .map(|(outpoint, blockid, value)| match blockid {
+                Some(blockid) => self.chain.to_utxo(outpoint, blockid, value),
+                None => mempool_utxos
+                    .remove(&outpoint)
+                    .expect("missing mempool utxo"),
+            })
+            .collect();
+        Ok((total, page))
+    }
+
     pub fn history_txids(&self, scripthash: &[u8], limit: usize) -> Vec<(Txid, Option<BlockId>)> {
         let confirmed_txids = self.chain.history_txids(scripthash, limit);
         let confirmed_len = confirmed_txids.len();
@@ -225,6 +288,7 @@ impl Query {
             chain,
             mempool,
             daemon,
+            broadcaster: Broadcaster::new(&config),
             config,
             asset_db,
             cached_estimates: RwLock::new((HashMap::new(), None)),
@@ -260,3 +324,240 @@ impl Query {
         Ok((total_num, results))
     }
 }
+
+/// An unspent output as filtered and sorted, with no block for mempool outputs
+type UtxoEntry = (OutPoint, Option<BlockId>, chain::Value);
+
+/// Which of a scripthash's unspent outputs to return, in which order
+pub struct UtxoFilter {
+    pub min_value: Option<u64>,
+    pub max_value: Option<u64>,
+    pub confirmed_only: bool,
+    pub sorting: UtxoSorting,
+    pub start_index: usize,
+    pub limit: usize,
+}
+
+impl UtxoFilter {
+    /// Outputs with confidential values never match a value range
+    fn matches(&self, value: &chain::Value) -> bool {
+        if self.min_value.is_none() && self.max_value.is_none() {
+            return true;
+        }
+        explicit_value(value).map_or(false, |value| {
+            self.min_value.map_or(true, |min| value >= min)
+                && self.max_value.map_or(true, |max| value <= max)
+        })
+    }
+
+    /// The page of the matching outputs, along with their number. The outputs sorting past the end
+    /// of the page are dropped every so often while scanning, so that at most twice as many as the
+    /// page ends at are held at once.
+    fn select_page(&self, candidates: impl Iterator<Item = UtxoEntry>) -> (usize, Vec<UtxoEntry>) {
+        let keep = self.start_index.saturating_add(self.limit);
+        let mut total = 0;
+        let mut kept = Vec::new();
+        for entry in candidates.filter(|(_, _, value)| self.matches(value)) {
+            total += 1;
+            if keep == 0 {
+                continue;
+            }
+            kept.push(entry);
+            if kept.len() >= keep.saturating_mul(2) {
+                kept.select_nth_unstable_by(keep, |a, b| self.sorting.cmp(a, b));
+                kept.truncate(keep);
+            }
+        }
+        kept.sort_by(|a, b| self.sorting.cmp(a, b));
+        let page = kept
+            .into_iter()
+            .skip(self.start_index)
+            .take(self.limit)
+            .collect();
+        (total, page)
+    }
+}
+
+pub struct UtxoSorting(UtxoSortField, UtxoSortDir);
+
+pub enum UtxoSortField {
+    Height,
+    Value,
+}
+pub enum UtxoSortDir {
+    Descending,
+    Ascending,
+}
+
+impl UtxoSorting {
+    /// Unconfirmed outputs sort as the highest, and confidential values as the lowest. Ties are
+    /// broken by outpoint, so that pages are stable.
+    fn cmp(&self, a: &UtxoEntry, b: &UtxoEntry) -> cmp::Ordering {
+        let height = |blockid: &Option<BlockId>| blockid.as_ref().map_or(usize::MAX, |b| b.height);
+        let ordering = match self.0 {
+            UtxoSortField::Height => height(&a.1).cmp(&height(&b.1)),
+            UtxoSortField::Value => explicit_value(&a.2).cmp(&explicit_value(&b.2)),
+        }
+        .then_with(|| (a.0.txid, a.0.vout).cmp(&(b.0.txid, b.0.vout)));
+
+        match self.1 {
+            UtxoSortDir::Ascending => ordering,
+            UtxoSortDir::Descending => ordering.reverse(),
+        }
+    }
+
+    pub fn from_query_params(query: &HashMap<String, String>) -> Result<Self> {
+        let field = match query.get("sort_field").map(String::as_str) {
+            None => UtxoSortField::Height,
+            Some("height") => UtxoSortField::Height,
+            Some("value") => UtxoSortField::Value,
+            _ => bail!("invalid sort field"),
+        };
+
+        let dir = match query.get("sort_dir").map(String::as_str) {
+            None => UtxoSortDir::Descending,
+            Some("asc") => UtxoSortDir::Ascending,
+            Some("desc") => UtxoSortDir::Descending,
+            _ => bail!("invalid sort direction"),
+        };
+
+        Ok(Self(field, dir))
+    }
+}
+
+#[cfg(not(feature = "liquid"))]
+fn explicit_value(value: &chain::Value) -> Option<u64> {
+    Some(*value)
+}
+#[cfg(feature = "liquid")]
+fn explicit_value(value: &chain::Value) -> Option<u64> {
+    value.explicit()
+}
+
+/// Extend a `testmempoolaccept` result with the transaction's `fee_rate` and the
+/// `effective_fee_rate` of its package in sat/vB, for accepted transactions
+fn add_fee_rates(result: &mut Value) {
+    let fees = result.get("fees");
+    let fee = fees
+        .and_then(|fees| fees.get("base"))
+        .and_then(Value::as_f64);
+    let vsize = result.get("vsize").and_then(Value::as_u64);
+    if let (Some(fee), Some(vsize)) = (fee, vsize) {
+        let fee_rate = (fee * 100_000_000.0).round() / vsize as f64;
+        // bitcoind reports the package's rate in BTC/kvB, since v25
+        let effective_fee_rate = fees
+            .and_then(|fees| fees.get("effective-feerate"))
+            .and_then(Value::as_f64)
+            .map_or(fee_rate, |rate| rate * 100_000.0);
+        result["fee_rate"] = json!(fee_rate);
+        result["effective_fee_rate"] = json!(effective_fee_rate);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_fee_rates() {
+        // as returned by bitcoind v25+, with the package's effective fee rate
+        let mut accepted = json!({
+            "txid": "00", "allowed": true, "vsize": 141,
+            "fees": { "base": 0.00002820, "effective-feerate": 0.00030000 },
+        });
+        add_fee_rates(&mut accepted);
+        assert_eq!(accepted["fee_rate"], 20.0);
+        let effective_fee_rate = accepted["effective_fee_rate"].as_f64().unwrap();
+        assert!((effective_fee_rate - 30.0).abs() < 1e-9);
+        assert_eq!(accepted["allowed"], true);
+
+        // older versions don't report packages, the transaction's own rate is used
+        let mut accepted = json!({ "allowed": true, "vsize": 200, "fees": { "base": 0.00001 } });
+        add_fee_rates(&mut accepted);
+        assert_eq!(accepted["fee_rate"], 5.0);
+        assert_eq!(accepted["effective_fee_rate"], 5.0);
+
+        // rejected transactions are left as is, with their reject reason
+        let rejected = json!({ "allowed": false, "reject-reason": "insufficient fee" });
+        let mut result = rejected.clone();
+        add_fee_rates(&mut result);
+        assert_eq!(result, rejected);
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    #[test]
+    fn test_select_page() {
+        let entries: Vec<UtxoEntry> = (0..100)
+            .map(|i| {
+                let outpoint = OutPoint::new(Txid::default(), i);
+                let blockid = BlockId {
+                    height: (i % 7) as usize,
+                    hash: Default::default(),
+                    time: 0,
+                };
+                // every tenth output is unconfirmed
+                (
+                    outpoint,
+                    Some(blockid).filter(|_| i % 10 != 0),
+                    (i * 37 % 101) as u64,
+                )
+            })
+            .collect();
+        let filter = |field, dir, start_index, limit| UtxoFilter {
+            min_value: Some(10),
+            max_value: None,
+            confirmed_only: false,
+            sorting: UtxoSorting(field, dir),
+            start_index,
+            limit,
+        };
+
+        // BlockId has no PartialEq, the outputs are compared by their outpoint, height and value
+        let key = |e: &UtxoEntry| (e.0, e.1.as_ref().map(|b| b.height), e.2);
+
+        let all = filter(
+            UtxoSortField::Height,
+            UtxoSortDir::Descending,
+            0,
+            usize::MAX,
+        );
+        let (total, sorted) = all.select_page(entries.iter().cloned());
+        assert_eq!(total, entries.iter().filter(|e| e.2 >= 10).count());
+        assert_eq!(sorted.len(), total);
+        // the mempool outputs sort first, all but the one below the minimum value
+        assert_eq!(sorted.iter().take_while(|e| e.1.is_none()).count(), 9);
+        assert!(sorted
+            .windows(2)
+            .all(|w| all.sorting.cmp(&w[0], &w[1]).is_lt()));
+
+        // the pages are the slices of the full sort, though only the outputs up to the end of
+        // each page are kept while scanning
+        for (start_index, limit) in vec![(0, 1), (0, 7), (5, 3), (40, 50), (85, 10), (200, 5)] {
+            let page_filter = filter(
+                UtxoSortField::Height,
+                UtxoSortDir::Descending,
+                start_index,
+                limit,
+            );
+            let (page_total, page) = page_filter.select_page(entries.iter().cloned());
+            assert_eq!(page_total, total);
+            let expected: Vec<_> = sorted
+                .iter()
+                .skip(start_index)
+                .take(limit)
+                .map(key)
+                .collect();
+            assert_eq!(page.iter().map(key).collect::<Vec<_>>(), expected);
+        }
+
+        let by_value = filter(UtxoSortField::Value, UtxoSortDir::Ascending, 3, 4);
+        let (_, page) = by_value.select_page(entries.iter().cloned());
+        let values: Vec<u64> = page.iter().map(|e| e.2).collect();
+        assert_eq!(values, vec![13, 14, 15, 16]);
+
+        // an empty page still counts the matching outputs
+        let empty = filter(UtxoSortField::Value, UtxoSortDir::Ascending, 0, 0);
+        let (empty_total, page) = empty.select_page(entries.iter().cloned());
+        assert_eq!((empty_total, page.len()), (total, 0));
+    }
+}
@@ -96,12 +96,19 @@ impl Store {
         &self.cache_db
     }
 
+    /// Flush the rows written without a WAL to disk, so they survive the shutdown
+    pub fn flush(&self) {
+        self.txstore_db.flush();
+        self.history_db.flush();
+        self.cache_db.flush();
+    }
+
     pub fn done_initial_sync(&self) -> bool {
         self.txstore_db.get(b"t").is_some()
     }
 }
 
-type UtxoMap = HashMap<OutPoint, (BlockId, Value)>;
+pub(super) type UtxoMap = HashMap<OutPoint, (BlockId, Value)>;
 
 #[derive(Debug)]
 pub struct Utxo {
@@ -502,6 +509,49 @@ impl ChainQuery {
             .collect()
     }
 
+    /// The confirmed transactions of the scripthash, the most recent first and those of the same
+    /// block by descending txid, starting after the `before` position. Positions stay meaningful
+    /// across reorgs, even when the transaction at one is no longer confirmed.
+    pub fn history_txids_before(
+        &self,
+        scripthash: &[u8],
+        before: Option<(usize, Txid)>,
+        limit: usize,
+    ) -> Vec<(Txid, BlockId)> {
+        let _timer = self.start_timer("history_txids_before");
+        let rows = match before {
+            Some((height, _)) => self.store.history_db.iter_scan_reverse(
+                &TxHistoryRow::filter(b'H', scripthash),
+                &TxHistoryRow::prefix_height(b'H', scripthash, (height as u32).saturating_add(1)),
+            ),
+            None => self.history_iter_scan_reverse(b'H', scripthash),
+        };
+        let heights = rows
+            .map(TxHistoryRow::from_row)
+            .group_by(|row| row.key.confirmed_height as usize);
+
+        let mut txids = vec![];
+        for (height, rows) in &heights {
+            let mut height_txids: Vec<Txid> = rows.map(|row| row.get_txid()).collect();
+            height_txids.sort_unstable_by(|a, b| b.cmp(a));
+            height_txids.dedup();
+            for txid in height_txids {
+                if before.map_or(false, |before| (height, txid) >= before) {
+                    continue;
+                }
+                // the rows of blocks that were reorged away are kept in the index
+                let blockid = self.tx_confirming_block(&txid);
+                if let Some(blockid) = blockid.filter(|blockid| blockid.height == height) {
+                    txids.push((txid, blockid));
+                    if txids.len() == limit {
+                        return txids;
+                    }
+                }
+            }
+        }
+        txids
+    }
+
     pub fn history_txids(&self, scripthash: &[u8], limit: usize) -> Vec<(Txid, BlockId)> {
         // scripthash lookup
         self._history_txids(b'H', scripthash, limit)
@@ -519,6 +569,16 @@ impl ChainQuery {
 
     // TODO: avoid duplication with stats/stats_delta?
     pub fn utxo(&self, scripthash: &[u8], limit: usize) -> Result<Vec<Utxo>> {
+        Ok(self
+            .utxo_set(scripthash, limit)?
+            .into_iter()
+            .map(|(outpoint, (blockid, value))| self.to_utxo(outpoint, blockid, value))
+            .collect())
+    }
+
+    /// The unspent outputs of the scripthash, without looking anything up about them. Filtering
+    /// these rather than the `Utxo`s keeps from materializing the ones left out.
+    pub(super) fn utxo_set(&self, scripthash: &[u8], limit: usize) -> Result<UtxoMap> {
         let _timer = self.start_timer("utxo");
 
         // get the last known utxo set and the blockhash it was updated for.
@@ -551,31 +611,30 @@ impl ChainQuery {
             }
         }
 
-        // format as Utxo objects
-        Ok(newutxos
-            .into_iter()
-            .map(|(outpoint, (blockid, value))| {
-                // in elements/liquid chains, we have to lookup the txo in order to get its
-                // associated asset. the asset information could be kept in the db history rows
-                // alongside the value to avoid this.
-                #[cfg(feature = "liquid")]
-                let txo = self.lookup_txo(&outpoint).expect("missing utxo");
-
-                Utxo {
-                    txid: outpoint.txid,
-                    vout: outpoint.vout,
-                    value,
-                    confirmed: Some(blockid),
-
-                    #[cfg(feature = "liquid")]
-                    asset: txo.asset,
-                    #[cfg(feature = "liquid")]
-                    nonce: txo.nonce,
-                    #[cfg(feature = "liquid")]
-                    witness: txo.witness,
-                }
-            })
-            .collect())
+        Ok(newutxos)
+    }
+
+    /// Format the unspent output as a `Utxo` object
+    pub(super) fn to_utxo(&self, outpoint: OutPoint, blockid: BlockId, value: Value) -> Utxo {
+        // in elements/liquid chains, we have to lookup the txo in order to get its
+        // associated asset. the asset information could be kept in the db history rows
+        // alongside the value to avoid this.
+        #[cfg(feature = "liquid")]
+        let txo = self.lookup_txo(&outpoint).expect("missing utxo");
+
+        Utxo {
+            txid: outpoint.txid,
+            vout: outpoint.vout,
+            value,
+            confirmed: Some(blockid),
+
+            #[cfg(feature = "liquid")]
+            asset: txo.asset,
+            #[cfg(feature = "liquid")]
+            nonce: txo.nonce,
+            #[cfg(feature = "liquid")]
+            witness: txo.witness,
+        }
     }
 
     fn utxo_delta(
@@ -1,23 +1,30 @@
 use crate::chain::{address, BlockHash, Network, OutPoint, Script, Transaction, TxIn, TxOut, Txid};
 use crate::config::Config;
 use crate::errors;
-use crate::new_index::{compute_script_hash, Query, SpendingInput, Utxo};
+use crate::new_index::{compute_script_hash, Query, SpendingInput, Utxo, UtxoFilter, UtxoSorting};
 use crate::util::{
     create_socket, electrum_merkle, extract_tx_prevouts, full_hash, get_innerscripts, get_tx_fee,
-    has_prevout, is_coinbase, BlockHeaderMeta, BlockId, FullHash, ScriptToAddr, ScriptToAsm,
-    TransactionStatus,
+    has_prevout, is_coinbase, proxy_protocol, spawn_thread, BlockHeaderMeta, BlockId, BoolThen,
+    FullHash, ScriptToAddr, ScriptToAsm, TransactionStatus,
 };
 
 #[cfg(not(feature = "liquid"))]
-use {bitcoin::consensus::encode, std::str::FromStr};
+use {
+    crate::account::{Account, AccountScan, DEFAULT_GAP_LIMIT},
+    bitcoin::consensus::encode,
+    std::str::FromStr,
+};
 
 use bitcoin::blockdata::opcodes;
 use bitcoin::hashes::hex::{FromHex, ToHex};
 use bitcoin::hashes::Error as HashError;
+use error_chain::ChainedError;
 use hex::{self, FromHexError};
+use hyper::server::accept::{self, Accept};
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Method, Response, Server, StatusCode};
-use tokio::sync::oneshot;
+use hyper::{Body, HeaderMap, Method, Response, Server, StatusCode};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
 
 use hyperlocal::UnixServerExt;
 use std::fs;
@@ -33,14 +40,29 @@ use {
 use serde::Serialize;
 use serde_json;
 use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
 use std::num::ParseIntError;
 use std::os::unix::fs::FileTypeExt;
 use std::sync::Arc;
 use std::thread;
 use url::form_urlencoded;
 
+mod compression;
+mod cors;
+mod etag;
+mod events;
+mod history;
+
+use self::cors::Cors;
+use self::events::EventSource;
+use self::history::Cursor;
+
 const CHAIN_TXS_PER_PAGE: usize = 25;
 const MAX_MEMPOOL_TXS: usize = 50;
+const HISTORY_MAX_PER_PAGE: usize = 100;
+// addresses with larger histories don't get their total count
+const HISTORY_COUNT_LIMIT: usize = 1000;
 const BLOCK_LIMIT: usize = 10;
 const ADDRESS_SEARCH_LIMIT: usize = 10;
 
@@ -508,48 +530,120 @@ fn prepare_txs(
         .collect()
 }
 
+/// Apply the conditional request headers, CORS and compression to the response
+async fn finish_response(
+    req_method: &Method,
+    req_headers: &HeaderMap,
+    resp: Response<Body>,
+    cors: Option<&Cors>,
+    compression_min_size: usize,
+) -> Result<Response<Body>, hyper::Error> {
+    let mut resp = etag::conditional(req_method, req_headers, resp).await?;
+    if let Some(cors) = cors {
+        cors.apply(req_headers, &mut resp);
+    }
+    compression::compress(req_headers, resp, compression_min_size).await
+}
+
 #[tokio::main]
-async fn run_server(config: Arc<Config>, query: Arc<Query>, rx: oneshot::Receiver<()>) {
+async fn run_server(
+    config: Arc<Config>,
+    query: Arc<Query>,
+    events: Arc<EventSource>,
+    rx: oneshot::Receiver<()>,
+) {
     let addr = &config.http_addr;
     let socket_file = &config.http_socket_file;
 
     let config = Arc::clone(&config);
     let query = Arc::clone(&query);
+    let cors = Arc::new(Cors::from_config(&config));
 
     let make_service_fn_inn = || {
         let query = Arc::clone(&query);
         let config = Arc::clone(&config);
+        let events = Arc::clone(&events);
+        let cors = Arc::clone(&cors);
 
         async move {
             Ok::<_, hyper::Error>(service_fn(move |req| {
                 let query = Arc::clone(&query);
                 let config = Arc::clone(&config);
+                let events = Arc::clone(&events);
+                let cors = Arc::clone(&cors);
 
                 async move {
-                    let method = req.method().clone();
-                    let uri = req.uri().clone();
-                    let body = hyper::body::to_bytes(req.into_body()).await?;
-
-                    let mut resp = handle_request(method, uri, body, &query, &config)
-                        .unwrap_or_else(|err| {
-                            warn!("{:?}", err);
-                            Response::builder()
-                                .status(err.0)
-                                .header("Content-Type", "text/plain")
-                                .body(Body::from(err.1))
-                                .unwrap()
-                        });
-                    if let Some(ref origins) = config.cors {
-                        resp.headers_mut()
-                            .insert("Access-Control-Allow-Origin", origins.parse().unwrap());
+                    let req_headers = req.headers().clone();
+                    if let Some(ref cors) = *cors {
+                        if Cors::is_preflight(req.method(), &req_headers) {
+                            return Ok::<_, hyper::Error>(cors.preflight(&req_headers));
+                        }
                     }
-                    Ok::<_, hyper::Error>(resp)
+
+                    // the streaming endpoints are routed before reading the request body
+                    let method = req.method().clone();
+                    let req_method = method.clone();
+                    let path = req.uri().path().to_string();
+                    let segments: Vec<&str> = path.split('/').skip(1).collect();
+                    let result = match (&method, &segments[..]) {
+                        (_, ["ws"]) => events::upgrade(req, &events, config.network_type),
+                        (&Method::GET, [script_type @ "address", script_str, "events"])
+                        | (&Method::GET, [script_type @ "scripthash", script_str, "events"]) => {
+                            to_scripthash(script_type, script_str, config.network_type).and_then(
+                                |script_hash| {
+                                    events::stream_history(
+                                        req.headers(),
+                                        script_hash,
+                                        &events,
+                                        Arc::clone(&query),
+                                    )
+                                },
+                            )
+                        }
+                        _ => {
+                            let uri = req.uri().clone();
+                            let body = hyper::body::to_bytes(req.into_body()).await?;
+                            handle_request(method, uri, body, &query, &config)
+                        }
+                    };
+
+                    let resp = result.unwrap_or_else(|err| {
+                        warn!("{:?}", err);
+                        Response::builder()
+                            .status(err.0)
+                            .header("Content-Type", "text/plain")
+                            .body(Body::from(err.1))
+                            .unwrap()
+                    });
+                    finish_response(
+                        &req_method,
+                        &req_headers,
+                        resp,
+                        (*cors).as_ref(),
+                        config.http_compression_min_size,
+                    )
+                    .await
                 }
             }))
         }
     };
 
+    let shutdown_events = Arc::clone(&events);
+    let shutdown = async move {
+        rx.await.ok();
+        shutdown_events.shutdown();
+    };
+
     let server = match socket_file {
+        None if !config.trusted_proxies.is_empty() => {
+            info!("REST server running on {} (PROXY protocol)", addr);
+
+            let incoming = accept_proxied(*addr, config.trusted_proxies.clone());
+            Server::builder(incoming)
+                .serve(make_service_fn(move |_| make_service_fn_inn()))
+                .with_graceful_shutdown(shutdown)
+                .await
+        }
         None => {
             info!("REST server running on {}", addr);
 
@@ -559,9 +653,7 @@ async fn run_server(config: Arc<Config>, query: Arc<Query>, rx: oneshot::Receive
             Server::from_tcp(socket.into())
                 .expect("Server::from_tcp failed")
                 .serve(make_service_fn(move |_| make_service_fn_inn()))
-                .with_graceful_shutdown(async {
-                    rx.await.ok();
-                })
+                .with_graceful_shutdown(shutdown)
                 .await
         }
         Some(path) => {
@@ -577,9 +669,7 @@ async fn run_server(config: Arc<Config>, query: Arc<Query>, rx: oneshot::Receive
             Server::bind_unix(path)
                 .expect("Server::bind_unix failed")
                 .serve(make_service_fn(move |_| make_service_fn_inn()))
-                .with_graceful_shutdown(async {
-                    rx.await.ok();
-                })
+                .with_graceful_shutdown(shutdown)
                 .await
         }
     };
@@ -589,23 +679,77 @@ async fn run_server(config: Arc<Config>, query: Arc<Query>, rx: oneshot::Receive
     }
 }
 
+/// Accept connections on `addr`, reading the PROXY header of those from the `trusted_proxies`
+/// before handing them over to hyper. Each header is read from a thread of its own, so that
+/// waiting on one holds up neither the server nor accepting other connections.
+fn accept_proxied(
+    addr: SocketAddr,
+    trusted_proxies: Vec<IpAddr>,
+) -> impl Accept<Conn = TcpStream, Error = io::Error> {
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    spawn_thread("rest-acceptor", move || {
+        let socket = create_socket(&addr);
+        socket.listen(511).expect("setting backlog failed");
+        let listener = std::net::TcpListener::from(socket);
+        let trusted_proxies = Arc::new(trusted_proxies);
+        loop {
+            let (stream, peer_addr) = listener.accept().expect("accept failed");
+            if !trusted_proxies.contains(&peer_addr.ip()) {
+                if sender.send(stream).is_err() {
+                    break; // the server was stopped
+                }
+                continue;
+            }
+            let (sender, trusted_proxies) = (sender.clone(), Arc::clone(&trusted_proxies));
+            spawn_thread("rest-proxy-header", move || {
+                if let Err(e) = proxy_protocol::client_addr(&stream, peer_addr, &trusted_proxies) {
+                    warn!(
+                        "[{}] rejecting connection: {}",
+                        peer_addr,
+                        e.display_chain()
+                    );
+                    return;
+                }
+                let _ = sender.send(stream);
+            });
+        }
+    });
+    accept::poll_fn(move |cx| {
+        receiver.poll_recv(cx).map(|stream| {
+            stream.map(|stream| {
+                stream.set_nonblocking(true)?;
+                TcpStream::from_std(stream)
+            })
+        })
+    })
+}
+
 pub fn start(config: Arc<Config>, query: Arc<Query>) -> Handle {
     let (tx, rx) = oneshot::channel::<()>();
+    let events = Arc::new(EventSource::start(Arc::clone(&query)));
+    let server_events = Arc::clone(&events);
 
     Handle {
         tx,
+        events,
         thread: thread::spawn(move || {
-            run_server(config, query, rx);
+            run_server(config, query, server_events, rx);
         }),
     }
 }
 
 pub struct Handle {
     tx: oneshot::Sender<()>,
+    events: Arc<EventSource>,
     thread: thread::JoinHandle<()>,
 }
 
 impl Handle {
+    /// Push the new blocks, mempool transactions and fee estimates to the `/ws` clients
+    pub fn notify(&self) {
+        self.events.notify();
+    }
+
     pub fn stop(self) {
         self.tx.send(()).expect("failed to send shutdown signal");
         self.thread.join().expect("REST server failed");
@@ -705,7 +849,7 @@ fn handle_request(
             Ok(Response::builder()
                 .status(StatusCode::OK)
                 .header("Content-Type", "application/octet-stream")
-                .header("Cache-Control", format!("public, max-age={:}", TTL_LONG))
+                .header("Cache-Control", cache_control(TTL_LONG))
                 .body(Body::from(raw))
                 .unwrap())
         }
@@ -791,28 +935,54 @@ fn handle_request(
             None,
         ) => {
             let script_hash = to_scripthash(script_type, script_str, config.network_type)?;
+            json_response(script_txs(&script_hash, query, config), TTL_SHORT)
+        }
 
-            let mut txs = vec![];
-
-            txs.extend(
-                query
-                    .mempool()
-                    .history(&script_hash[..], MAX_MEMPOOL_TXS)
-                    .into_iter()
-                    .map(|tx| (tx, None)),
-            );
-
-            txs.extend(
-                query
-                    .chain()
-                    .history(&script_hash[..], None, CHAIN_TXS_PER_PAGE)
-                    .into_iter()
-                    .map(|(tx, blockid)| (tx, Some(blockid))),
-            );
+        (
+            &Method::GET,
+            Some(script_type @ &"address"),
+            Some(script_str),
+            Some(&"txs"),
+            Some(&"page"),
+            None,
+        )
+        | (
+            &Method::GET,
+            Some(script_type @ &"scripthash"),
+            Some(script_str),
+            Some(&"txs"),
+            Some(&"page"),
+            None,
+        ) => {
+            let script_hash = to_scripthash(script_type, script_str, config.network_type)?;
+            let cursor = query_params
+                .get("cursor")
+                .map(|cursor| cursor.parse::<Cursor>())
+                .transpose()?;
+            let limit = history::page_limit(query_params.get("limit"));
+
+            // only counted for the first page
+            let total = cursor.is_none().and_then(|| {
+                let txids = query.history_txids(&script_hash[..], HISTORY_COUNT_LIMIT + 1);
+                (txids.len() <= HISTORY_COUNT_LIMIT).and_then(|| Some(txids.len()))
+            });
+
+            let (page, next_cursor) = history::history_page(query, &script_hash, cursor, limit);
+            // mempool transactions may have been evicted since
+            let txs = page
+                .into_iter()
+                .filter_map(|(txid, blockid)| query.lookup_txn(&txid).map(|tx| (tx, blockid)))
+                .collect();
 
-            json_response(prepare_txs(txs, query, config), TTL_SHORT)
+            json_response(
+                json!({
+                    "txs": prepare_txs(txs, query, config),
+                    "next_cursor": next_cursor.map(|cursor| cursor.to_string()),
+                    "total": total,
+                }),
+                TTL_SHORT,
+            )
         }
-
         (
             &Method::GET,
             Some(script_type @ &"address"),
@@ -890,13 +1060,55 @@ fn handle_request(
             None,
         ) => {
             let script_hash = to_scripthash(script_type, script_str, config.network_type)?;
-            let utxos: Vec<UtxoValue> = query
-                .utxo(&script_hash[..])?
-                .into_iter()
-                .map(UtxoValue::from)
-                .collect();
-            // XXX paging?
-            json_response(utxos, TTL_SHORT)
+            let parse_value = |name: &str| -> Result<Option<u64>, HttpError> {
+                query_params
+                    .get(name)
+                    .map(|n| n.parse())
+                    .transpose()
+                    .map_err(|_| HttpError::from(format!("Invalid {}", name)))
+            };
+            let filter = UtxoFilter {
+                min_value: parse_value("min_value")?,
+                max_value: parse_value("max_value")?,
+                confirmed_only: query_params
+                    .get("confirmed_only")
+                    .map_or(false, |b| b == "true" || b == "1"),
+                sorting: UtxoSorting::from_query_params(&query_params)?,
+                start_index: query_params
+                    .get("start_index")
+                    .and_then(|n| n.parse().ok())
+                    .unwrap_or(0),
+                limit: query_params
+                    .get("limit")
+                    .and_then(|n| n.parse().ok())
+                    .unwrap_or(usize::MAX),
+            };
+
+            let (total_num, utxos) = query.utxo_page(&script_hash[..], &filter)?;
+            let utxos: Vec<UtxoValue> = utxos.into_iter().map(UtxoValue::from).collect();
+
+            let mut resp = json_response(utxos, TTL_SHORT)?;
+            resp.headers_mut()
+                .insert("X-Total-Results", total_num.into());
+            Ok(resp)
+        }
+        (&Method::POST, Some(&"addresses"), Some(&"txs"), None, None, None) => {
+            let mut results = serde_json::Map::new();
+            for (key, script_hash) in
+                batch_scripthashes(&body, config.http_batch_limit, config.network_type)?
+            {
+                results.insert(key, json!(script_txs(&script_hash, query, config)));
+            }
+            json_response(results, TTL_SHORT)
+        }
+        (&Method::POST, Some(&"addresses"), Some(&"utxo"), None, None, None) => {
+            let mut results = serde_json::Map::new();
+            for (key, script_hash) in
+                batch_scripthashes(&body, config.http_batch_limit, config.network_type)?
+            {
+                results.insert(key, json!(script_utxos(&script_hash, query)?));
+            }
+            json_response(results, TTL_SHORT)
         }
         (&Method::GET, Some(&"address-prefix"), Some(prefix), None, None, None) => {
             if !config.address_search {
@@ -905,6 +1117,69 @@ fn handle_request(
             let results = query.chain().address_search(prefix, ADDRESS_SEARCH_LIMIT);
             json_response(results, TTL_SHORT)
         }
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"account"), None, None, None, None) => {
+            let scan = account_scan(&query_params, query, config)?;
+            let (confirmed, unconfirmed) = scan.balance(query);
+            let scripts: Vec<serde_json::Value> = scan
+                .scripts
+                .iter()
+                .map(|script| {
+                    json!({
+                        "chain": script.chain,
+                        "index": script.index,
+                        "scripthash": hex::encode(script.scripthash),
+                        "address": script.script.to_address_str(config.network_type),
+                    })
+                })
+                .collect();
+            json_response(
+                json!({
+                    "balance": {
+                        "confirmed": confirmed,
+                        "unconfirmed": unconfirmed,
+                    },
+                    "scripts": scripts,
+                    "next_index": scan.next_indexes,
+                }),
+                TTL_SHORT,
+            )
+        }
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"account"), Some(&"utxo"), None, None, None) => {
+            let scan = account_scan(&query_params, query, config)?;
+            let utxos: Vec<serde_json::Value> = scan
+                .utxos(query)?
+                .into_iter()
+                .map(|(script, utxo)| {
+                    let mut utxo = json!(UtxoValue::from(utxo));
+                    utxo["chain"] = json!(script.chain);
+                    utxo["index"] = json!(script.index);
+                    utxo
+                })
+                .collect();
+            json_response(utxos, TTL_SHORT)
+        }
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"account"), Some(&"txs"), None, None, None) => {
+            let scan = account_scan(&query_params, query, config)?;
+            let start_index = query_params
+                .get("start_index")
+                .map_or(Ok(0), |start_index| start_index.parse::<usize>())?;
+
+            // the most recent first, like the address history
+            let history_txids = scan.history_txids(query, config.electrum_txs_limit)?;
+            let txs = history_txids
+                .into_iter()
+                .rev()
+                .skip(start_index)
+                .take(CHAIN_TXS_PER_PAGE)
+                .map(|(txid, blockid)| query.lookup_txn(&txid).map(|tx| (tx, blockid)))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| HttpError::not_found("Transaction not found".to_string()))?;
+
+            json_response(prepare_txs(txs, query, config), TTL_SHORT)
+        }
         (&Method::GET, Some(&"tx"), Some(hash), None, None, None) => {
             let hash = Txid::from_hex(hash)?;
             let tx = query
@@ -934,7 +1209,7 @@ fn handle_request(
             Ok(Response::builder()
                 .status(StatusCode::OK)
                 .header("Content-Type", content_type)
-                .header("Cache-Control", format!("public, max-age={:}", ttl))
+                .header("Cache-Control", cache_control(ttl))
                 .body(body)
                 .unwrap())
         }
@@ -1025,6 +1300,13 @@ fn handle_request(
                 .map_err(|err| HttpError::from(err.description().to_string()))?;
             http_message(StatusCode::OK, txid.to_hex(), 0)
         }
+        (&Method::POST, Some(&"tx"), Some(&"test"), None, None, None) => {
+            let txhexes = parse_test_txs(&body)?;
+            let results = query
+                .test_mempool_accept(&txhexes)
+                .map_err(|err| HttpError::from(err.description().to_string()))?;
+            json_response(results, 0)
+        }
 
         (&Method::GET, Some(&"mempool"), None, None, None, None) => {
             json_response(query.mempool().backlog_stats(), TTL_SHORT)
@@ -1173,16 +1455,26 @@ where
     Ok(Response::builder()
         .status(status)
         .header("Content-Type", "text/plain")
-        .header("Cache-Control", format!("public, max-age={:}", ttl))
+        .header("Cache-Control", cache_control(ttl))
         .body(message.into())
         .unwrap())
 }
 
+/// Confirmed data that can no longer be reorged (or is addressed by its hash) never changes, so
+/// that caches don't even have to revalidate it
+fn cache_control(ttl: u32) -> String {
+    if ttl == TTL_LONG {
+        format!("public, max-age={}, immutable", ttl)
+    } else {
+        format!("public, max-age={}", ttl)
+    }
+}
+
 fn json_response<T: Serialize>(value: T, ttl: u32) -> Result<Response<Body>, HttpError> {
     let value = serde_json::to_string(&value)?;
     Ok(Response::builder()
         .header("Content-Type", "application/json")
-        .header("Cache-Control", format!("public, max-age={:}", ttl))
+        .header("Cache-Control", cache_control(ttl))
         .body(Body::from(value))
         .unwrap())
 }
@@ -1239,6 +1531,105 @@ fn to_scripthash(
     }
 }
 
+/// The transactions to test with testmempoolaccept, given as a hex body or a JSON array of hex
+fn parse_test_txs(body: &[u8]) -> Result<Vec<String>, HttpError> {
+    let body = String::from_utf8(body.to_vec())?;
+    Ok(match serde_json::from_str::<Vec<String>>(&body) {
+        Ok(txhexes) => txhexes,
+        Err(_) => vec![body.trim().to_string()],
+    })
+}
+
+/// The mempool transactions of the script, followed by the first page of its confirmed ones
+fn script_txs(script_hash: &FullHash, query: &Query, config: &Config) -> Vec<TransactionValue> {
+    let mut txs = vec![];
+
+    txs.extend(
+        query
+            .mempool()
+            .history(&script_hash[..], MAX_MEMPOOL_TXS)
+            .into_iter()
+            .map(|tx| (tx, None)),
+    );
+
+    txs.extend(
+        query
+            .chain()
+            .history(&script_hash[..], None, CHAIN_TXS_PER_PAGE)
+            .into_iter()
+            .map(|(tx, blockid)| (tx, Some(blockid))),
+    );
+
+    prepare_txs(txs, query, config)
+}
+
+fn script_utxos(script_hash: &FullHash, query: &Query) -> Result<Vec<UtxoValue>, HttpError> {
+    Ok(query
+        .utxo(&script_hash[..])?
+        .into_iter()
+        .map(UtxoValue::from)
+        .collect())
+}
+
+/// The scripthashes of a batch request, given as `{"addresses": [..], "scripthashes": [..]}`,
+/// along with the address or scripthash they were given as
+fn batch_scripthashes(
+    body: &[u8],
+    batch_limit: usize,
+    network: Network,
+) -> Result<Vec<(String, FullHash)>, HttpError> {
+    let request: serde_json::Value = serde_json::from_slice(body)?;
+    let mut scripts = vec![];
+    for (key, script_type) in &[("addresses", "address"), ("scripthashes", "scripthash")] {
+        let values = match request.get(key) {
+            Some(values) => values
+                .as_array()
+                .and_then(|values| {
+                    values
+                        .iter()
+                        .map(|v| v.as_str())
+                        .collect::<Option<Vec<_>>>()
+                })
+                .ok_or_else(|| HttpError::from(format!("{} must be an array of strings", key)))?,
+            None => vec![],
+        };
+        scripts.extend(
+            values
+                .into_iter()
+                .map(|script_str| (*script_type, script_str)),
+        );
+    }
+    if scripts.len() > batch_limit {
+        return Err(HttpError::from(format!(
+            "Too many addresses and scripthashes (max {})",
+            batch_limit
+        )));
+    }
+    scripts
+        .into_iter()
+        .map(|(script_type, script_str)| {
+            let script_hash = to_scripthash(script_type, script_str, network)?;
+            Ok((script_str.to_string(), script_hash))
+        })
+        .collect()
+}
+
+/// Scan the account given by the `descriptor` and optional `gap_limit` query parameters
+#[cfg(not(feature = "liquid"))]
+fn account_scan(
+    query_params: &HashMap<String, String>,
+    query: &Query,
+    config: &Config,
+) -> Result<AccountScan, HttpError> {
+    let descriptor = query_params
+        .get("descriptor")
+        .ok_or_else(|| HttpError::from("Missing descriptor".to_string()))?;
+    let gap_limit = query_params
+        .get("gap_limit")
+        .map_or(Ok(DEFAULT_GAP_LIMIT), |gap_limit| gap_limit.parse())?;
+    Ok(Account::parse(descriptor, config.network_type)?.scan(query, gap_limit)?)
+}
+
 fn address_to_scripthash(addr: &str, network: Network) -> Result<FullHash, HttpError> {
     #[cfg(not(feature = "liquid"))]
     let addr = address::Address::from_str(addr)?;
@@ -1354,9 +1745,13 @@ impl From<address::AddressError> for HttpError {
 
 #[cfg(test)]
 mod tests {
-    use crate::rest::HttpError;
+    use crate::rest::{batch_scripthashes, finish_response, parse_test_txs, HttpError};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
     use serde_json::Value;
     use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
 
     #[test]
     fn test_parse_query_param() {
@@ -1419,4 +1814,102 @@ mod tests {
 
         assert!(err.is_err());
     }
+
+    #[test]
+    fn test_parse_test_txs() {
+        assert_eq!(parse_test_txs(b"0100\n").unwrap(), vec!["0100"]);
+        let package = br#"["0100", "0200"]"#;
+        assert_eq!(parse_test_txs(package).unwrap(), vec!["0100", "0200"]);
+        assert!(parse_test_txs(b"\xff").is_err());
+    }
+
+    #[test]
+    #[cfg(not(feature = "liquid"))]
+    fn test_batch_scripthashes() {
+        use crate::chain::Network;
+
+        let script_hash = "6191c3b590bfcfa0475e877c302da1e323497acf3b42c08d8fa28e364edf018b";
+        let body = json!({
+            "addresses": ["1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa"],
+            "scripthashes": [script_hash],
+        })
+        .to_string();
+        let scripts = batch_scripthashes(body.as_bytes(), 2, Network::Bitcoin).unwrap();
+        // grouped by the address or scripthash they were given as
+        assert_eq!(scripts.len(), 2);
+        assert_eq!(scripts[0].0, "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa");
+        assert_eq!(scripts[1].0, script_hash);
+        assert_eq!(hex::encode(scripts[0].1), script_hash);
+        assert_eq!(scripts[0].1, scripts[1].1);
+
+        assert!(batch_scripthashes(body.as_bytes(), 1, Network::Bitcoin).is_err());
+        assert!(batch_scripthashes(body.as_bytes(), 2, Network::Testnet).is_err());
+        let empty = batch_scripthashes(b"{}", 2, Network::Bitcoin).unwrap();
+        assert!(empty.is_empty());
+        for invalid in &[
+            r#"{"addresses": "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa"}"#,
+            r#"{"scripthashes": [1]}"#,
+            r#"{"scripthashes": ["00"]}"#,
+            "[]x",
+        ] {
+            assert!(batch_scripthashes(invalid.as_bytes(), 2, Network::Bitcoin).is_err());
+        }
+    }
+
+    /// Request `/` over a new connection, returning the (lowercased) headers and the body
+    fn get(addr: SocketAddr, accept_encoding: &str) -> (String, Vec<u8>) {
+        let mut sock = TcpStream::connect(addr).unwrap();
+        write!(
+            sock,
+            "GET / HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: {}\r\nConnection: close\r\n\r\n",
+            accept_encoding
+        )
+        .unwrap();
+        let mut reply = vec![];
+        sock.read_to_end(&mut reply).unwrap();
+        let end = reply.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let headers = String::from_utf8_lossy(&reply[..end]).to_lowercase();
+        (headers, reply[end + 4..].to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_compressed_response() {
+        // large enough to be compressed on the blocking thread pool
+        let body = json!(vec!["00".repeat(100); 2000]).to_string();
+        let served = body.clone();
+        let service = make_service_fn(move |_| {
+            let body = served.clone();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+                    let resp = Response::builder()
+                        .header("Content-Type", "application/json")
+                        .body(Body::from(body.clone()))
+                        .unwrap();
+                    async move { finish_response(req.method(), req.headers(), resp, None, 1024).await }
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let (gzip, plain) =
+            tokio::task::spawn_blocking(move || (get(addr, "gzip"), get(addr, "identity")))
+                .await
+                .unwrap();
+        let (headers, compressed) = gzip;
+        assert!(headers.contains("content-encoding: gzip"), "{}", headers);
+        assert!(headers.contains("vary: accept-encoding"), "{}", headers);
+        assert!(headers.contains("etag: "), "{}", headers);
+        assert!(compressed.len() < body.len());
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&compressed[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, body);
+
+        let (headers, uncompressed) = plain;
+        assert!(!headers.contains("content-encoding"), "{}", headers);
+        assert_eq!(uncompressed, body.as_bytes());
+    }
 }
@@ -0,0 +1,158 @@
+//! Compression of the JSON and hex responses of the HTTP server, with brotli or gzip as negotiated
+//! via `Accept-Encoding`. Responses smaller than `--http-compression-min-size` are left as they
+//! are, since compressing them saves next to nothing.
+
+use std::io::Write;
+
+use flate2::write::GzEncoder;
+use hyper::body::HttpBody;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Body, Response};
+
+// brotli's default quality (11) is meant for static content and way too slow for responses
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+/// Bodies from this size on are compressed on the blocking thread pool, not to hold up the
+/// runtime's threads serving the other requests
+const BLOCKING_MIN_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    fn encode(self, data: &[u8]) -> Vec<u8> {
+        // writing to a Vec can't fail
+        match self {
+            Encoding::Brotli => {
+                let mut encoder =
+                    brotli::CompressorWriter::new(vec![], 4096, BROTLI_QUALITY, BROTLI_WINDOW);
+                encoder.write_all(data).unwrap();
+                encoder.into_inner()
+            }
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+        }
+    }
+}
+
+/// The preferred encoding among those accepted, brotli winning ties
+fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let qualities: Vec<(String, f32)> = accept_encoding
+        .split(',')
+        .map(|item| {
+            let mut params = item.split(';').map(str::trim);
+            let name = params.next().unwrap_or("").to_lowercase();
+            let quality = params
+                .find_map(|param| param.strip_prefix("q="))
+                .map_or(1.0, |q| q.parse().unwrap_or(0.0));
+            (name, quality)
+        })
+        .collect();
+    // explicitly listed encodings take precedence over the wildcard
+    let quality = |encoding: Encoding| {
+        qualities
+            .iter()
+            .find(|(name, _)| name == encoding.name())
+            .or_else(|| qualities.iter().find(|(name, _)| name == "*"))
+            .map_or(0.0, |(_, quality)| *quality)
+    };
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for &encoding in &[Encoding::Brotli, Encoding::Gzip] {
+        let quality = quality(encoding);
+        if quality > 0.0 && best.map_or(true, |(_, best_quality)| quality > best_quality) {
+            best = Some((encoding, quality));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+fn is_compressible(headers: &HeaderMap) -> bool {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+    (content_type.starts_with("application/json") || content_type.starts_with("text/plain"))
+        && !headers.contains_key(header::CONTENT_ENCODING)
+}
+
+/// Compress the response to the request with the given headers, unless it's streamed (like the
+/// events or websocket upgrades) or smaller than `min_size`
+pub(super) async fn compress(
+    req_headers: &HeaderMap,
+    resp: Response<Body>,
+    min_size: usize,
+) -> Result<Response<Body>, hyper::Error> {
+    let encoding = match req_headers
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .and_then(negotiate)
+    {
+        Some(encoding) if is_compressible(resp.headers()) => encoding,
+        _ => return Ok(resp),
+    };
+    match resp.body().size_hint().exact() {
+        Some(size) if size as usize >= min_size => (),
+        _ => return Ok(resp),
+    }
+
+    let (mut parts, body) = resp.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+    let compressed = if body.len() >= BLOCKING_MIN_SIZE {
+        tokio::task::spawn_blocking(move || encoding.encode(&body))
+            .await
+            .expect("compression panicked")
+    } else {
+        encoding.encode(&body)
+    };
+
+    parts.headers.insert(
+        header::CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.name()),
+    );
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts
+        .headers
+        .append(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+    Ok(Response::from_parts(parts, Body::from(compressed)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate("gzip, deflate, br"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip, deflate"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("br;q=0.5, gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("br;q=0, *"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("identity"), None);
+        assert_eq!(negotiate(""), None);
+    }
+
+    #[test]
+    fn test_encode() {
+        let data = "00".repeat(1000);
+        let gzip = Encoding::Gzip.encode(data.as_bytes());
+        let mut decoded = String::new();
+        std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(&gzip[..]), &mut decoded)
+            .unwrap();
+        assert_eq!(decoded, data);
+        assert!(Encoding::Brotli.encode(data.as_bytes()).len() < data.len());
+    }
+}
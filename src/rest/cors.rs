@@ -0,0 +1,160 @@
+//! The CORS policy of the HTTP server, from `--cors`, `--cors-methods`, `--cors-headers` and
+//! `--cors-max-age`. The `*` origin allows any origin along with any request headers, for
+//! development. Otherwise, the origin of the requests is sent back when it's one of the allowed
+//! ones (and nothing at all when it isn't).
+
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Body, Response, StatusCode};
+
+use crate::config::Config;
+
+// custom headers of the API responses, readable by browsers only when exposed
+const EXPOSED_HEADERS: &str = "X-Total-Results, ETag";
+
+pub(super) struct Cors {
+    // none for any origin
+    origins: Option<Vec<HeaderValue>>,
+    methods: HeaderValue,
+    headers: HeaderValue,
+    max_age: HeaderValue,
+}
+
+impl Cors {
+    /// The `--cors-*` values are checked for being valid header values as the arguments are parsed
+    pub(super) fn from_config(config: &Config) -> Option<Self> {
+        let origins = config.cors.as_ref()?;
+        let parse = |value: &str| {
+            HeaderValue::from_str(value.trim()).expect("checked by Config::from_args")
+        };
+        Some(Cors {
+            origins: if origins.trim() == "*" {
+                None
+            } else {
+                Some(origins.split(',').map(parse).collect())
+            },
+            methods: parse(&config.cors_methods),
+            headers: parse(&config.cors_headers),
+            max_age: HeaderValue::from(config.cors_max_age),
+        })
+    }
+
+    /// Preflight requests are `OPTIONS` requests with an `Access-Control-Request-Method` header,
+    /// answered without going through the routes
+    pub(super) fn is_preflight(method: &hyper::Method, headers: &HeaderMap) -> bool {
+        method == hyper::Method::OPTIONS
+            && headers.contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+    }
+
+    pub(super) fn preflight(&self, req_headers: &HeaderMap) -> Response<Body> {
+        let mut resp = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .unwrap();
+        if self.allow_origin(req_headers, resp.headers_mut()) {
+            let headers = resp.headers_mut();
+            headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, self.methods.clone());
+            let allowed_headers = match (
+                &self.origins,
+                req_headers.get(header::ACCESS_CONTROL_REQUEST_HEADERS),
+            ) {
+                (None, Some(requested)) => requested.clone(),
+                _ => self.headers.clone(),
+            };
+            headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, allowed_headers);
+            headers.insert(header::ACCESS_CONTROL_MAX_AGE, self.max_age.clone());
+        }
+        resp
+    }
+
+    /// Add the CORS headers to the response of the request
+    pub(super) fn apply(&self, req_headers: &HeaderMap, resp: &mut Response<Body>) {
+        if self.allow_origin(req_headers, resp.headers_mut()) {
+            resp.headers_mut().insert(
+                header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                HeaderValue::from_static(EXPOSED_HEADERS),
+            );
+        }
+    }
+
+    fn allow_origin(&self, req_headers: &HeaderMap, headers: &mut HeaderMap) -> bool {
+        let origins = match &self.origins {
+            None => {
+                headers.insert(
+                    header::ACCESS_CONTROL_ALLOW_ORIGIN,
+                    HeaderValue::from_static("*"),
+                );
+                return true;
+            }
+            Some(origins) => origins,
+        };
+        // the response depends on the origin, even when it isn't allowed
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        match req_headers.get(header::ORIGIN) {
+            Some(origin) if origins.contains(origin) => {
+                headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cors(origins: &str) -> Cors {
+        Cors {
+            origins: if origins == "*" {
+                None
+            } else {
+                Some(origins.split(',').map(|o| o.parse().unwrap()).collect())
+            },
+            methods: HeaderValue::from_static("GET, POST"),
+            headers: HeaderValue::from_static("Content-Type"),
+            max_age: HeaderValue::from(3600u64),
+        }
+    }
+
+    fn request(origin: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ORIGIN, origin.parse().unwrap());
+        headers.insert(
+            header::ACCESS_CONTROL_REQUEST_HEADERS,
+            "X-Foo".parse().unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn test_allow_origin() {
+        let cors = cors("https://a.example,https://b.example");
+
+        let resp = cors.preflight(&request("https://b.example"));
+        let headers = resp.headers();
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://b.example"
+        );
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "Content-Type"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "3600");
+        assert_eq!(headers[header::VARY], "Origin");
+
+        let resp = cors.preflight(&request("https://c.example"));
+        assert!(!resp
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+        assert!(!resp
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_METHODS));
+
+        let resp = cors("*").preflight(&request("https://c.example"));
+        let headers = resp.headers();
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_HEADERS], "X-Foo");
+        assert!(!headers.contains_key(header::VARY));
+    }
+}
@@ -0,0 +1,84 @@
+//! Conditional requests on the HTTP server. Responses get an ETag hashed from their body, and
+//! requests with a matching `If-None-Match` get a `304 Not Modified` instead. The ETags are weak,
+//! since they are shared by the compressed variants of the responses.
+
+use bitcoin::hashes::{sha256, Hash};
+use hyper::body::HttpBody;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Body, Method, Response, StatusCode};
+
+// headers of the full response that 304 responses must carry as well
+const PRESERVED_HEADERS: [header::HeaderName; 3] =
+    [header::CACHE_CONTROL, header::ETAG, header::VARY];
+
+fn etag(body: &[u8]) -> HeaderValue {
+    let hash = sha256::Hash::hash(body);
+    let tag = format!("W/\"{}\"", hex::encode(&hash[..16]));
+    HeaderValue::from_str(&tag).unwrap()
+}
+
+/// Whether the `If-None-Match` of the request matches the ETag, using the weak comparison
+fn matches(if_none_match: &str, etag: &HeaderValue) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let etag = opaque(etag.to_str().unwrap());
+    if_none_match
+        .split(',')
+        .any(|tag| tag.trim() == "*" || opaque(tag) == etag)
+}
+
+/// Tag the successful `GET` responses with their ETag, unless they're streamed, and reply to the
+/// requests that already have it with `304 Not Modified`
+pub(super) async fn conditional(
+    method: &Method,
+    req_headers: &HeaderMap,
+    resp: Response<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+    if method != Method::GET
+        || resp.status() != StatusCode::OK
+        || resp.body().size_hint().exact().is_none()
+    {
+        return Ok(resp);
+    }
+
+    let (mut parts, body) = resp.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+    let etag = etag(&body);
+    parts.headers.insert(header::ETAG, etag.clone());
+
+    let not_modified = req_headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |if_none_match| matches(if_none_match, &etag));
+    if !not_modified {
+        return Ok(Response::from_parts(parts, Body::from(body)));
+    }
+
+    let mut resp = Response::builder()
+        .status(StatusCode::NOT_MODIFIED)
+        .body(Body::empty())
+        .unwrap();
+    for name in PRESERVED_HEADERS.iter() {
+        for value in parts.headers.get_all(name) {
+            resp.headers_mut().append(name, value.clone());
+        }
+    }
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        let etag = etag(b"{}");
+        let tag = etag.to_str().unwrap().to_string();
+        let strong = tag.trim_start_matches("W/");
+        assert!(matches(&tag, &etag));
+        assert!(matches(strong, &etag));
+        assert!(matches(&format!("\"foo\", {}", tag), &etag));
+        assert!(matches("*", &etag));
+        assert!(!matches("\"foo\"", &etag));
+        assert_ne!(super::etag(b"[]"), etag);
+    }
+}
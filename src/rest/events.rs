@@ -0,0 +1,499 @@
+//! Pushing events to clients as they happen so that they don't have to poll:
+//!
+//! - The `/ws` endpoint streams new blocks, transactions and fee estimates over WebSocket. Clients
+//!   subscribe to the transactions of addresses and scripthashes with the query string, like
+//!   `/ws?address=<addr>,<addr>&scripthash=<hash>`.
+//! - The `/address/:addr/events` and `/scripthash/:hash/events` endpoints stream the funding and
+//!   spending events of a single script as Server-Sent Events, for clients that can't use
+//!   WebSocket. After each block, the stream's event id is set to its height so that reconnecting
+//!   clients resume from the last block they were told about with `Last-Event-ID`.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
+
+use crossbeam_channel::{self as channel, Receiver, Sender};
+use hyper::body::{Bytes, Sender as BodySender};
+use hyper::header::HeaderMap;
+use hyper::upgrade::Upgraded;
+use hyper::{Body, Request, Response, StatusCode};
+use serde_json::Value;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::watch;
+use url::form_urlencoded;
+
+use crate::chain::{BlockHash, Network, OutPoint, Txid};
+use crate::new_index::{compute_script_hash, Query, TxHistoryInfo, TxHistoryRow};
+use crate::util::{has_prevout, spawn_thread, websocket, BlockId, FullHash, TransactionStatus};
+
+use super::{address_to_scripthash, parse_scripthash, HttpError};
+
+/// Events queued per client, beyond which slow clients miss events
+const EVENTS_CAPACITY: usize = 1024;
+/// Addresses and scripthashes a client may subscribe to
+const MAX_SUBSCRIPTIONS: usize = 100;
+
+#[derive(Debug)]
+enum Event {
+    Block {
+        height: usize,
+        hash: BlockHash,
+    },
+    /// A transaction entering the mempool, with the scripthashes of its inputs and outputs
+    Transaction {
+        txid: Txid,
+        scripthashes: HashSet<FullHash>,
+    },
+    FeeEstimates(HashMap<u16, f64>),
+}
+
+impl Event {
+    /// The event as sent to a client subscribed to the scripthashes, unless it doesn't concern it
+    fn to_json(&self, subscriptions: &HashSet<FullHash>) -> Option<Value> {
+        Some(match self {
+            Event::Block { height, hash } => {
+                json!({"event": "block", "height": height, "hash": hash})
+            }
+            Event::Transaction { txid, scripthashes } => {
+                let matched: Vec<String> = scripthashes
+                    .intersection(subscriptions)
+                    .map(hex::encode)
+                    .collect();
+                if matched.is_empty() {
+                    return None;
+                }
+                json!({"event": "transaction", "txid": txid, "scripthashes": matched})
+            }
+            Event::FeeEstimates(estimates) => {
+                json!({"event": "fee-estimates", "estimates": estimates})
+            }
+        })
+    }
+}
+
+/// Looks for new blocks, mempool transactions and fee estimates when notified, publishing them
+/// to the `/ws` clients
+pub struct EventSource {
+    sender: broadcast::Sender<Arc<Event>>,
+    notifier: Sender<()>,
+    // streams are ended on shutdown, as the server waits for open responses to complete
+    shutdown: watch::Sender<bool>,
+    shutdown_receiver: watch::Receiver<bool>,
+}
+
+impl EventSource {
+    pub fn start(query: Arc<Query>) -> Self {
+        let (sender, _) = broadcast::channel(EVENTS_CAPACITY);
+        let (notifier, notifications) = channel::unbounded();
+        let (shutdown, shutdown_receiver) = watch::channel(false);
+        let events = sender.clone();
+        spawn_thread("rest-events", move || run(query, events, notifications));
+        EventSource {
+            sender,
+            notifier,
+            shutdown,
+            shutdown_receiver,
+        }
+    }
+
+    /// Look for new events, once the index and mempool were updated
+    pub fn notify(&self) {
+        let _ = self.notifier.send(());
+    }
+
+    /// End the streams of all clients
+    pub fn shutdown(&self) {
+        let _ = self.shutdown.send(true);
+    }
+
+    fn subscribe(&self) -> (broadcast::Receiver<Arc<Event>>, watch::Receiver<bool>) {
+        (self.sender.subscribe(), self.shutdown_receiver.clone())
+    }
+}
+
+fn run(query: Arc<Query>, sender: broadcast::Sender<Arc<Event>>, notifications: Receiver<()>) {
+    let tip = query.chain().best_header();
+    let (mut tip_height, mut tip_hash) = (tip.height(), *tip.hash());
+    let mut txids = mempool_txids(&query);
+    let mut fee_estimates = HashMap::new();
+    let publish = |event| {
+        // fails when there are no clients, which is fine
+        let _ = sender.send(Arc::new(event));
+    };
+
+    for () in notifications.iter() {
+        // the baseline is kept up to date, but events are only looked into if anyone listens
+        let listened = sender.receiver_count() > 0;
+
+        let tip = query.chain().best_header();
+        if *tip.hash() != tip_hash && listened {
+            // every new block, or just the new tip after a reorg
+            let from = if tip.height() > tip_height {
+                tip_height + 1
+            } else {
+                tip.height()
+            };
+            for header in (from..=tip.height()).filter_map(|h| query.chain().header_by_height(h)) {
+                publish(Event::Block {
+                    height: header.height(),
+                    hash: *header.hash(),
+                });
+            }
+        }
+        tip_height = tip.height();
+        tip_hash = *tip.hash();
+
+        let current_txids = mempool_txids(&query);
+        if listened {
+            for txid in current_txids.difference(&txids) {
+                if let Some(scripthashes) = tx_scripthashes(&query, txid) {
+                    publish(Event::Transaction {
+                        txid: *txid,
+                        scripthashes,
+                    });
+                }
+            }
+        }
+        txids = current_txids;
+
+        if listened {
+            let estimates = query.estimate_fee_map();
+            if estimates != fee_estimates {
+                publish(Event::FeeEstimates(estimates.clone()));
+                fee_estimates = estimates;
+            }
+        }
+    }
+}
+
+fn mempool_txids(query: &Query) -> HashSet<Txid> {
+    query.mempool().txids().into_iter().cloned().collect()
+}
+
+/// The scripthashes of the transaction's outputs and of the outputs it spends
+fn tx_scripthashes(query: &Query, txid: &Txid) -> Option<HashSet<FullHash>> {
+    let mempool = query.mempool();
+    let tx = mempool.lookup_txn(txid)?;
+    let mut scripthashes: HashSet<FullHash> = tx
+        .output
+        .iter()
+        .map(|txout| compute_script_hash(&txout.script_pubkey))
+        .collect();
+    let prevouts: BTreeSet<OutPoint> = tx
+        .input
+        .iter()
+        .filter(|txin| has_prevout(txin))
+        .map(|txin| txin.previous_output)
+        .collect();
+    if let Ok(prevouts) = mempool.lookup_txos(&prevouts) {
+        scripthashes.extend(
+            prevouts
+                .values()
+                .map(|txout| compute_script_hash(&txout.script_pubkey)),
+        );
+    }
+    Some(scripthashes)
+}
+
+/// Accept the WebSocket handshake, and stream the events to the client once upgraded
+pub(super) fn upgrade(
+    mut req: Request<Body>,
+    events: &EventSource,
+    network: Network,
+) -> Result<Response<Body>, HttpError> {
+    let key = req
+        .headers()
+        .get("Sec-WebSocket-Key")
+        .and_then(|key| key.to_str().ok())
+        .ok_or_else(|| HttpError::from("Missing Sec-WebSocket-Key header".to_string()))?
+        .to_string();
+    let subscriptions = subscriptions_from_query(req.uri().query(), network)?;
+
+    let on_upgrade = hyper::upgrade::on(&mut req);
+    let (receiver, shutdown) = events.subscribe();
+    tokio::spawn(async move {
+        match on_upgrade.await {
+            Ok(upgraded) => stream_events(upgraded, receiver, shutdown, subscriptions).await,
+            Err(e) => warn!("websocket upgrade failed: {}", e),
+        }
+    });
+
+    Ok(Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header("Upgrade", "websocket")
+        .header("Connection", "Upgrade")
+        .header("Sec-WebSocket-Accept", websocket::accept_key(&key))
+        .body(Body::empty())
+        .unwrap())
+}
+
+fn subscriptions_from_query(
+    query: Option<&str>,
+    network: Network,
+) -> Result<HashSet<FullHash>, HttpError> {
+    let mut subscriptions = HashSet::new();
+    for (name, values) in form_urlencoded::parse(query.unwrap_or("").as_bytes()) {
+        for value in values.split(',').filter(|value| !value.is_empty()) {
+            subscriptions.insert(match name.as_ref() {
+                "address" => address_to_scripthash(value, network)?,
+                "scripthash" => parse_scripthash(value)?,
+                _ => continue,
+            });
+        }
+    }
+    if subscriptions.len() > MAX_SUBSCRIPTIONS {
+        return Err(HttpError::from(format!(
+            "Too many subscriptions (max {})",
+            MAX_SUBSCRIPTIONS
+        )));
+    }
+    Ok(subscriptions)
+}
+
+async fn stream_events(
+    upgraded: Upgraded,
+    mut events: broadcast::Receiver<Arc<Event>>,
+    mut shutdown: watch::Receiver<bool>,
+    subscriptions: HashSet<FullHash>,
+) {
+    let (mut reader, mut writer) = tokio::io::split(upgraded);
+    let mut buf = [0u8; 1024];
+    loop {
+        let mut frame = vec![];
+        tokio::select! {
+            // messages from the client aren't used, reading them only tells when it goes away
+            read = reader.read(&mut buf) => match read {
+                Ok(0) | Err(_) => break,
+                Ok(_) => continue,
+            },
+            event = events.recv() => match event {
+                Ok(event) => match event.to_json(&subscriptions) {
+                    Some(json) => websocket::write_text(&mut frame, &json.to_string()).unwrap(),
+                    None => continue,
+                },
+                Err(RecvError::Lagged(missed)) => {
+                    debug!("websocket client missed {} events", missed);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            },
+            _ = shutdown.changed() => {
+                websocket::write_close(&mut frame, websocket::CLOSE_GOING_AWAY).unwrap();
+                let _ = writer.write_all(&frame).await;
+                break;
+            }
+        }
+        if writer.write_all(&frame).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Stream the funding and spending events of the scripthash, replaying those confirmed after the
+/// block given by `Last-Event-ID` and those in the mempool when resuming
+pub(super) fn stream_history(
+    headers: &HeaderMap,
+    scripthash: FullHash,
+    events: &EventSource,
+    query: Arc<Query>,
+) -> Result<Response<Body>, HttpError> {
+    let last_height = headers
+        .get("Last-Event-ID")
+        .map(|id| {
+            id.to_str()
+                .ok()
+                .and_then(|id| id.parse::<usize>().ok())
+                .ok_or_else(|| HttpError::from("Invalid Last-Event-ID".to_string()))
+        })
+        .transpose()?;
+
+    let (body, response) = Body::channel();
+    let (receiver, shutdown) = events.subscribe();
+    tokio::spawn(send_history(
+        body,
+        query,
+        scripthash,
+        last_height,
+        receiver,
+        shutdown,
+    ));
+
+    Ok(Response::builder()
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .body(response)
+        .unwrap())
+}
+
+async fn send_history(
+    mut body: BodySender,
+    query: Arc<Query>,
+    scripthash: FullHash,
+    last_height: Option<usize>,
+    mut events: broadcast::Receiver<Arc<Event>>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    // events are subscribed to before looking at the chain, so that no block is missed in between
+    let tip_height = query.chain().best_height();
+    let resumed = match last_height {
+        Some(last_height) => {
+            confirmed_history(&query, &scripthash, last_height + 1, tip_height)
+                + &mempool_history(&query, &scripthash, None)
+        }
+        None => format!("id: {}\n\n", tip_height),
+    };
+    if body.send_data(Bytes::from(resumed)).await.is_err() {
+        return;
+    }
+
+    loop {
+        let messages = tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => match &*event {
+                    // already covered when connecting
+                    Event::Block { height, .. } if *height <= tip_height => continue,
+                    Event::Block { height, .. } => {
+                        confirmed_history(&query, &scripthash, *height, *height)
+                    }
+                    Event::Transaction { txid, scripthashes } if scripthashes.contains(&scripthash) => {
+                        mempool_history(&query, &scripthash, Some(txid))
+                    }
+                    _ => continue,
+                },
+                // end the stream, for the client to reconnect and resume from its last block
+                Err(RecvError::Lagged(_)) | Err(RecvError::Closed) => break,
+            },
+            _ = shutdown.changed() => break,
+        };
+        if !messages.is_empty() && body.send_data(Bytes::from(messages)).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// The messages of the scripthash's history confirmed within the heights, followed by the id of
+/// the last one
+fn confirmed_history(
+    query: &Query,
+    scripthash: &FullHash,
+    from_height: usize,
+    to_height: usize,
+) -> String {
+    let chain = query.chain();
+    let mut messages = String::new();
+    let rows = chain
+        .history_iter_scan(b'H', scripthash, from_height)
+        .map(TxHistoryRow::from_row)
+        .take_while(|row| row.key.confirmed_height as usize <= to_height);
+    for row in rows {
+        // the index keeps the rows of the blocks that were reorged away
+        match chain.tx_confirming_block(&row.get_txid()) {
+            Some(blockid) if blockid.height == row.key.confirmed_height as usize => {
+                messages += &history_message(&row.key.txinfo, Some(blockid))
+            }
+            _ => (),
+        }
+    }
+    messages + &format!("id: {}\n\n", to_height)
+}
+
+/// The messages of the scripthash's mempool history, or only of the transaction's part in it
+fn mempool_history(query: &Query, scripthash: &FullHash, txid: Option<&Txid>) -> String {
+    query
+        .mempool()
+        .history_entries(scripthash)
+        .iter()
+        .filter(|info| txid.map_or(true, |txid| info.get_txid() == *txid))
+        .map(|info| history_message(info, None))
+        .collect()
+}
+
+fn history_message(info: &TxHistoryInfo, blockid: Option<BlockId>) -> String {
+    let status = TransactionStatus::from(blockid);
+    let (event, data) = match info {
+        TxHistoryInfo::Funding(funding) => (
+            "funding",
+            json!({
+                "txid": info.get_txid(),
+                "vout": funding.vout,
+                "value": funding.value,
+                "status": status,
+            }),
+        ),
+        TxHistoryInfo::Spending(spending) => {
+            let prevout = info.get_funded_outpoint();
+            (
+                "spending",
+                json!({
+                    "txid": info.get_txid(),
+                    "vin": spending.vin,
+                    "prev_txid": prevout.txid,
+                    "prev_vout": prevout.vout,
+                    "value": spending.value,
+                    "status": status,
+                }),
+            )
+        }
+        #[cfg(feature = "liquid")]
+        _ => return String::new(),
+    };
+    format!("event: {}\ndata: {}\n\n", event, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_json() {
+        let (watched, other) = ([1u8; 32], [2u8; 32]);
+        let subscriptions: HashSet<FullHash> = vec![watched].into_iter().collect();
+        let txid = Txid::default();
+        let event = |scripthashes: Vec<FullHash>| Event::Transaction {
+            txid,
+            scripthashes: scripthashes.into_iter().collect(),
+        };
+        assert_eq!(event(vec![other]).to_json(&subscriptions), None);
+        assert_eq!(
+            event(vec![watched, other]).to_json(&subscriptions),
+            Some(json!({
+                "event": "transaction",
+                "txid": txid,
+                "scripthashes": [hex::encode(watched)],
+            }))
+        );
+
+        let estimates: HashMap<u16, f64> = vec![(1, 20.5)].into_iter().collect();
+        assert_eq!(
+            Event::FeeEstimates(estimates).to_json(&HashSet::new()),
+            Some(json!({"event": "fee-estimates", "estimates": {"1": 20.5}}))
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "liquid"))]
+    fn test_history_message() {
+        use crate::new_index::FundingInfo;
+
+        let funding = TxHistoryInfo::Funding(FundingInfo {
+            txid: [0; 32],
+            vout: 1,
+            value: 1000,
+        });
+        let blockid = BlockId {
+            height: 100,
+            hash: BlockHash::default(),
+            time: 0,
+        };
+        let data = json!({
+            "txid": Txid::default(),
+            "vout": 1,
+            "value": 1000,
+            "status": TransactionStatus::from(Some(blockid.clone())),
+        });
+        assert_eq!(
+            history_message(&funding, Some(blockid)),
+            format!("event: funding\ndata: {}\n\n", data)
+        );
+    }
+}
@@ -0,0 +1,220 @@
+//! Paginating the history of addresses with opaque cursors. Pages list the mempool transactions
+//! first, then the confirmed ones from the most recent, those of the same block (and those of the
+//! mempool) being ordered by txid. Cursors give the position of the last transaction of the page
+//! rather than the transaction itself, so that they remain usable when it gets confirmed or
+//! reorged away in the meantime.
+
+use std::fmt;
+use std::str::FromStr;
+
+use bitcoin::hashes::Hash;
+
+use crate::chain::Txid;
+use crate::new_index::Query;
+use crate::util::BlockId;
+
+use super::{HttpError, CHAIN_TXS_PER_PAGE, HISTORY_MAX_PER_PAGE};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum Cursor {
+    Mempool(Txid),
+    Confirmed(usize, Txid),
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = match self {
+            Cursor::Mempool(txid) => [&[0u8][..], &txid[..]].concat(),
+            Cursor::Confirmed(height, txid) => {
+                [&[1u8][..], &(*height as u32).to_be_bytes(), &txid[..]].concat()
+            }
+        };
+        write!(f, "{}", hex::encode(bytes))
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = HttpError;
+
+    fn from_str(s: &str) -> Result<Self, HttpError> {
+        let invalid = || HttpError::from("Invalid cursor".to_string());
+        let bytes = hex::decode(s).map_err(|_| invalid())?;
+        match bytes.split_first() {
+            Some((0, txid)) => Ok(Cursor::Mempool(
+                Txid::from_slice(txid).map_err(|_| invalid())?,
+            )),
+            Some((1, rest)) if rest.len() == 36 => {
+                let mut height = [0u8; 4];
+                height.copy_from_slice(&rest[..4]);
+                let txid = Txid::from_slice(&rest[4..]).map_err(|_| invalid())?;
+                Ok(Cursor::Confirmed(u32::from_be_bytes(height) as usize, txid))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// The page size given by the `limit` query parameter, the default one if missing or invalid
+pub(super) fn page_limit(limit: Option<&String>) -> usize {
+    limit
+        .and_then(|n| n.parse().ok())
+        .map(|n: usize| n.max(1).min(HISTORY_MAX_PER_PAGE))
+        .unwrap_or(CHAIN_TXS_PER_PAGE)
+}
+
+/// The page of the scripthash's history following the cursor, and the cursor of the next page
+/// unless it's the last one
+pub(super) fn history_page(
+    query: &Query,
+    scripthash: &[u8],
+    cursor: Option<Cursor>,
+    limit: usize,
+) -> (Vec<(Txid, Option<BlockId>)>, Option<Cursor>) {
+    paginate(
+        || query.mempool().history_txids(scripthash, usize::MAX),
+        |before, limit| {
+            query
+                .chain()
+                .history_txids_before(scripthash, before, limit)
+        },
+        cursor,
+        limit,
+    )
+}
+
+/// Paginate over the `mempool` txids, then over the `confirmed` ones (which lists up to a number of
+/// them before a position)
+fn paginate<M, C>(
+    mempool: M,
+    confirmed: C,
+    cursor: Option<Cursor>,
+    limit: usize,
+) -> (Vec<(Txid, Option<BlockId>)>, Option<Cursor>)
+where
+    M: FnOnce() -> Vec<Txid>,
+    C: FnOnce(Option<(usize, Txid)>, usize) -> Vec<(Txid, BlockId)>,
+{
+    // one extra, to tell whether there is a next page
+    let mut page: Vec<(Txid, Option<BlockId>)> = vec![];
+
+    let confirmed_before = match cursor {
+        Some(Cursor::Confirmed(height, txid)) => Some((height, txid)),
+        _ => {
+            let mempool_after = match cursor {
+                Some(Cursor::Mempool(txid)) => Some(txid),
+                _ => None,
+            };
+            let mut txids = mempool();
+            txids.sort_unstable_by(|a, b| b.cmp(a));
+            page.extend(
+                txids
+                    .into_iter()
+                    .filter(|txid| mempool_after.map_or(true, |after| *txid < after))
+                    .take(limit + 1)
+                    .map(|txid| (txid, None)),
+            );
+            None
+        }
+    };
+    if page.len() <= limit {
+        let confirmed = confirmed(confirmed_before, limit + 1 - page.len());
+        page.extend(
+            confirmed
+                .into_iter()
+                .map(|(txid, blockid)| (txid, Some(blockid))),
+        );
+    }
+
+    if page.len() <= limit {
+        return (page, None);
+    }
+    page.truncate(limit);
+    let next_cursor = page.last().map(|(txid, blockid)| match blockid {
+        Some(blockid) => Cursor::Confirmed(blockid.height, *txid),
+        None => Cursor::Mempool(*txid),
+    });
+    (page, next_cursor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cursor() {
+        let txid = Txid::from_slice(&[7; 32]).unwrap();
+        for cursor in &[Cursor::Mempool(txid), Cursor::Confirmed(700_000, txid)] {
+            assert_eq!(cursor.to_string().parse::<Cursor>().unwrap(), *cursor);
+        }
+        assert!("".parse::<Cursor>().is_err());
+        assert!("01".parse::<Cursor>().is_err());
+        assert!(format!("02{}", hex::encode([7; 32]))
+            .parse::<Cursor>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_page_limit() {
+        let limit = |limit: &str| page_limit(Some(&limit.to_string()));
+        assert_eq!(page_limit(None), CHAIN_TXS_PER_PAGE);
+        assert_eq!(limit("10"), 10);
+        assert_eq!(limit("0"), 1);
+        assert_eq!(limit("1000"), HISTORY_MAX_PER_PAGE);
+        assert_eq!(limit("ten"), CHAIN_TXS_PER_PAGE);
+    }
+
+    #[test]
+    fn test_paginate() {
+        let txid = |i: u8| Txid::from_slice(&[i; 32]).unwrap();
+        let blockid = |height: usize| BlockId {
+            height,
+            hash: Default::default(),
+            time: 0,
+        };
+        // like `history_txids_before`, the most recent first and by descending txid within blocks
+        let chain = |txs: Vec<(u8, usize)>| {
+            move |before: Option<(usize, Txid)>, limit: usize| {
+                txs.iter()
+                    .map(|(i, height)| (txid(*i), blockid(*height)))
+                    .filter(|(txid, blockid)| before.map_or(true, |b| (blockid.height, *txid) < b))
+                    .take(limit)
+                    .collect::<Vec<_>>()
+            }
+        };
+        let mempool = vec![txid(1), txid(3), txid(2)];
+        let confirmed = vec![(6, 20), (5, 10), (4, 10)];
+
+        let mut pages = vec![];
+        let mut cursor = None;
+        loop {
+            let (page, next) = paginate(|| mempool.clone(), chain(confirmed.clone()), cursor, 2);
+            pages.push(page);
+            cursor = match next {
+                Some(next) => Some(next),
+                None => break,
+            };
+        }
+        let txids = |page: &[(Txid, Option<BlockId>)]| {
+            page.iter().map(|(txid, _)| txid[0]).collect::<Vec<_>>()
+        };
+        let pages: Vec<_> = pages.iter().map(|page| txids(page.as_slice())).collect();
+        assert_eq!(pages, vec![vec![3, 2], vec![1, 6], vec![5, 4]]);
+
+        // cursors remain usable when their transaction was confirmed or reorged away since
+        let (page, _) = paginate(
+            || vec![txid(1)],
+            chain(vec![(3, 30), (6, 20), (4, 10)]),
+            Some(Cursor::Mempool(txid(3))),
+            2,
+        );
+        assert_eq!(txids(page.as_slice()), vec![1, 3]);
+        let (page, next) = paginate(
+            Vec::new,
+            chain(vec![(6, 20), (4, 10)]),
+            Some(Cursor::Confirmed(10, txid(5))),
+            2,
+        );
+        assert_eq!(txids(page.as_slice()), vec![4]);
+        assert_eq!(next, None);
+    }
+}
@@ -78,6 +78,23 @@ pub fn get_id_from_pos(
     Ok((txid, branch))
 }
 
+/// Compute the merkle root from a leaf hash at `index` and its merkle branch
+pub fn get_merkle_root_from_branch(
+    mut hash: Sha256dHash,
+    branch: &[Sha256dHash],
+    mut index: usize,
+) -> Sha256dHash {
+    for sibling in branch {
+        hash = if index % 2 == 0 {
+            merklize(hash, *sibling)
+        } else {
+            merklize(*sibling, hash)
+        };
+        index /= 2;
+    }
+    hash
+}
+
 fn merklize(left: Sha256dHash, right: Sha256dHash) -> Sha256dHash {
     let data = [&left[..], &right[..]].concat();
     Sha256dHash::hash(&data)
@@ -103,3 +120,24 @@ fn create_merkle_branch_and_root(
     }
     (merkle, hashes[0])
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merkle_branch() {
+        for count in 1..=9u8 {
+            let hashes: Vec<Sha256dHash> = (0..count).map(|i| Sha256dHash::hash(&[i])).collect();
+            let (_, root) = create_merkle_branch_and_root(hashes.clone(), 0);
+            for (index, hash) in hashes.iter().enumerate() {
+                let (branch, branch_root) = create_merkle_branch_and_root(hashes.clone(), index);
+                assert_eq!(branch_root, root);
+                assert_eq!(get_merkle_root_from_branch(*hash, &branch, index), root);
+            }
+        }
+        // a single hash is its own root
+        let hash = Sha256dHash::hash(b"genesis");
+        assert_eq!(create_merkle_branch_and_root(vec![hash], 0), (vec![], hash));
+    }
+}
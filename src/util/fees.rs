@@ -1,8 +1,13 @@
-use crate::chain::{Network, Transaction, TxOut};
-use std::collections::HashMap;
+use crate::chain::{Network, Transaction, TxOut, Txid};
+use std::collections::{BTreeMap, HashMap};
 
 const VSIZE_BIN_WIDTH: u32 = 50_000; // in vbytes
 
+// The vsize of the first bin of the electrum fee histogram, growing by 10% for every next one
+const ELECTRUM_FIRST_BIN_VSIZE: f64 = 100_000.0;
+// Fee rates of the electrum fee histogram are rounded down to a tenth of sat/vbyte
+const ELECTRUM_BINS_PER_SAT: f64 = 10.0;
+
 pub struct TxFeeInfo {
     pub fee: u64,   // in satoshis
     pub vsize: u32, // in virtual bytes (= weight/4)
@@ -58,3 +63,80 @@ pub fn make_fee_histogram(mut entries: Vec<&TxFeeInfo>) -> Vec<(f32, u32)> {
     }
     histogram
 }
+
+/// The vsize of mempool transactions by fee rate, as returned by `mempool.get_fee_histogram`.
+/// Transactions are added at their effective fee rate, which accounts for their ancestors and
+/// descendants, and updated as these enter and leave the mempool.
+#[derive(Default)]
+pub struct FeeHistogram {
+    bins: BTreeMap<u64, u64>, // fee rate (in 1/ELECTRUM_BINS_PER_SAT sat/vbyte) -> vsize
+    entries: HashMap<Txid, (u64, u32)>, // txid -> (fee rate bin, vsize)
+}
+
+impl FeeHistogram {
+    /// Add the transaction, or move it to another bin if it was already added
+    pub fn insert(&mut self, txid: Txid, fee_per_vbyte: f32, vsize: u32) {
+        self.remove(&txid);
+        let bin = (fee_per_vbyte as f64 * ELECTRUM_BINS_PER_SAT).floor() as u64;
+        *self.bins.entry(bin).or_insert(0) += vsize as u64;
+        self.entries.insert(txid, (bin, vsize));
+    }
+
+    pub fn remove(&mut self, txid: &Txid) {
+        if let Some((bin, vsize)) = self.entries.remove(txid) {
+            let bin_vsize = self.bins.get_mut(&bin).expect("missing fee histogram bin");
+            *bin_vsize -= vsize as u64;
+            if *bin_vsize == 0 {
+                self.bins.remove(&bin);
+            }
+        }
+    }
+
+    /// Compact the histogram into (fee rate, vsize) pairs by decreasing fee rate, the way ElectrumX
+    /// does: each pair holds the vsize of transactions paying at least its fee rate (and less than
+    /// the previous one's), with bins growing by 10%. The remaining lowest-paying transactions
+    /// that don't fill a bin are left out.
+    pub fn compact(&self) -> Vec<(f64, u64)> {
+        let mut histogram = vec![];
+        let mut bin_size = ELECTRUM_FIRST_BIN_VSIZE;
+        let mut cum_size = 0;
+        let mut excess = 0.0;
+        for (bin, vsize) in self.bins.iter().rev() {
+            cum_size += vsize;
+            if cum_size as f64 + excess > bin_size {
+                histogram.push((*bin as f64 / ELECTRUM_BINS_PER_SAT, cum_size));
+                excess += cum_size as f64 - bin_size;
+                cum_size = 0;
+                bin_size *= 1.1;
+            }
+        }
+        histogram
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+
+    #[test]
+    fn test_fee_histogram() {
+        let txid = |n: u8| Txid::from_slice(&[n; 32]).unwrap();
+        let mut histogram = FeeHistogram::default();
+        histogram.insert(txid(1), 50.0, 60_000);
+        histogram.insert(txid(2), 50.05, 50_000);
+        histogram.insert(txid(3), 20.0, 80_000);
+        histogram.insert(txid(4), 10.0, 80_000);
+        histogram.insert(txid(5), 1.0, 1_000);
+        assert_eq!(histogram.compact(), vec![(50.0, 110_000), (10.0, 160_000)]);
+
+        // moved to a lower bin as its effective fee rate changes
+        histogram.insert(txid(2), 15.0, 50_000);
+        assert_eq!(histogram.compact(), vec![(20.0, 140_000), (10.0, 130_000)]);
+
+        histogram.remove(&txid(2));
+        histogram.remove(&txid(3));
+        assert_eq!(histogram.compact(), vec![(10.0, 140_000)]);
+        assert!(histogram.bins.get(&200).is_none());
+    }
+}
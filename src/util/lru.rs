@@ -0,0 +1,91 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// A map holding up to `capacity` entries, evicting the least recently used one when full.
+/// Used to keep the memory of tracking maps keyed by untrusted input (like IP addresses) bounded.
+#[derive(Debug)]
+pub struct LruMap<K, V> {
+    capacity: usize,
+    entries: HashMap<K, (V, u64)>,
+    /// The entry keys, by the tick of their last use
+    order: BTreeMap<u64, K>,
+    tick: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> LruMap<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        LruMap {
+            capacity,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Get the value for `key`, marking it as recently used
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        let tick = self.next_tick();
+        let (value, last_used) = self.entries.get_mut(key)?;
+        self.order.remove(last_used);
+        self.order.insert(tick, key.clone());
+        *last_used = tick;
+        Some(value)
+    }
+
+    /// Get a mutable reference to the value for `key`, marking it as recently used
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let tick = self.next_tick();
+        let (value, last_used) = self.entries.get_mut(key)?;
+        self.order.remove(last_used);
+        self.order.insert(tick, key.clone());
+        *last_used = tick;
+        Some(value)
+    }
+
+    /// Insert a value, evicting the least recently used entry if the map is full
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let tick = self.next_tick();
+        let old = self.entries.insert(key.clone(), (value, tick));
+        if let Some((_, last_used)) = old {
+            self.order.remove(&last_used);
+        }
+        self.order.insert(tick, key);
+
+        while self.entries.len() > self.capacity {
+            let oldest = *self.order.keys().next().unwrap();
+            let key = self.order.remove(&oldest).unwrap();
+            self.entries.remove(&key);
+        }
+        old.map(|(value, _)| value)
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let (value, last_used) = self.entries.remove(key)?;
+        self.order.remove(&last_used);
+        Some(value)
+    }
+
+    pub fn retain(&mut self, mut f: impl FnMut(&K, &mut V) -> bool) {
+        let order = &mut self.order;
+        self.entries.retain(|key, (value, last_used)| {
+            let keep = f(key, value);
+            if !keep {
+                order.remove(last_used);
+            }
+            keep
+        });
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
@@ -1,12 +1,17 @@
 mod block;
+mod lru;
 mod script;
 mod transaction;
 
 pub mod electrum_merkle;
 pub mod fees;
+pub mod proxy_protocol;
+pub mod socks;
+pub mod websocket;
 
 pub use self::block::{BlockHeaderMeta, BlockId, BlockMeta, BlockStatus, HeaderEntry, HeaderList};
 pub use self::fees::get_tx_fee;
+pub use self::lru::LruMap;
 pub use self::script::{get_innerscripts, ScriptToAddr, ScriptToAsm};
 pub use self::transaction::{
     extract_tx_prevouts, has_prevout, is_coinbase, is_spendable, serialize_outpoint,
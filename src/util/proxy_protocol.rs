@@ -0,0 +1,166 @@
+//! The PROXY protocol header (v1 and v2), sent by proxies like HAProxy and nginx at the start of
+//! the connections they forward, to pass on the address of the actual client.
+//! See https://www.haproxy.org/download/2.4/doc/proxy-protocol.txt
+
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream};
+use std::time::Duration;
+
+use crate::errors::*;
+
+/// Trusted proxies send the header right away, so this is only reached if they're misconfigured
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The address of the client behind the connection, read from the PROXY header if it comes from
+/// one of the `trusted_proxies`. Connections from the proxies that carry no client address (like
+/// their health checks) are attributed to the proxies.
+pub fn client_addr(
+    stream: &TcpStream,
+    peer_addr: SocketAddr,
+    trusted_proxies: &[IpAddr],
+) -> Result<SocketAddr> {
+    if !trusted_proxies.contains(&peer_addr.ip()) {
+        return Ok(peer_addr);
+    }
+    stream
+        .set_read_timeout(Some(HEADER_TIMEOUT))
+        .chain_err(|| "failed setting read timeout")?;
+    let client_addr = read_header(&mut &*stream);
+    if client_addr.is_err() {
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+    stream
+        .set_read_timeout(None)
+        .chain_err(|| "failed resetting read timeout")?;
+    Ok(client_addr?.unwrap_or(peer_addr))
+}
+
+/// Read the header at the start of the connection, without reading past it. Returns the source
+/// address it carries, if any.
+pub fn read_header(reader: &mut impl Read) -> Result<Option<SocketAddr>> {
+    let mut start = [0u8; 12];
+    reader
+        .read_exact(&mut start[..V1_PREFIX.len()])
+        .chain_err(|| "failed reading PROXY header")?;
+    if &start[..V1_PREFIX.len()] == V1_PREFIX {
+        return read_v1(reader);
+    }
+    reader
+        .read_exact(&mut start[V1_PREFIX.len()..])
+        .chain_err(|| "failed reading PROXY header")?;
+    ensure!(start == V2_SIGNATURE, "missing PROXY header");
+    read_v2(reader)
+}
+
+/// The human-readable header, like "PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n"
+fn read_v1(reader: &mut impl Read) -> Result<Option<SocketAddr>> {
+    let mut line = V1_PREFIX.to_vec();
+    while !line.ends_with(b"\r\n") {
+        ensure!(line.len() < V1_MAX_LEN, "PROXY header too long");
+        let mut byte = [0u8];
+        reader
+            .read_exact(&mut byte)
+            .chain_err(|| "failed reading PROXY header")?;
+        line.push(byte[0]);
+    }
+    let line = String::from_utf8(line).chain_err(|| "invalid PROXY header")?;
+    let fields: Vec<&str> = line.trim_end().split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4", src, _dst, src_port, _dst_port]
+        | ["PROXY", "TCP6", src, _dst, src_port, _dst_port] => {
+            let ip: IpAddr = src.parse().chain_err(|| "invalid PROXY source address")?;
+            let port = src_port.parse().chain_err(|| "invalid PROXY source port")?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => bail!("invalid PROXY header: {:?}", line),
+    }
+}
+
+/// The binary header, following the signature
+fn read_v2(reader: &mut impl Read) -> Result<Option<SocketAddr>> {
+    let mut head = [0u8; 4];
+    reader
+        .read_exact(&mut head)
+        .chain_err(|| "failed reading PROXY header")?;
+    let (version, command, family) = (head[0] >> 4, head[0] & 0x0f, head[1] >> 4);
+    ensure!(version == 2, "unsupported PROXY version {}", version);
+    let mut addrs = vec![0u8; u16::from_be_bytes([head[2], head[3]]) as usize];
+    reader
+        .read_exact(&mut addrs)
+        .chain_err(|| "failed reading PROXY addresses")?;
+
+    // LOCAL connections are made by the proxy itself
+    if command == 0 {
+        return Ok(None);
+    }
+    ensure!(command == 1, "unsupported PROXY command {}", command);
+    match family {
+        // AF_INET, followed by the source and destination addresses and ports
+        1 => {
+            ensure!(addrs.len() >= 12, "truncated PROXY addresses");
+            let ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            let port = u16::from_be_bytes([addrs[8], addrs[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // AF_INET6
+        2 => {
+            ensure!(addrs.len() >= 36, "truncated PROXY addresses");
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&addrs[..16]);
+            let port = u16::from_be_bytes([addrs[32], addrs[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        // AF_UNSPEC and AF_UNIX carry no usable address
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_v1() {
+        let mut header: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 50001\r\n{\"id\":0}";
+        assert_eq!(
+            read_header(&mut header).unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        // the rest of the connection is left unread
+        assert_eq!(header, b"{\"id\":0}");
+
+        let mut header: &[u8] = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 50001\r\n";
+        assert_eq!(
+            read_header(&mut header).unwrap(),
+            Some("[2001:db8::1]:56324".parse().unwrap())
+        );
+        assert_eq!(read_header(&mut &b"PROXY UNKNOWN\r\n"[..]).unwrap(), None);
+        assert!(read_header(&mut &b"PROXY TCP4 192.0.2.1\r\n"[..]).is_err());
+        assert!(read_header(&mut &[b'A'; 200][..]).is_err());
+    }
+
+    #[test]
+    fn test_v2() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend(&[0x21, 0x11, 0, 12]); // PROXY command, TCP over IPv4
+        header.extend(&[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0xc3, 0x51]);
+        header.extend(b"rest");
+        let mut reader = &header[..];
+        assert_eq!(
+            read_header(&mut reader).unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(reader, b"rest");
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend(&[0x20, 0x00, 0, 0]);
+        assert_eq!(read_header(&mut &local[..]).unwrap(), None);
+
+        assert!(read_header(&mut &b"{\"id\":0,\"method\":\"x\"}"[..]).is_err());
+    }
+}
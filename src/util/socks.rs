@@ -0,0 +1,256 @@
+//! The client side of SOCKS5 (RFC 1928), with username/password authentication (RFC 1929).
+//! Hostnames are passed on to the proxy to be resolved there, as tor requires for onion hosts and
+//! to avoid leaking DNS queries for clearnet ones. Tor's RESOLVE extension is supported too, for
+//! resolving hostnames without connecting to them.
+
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::time::Duration;
+
+use crate::errors::*;
+
+const VERSION: u8 = 5;
+const METHOD_NONE: u8 = 0;
+const METHOD_PASSWORD: u8 = 2;
+const PASSWORD_VERSION: u8 = 1;
+const CMD_CONNECT: u8 = 1;
+/// Tor's extension resolving the hostname, replying with its address as the bound address
+const CMD_RESOLVE: u8 = 0xf0;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// Connect to `host:port` through the proxy. The read and write timeouts of the returned stream are
+/// set to `timeout`, which also bounds each step of the proxy handshake.
+pub fn connect(
+    proxy: SocketAddr,
+    credentials: Option<(&str, &str)>,
+    host: &str,
+    port: u16,
+    timeout: Option<Duration>,
+) -> Result<TcpStream> {
+    let mut stream = connect_proxy(proxy, timeout)?;
+    request(&mut stream, credentials, CMD_CONNECT, host, port)
+        .chain_err(|| format!("failed to connect to {}:{} via {}", host, port, proxy))?;
+    Ok(stream)
+}
+
+/// Resolve the hostname through the proxy, which must be tor's
+pub fn resolve(
+    proxy: SocketAddr,
+    credentials: Option<(&str, &str)>,
+    host: &str,
+    timeout: Option<Duration>,
+) -> Result<IpAddr> {
+    let mut stream = connect_proxy(proxy, timeout)?;
+    let ip = request(&mut stream, credentials, CMD_RESOLVE, host, 0)
+        .chain_err(|| format!("failed to resolve {} via {}", host, proxy))?;
+    ip.chain_err(|| format!("no address for {} from {}", host, proxy))
+}
+
+fn connect_proxy(proxy: SocketAddr, timeout: Option<Duration>) -> Result<TcpStream> {
+    let stream = match timeout {
+        Some(timeout) => TcpStream::connect_timeout(&proxy, timeout),
+        None => TcpStream::connect(proxy),
+    }
+    .chain_err(|| format!("failed to connect to SOCKS5 proxy {}", proxy))?;
+    stream
+        .set_read_timeout(timeout)
+        .chain_err(|| "failed setting read timeout")?;
+    stream
+        .set_write_timeout(timeout)
+        .chain_err(|| "failed setting write timeout")?;
+    Ok(stream)
+}
+
+/// Authenticate and send the command for `host:port`, returning the bound address of the reply if
+/// it's an IP address
+fn request(
+    stream: &mut (impl Read + Write),
+    credentials: Option<(&str, &str)>,
+    command: u8,
+    host: &str,
+    port: u16,
+) -> Result<Option<IpAddr>> {
+    let method = match credentials {
+        Some(_) => METHOD_PASSWORD,
+        None => METHOD_NONE,
+    };
+    stream
+        .write_all(&[VERSION, 1, method])
+        .chain_err(|| "failed to send greeting")?;
+    let mut reply = [0u8; 2];
+    stream
+        .read_exact(&mut reply)
+        .chain_err(|| "failed to read greeting")?;
+    ensure!(reply[0] == VERSION, "not a SOCKS5 proxy");
+    ensure!(reply[1] == method, "authentication method refused");
+
+    if let Some((username, password)) = credentials {
+        ensure!(
+            username.len() <= 255 && password.len() <= 255,
+            "credentials too long"
+        );
+        let mut request = vec![PASSWORD_VERSION, username.len() as u8];
+        request.extend(username.as_bytes());
+        request.push(password.len() as u8);
+        request.extend(password.as_bytes());
+        stream
+            .write_all(&request)
+            .chain_err(|| "failed to send credentials")?;
+        stream
+            .read_exact(&mut reply)
+            .chain_err(|| "failed to read authentication reply")?;
+        ensure!(reply[1] == 0, "credentials rejected");
+    }
+
+    let mut request = vec![VERSION, command, 0];
+    match host.parse() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(ATYP_IPV4);
+            request.extend(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(ATYP_IPV6);
+            request.extend(&ip.octets());
+        }
+        Err(_) => {
+            ensure!(
+                !host.is_empty() && host.len() <= 255,
+                "invalid hostname {:?}",
+                host
+            );
+            request.push(ATYP_DOMAIN);
+            request.push(host.len() as u8);
+            request.extend(host.as_bytes());
+        }
+    }
+    request.extend(&port.to_be_bytes());
+    stream
+        .write_all(&request)
+        .chain_err(|| "failed to send request")?;
+
+    let mut head = [0u8; 4];
+    stream
+        .read_exact(&mut head)
+        .chain_err(|| "failed to read reply")?;
+    ensure!(head[0] == VERSION, "not a SOCKS5 proxy");
+    if head[1] != 0 {
+        // map the failures to the errors of direct connections, for them to be told apart
+        let kind = match head[1] {
+            3 | 4 => io::ErrorKind::NotConnected,
+            5 => io::ErrorKind::ConnectionRefused,
+            6 => io::ErrorKind::TimedOut,
+            _ => io::ErrorKind::Other,
+        };
+        let message = format!("proxy replied with error {}", head[1]);
+        return Err(Error::with_chain(
+            io::Error::new(kind, message),
+            "request refused",
+        ));
+    }
+    let addr_len = match head[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            stream
+                .read_exact(&mut len)
+                .chain_err(|| "failed to read reply")?;
+            len[0] as usize
+        }
+        atyp => bail!("invalid address type {}", atyp),
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    stream
+        .read_exact(&mut bound)
+        .chain_err(|| "failed to read reply")?;
+    Ok(match head[3] {
+        ATYP_IPV4 => Some(IpAddr::from(*array_ref![bound, 0, 4])),
+        ATYP_IPV6 => Some(IpAddr::from(*array_ref![bound, 0, 16])),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Accept a single connection, and return the bytes of the destination requested with the
+    /// credentials, after replying with `status` and 1.2.3.4 as the bound address
+    fn stub_proxy(status: u8) -> (SocketAddr, thread::JoinHandle<(Vec<u8>, Vec<u8>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            let mut greeting = [0u8; 3];
+            sock.read_exact(&mut greeting).unwrap();
+            sock.write_all(&[VERSION, greeting[2]]).unwrap();
+            let mut credentials = vec![];
+            if greeting[2] == METHOD_PASSWORD {
+                let mut len = [0u8; 2];
+                sock.read_exact(&mut len).unwrap();
+                let mut username = vec![0u8; len[1] as usize + 1];
+                sock.read_exact(&mut username).unwrap();
+                let mut password = vec![0u8; username.pop().unwrap() as usize];
+                sock.read_exact(&mut password).unwrap();
+                credentials = [username, password].join(&b':');
+                sock.write_all(&[PASSWORD_VERSION, 0]).unwrap();
+            }
+            let mut head = [0u8; 5];
+            sock.read_exact(&mut head).unwrap();
+            assert_eq!(head[3], ATYP_DOMAIN);
+            let mut destination = vec![0u8; head[4] as usize + 2];
+            sock.read_exact(&mut destination).unwrap();
+            sock.write_all(&[VERSION, status, 0, ATYP_IPV4, 1, 2, 3, 4, 0, 0])
+                .unwrap();
+            // the client is gone if the connection was refused
+            let _ = sock.write_all(b"hello");
+            (destination, credentials)
+        });
+        (addr, handle)
+    }
+
+    #[test]
+    fn test_connect() {
+        let (proxy, handle) = stub_proxy(0);
+        let timeout = Some(Duration::from_secs(5));
+        let mut stream = connect(
+            proxy,
+            Some(("user", "pass")),
+            "example.onion",
+            50001,
+            timeout,
+        )
+        .unwrap();
+        let mut greeting = [0u8; 5];
+        stream.read_exact(&mut greeting).unwrap();
+        assert_eq!(&greeting, b"hello");
+        let (destination, credentials) = handle.join().unwrap();
+        // the hostname is passed on unresolved
+        assert_eq!(destination, b"example.onion\xc3\x51");
+        assert_eq!(credentials, b"user:pass");
+    }
+
+    #[test]
+    fn test_connect_refused() {
+        let (proxy, handle) = stub_proxy(5);
+        let err = connect(proxy, None, "example.com", 50001, None).unwrap_err();
+        let refused = err
+            .iter()
+            .any(|e| e.to_string().contains("proxy replied with error 5"));
+        assert!(refused, "{}", err.display_chain());
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_resolve() {
+        let (proxy, handle) = stub_proxy(0);
+        let ip = resolve(proxy, None, "example.com", None).unwrap();
+        assert_eq!(ip, IpAddr::from([1, 2, 3, 4]));
+        let (destination, _) = handle.join().unwrap();
+        assert_eq!(destination, b"example.com\0\0");
+    }
+}
@@ -0,0 +1,466 @@
+//! The WebSocket protocol (RFC 6455), carrying the Electrum JSON-RPC messages and the events of the
+//! HTTP API as text frames. Only what's needed to serve browsers (and to probe other servers'
+//! WebSocket services) is implemented: pings are answered and close frames echoed while reading
+//! messages, and extensions aren't negotiated.
+
+use std::io::{self, BufRead, Read, Take, Write};
+
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+
+use crate::errors::*;
+
+/// Appended to the client's key to compute the `Sec-WebSocket-Accept` header
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Messages are rejected above this size, which fits the hex of the largest transactions
+const MAX_MESSAGE_SIZE: u64 = 16 * 1024 * 1024;
+/// Opening handshakes are rejected above this size, headers included
+const MAX_HANDSHAKE_SIZE: u64 = 16 * 1024;
+/// The payload of control frames is limited by the protocol
+const MAX_CONTROL_SIZE: u64 = 125;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// The close status of servers going down
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+
+/// Read the client's opening handshake and accept it
+pub fn accept(reader: &mut impl BufRead, writer: &mut impl Write) -> Result<()> {
+    let mut reader = reader.take(MAX_HANDSHAKE_SIZE);
+    let mut key = None;
+    loop {
+        let line = read_header_line(&mut reader)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("sec-websocket-key") {
+                key = Some(value.trim().to_string());
+            }
+        }
+    }
+    let key = key.chain_err(|| "missing Sec-WebSocket-Key header")?;
+    write!(
+        writer,
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(&key)
+    )
+    .chain_err(|| "failed to send handshake")
+}
+
+/// Send the client's opening handshake for `host`. `key` identifies the handshake, and should be
+/// random.
+pub fn connect(writer: &mut impl Write, host: &str, key: [u8; 16]) -> Result<()> {
+    write!(
+        writer,
+        "GET / HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\n\
+         Sec-WebSocket-Version: 13\r\n\r\n",
+        host,
+        base64::encode(key)
+    )
+    .and_then(|()| writer.flush())
+    .chain_err(|| "failed to send handshake")
+}
+
+/// Read the server's reply to the opening handshake sent with `key`, and check that it accepted it
+pub fn read_connect_reply(reader: &mut impl BufRead, key: [u8; 16]) -> Result<()> {
+    let mut reader = reader.take(MAX_HANDSHAKE_SIZE);
+    let status = read_header_line(&mut reader)?;
+    ensure!(
+        status.split_whitespace().nth(1) == Some("101"),
+        "handshake refused: {:?}",
+        status.trim_end()
+    );
+    let mut accepted = false;
+    loop {
+        let line = read_header_line(&mut reader)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("sec-websocket-accept") {
+                accepted = value.trim() == accept_key(&base64::encode(key));
+            }
+        }
+    }
+    ensure!(accepted, "missing or invalid Sec-WebSocket-Accept header");
+    Ok(())
+}
+
+/// Read a line of the opening handshake, failing once it exceeds `MAX_HANDSHAKE_SIZE`
+fn read_header_line(reader: &mut Take<impl BufRead>) -> Result<String> {
+    let mut line = String::new();
+    reader
+        .read_line(&mut line)
+        .chain_err(|| "failed to read handshake")?;
+    ensure!(!line.is_empty(), "connection closed during handshake");
+    ensure!(line.ends_with('\n'), "handshake too large");
+    Ok(line)
+}
+
+/// The `Sec-WebSocket-Accept` header value accepting the client's `Sec-WebSocket-Key`
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.input_str(key);
+    sha1.input_str(ACCEPT_GUID);
+    let mut digest = [0u8; 20];
+    sha1.result(&mut digest);
+    base64::encode(digest)
+}
+
+/// Read the next text message sent by the client, joining fragmented ones and answering its pings
+/// on `writer`. None once the client closes the connection, after echoing its close frame.
+pub fn read_message(reader: &mut impl Read, writer: &mut impl Write) -> Result<Option<String>> {
+    read_text(reader, writer, true)
+}
+
+/// Read the next text message sent by the server, like `read_message` does for clients
+pub fn read_server_message(
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+) -> Result<Option<String>> {
+    read_text(reader, writer, false)
+}
+
+/// Read the next text message, whose frames must be masked if sent by a client (and so must our
+/// replies to the control frames if sent by a server)
+fn read_text(
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+    from_client: bool,
+) -> Result<Option<String>> {
+    let mut message = vec![];
+    loop {
+        let mut head = [0u8; 2];
+        match reader.read_exact(&mut head) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            result => result.chain_err(|| "failed to read frame")?,
+        }
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0f;
+        let masked = head[1] & 0x80 != 0;
+        ensure!(masked || !from_client, "unmasked client frame");
+        ensure!(!masked || from_client, "masked server frame");
+        let len = match head[1] & 0x7f {
+            126 => {
+                let mut len = [0u8; 2];
+                reader
+                    .read_exact(&mut len)
+                    .chain_err(|| "truncated frame")?;
+                u16::from_be_bytes(len) as u64
+            }
+            127 => {
+                let mut len = [0u8; 8];
+                reader
+                    .read_exact(&mut len)
+                    .chain_err(|| "truncated frame")?;
+                let len = u64::from_be_bytes(len);
+                // the most significant bit must be 0
+                ensure!(len >> 63 == 0, "invalid frame length");
+                len
+            }
+            len => len as u64,
+        };
+        let control = opcode & 0x08 != 0;
+        ensure!(
+            !control || (fin && len <= MAX_CONTROL_SIZE),
+            "invalid control frame"
+        );
+        // never above the limit, as checked for the previous fragments
+        ensure!(
+            len <= MAX_MESSAGE_SIZE - message.len() as u64,
+            "message too large"
+        );
+        let mut mask = [0u8; 4];
+        if masked {
+            reader
+                .read_exact(&mut mask)
+                .chain_err(|| "truncated frame")?;
+        }
+        let mut payload = vec![0u8; len as usize];
+        reader
+            .read_exact(&mut payload)
+            .chain_err(|| "truncated frame")?;
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+
+        // control frames may arrive between the fragments of a message
+        let reply_mask = if from_client {
+            None
+        } else {
+            Some(rand::random())
+        };
+        match opcode {
+            OPCODE_CLOSE => {
+                // echo the status code, if any. the peer may not wait for it, so failing is fine.
+                let code = &payload[..payload.len().min(2)];
+                let _ = write_frame(writer, OPCODE_CLOSE, code, reply_mask)
+                    .and_then(|()| writer.flush());
+                return Ok(None);
+            }
+            OPCODE_PING => {
+                write_frame(writer, OPCODE_PONG, &payload, reply_mask)
+                    .and_then(|()| writer.flush())
+                    .chain_err(|| "failed to send pong")?;
+                continue;
+            }
+            OPCODE_PONG => continue,
+            OPCODE_TEXT | OPCODE_CONTINUATION => message.extend(payload),
+            _ => bail!("unsupported websocket opcode {}", opcode),
+        }
+        if fin {
+            let message = String::from_utf8(message).chain_err(|| "invalid UTF8")?;
+            return Ok(Some(message));
+        }
+    }
+}
+
+/// Send the text as a single unmasked frame, as servers do
+pub fn write_text(writer: &mut impl Write, text: &str) -> io::Result<()> {
+    write_frame(writer, OPCODE_TEXT, text.as_bytes(), None)
+}
+
+/// Send the text as a single frame masked with `mask`, as clients do. The mask should be random
+/// for each frame.
+pub fn write_masked_text(writer: &mut impl Write, text: &str, mask: [u8; 4]) -> io::Result<()> {
+    write_frame(writer, OPCODE_TEXT, text.as_bytes(), Some(mask))
+}
+
+/// Send a close frame with the status code, after which no more messages may be sent
+pub fn write_close(writer: &mut impl Write, code: u16) -> io::Result<()> {
+    write_frame(writer, OPCODE_CLOSE, &code.to_be_bytes(), None)
+}
+
+fn write_frame(
+    writer: &mut impl Write,
+    opcode: u8,
+    payload: &[u8],
+    mask: Option<[u8; 4]>,
+) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+    frame.push(0x80 | opcode);
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    match payload.len() {
+        len if len < 126 => frame.push(mask_bit | len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(mask_bit | 126);
+            frame.extend(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(mask_bit | 127);
+            frame.extend(&(len as u64).to_be_bytes());
+        }
+    }
+    match mask {
+        Some(mask) => {
+            frame.extend(&mask);
+            let masked = payload
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| byte ^ mask[i % 4]);
+            frame.extend(masked);
+        }
+        None => frame.extend(payload),
+    }
+    writer.write_all(&frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handshake() {
+        // the example from RFC 6455
+        let request = "GET /chat HTTP/1.1\r\n\
+                       Host: server.example.com\r\n\
+                       Upgrade: websocket\r\n\
+                       Connection: Upgrade\r\n\
+                       Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                       Sec-WebSocket-Version: 13\r\n\r\n";
+        let mut response = vec![];
+        accept(&mut request.as_bytes(), &mut response).unwrap();
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 "));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+        assert!(accept(&mut "GET / HTTP/1.1\r\n\r\n".as_bytes(), &mut vec![]).is_err());
+
+        // endless header lines aren't read into memory
+        let endless = format!("GET / HTTP/1.1\r\nX-Padding: {}", "x".repeat(1 << 20));
+        let err = accept(&mut endless.as_bytes(), &mut vec![]).unwrap_err();
+        assert_eq!(err.to_string(), "handshake too large");
+        let err = read_connect_reply(&mut endless.as_bytes(), [0; 16]).unwrap_err();
+        assert_eq!(err.to_string(), "handshake too large");
+    }
+
+    #[test]
+    fn test_frames() {
+        // a masked "Hello" sent as two fragments with a ping in between, from RFC 6455
+        let frames: &[u8] = &[
+            0x01, 0x83, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, // "Hel"
+            0x89, 0x80, 0x00, 0x00, 0x00, 0x00, // ping
+            0x80, 0x82, 0x37, 0xfa, 0x21, 0x3d, 0x5b, 0x95, // "lo"
+            0x88, 0x80, 0x00, 0x00, 0x00, 0x00, // close
+        ];
+        let mut reader = frames;
+        let mut replies = vec![];
+        assert_eq!(
+            read_message(&mut reader, &mut replies).unwrap().unwrap(),
+            "Hello"
+        );
+        // the empty ping was answered
+        assert_eq!(replies, [0x8a, 0x00]);
+        replies.clear();
+        assert_eq!(read_message(&mut reader, &mut replies).unwrap(), None);
+        assert_eq!(replies, [0x88, 0x00]);
+
+        // unmasked frames are rejected
+        let mut unmasked: &[u8] = &[0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+        assert!(read_message(&mut unmasked, &mut vec![]).is_err());
+
+        let mut frame = vec![];
+        write_text(&mut frame, "Hello").unwrap();
+        assert_eq!(frame, [0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f]);
+        let mut frame = vec![];
+        write_text(&mut frame, &"x".repeat(256)).unwrap();
+        assert_eq!(frame[..4], [0x81, 126, 0x01, 0x00]);
+
+        let mut frame = vec![];
+        write_close(&mut frame, CLOSE_GOING_AWAY).unwrap();
+        assert_eq!(frame, [0x88, 0x02, 0x03, 0xe9]);
+    }
+
+    #[test]
+    fn test_client() {
+        // the server accepts the client's handshake, and the client checks the server's reply
+        let key = *b"the sample nonce";
+        let mut request = vec![];
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(&base64::encode(key))
+        );
+        connect(&mut request, "example.com", key).unwrap();
+        read_connect_reply(&mut response.as_bytes(), key).unwrap();
+        accept(&mut request.as_slice(), &mut vec![]).unwrap();
+        assert!(String::from_utf8(request)
+            .unwrap()
+            .contains("Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n"));
+
+        let refused = "HTTP/1.1 400 Bad Request\r\n\r\n";
+        assert!(read_connect_reply(&mut refused.as_bytes(), key).is_err());
+        let invalid = "HTTP/1.1 101 Switching Protocols\r\nSec-WebSocket-Accept: x\r\n\r\n";
+        assert!(read_connect_reply(&mut invalid.as_bytes(), key).is_err());
+
+        // masked frames are read by servers, unmasked ones by clients
+        let mut frame = vec![];
+        write_masked_text(&mut frame, "Hello", [0x37, 0xfa, 0x21, 0x3d]).unwrap();
+        assert_eq!(
+            frame,
+            [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58]
+        );
+        assert!(read_server_message(&mut frame.as_slice(), &mut vec![]).is_err());
+        assert_eq!(
+            read_message(&mut frame.as_slice(), &mut vec![])
+                .unwrap()
+                .unwrap(),
+            "Hello"
+        );
+        let mut frame = vec![];
+        write_text(&mut frame, "Hello").unwrap();
+        assert_eq!(
+            read_server_message(&mut frame.as_slice(), &mut vec![])
+                .unwrap()
+                .unwrap(),
+            "Hello"
+        );
+    }
+
+    #[test]
+    fn test_control_frames() {
+        // a masked ping with a payload, then a close with its status code and reason
+        let mut frames = vec![];
+        write_frame(&mut frames, OPCODE_PING, b"ping", Some([1, 2, 3, 4])).unwrap();
+        let mut close = CLOSE_GOING_AWAY.to_be_bytes().to_vec();
+        close.extend(b"bye");
+        write_frame(&mut frames, OPCODE_CLOSE, &close, Some([5, 6, 7, 8])).unwrap();
+
+        // servers answer with unmasked frames, pongs echoing the ping payload and closes the code
+        let mut reader = frames.as_slice();
+        let mut replies = vec![];
+        assert_eq!(read_message(&mut reader, &mut replies).unwrap(), None);
+        let mut expected = vec![];
+        write_frame(&mut expected, OPCODE_PONG, b"ping", None).unwrap();
+        write_close(&mut expected, CLOSE_GOING_AWAY).unwrap();
+        assert_eq!(replies, expected);
+
+        // clients answer with masked frames
+        let mut frames = vec![];
+        write_frame(&mut frames, OPCODE_PING, b"ping", None).unwrap();
+        write_text(&mut frames, "Hello").unwrap();
+        let mut replies = vec![];
+        assert_eq!(
+            read_server_message(&mut frames.as_slice(), &mut replies)
+                .unwrap()
+                .unwrap(),
+            "Hello"
+        );
+        assert_eq!(replies[..2], [0x80 | OPCODE_PONG, 0x80 | 4]);
+        let mask = &replies[2..6];
+        let payload: Vec<u8> = replies[6..]
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ mask[i % 4])
+            .collect();
+        assert_eq!(payload, b"ping");
+
+        // control frames can't be fragmented nor too large
+        let mut fragmented: &[u8] = &[0x09, 0x80, 0x00, 0x00, 0x00, 0x00];
+        assert!(read_message(&mut fragmented, &mut vec![]).is_err());
+        let mut large = vec![];
+        write_frame(&mut large, OPCODE_PING, &[0; 126], Some([0; 4])).unwrap();
+        assert!(read_message(&mut large.as_slice(), &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn test_message_size() {
+        // a non-final "Hel" fragment, then a continuation whose length would wrap the total size
+        // around below the limit
+        let frames = |len: u64| {
+            let mut frames = vec![0x01, 0x83, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d];
+            frames.extend(&[0x80, 0x80 | 127]);
+            frames.extend(&len.to_be_bytes());
+            frames.extend(&[0x00, 0x00, 0x00, 0x00]);
+            frames
+        };
+        let wrapping = frames(u64::MAX - 2);
+        let err = read_message(&mut wrapping.as_slice(), &mut vec![]).unwrap_err();
+        assert_eq!(err.to_string(), "invalid frame length");
+        let large = frames(u64::MAX >> 1);
+        let err = read_message(&mut large.as_slice(), &mut vec![]).unwrap_err();
+        assert_eq!(err.to_string(), "message too large");
+        let large = frames(MAX_MESSAGE_SIZE - 2);
+        let err = read_message(&mut large.as_slice(), &mut vec![]).unwrap_err();
+        assert_eq!(err.to_string(), "message too large");
+
+        // up to the limit, the payload is read
+        let truncated = frames(MAX_MESSAGE_SIZE - 3);
+        let err = read_message(&mut truncated.as_slice(), &mut vec![]).unwrap_err();
+        assert_eq!(err.to_string(), "truncated frame");
+    }
+}